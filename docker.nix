@@ -15,5 +15,8 @@ dockerTools.buildLayeredImage {
     ExposedPorts = {
       "80/tcp" = { };
     };
+    Healthcheck = {
+      Test = [ "CMD" "taspromto" "healthcheck" ];
+    };
   };
 }
@@ -91,6 +91,8 @@ in
 
         ExecStart = "${cfg.package}/bin/taspromto ${configFile}";
 
+        Type = "notify";
+        WatchdogSec = 60;
         Restart = "on-failure";
         DynamicUser = true;
         PrivateTmp = true;
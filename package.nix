@@ -5,7 +5,7 @@
 }:
 let
   inherit (lib.sources) sourceByRegex;
-  src = sourceByRegex ./. [ "Cargo.*" "(src)(/.*)?" ];
+  src = sourceByRegex ./. [ "Cargo.*" "(src|fixtures)(/.*)?" ];
 in
 rustPlatform.buildRustPackage rec {
   pname = "taspromto";
@@ -0,0 +1,97 @@
+use crate::config::AirQualityConfig;
+use crate::metrics::{self, Registry};
+
+/// Band a CO² concentration falls in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Co2Level {
+    Good,
+    Elevated,
+    High,
+}
+
+impl Co2Level {
+    const ALL: [Co2Level; 3] = [Co2Level::Good, Co2Level::Elevated, Co2Level::High];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Co2Level::Good => "good",
+            Co2Level::Elevated => "elevated",
+            Co2Level::High => "high",
+        }
+    }
+}
+
+impl AirQualityConfig {
+    pub fn co2_level(&self, co2: f32) -> Co2Level {
+        if co2 >= self.co2_high {
+            Co2Level::High
+        } else if co2 >= self.co2_elevated {
+            Co2Level::Elevated
+        } else {
+            Co2Level::Good
+        }
+    }
+
+    /// Air quality index for a PM2.5 concentration, interpolated between the breakpoints
+    pub fn index(&self, pm2_5: f32) -> Option<f32> {
+        let (first, rest) = self.pm2_5.split_first()?;
+        if pm2_5 <= first.0 {
+            return Some(first.1);
+        }
+        let mut low = first;
+        for high in rest {
+            if pm2_5 <= high.0 {
+                let fraction = (pm2_5 - low.0) / (high.0 - low.0);
+                return Some((low.1 + fraction * (high.1 - low.1)).round());
+            }
+            low = high;
+        }
+        // beyond the last breakpoint
+        Some(low.1)
+    }
+}
+
+/// Add the air quality metrics for the CO² and PM2.5 readings of a device, if enabled
+pub fn format_air_quality(
+    registry: &mut Registry,
+    labels: &[(&'static str, &str)],
+    co2: Option<f32>,
+    pm2_5: Option<f32>,
+) {
+    let Some(config) = registry.air_quality() else {
+        return;
+    };
+    let index = pm2_5.and_then(|pm2_5| config.index(pm2_5));
+    let level = co2.map(|co2| config.co2_level(co2));
+
+    if let Some(index) = index {
+        registry.add(&metrics::AIR_QUALITY_INDEX, labels, index);
+    }
+    if let Some(level) = level {
+        for category in Co2Level::ALL {
+            let mut labels = labels.to_vec();
+            labels.push(("category", category.as_str()));
+            registry.add(&metrics::CO2_LEVEL, &labels, category == level);
+        }
+    }
+}
+
+#[test]
+fn test_air_quality() {
+    let config = AirQualityConfig::default();
+    assert_eq!(Co2Level::Good, config.co2_level(450.0));
+    assert_eq!(Co2Level::Elevated, config.co2_level(1000.0));
+    assert_eq!(Co2Level::High, config.co2_level(2400.0));
+
+    assert_eq!(Some(0.0), config.index(0.0));
+    assert_eq!(Some(50.0), config.index(9.0));
+    assert_eq!(Some(75.0), config.index(22.2));
+    assert_eq!(Some(175.0), config.index(90.4));
+    assert_eq!(Some(500.0), config.index(800.0));
+
+    let empty = AirQualityConfig {
+        pm2_5: Vec::new(),
+        ..AirQualityConfig::default()
+    };
+    assert_eq!(None, empty.index(10.0));
+}
@@ -0,0 +1,94 @@
+use crate::config::AnomalyConfig;
+use std::time::{Duration, Instant};
+
+/// Rolling averages of the power usage of a device, to detect usage that deviates from the usual pattern
+///
+/// The averages are weighted by time, so a device that only reports changes is averaged correctly.
+#[derive(Debug, Clone)]
+pub struct PowerBaseline {
+    /// Average over the anomaly window
+    pub recent: f64,
+    /// Average over the baseline period
+    pub baseline: f64,
+    /// Ratio between the recent average and the baseline, in either direction
+    pub score: Option<f64>,
+    pub anomalous: bool,
+    power: f64,
+    first_seen: Instant,
+    last_update: Instant,
+}
+
+impl PowerBaseline {
+    pub fn new(power: f32, now: Instant) -> Self {
+        PowerBaseline {
+            recent: power.into(),
+            baseline: power.into(),
+            score: None,
+            anomalous: false,
+            power: power.into(),
+            first_seen: now,
+            last_update: now,
+        }
+    }
+
+    pub fn update(&mut self, power: f32, now: Instant, config: &AnomalyConfig) {
+        // the previous reading applied until now
+        let elapsed = now.duration_since(self.last_update);
+        self.recent = average(self.recent, self.power, elapsed, config.window());
+        self.baseline = average(self.baseline, self.power, elapsed, config.baseline());
+        self.power = power.into();
+        self.last_update = now;
+
+        // the recent average isn't meaningful before a full window has passed
+        self.score = (now.duration_since(self.first_seen) >= config.window()).then(|| {
+            let recent = self.recent.max(config.min_power);
+            let baseline = self.baseline.max(config.min_power);
+            (recent / baseline).max(baseline / recent)
+        });
+        self.anomalous = self.score.is_some_and(|score| score >= config.factor);
+    }
+}
+
+/// Exponential moving average with a time constant of `period`
+fn average(average: f64, value: f64, elapsed: Duration, period: Duration) -> f64 {
+    let weight = 1.0 - (-elapsed.as_secs_f64() / period.as_secs_f64().max(1.0)).exp();
+    average + (value - average) * weight
+}
+
+#[test]
+fn test_power_baseline() {
+    let config = AnomalyConfig::default();
+    let start = Instant::now();
+    let minutes = |minutes: u64| start + Duration::from_secs(minutes * 60);
+
+    // compressor running for 10 minutes out of every 40
+    let mut baseline = PowerBaseline::new(100.0, start);
+    for minute in (10..=48 * 60).step_by(10) {
+        let power = if minute % 40 == 0 { 100.0 } else { 0.0 };
+        baseline.update(power, minutes(minute), &config);
+    }
+    assert!(!baseline.anomalous);
+    let mut stopped = baseline.clone();
+
+    // stuck running
+    for minute in (48 * 60 + 10..=51 * 60).step_by(10) {
+        baseline.update(100.0, minutes(minute), &config);
+    }
+    assert!(baseline.anomalous);
+
+    // not running at all
+    for minute in (48 * 60 + 10..=51 * 60).step_by(10) {
+        stopped.update(0.0, minutes(minute), &config);
+    }
+    assert!(stopped.anomalous);
+}
+
+#[test]
+fn test_power_baseline_warm_up() {
+    let config = AnomalyConfig::default();
+    let start = Instant::now();
+    let mut baseline = PowerBaseline::new(0.0, start);
+    baseline.update(2000.0, start + Duration::from_secs(60), &config);
+    assert_eq!(None, baseline.score);
+    assert!(!baseline.anomalous);
+}
@@ -0,0 +1,561 @@
+use crate::config::{persist_name, Config, NamesConfig};
+use crate::csv::{self, format_csv};
+use crate::device::{BDAddr, Device, RfDeviceId, SharedDeviceStates};
+use crate::history::{numeric_readings, unix_time, History, HistoryQuery, Row};
+use crate::mqtt::{command, ConnectionState};
+use crate::pattern::Pattern;
+use crate::stats::{FailedPayload, Stats};
+use rumqttc::AsyncClient;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::spawn;
+use tokio::time::sleep;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::error;
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::sse::Event;
+use warp::{Filter, Reply};
+
+/// Maximum size of a command payload
+const MAX_BODY_SIZE: u64 = 4 * 1024;
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub device_states: SharedDeviceStates,
+    pub config: Arc<RwLock<Config>>,
+    pub connection: Arc<Mutex<ConnectionState>>,
+    pub stats: Arc<Stats>,
+    pub history: Option<Arc<History>>,
+    /// Config file to save names to, if the config wasn't loaded from the environment
+    pub config_path: Option<String>,
+}
+
+/// All routes under `/api`
+pub fn routes(state: ApiState) -> BoxedFilter<(Response,)> {
+    let with_state = warp::any().map(move || state.clone());
+
+    let device = warp::path!("device" / String)
+        .and(warp::get())
+        .and(with_state.clone())
+        .then(device);
+
+    let power = warp::path!("device" / String / "power")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(power);
+
+    let stream = warp::path!("stream")
+        .and(warp::get())
+        .and(with_state.clone())
+        .then(stream);
+
+    let errors = warp::path!("errors")
+        .and(warp::get())
+        .and(with_state.clone())
+        .map(errors);
+
+    let history = warp::path!("history")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_state.clone())
+        .and_then(history);
+
+    let export = warp::path!("export.csv")
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_state.clone())
+        .and_then(export);
+
+    let mitemp_name = warp::path!("names" / "mitemp" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(mitemp_name);
+
+    let rftemp_name = warp::path!("names" / "rftemp" / String)
+        .and(warp::put())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(rftemp_name);
+
+    let cmnd = warp::path!("device" / String / "cmnd" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(cmnd);
+
+    let bulk = warp::path!("command")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(bulk_command);
+
+    device
+        .or(power)
+        .unify()
+        .or(cmnd)
+        .unify()
+        .or(bulk)
+        .unify()
+        .or(stream)
+        .unify()
+        .or(errors)
+        .unify()
+        .or(history)
+        .unify()
+        .or(export)
+        .unify()
+        .or(mitemp_name)
+        .unify()
+        .or(rftemp_name)
+        .unify()
+        .boxed()
+}
+
+/// Prometheus http service discovery for all tracked devices
+pub fn service_discovery(state: ApiState) -> BoxedFilter<(Response,)> {
+    warp::get()
+        .then(move || service_discovery_targets(state.clone()))
+        .boxed()
+}
+
+async fn service_discovery_targets(state: ApiState) -> Response {
+    let device_states = state.device_states.read().await;
+    let config = state.config.read().unwrap();
+    let mut targets = Vec::new();
+    for (device, device_state) in device_states.devices() {
+        let name = device_state.display_name(device, &config.names.tasmota);
+        let target = device_state.ip.as_deref().unwrap_or(&device.hostname);
+        let mut labels = json!({
+            "hostname": device.hostname.as_ref(),
+            "name": name,
+            "type": "tasmota",
+        });
+        for (label, value) in config.metrics.device_labels(&[&device.hostname, name]) {
+            labels[label] = value.into();
+        }
+        targets.push(json!({ "targets": [target], "labels": labels }));
+    }
+    for (device, _) in device_states.dsmr_devices() {
+        let mut labels = json!({
+            "hostname": device.hostname.as_ref(),
+            "name": device.hostname.as_ref(),
+            "type": "dsmr",
+        });
+        for (label, value) in config.metrics.device_labels(&[&device.hostname]) {
+            labels[label] = value.into();
+        }
+        targets.push(json!({ "targets": [device.hostname.as_ref()], "labels": labels }));
+    }
+    json_response(targets.into(), StatusCode::OK)
+}
+
+fn json_response(json: Value, status: StatusCode) -> Response {
+    warp::reply::with_status(
+        warp::reply::with_header(json.to_string(), header::CONTENT_TYPE, "application/json"),
+        status,
+    )
+    .into_response()
+}
+
+fn error_response(error: &str, status: StatusCode) -> Response {
+    json_response(json!({ "error": error }), status)
+}
+
+async fn device(hostname: String, state: ApiState) -> Response {
+    let device_states = state.device_states.read().await;
+    let names = &state.config.read().unwrap().names.tasmota;
+    match device_states.devices.get_key_value(hostname.as_str()) {
+        Some((device, device_state)) => {
+            json_response(device_state.to_json(device, names), StatusCode::OK)
+        }
+        None => error_response("device not found", StatusCode::NOT_FOUND),
+    }
+}
+
+/// Server-sent events for every changed value
+async fn stream(state: ApiState) -> Response {
+    let updates = state.device_states.read().await.subscribe();
+    // updates missed by slow clients are skipped
+    let events = BroadcastStream::new(updates).filter_map(|update| {
+        let update = update.ok()?;
+        Some(Ok::<_, Infallible>(
+            Event::default()
+                .event("update")
+                .data(update.to_json().to_string()),
+        ))
+    });
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+/// The most recent payloads that couldn't be parsed
+fn errors(state: ApiState) -> Response {
+    let errors = state
+        .stats
+        .failed_payloads()
+        .iter()
+        .map(FailedPayload::to_json)
+        .collect::<Vec<_>>();
+    json_response(errors.into(), StatusCode::OK)
+}
+
+async fn history(query: HistoryQuery, state: ApiState) -> Result<Response, warp::Rejection> {
+    let Some(history) = &state.history else {
+        return Err(warp::reject::not_found());
+    };
+    let now = unix_time();
+    match history
+        .blocking(move |history| history.query(&query, now))
+        .await
+    {
+        Ok(series) => Ok(json_response(series, StatusCode::OK)),
+        Err(e) => {
+            error!("Failed to query history: {:#}", e);
+            Ok(error_response(
+                "failed to query history",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+/// Current readings as csv, or the stored readings if a time range is requested
+async fn export(query: HistoryQuery, state: ApiState) -> Result<Response, warp::Rejection> {
+    let now = unix_time();
+    let rows = if query.from.is_some() || query.to.is_some() {
+        let Some(history) = &state.history else {
+            return Ok(error_response(
+                "history is not enabled",
+                StatusCode::BAD_REQUEST,
+            ));
+        };
+        match history
+            .blocking(move |history| history.rows(&query, now))
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Failed to query history: {:#}", e);
+                return Ok(error_response(
+                    "failed to query history",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ));
+            }
+        }
+    } else {
+        current_rows(&state, &query, now).await
+    };
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(format_csv(&rows), header::CONTENT_TYPE, csv::CONTENT_TYPE),
+        header::CONTENT_DISPOSITION,
+        "attachment; filename=\"export.csv\"",
+    )
+    .into_response())
+}
+
+async fn current_rows(state: &ApiState, query: &HistoryQuery, now: u64) -> Vec<Row> {
+    let snapshots = {
+        let device_states = state.device_states.read().await;
+        device_states.snapshots(&state.config.read().unwrap().names)
+    };
+    let mut rows = Vec::new();
+    for (device, snapshot) in &snapshots {
+        if query.device.as_ref().is_some_and(|filter| filter != device) {
+            continue;
+        }
+        for (metric, value) in numeric_readings(snapshot) {
+            if query
+                .metric
+                .as_deref()
+                .map_or(true, |filter| filter == metric)
+            {
+                rows.push((now, device.clone(), metric.to_string(), value));
+            }
+        }
+    }
+    rows
+}
+
+async fn mitemp_name(
+    mac: String,
+    body: Bytes,
+    state: ApiState,
+) -> Result<Response, warp::Rejection> {
+    let Ok(address) = BDAddr::from_mi_temp_mac_part(&mac) else {
+        return Ok(error_response(
+            "invalid mac address",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    set_name(
+        &state,
+        &body,
+        "mitemp",
+        &mac.to_ascii_uppercase(),
+        |key| BDAddr::from_mi_temp_mac_part(key).is_ok_and(|key| key == address),
+        |names, name| {
+            names.mi_temp.insert(address, name);
+        },
+    )
+}
+
+async fn rftemp_name(
+    id: String,
+    body: Bytes,
+    state: ApiState,
+) -> Result<Response, warp::Rejection> {
+    let Ok(device_id) = RfDeviceId::from_str(&id) else {
+        return Ok(error_response(
+            "invalid device id, expected <name>:<id>:<channel>",
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+    // names apply to the sensor regardless of the receiver
+    let device_id = device_id.sensor().to_owned();
+    set_name(
+        &state,
+        &body,
+        "rftemp",
+        &device_id.to_string(),
+        |key| RfDeviceId::from_str(key).is_ok_and(|key| key == device_id),
+        |names, name| {
+            names.rf_temp.insert(device_id.clone(), name);
+        },
+    )
+}
+
+/// Update a name at runtime and optionally save it to the config file
+fn set_name(
+    state: &ApiState,
+    body: &[u8],
+    kind: &str,
+    key: &str,
+    matches: impl Fn(&str) -> bool,
+    insert: impl FnOnce(&mut NamesConfig, String),
+) -> Result<Response, warp::Rejection> {
+    let name = String::from_utf8_lossy(body).trim().to_string();
+    // the lock is held while saving, so concurrent updates don't overwrite each other
+    let mut config = state.config.write().unwrap();
+    let persist = match &config.control {
+        Some(control) => control.persist_names,
+        None => return Err(warp::reject::not_found()),
+    };
+    if name.is_empty() {
+        return Ok(error_response(
+            "name can't be empty",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    insert(&mut config.names, name.clone());
+    config.revision += 1;
+    if let (true, Some(path)) = (persist, &state.config_path) {
+        if let Err(e) = persist_name(path, kind, key, &name, matches) {
+            error!("Failed to save name: {:#}", e);
+            return Ok(error_response(
+                "failed to save name to the config file",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+    Ok(json_response(json!({ "name": name }), StatusCode::OK))
+}
+
+async fn power(
+    hostname: String,
+    body: Bytes,
+    state: ApiState,
+) -> Result<Response, warp::Rejection> {
+    if state.config.read().unwrap().control.is_none() {
+        return Err(warp::reject::not_found());
+    }
+    let body = String::from_utf8_lossy(&body);
+    let power = match body.trim().to_ascii_lowercase().as_str() {
+        "on" => "ON",
+        "off" => "OFF",
+        "toggle" => "TOGGLE",
+        _ => {
+            return Ok(error_response(
+                "power state must be one of on, off or toggle",
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    Ok(send_command(&state, Device::from(hostname.as_str()), "POWER", power).await)
+}
+
+async fn cmnd(
+    hostname: String,
+    command: String,
+    body: Bytes,
+    state: ApiState,
+) -> Result<Response, warp::Rejection> {
+    let allowed = match &state.config.read().unwrap().control {
+        Some(control) => control.allows(&command),
+        None => return Err(warp::reject::not_found()),
+    };
+    if !allowed {
+        return Ok(error_response("command not allowed", StatusCode::FORBIDDEN));
+    }
+    let body = String::from_utf8_lossy(&body);
+    Ok(send_command(&state, Device::from(hostname.as_str()), &command, &body).await)
+}
+
+/// A command to send to every device matching the selectors
+#[derive(Debug, Deserialize)]
+struct BulkCommand {
+    command: String,
+    #[serde(default)]
+    payload: String,
+    /// Pattern matching the hostname or name of the device
+    name: Option<Pattern>,
+    /// Labels the device needs to have, as configured in `metrics.devices`
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl BulkCommand {
+    fn matches(&self, hostname: &str, name: &str, labels: &BTreeMap<String, String>) -> bool {
+        let name_matches = self.name.as_ref().map_or(true, |pattern| {
+            pattern.matches(hostname) || pattern.matches(name)
+        });
+        name_matches
+            && self
+                .labels
+                .iter()
+                .all(|(label, value)| labels.get(label) == Some(value))
+    }
+}
+
+async fn bulk_command(body: Bytes, state: ApiState) -> Result<Response, warp::Rejection> {
+    let Some(control) = state.config.read().unwrap().control.clone() else {
+        return Err(warp::reject::not_found());
+    };
+    let request: BulkCommand = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return Ok(error_response(
+                &format!("invalid command request: {e}"),
+                StatusCode::BAD_REQUEST,
+            ))
+        }
+    };
+    // power is always allowed, same as for the power endpoint
+    if !request.command.eq_ignore_ascii_case("POWER") && !control.allows(&request.command) {
+        return Ok(error_response("command not allowed", StatusCode::FORBIDDEN));
+    }
+    if request.name.is_none() && request.labels.is_empty() {
+        return Ok(error_response(
+            "a name or labels selector is required, use \"*\" as name to select every device",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let mut devices: Vec<Device> = {
+        let device_states = state.device_states.read().await;
+        let config = state.config.read().unwrap();
+        device_states
+            .devices()
+            .filter(|(device, device_state)| {
+                let name = device_state.display_name(device, &config.names.tasmota);
+                let labels = config.metrics.device_labels(&[&device.hostname, name]);
+                request.matches(&device.hostname, name, &labels)
+            })
+            .map(|(device, _)| device.clone())
+            .collect()
+    };
+    devices.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+    let Some(client) = state.connection.lock().unwrap().client() else {
+        return Ok(error_response(
+            "mqtt not connected",
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    };
+
+    let hostnames: Vec<Value> = devices
+        .iter()
+        .map(|device| device.hostname.as_ref().into())
+        .collect();
+    spawn(send_bulk_command(
+        client,
+        state.stats.clone(),
+        devices,
+        request,
+        control.bulk_interval(),
+    ));
+    Ok(json_response(
+        json!({ "devices": hostnames }),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// Send the command to every device in turn, to not flood the broker
+async fn send_bulk_command(
+    client: AsyncClient,
+    stats: Arc<Stats>,
+    devices: Vec<Device>,
+    request: BulkCommand,
+    interval: Duration,
+) {
+    for (index, device) in devices.iter().enumerate() {
+        if index > 0 {
+            sleep(interval).await;
+        }
+        if let Err(e) = command(
+            &client,
+            &stats,
+            "",
+            device,
+            &request.command,
+            &request.payload,
+        )
+        .await
+        {
+            error!(
+                "Failed to send {} to {}: {:#}",
+                request.command, device.hostname, e
+            );
+        }
+    }
+}
+
+async fn send_command(
+    state: &ApiState,
+    device: Device,
+    command_name: &str,
+    body: &str,
+) -> Response {
+    if !state
+        .device_states
+        .read()
+        .await
+        .devices
+        .contains_key(&device)
+    {
+        return error_response("device not found", StatusCode::NOT_FOUND);
+    }
+    let Some(client) = state.connection.lock().unwrap().client() else {
+        return error_response("mqtt not connected", StatusCode::SERVICE_UNAVAILABLE);
+    };
+    match command(&client, &state.stats, "", &device, command_name, body).await {
+        Ok(()) => json_response(json!({ "sent": true }), StatusCode::ACCEPTED),
+        Err(e) => {
+            error!("Failed to send command: {:#}", e);
+            error_response("failed to send command", StatusCode::BAD_GATEWAY)
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+#[derive(Debug)]
+struct Forbidden;
+
+impl Reject for Forbidden {}
+
+/// Reject any request that doesn't provide the expected `Authorization` header
+pub fn authorization(expected: Option<String>) -> BoxedFilter<()> {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = match (&expected, header) {
+                (None, _) => true,
+                (Some(expected), Some(header)) => {
+                    constant_time_eq(expected.as_bytes(), header.as_bytes())
+                }
+                (Some(_), None) => false,
+            };
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Reject any request from a client outside the allowed networks
+///
+/// Requests without a remote address (from a unix socket) are always allowed
+pub fn remote_allowed(allow: Vec<IpNet>) -> BoxedFilter<()> {
+    warp::addr::remote()
+        .and_then(move |remote: Option<SocketAddr>| {
+            let allowed = match remote.map(|addr| canonical_ip(addr.ip())) {
+                _ if allow.is_empty() => true,
+                Some(ip) => allow.iter().any(|net| net.contains(&ip)),
+                None => true,
+            };
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Forbidden))
+                }
+            }
+        })
+        .untuple_one()
+        .boxed()
+}
+
+/// Clients connecting over ipv4 to a dual stack listener show up as ipv4-mapped ipv6 addresses
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// Turn authorization failures into proper 401 or 403 responses
+pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    if err.find::<Forbidden>().is_some() {
+        Ok(Box::new(warp::reply::with_status(
+            "Forbidden",
+            StatusCode::FORBIDDEN,
+        )))
+    } else if err.find::<Unauthorized>().is_some() {
+        Ok(Box::new(warp::reply::with_header(
+            warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED),
+            header::WWW_AUTHENTICATE,
+            r#"Basic realm="taspromto""#,
+        )))
+    } else {
+        Err(err)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
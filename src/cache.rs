@@ -0,0 +1,266 @@
+use crate::config::Config;
+use crate::device::DeviceStates;
+use crate::firmware::FirmwareVersion;
+use crate::metrics::{render_header, MetricFamily, Registry};
+use crate::stats::{format_stats, Stats};
+use crate::{visit_devices, visit_tenant, DeviceKey, MetricsQuery};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use warp::hyper::body::Bytes;
+
+/// Revisions of the device state and config that a device was rendered for
+type Revision = (u64, u64, Option<FirmwareVersion>);
+
+struct CachedDevice {
+    revision: Revision,
+    /// Rendered samples by metric family
+    families: Vec<(&'static MetricFamily, Bytes)>,
+    series: usize,
+    /// The render this device was last included in
+    used: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    devices: HashMap<DeviceKey, CachedDevice>,
+    renders: u64,
+}
+
+/// The rendered metrics of every device, so only devices that changed since the previous scrape are rendered again
+#[derive(Default)]
+pub struct RenderCache {
+    cache: Mutex<Cache>,
+}
+
+/// Rendered samples grouped by family, in the order the families are first seen
+#[derive(Default)]
+struct Families {
+    families: Vec<(&'static MetricFamily, Vec<Bytes>)>,
+    index: HashMap<&'static str, usize>,
+    series: usize,
+}
+
+impl Families {
+    fn extend(&mut self, rendered: &[(&'static MetricFamily, Bytes)], series: usize) {
+        for (family, samples) in rendered {
+            let index = *self.index.entry(family.name).or_insert_with(|| {
+                self.families.push((family, Vec::new()));
+                self.families.len() - 1
+            });
+            // only increments the reference count of the cached samples
+            self.families[index].1.push(samples.clone());
+        }
+        self.series += series;
+    }
+}
+
+/// Metrics in the text exposition format, split in chunks that can be streamed
+pub struct Rendered {
+    pub chunks: Vec<Bytes>,
+    pub series: usize,
+}
+
+impl Rendered {
+    pub fn size(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.size());
+        for chunk in &self.chunks {
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+}
+
+impl RenderCache {
+    /// Render the metrics in the text exposition format
+    ///
+    /// The output shares the rendered samples with the cache instead of copying them into a single buffer.
+    pub fn render(
+        &self,
+        config: &Config,
+        state: &DeviceStates,
+        stats: &Stats,
+        query: &MetricsQuery,
+    ) -> Rendered {
+        self.render_with(
+            config,
+            state.latest_firmware,
+            query,
+            |f| visit_devices(config, state, query, f),
+            |registry| format_stats(registry, stats, state),
+        )
+    }
+
+    /// Render the devices of a single tenant, without the metrics about the exporter itself
+    ///
+    /// The devices are rendered without a `tenant` label, so every tenant needs a separate cache.
+    pub fn render_tenant(
+        &self,
+        config: &Config,
+        state: &DeviceStates,
+        latest_firmware: Option<FirmwareVersion>,
+        query: &MetricsQuery,
+    ) -> Rendered {
+        self.render_with(
+            config,
+            latest_firmware,
+            query,
+            |mut f| visit_tenant(config, state, None, latest_firmware, query, &mut f),
+            |_| {},
+        )
+    }
+
+    fn render_with(
+        &self,
+        config: &Config,
+        latest_firmware: Option<FirmwareVersion>,
+        query: &MetricsQuery,
+        visit: impl FnOnce(&mut dyn FnMut(DeviceKey, u64, &dyn Fn(&mut Registry))),
+        format_stats: impl FnOnce(&mut Registry),
+    ) -> Rendered {
+        let mut cache = self.cache.lock().unwrap();
+        let cache = &mut *cache;
+        cache.renders += 1;
+        let render = cache.renders;
+
+        let mut families = Families::default();
+        visit(&mut |key, revision, format| {
+            let revision = (revision, config.revision, latest_firmware);
+            let cached = cache
+                .devices
+                .entry(key)
+                .and_modify(|cached| {
+                    if cached.revision != revision {
+                        *cached = render_device(config, revision, format);
+                    }
+                })
+                .or_insert_with(|| render_device(config, revision, format));
+            cached.used = render;
+            families.extend(&cached.families, cached.series);
+        });
+
+        let mut registry = Registry::new(&config.metrics);
+        if !query.is_filtered() {
+            // forget devices that are no longer tracked
+            cache.devices.retain(|_, cached| cached.used == render);
+            format_stats(&mut registry);
+            families.extend(&render_samples(&registry), registry.series());
+        }
+
+        let mut chunks = Vec::with_capacity(families.families.len() * 2);
+        for (family, samples) in families.families {
+            let mut header = String::new();
+            render_header(&mut header, registry.name(family), family).unwrap();
+            chunks.push(header.into());
+            chunks.extend(samples);
+        }
+        Rendered {
+            chunks,
+            series: families.series,
+        }
+    }
+}
+
+fn render_device(
+    config: &Config,
+    revision: Revision,
+    format: &dyn Fn(&mut Registry),
+) -> CachedDevice {
+    let mut registry = Registry::new(&config.metrics);
+    format(&mut registry);
+    CachedDevice {
+        revision,
+        families: render_samples(&registry),
+        series: registry.series(),
+        used: 0,
+    }
+}
+
+fn render_samples(registry: &Registry) -> Vec<(&'static MetricFamily, Bytes)> {
+    registry
+        .render_samples()
+        .into_iter()
+        .map(|(family, samples)| (family, samples.into()))
+        .collect()
+}
+
+#[test]
+fn test_render_cache() {
+    use crate::collect_metrics;
+    use crate::payload::TasmotaPayload;
+
+    let mut config: Config = toml::from_str(
+        r#"
+        listen = { port = 80 }
+        [names]
+        mitemp = {}
+        rftemp = {}
+        [mqtt]
+        hostname = "mqtt"
+        "#,
+    )
+    .unwrap();
+    let mut state = DeviceStates::default();
+    let stats = Stats::default();
+    let query = MetricsQuery::default();
+    let cache = RenderCache::default();
+    // uptime and process metrics change between renders
+    let stable = |out: String| {
+        out.lines()
+            .filter(|line| !line.contains("uptime") && !line.contains("process_"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let uncached = |config: &Config, state: &DeviceStates| {
+        let mut out = String::new();
+        let registry = collect_metrics(config, state, &stats, &query);
+        registry.render(&mut out).unwrap();
+        (stable(out), registry.series())
+    };
+
+    state
+        .update(
+            "plug",
+            TasmotaPayload::parse(r#"{"DeviceName":"Plug","POWER":"ON"}"#).unwrap(),
+        )
+        .unwrap();
+    let render = |config: &Config, state: &DeviceStates| {
+        let rendered = cache.render(config, state, &stats, &query);
+        (
+            stable(String::from_utf8(rendered.to_vec()).unwrap()),
+            rendered.series,
+        )
+    };
+
+    let rendered = render(&config, &state);
+    assert!(rendered
+        .0
+        .contains(r#"switch_state{tasmota_id="plug", name="Plug"} 1"#));
+    assert_eq!(uncached(&config, &state), rendered);
+
+    state
+        .update("plug", TasmotaPayload::parse(r#"{"POWER":"OFF"}"#).unwrap())
+        .unwrap();
+    let rendered = render(&config, &state);
+    assert!(rendered
+        .0
+        .contains(r#"switch_state{tasmota_id="plug", name="Plug"} 0"#));
+    assert_eq!(uncached(&config, &state), rendered);
+
+    config.names.tasmota.insert("plug".into(), "Lamp".into());
+    config.revision += 1;
+    let rendered = render(&config, &state);
+    assert!(rendered
+        .0
+        .contains(r#"switch_state{tasmota_id="plug", name="Lamp"} 0"#));
+    assert_eq!(uncached(&config, &state), rendered);
+
+    let tenant_cache = RenderCache::default();
+    let rendered = tenant_cache.render_tenant(&config, &state, None, &query);
+    let rendered = String::from_utf8(rendered.to_vec()).unwrap();
+    assert!(rendered.contains(r#"switch_state{tasmota_id="plug", name="Lamp"} 0"#));
+    assert!(!rendered.contains("taspromto_"));
+}
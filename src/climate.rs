@@ -0,0 +1,121 @@
+use crate::metrics::{self, Registry};
+
+// coefficients for the Magnus formula over water
+const MAGNUS_A: f32 = 17.62;
+const MAGNUS_B: f32 = 243.12;
+
+/// Dew point in degrees celsius, using the Magnus formula
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    let gamma = (humidity / 100.0).ln() + MAGNUS_A * temperature / (MAGNUS_B + temperature);
+    MAGNUS_B * gamma / (MAGNUS_A - gamma)
+}
+
+/// Absolute humidity in g/m³
+pub fn absolute_humidity(temperature: f32, humidity: f32) -> f32 {
+    // saturation vapor pressure in hPa
+    let saturation = 6.112 * (MAGNUS_A * temperature / (MAGNUS_B + temperature)).exp();
+    saturation * humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// Apparent temperature in degrees celsius, using the algorithm from the US National Weather Service
+pub fn heat_index(temperature: f32, humidity: f32) -> f32 {
+    let t = temperature * 1.8 + 32.0;
+    let rh = humidity;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let index = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut index = -42.379 + 2.049_015_2 * t + 10.143_331 * rh
+            - 0.224_755_4 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        index
+    };
+    (index - 32.0) / 1.8
+}
+
+/// Humidex as used by Environment Canada
+pub fn humidex(temperature: f32, humidity: f32) -> f32 {
+    let dew_point = dew_point(temperature, humidity) + 273.15;
+    let vapor_pressure = 6.11 * (5417.753 * (1.0 / 273.16 - 1.0 / dew_point)).exp();
+    temperature + 0.5555 * (vapor_pressure - 10.0)
+}
+
+/// Wind chill in degrees celsius for a wind speed in km/h
+///
+/// Outside the range the formula is defined for, the wind chill is the air temperature.
+pub fn wind_chill(temperature: f32, wind_speed: f32) -> f32 {
+    if temperature > 10.0 || wind_speed < 4.8 {
+        return temperature;
+    }
+    let wind = wind_speed.powf(0.16);
+    13.12 + 0.6215 * temperature - 11.37 * wind + 0.3965 * temperature * wind
+}
+
+/// Add the enabled metrics derived from a sensor reading
+pub fn format_derived(
+    registry: &mut Registry,
+    labels: &[(&'static str, &str)],
+    temperature: f32,
+    humidity: Option<f32>,
+    wind_speed: Option<f32>,
+) {
+    let derived = *registry.derived();
+    if let Some(humidity) = humidity.filter(|humidity| *humidity > 0.0) {
+        if derived.dew_point {
+            let dew_point = round(dew_point(temperature, humidity));
+            registry.add(&metrics::SENSOR_DEW_POINT, labels, dew_point);
+            let absolute_humidity = round(absolute_humidity(temperature, humidity));
+            registry.add(
+                &metrics::SENSOR_ABSOLUTE_HUMIDITY,
+                labels,
+                absolute_humidity,
+            );
+        }
+        if derived.heat_index {
+            let heat_index = round(heat_index(temperature, humidity));
+            registry.add(&metrics::SENSOR_HEAT_INDEX, labels, heat_index);
+            let humidex = round(humidex(temperature, humidity));
+            registry.add(&metrics::SENSOR_HUMIDEX, labels, humidex);
+        }
+    }
+    if let Some(wind_speed) = wind_speed {
+        if derived.heat_index {
+            // tracked in m/s
+            let wind_chill = round(wind_chill(temperature, wind_speed * 3.6));
+            registry.add(&metrics::SENSOR_WIND_CHILL, labels, wind_chill);
+        }
+    }
+}
+
+/// Round to 2 decimals, the readings themselves aren't more precise
+fn round(value: f32) -> f32 {
+    (value * 100.0).round() / 100.0
+}
+
+#[test]
+fn test_dew_point() {
+    assert_eq!(9.26, round(dew_point(20.0, 50.0)));
+    assert_eq!(20.0, round(dew_point(20.0, 100.0)));
+    assert_eq!(-4.58, round(dew_point(5.0, 50.0)));
+    assert_eq!(8.62, round(absolute_humidity(20.0, 50.0)));
+    assert_eq!(30.27, round(absolute_humidity(30.0, 100.0)));
+}
+
+#[test]
+fn test_apparent_temperature() {
+    // 90°F at 60% is 99.7°F, rounded to 100°F in the NWS table
+    assert_eq!(37.6, (heat_index(32.22, 60.0) * 10.0).round() / 10.0);
+    assert_eq!(19.4, (heat_index(20.0, 50.0) * 10.0).round() / 10.0);
+    assert_eq!(39.0, humidex(30.0, 60.0).round());
+    assert_eq!(-26.0, wind_chill(-15.0, 30.0).round());
+    assert_eq!(15.0, wind_chill(15.0, 30.0));
+}
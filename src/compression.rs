@@ -0,0 +1,83 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::io::Write;
+
+/// Content encodings supported for responses
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Pick an encoding from the `Accept-Encoding` header, preferring gzip
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let accepted: Vec<&str> = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let name = parts.next()?.trim();
+                let rejected = parts.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                (!rejected).then_some(name)
+            })
+            .collect();
+        if accepted
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("gzip"))
+        {
+            Some(Encoding::Gzip)
+        } else if accepted
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case("deflate"))
+        {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        // writing to a Vec can't fail
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            // the http "deflate" encoding is the zlib format
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+}
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(
+        Some(Encoding::Gzip),
+        Encoding::negotiate("gzip, deflate, br")
+    );
+    assert_eq!(Some(Encoding::Deflate), Encoding::negotiate("deflate"));
+    assert_eq!(
+        Some(Encoding::Deflate),
+        Encoding::negotiate("GZIP;q=0, deflate;q=0.5")
+    );
+    assert_eq!(None, Encoding::negotiate("identity"));
+    assert_eq!(None, Encoding::negotiate(""));
+}
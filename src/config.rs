@@ -1,31 +1,121 @@
-use crate::device::{BDAddr, RfDeviceId};
+use crate::device::{BDAddr, DsmrMessageType, RfDeviceId};
+use crate::evse;
+use crate::firmware::FirmwareVersion;
+use crate::pattern::Pattern;
+use crate::pulse::{PulseMeter, PulseSource, TasmotaCounter};
+use crate::rules::Condition;
+use crate::solar::SolarValue;
+use crate::topic::MappedTopic;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use color_eyre::{eyre::WrapErr, Report, Result};
+use ipnet::IpNet;
+use jiff::tz::TimeZone;
 use rumqttc::MqttOptions;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::collections::{BTreeMap, HashMap};
-use std::fs::read_to_string;
+use std::fs::{read_to_string, write};
 use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use toml_edit::DocumentMut;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub listen: ListenConfig,
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<ListenConfig>,
+    /// Separate listeners for the dashboard and api, if set these are no longer served on the main listeners
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub admin_listen: Vec<ListenConfig>,
     pub names: NamesConfig,
+    /// Only optional when simulating devices
+    #[serde(default)]
     pub mqtt: MqttConfig,
+    pub update_check: Option<UpdateCheckConfig>,
+    /// Devices running an older firmware are reported as outdated
+    #[serde(default, deserialize_with = "firmware_version")]
+    pub min_firmware: Option<FirmwareVersion>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub limits: DeviceLimits,
+    /// Timezone daily values roll over at midnight in, defaults to the system timezone
+    #[serde(default = "TimeZone::system", deserialize_with = "timezone")]
+    pub timezone: TimeZone,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    /// Sensors that aren't exported, by device hostname
+    #[serde(default)]
+    pub ignore: HashMap<String, IgnoreList>,
+    #[serde(default)]
+    pub bounds: BoundsConfig,
+    #[serde(default)]
+    pub spikes: SpikeConfig,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    pub control: Option<ControlConfig>,
+    pub influxdb: Option<InfluxDbConfig>,
+    pub otlp: Option<OtlpConfig>,
+    pub statsd: Option<StatsdConfig>,
+    pub publish: Option<PublishConfig>,
+    pub webhook: Option<WebhookConfig>,
+    #[serde(default, deserialize_with = "unique_rules")]
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    pub history: Option<HistoryConfig>,
+    pub anomaly: Option<AnomalyConfig>,
+    /// Rooms to derive occupancy for, by room name
+    #[serde(default)]
+    pub occupancy: BTreeMap<String, OccupancyConfig>,
+    pub ebusd: Option<EbusdConfig>,
+    /// Topics inverter bridges publish their values on, by inverter name
+    #[serde(default)]
+    pub solar: BTreeMap<String, BTreeMap<SolarValue, String>>,
+    /// Topics with HomeWizard P1 json payloads, with the name to export the meter under
+    #[serde(default)]
+    pub p1: BTreeMap<String, String>,
+    /// Base topics of OpenEVSE chargers, with the name to export the charger under
+    #[serde(default)]
+    pub evse: BTreeMap<String, String>,
+    /// Water meters read by counting pulses, by the name to export the meter under
+    #[serde(default)]
+    pub water: BTreeMap<String, WaterMeterConfig>,
+    /// Gas meters read by counting pulses, by the name to export the meter under
+    #[serde(default)]
+    pub gas: BTreeMap<String, GasMeterConfig>,
+    /// Topic namespaces whose devices are tracked separately, by tenant name
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantConfig>,
+    /// Incremented every time the config is changed at runtime
+    #[serde(skip)]
+    pub revision: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum ListenConfig {
     Ip {
         #[serde(default = "default_address")]
         address: IpAddr,
         port: u16,
+        #[serde(default)]
+        tls: Option<TlsConfig>,
     },
     Unix {
         socket: String,
+        /// Permissions for the created socket
+        #[serde(default)]
+        mode: Option<u32>,
     },
 }
 
@@ -33,29 +123,446 @@ fn default_address() -> IpAddr {
     Ipv4Addr::UNSPECIFIED.into()
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the pem encoded certificate chain
+    pub cert: String,
+    /// Path to the pem encoded private key
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+/// Allow a config option to be either a single item or a list of items
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(item) => vec![item],
+        OneOrMany::Many(items) => items,
+    })
+}
+
 #[derive(Debug, Deserialize)]
 pub struct NamesConfig {
+    /// Names for tasmota devices by hostname, overriding the name reported by the device
+    #[serde(default)]
+    pub tasmota: HashMap<String, String>,
     #[serde(rename = "mitemp")]
     pub mi_temp: BTreeMap<BDAddr, String>,
     #[serde(rename = "rftemp")]
     pub rf_temp: HashMap<RfDeviceId<'static>, String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct TenantConfig {
+    /// Prefix of the topics of the tenant, such as `garage/`
+    pub prefix: String,
+    /// Broker the tenant's devices are connected to, defaults to the main broker
+    pub mqtt: Option<MqttConfig>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct MqttConfig {
-    #[serde(rename = "hostname")]
-    host: String,
+    /// Brokers to connect to in order of preference, as `host` or `host:port`
+    #[serde(rename = "hostname", deserialize_with = "one_or_many")]
+    hosts: Vec<String>,
+    /// Port for brokers without an explicit port
     #[serde(default = "default_mqtt_port")]
     port: u16,
     #[serde(flatten)]
     credentials: Option<Credentials>,
+    /// Proxy to tunnel the connections to the brokers trough
+    pub proxy: Option<ProxyConfig>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            hosts: Vec::new(),
+            port: default_mqtt_port(),
+            credentials: None,
+            proxy: None,
+        }
+    }
+}
+
+/// An HTTP CONNECT or SOCKS5 proxy
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    #[serde(rename = "type")]
+    pub kind: ProxyType,
+    /// `host:port` of the proxy
+    pub address: String,
+    #[serde(flatten)]
+    pub credentials: Option<Credentials>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyType {
+    Http,
+    Socks5,
+}
+
+fn timezone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TimeZone, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    TimeZone::get(&name).map_err(serde::de::Error::custom)
+}
+
+fn firmware_version<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<FirmwareVersion>, D::Error> {
+    let version = String::deserialize(deserializer)?;
+    version.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Intervals in seconds, zero would make the periodic tasks panic or spin
+fn non_zero_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "interval must be at least 1 second",
+        )),
+        interval => Ok(interval),
+    }
 }
 
 fn default_mqtt_port() -> u16 {
     1883
 }
 
-#[derive(Debug, Deserialize)]
+/// Split a broker address into the host and port, falling back to the default port
+fn broker_address(broker: &str, default_port: u16) -> (&str, u16) {
+    if let Some((host, port)) = broker.rsplit_once(':') {
+        // ipv6 addresses need brackets when a port is set
+        if !host.contains(':') || (host.starts_with('[') && host.ends_with(']')) {
+            if let Ok(port) = port.parse() {
+                return (host.trim_start_matches('[').trim_end_matches(']'), port);
+            }
+        }
+    }
+    (broker, default_port)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpdateCheckConfig {
+    #[serde(default = "default_update_url")]
+    pub url: String,
+    /// Interval between checks in seconds
+    #[serde(
+        default = "default_update_interval",
+        deserialize_with = "non_zero_interval"
+    )]
+    pub interval: u64,
+}
+
+/// How long devices of each type are kept around when no updates are received
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    pub tasmota: RetentionTimes,
+    pub dsmr: RetentionTimes,
+    pub otgw: RetentionTimes,
+    pub ebusd: RetentionTimes,
+    pub solar: RetentionTimes,
+    pub evse: RetentionTimes,
+    #[serde(rename = "mitemp")]
+    pub mi_temp: RetentionTimes,
+    pub rf: RetentionTimes,
+    pub rtl: RetentionTimes,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RetentionTimes {
+    /// Seconds without updates after which the device is asked for its state, only applies to tasmota devices
+    pub ping: u64,
+    /// Seconds without updates after which the device is removed, 0 keeps the device until restarted
+    pub remove: u64,
+    /// Seconds a device is kept as offline after the remove timeout before actually removing it, only applies to tasmota devices
+    pub grace: u64,
+}
+
+impl Default for RetentionTimes {
+    fn default() -> Self {
+        RetentionTimes {
+            ping: 10 * 60,
+            remove: 15 * 60,
+            grace: 0,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        // meters and bridges can go quiet for hours when nothing changes, so they're kept by default
+        let keep = RetentionTimes {
+            remove: 0,
+            ..RetentionTimes::default()
+        };
+        RetentionConfig {
+            tasmota: RetentionTimes::default(),
+            dsmr: keep,
+            otgw: keep,
+            ebusd: keep,
+            solar: keep,
+            evse: keep,
+            mi_temp: RetentionTimes::default(),
+            rf: keep,
+            rtl: keep,
+        }
+    }
+}
+
+/// Maximum number of tracked devices of each type, the least recently seen devices are dropped when exceeded
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceLimits {
+    pub tasmota: Option<usize>,
+    pub dsmr: Option<usize>,
+    pub otgw: Option<usize>,
+    pub ebusd: Option<usize>,
+    pub solar: Option<usize>,
+    pub evse: Option<usize>,
+    #[serde(rename = "mitemp")]
+    pub mi_temp: Option<usize>,
+    pub rf: Option<usize>,
+}
+
+impl RetentionTimes {
+    pub fn ping(&self) -> Duration {
+        Duration::from_secs(self.ping)
+    }
+
+    pub fn remove(&self) -> Duration {
+        Duration::from_secs(self.remove)
+    }
+
+    /// Whether a device last seen at `last_seen` should be removed
+    pub fn expired(&self, last_seen: Instant) -> bool {
+        self.remove > 0 && last_seen.elapsed() > self.remove()
+    }
+
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace)
+    }
+}
+
+/// Limit which devices are tracked, patterns are matched against both the device hostname and the full mqtt topic
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// If not empty, only devices matching one of the patterns are tracked
+    pub allow: Vec<Pattern>,
+    /// Devices matching any of the patterns are ignored
+    pub deny: Vec<Pattern>,
+}
+
+impl FilterConfig {
+    pub fn allows(&self, hostname: Option<&str>, topic: &str) -> bool {
+        let matches = |pattern: &Pattern| {
+            pattern.matches(topic) || hostname.is_some_and(|hostname| pattern.matches(hostname))
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Which received messages are logged at info level instead of debug, patterns are matched against both the device
+/// hostname and the full mqtt topic
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Messages matching one of the patterns are logged at info level
+    pub include: Vec<Pattern>,
+    /// Messages matching any of the patterns are only logged at debug level, even if included
+    pub exclude: Vec<Pattern>,
+}
+
+impl LogConfig {
+    pub fn logs(&self, hostname: Option<&str>, topic: &str) -> bool {
+        let matches = |pattern: &Pattern| {
+            pattern.matches(topic) || hostname.is_some_and(|hostname| pattern.matches(hostname))
+        };
+        self.include.iter().any(matches) && !self.exclude.iter().any(matches)
+    }
+}
+
+/// A water meter read by counting pulses
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "PulseMeterKeys")]
+pub struct WaterMeterConfig {
+    pub source: PulseSource,
+    pub liters_per_pulse: f64,
+    /// Meter reading in m³ at a pulse count of 0
+    pub offset: f64,
+}
+
+/// A gas meter read by counting pulses
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "PulseMeterKeys")]
+pub struct GasMeterConfig {
+    /// Defaults to the pulse count published on `<name>/gas_pulse`
+    pub source: Option<PulseSource>,
+    pub m3_per_pulse: f64,
+    /// Meter reading in m³ at a pulse count of 0
+    pub offset: f64,
+}
+
+impl GasMeterConfig {
+    /// Whether the pulse count of the meter is published on `topic`
+    fn reads_topic(&self, name: &str, topic: &str) -> bool {
+        match &self.source {
+            Some(PulseSource::Topic(source)) => source == topic,
+            Some(PulseSource::Counter(_)) => false,
+            None => topic.strip_suffix("/gas_pulse") == Some(name),
+        }
+    }
+}
+
+/// The keys of a water or gas meter, `#[serde(flatten)]` can't be combined with rejecting unknown keys
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PulseMeterKeys {
+    counter: Option<TasmotaCounter>,
+    topic: Option<String>,
+    liters_per_pulse: Option<f64>,
+    m3_per_pulse: Option<f64>,
+    #[serde(default)]
+    offset: f64,
+}
+
+impl PulseMeterKeys {
+    fn source(&mut self) -> Result<Option<PulseSource>, &'static str> {
+        match (self.counter.take(), self.topic.take()) {
+            (Some(_), Some(_)) => Err("a meter can't have both a `counter` and a `topic`"),
+            (Some(counter), None) => Ok(Some(PulseSource::Counter(counter))),
+            (None, Some(topic)) => Ok(Some(PulseSource::Topic(topic))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+impl TryFrom<PulseMeterKeys> for WaterMeterConfig {
+    type Error = &'static str;
+
+    fn try_from(mut keys: PulseMeterKeys) -> Result<Self, Self::Error> {
+        if keys.m3_per_pulse.is_some() {
+            return Err("unknown field `m3_per_pulse`, water meters use `liters_per_pulse`");
+        }
+        Ok(WaterMeterConfig {
+            source: keys
+                .source()?
+                .ok_or("missing `counter` or `topic` for water meter")?,
+            liters_per_pulse: keys
+                .liters_per_pulse
+                .ok_or("missing field `liters_per_pulse`")?,
+            offset: keys.offset,
+        })
+    }
+}
+
+impl TryFrom<PulseMeterKeys> for GasMeterConfig {
+    type Error = &'static str;
+
+    fn try_from(mut keys: PulseMeterKeys) -> Result<Self, Self::Error> {
+        if keys.liters_per_pulse.is_some() {
+            return Err("unknown field `liters_per_pulse`, gas meters use `m3_per_pulse`");
+        }
+        Ok(GasMeterConfig {
+            source: keys.source()?,
+            m3_per_pulse: keys.m3_per_pulse.ok_or("missing field `m3_per_pulse`")?,
+            offset: keys.offset,
+        })
+    }
+}
+
+/// Plausible ranges for sensor readings, readings outside of the range are discarded
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct BoundsConfig {
+    /// In degrees celsius
+    pub temperature: Option<Bounds>,
+    /// In percent
+    pub humidity: Option<Bounds>,
+    /// In watts
+    pub power: Option<Bounds>,
+    /// In m/s
+    pub wind_speed: Option<Bounds>,
+    /// In hPa
+    pub pressure: Option<Bounds>,
+}
+
+/// Largest jump between consecutive readings of 433Mhz and bluetooth sensors that is accepted without confirmation
+///
+/// Larger jumps are only accepted once the next reading confirms them.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct SpikeConfig {
+    /// In degrees celsius
+    pub temperature: Option<f32>,
+    /// In percent
+    pub humidity: Option<f32>,
+    /// In m/s
+    pub wind_speed: Option<f32>,
+    /// In hPa
+    pub pressure: Option<f32>,
+}
+
+/// Lowest and highest plausible value, configured as `[min, max]`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(from = "(f32, f32)")]
+pub struct Bounds {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl From<(f32, f32)> for Bounds {
+    fn from((min, max): (f32, f32)) -> Self {
+        Bounds { min, max }
+    }
+}
+
+impl Bounds {
+    pub fn contains(&self, value: f32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+}
+
+/// Sensor blocks of a device that aren't exported, such as `ANALOG`, or single fields of a block, such as `ESP32.Temperature`
+///
+/// Top level values, such as `POWER` or `Switch1`, are ignored by their name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct IgnoreList(Vec<String>);
+
+impl IgnoreList {
+    /// Whether a value is ignored, either by itself or as part of an ignored block
+    pub fn ignores(&self, block: &str, field: Option<&str>) -> bool {
+        self.0.iter().any(|entry| match entry.split_once('.') {
+            Some((ignored_block, ignored_field)) => {
+                ignored_block == block && Some(ignored_field) == field
+            }
+            None => entry == block,
+        })
+    }
+}
+
+fn default_update_url() -> String {
+    "https://api.github.com/repos/arendst/Tasmota/releases/latest".into()
+}
+
+fn default_update_interval() -> u64 {
+    6 * 60 * 60
+}
+
+#[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum Credentials {
     Raw {
@@ -75,17 +582,487 @@ impl Credentials {
             Credentials::File { username, .. } => username.clone(),
         }
     }
-    pub fn password(&self) -> String {
-        match self {
+    /// The password, loading it from the password file if configured
+    pub fn try_password(&self) -> Result<String> {
+        Ok(match self {
             Credentials::Raw { password, .. } => password.clone(),
-            Credentials::File { password_file, .. } => secretfile::load(password_file).unwrap(),
+            Credentials::File { password_file, .. } => secretfile::load(password_file)
+                .wrap_err_with(|| format!("Failed to load password from {password_file}"))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds the mqtt connection can be lost before reporting as not ready
+    pub disconnect_grace: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            disconnect_grace: 60,
+        }
+    }
+}
+
+impl HealthConfig {
+    pub fn disconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.disconnect_grace)
+    }
+}
+
+/// Commands sent to newly discovered devices
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Seconds between telemetry messages to configure on the device, between 10 and 3600
+    pub tele_period: Option<u16>,
+}
+
+/// Compare the recent power usage of devices against their longer term baseline
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct AnomalyConfig {
+    /// Seconds to average the recent power usage over
+    pub window: u64,
+    /// Seconds to average the baseline power usage over
+    pub baseline: u64,
+    /// How many times higher or lower the recent usage has to be than the baseline to count as an anomaly
+    pub factor: f64,
+    /// Averages below this many watts are rounded up, so idle devices don't cause huge scores
+    pub min_power: f64,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            window: 60 * 60,
+            baseline: 24 * 60 * 60,
+            factor: 2.0,
+            min_power: 5.0,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window)
+    }
+
+    pub fn baseline(&self) -> Duration {
+        Duration::from_secs(self.baseline)
+    }
+}
+
+/// Devices that indicate activity in a room
+///
+/// Switches are referenced as `<device>/<switch>`, lights by device, devices can be referenced by hostname or name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OccupancyConfig {
+    /// Seconds a room stays occupied after the last activity
+    #[serde(default = "default_occupancy_timeout")]
+    pub timeout: u64,
+    /// Switches that are on while motion is detected
+    #[serde(default)]
+    pub motion: Vec<String>,
+    /// Switches where every change counts as activity
+    #[serde(default)]
+    pub doors: Vec<String>,
+    /// Devices that switch a light, which counts as activity while on
+    #[serde(default)]
+    pub lights: Vec<String>,
+}
+
+fn default_occupancy_timeout() -> u64 {
+    15 * 60
+}
+
+impl OccupancyConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Values published by ebusd, for heat pumps and other devices on an eBUS
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EbusdConfig {
+    /// Topic to subscribe to, can be narrowed down to specific circuits such as `ebusd/hmu/#`
+    pub topic: String,
+    /// Names to export the values under, by `<circuit>/<field>`, the field name is used by default
+    pub fields: HashMap<String, String>,
+}
+
+impl Default for EbusdConfig {
+    fn default() -> Self {
+        EbusdConfig {
+            topic: "ebusd/#".into(),
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl EbusdConfig {
+    pub fn field_name<'a>(&'a self, circuit: &str, field: &'a str) -> &'a str {
+        self.fields
+            .get(&format!("{circuit}/{field}"))
+            .map(String::as_str)
+            .unwrap_or(field)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Path the metrics are served on
+    pub path: String,
+    /// Labels added to every exported sample
+    pub labels: BTreeMap<String, String>,
+    /// Labels added to the samples of devices with a hostname or name matching the pattern
+    pub devices: BTreeMap<Pattern, BTreeMap<String, String>>,
+    /// Metric families that aren't exported, matched against the metric name
+    pub disable: Vec<Pattern>,
+    /// Names to export metric families under instead of their default name
+    pub rename: HashMap<String, String>,
+    pub derived: DerivedConfig,
+    pub air_quality: Option<AirQualityConfig>,
+    /// Names to export values published by rules under, rule variables (`Var<n>` and `Mem<n>`) are exported by default
+    pub variables: HashMap<String, String>,
+}
+
+/// Metrics calculated from the readings of sensors
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct DerivedConfig {
+    /// Dew point and absolute humidity for sensors reporting both temperature and humidity
+    pub dew_point: bool,
+    /// Heat index and humidex for sensors reporting both temperature and humidity,
+    /// and wind chill for sensors reporting both temperature and wind speed
+    pub heat_index: bool,
+    /// Keep power and gas totals increasing when a device resets them, for example after being re-flashed
+    pub monotonic_totals: bool,
+}
+
+/// Breakpoints for categorizing the CO² and PM2.5 readings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AirQualityConfig {
+    /// CO² concentration in ppm from which the level is elevated
+    pub co2_elevated: f32,
+    /// CO² concentration in ppm from which the level is high
+    pub co2_high: f32,
+    /// PM2.5 concentrations in µg/m³ with their air quality index, the index is interpolated between them
+    pub pm2_5: Vec<(f32, f32)>,
+}
+
+impl Default for AirQualityConfig {
+    fn default() -> Self {
+        AirQualityConfig {
+            co2_elevated: 1000.0,
+            co2_high: 1500.0,
+            // the US EPA breakpoints
+            pm2_5: vec![
+                (0.0, 0.0),
+                (9.0, 50.0),
+                (35.4, 100.0),
+                (55.4, 150.0),
+                (125.4, 200.0),
+                (225.4, 300.0),
+                (325.4, 500.0),
+            ],
         }
     }
 }
 
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            path: "/metrics".into(),
+            labels: BTreeMap::default(),
+            devices: BTreeMap::default(),
+            disable: Vec::default(),
+            rename: HashMap::default(),
+            derived: DerivedConfig::default(),
+            air_quality: None,
+            variables: HashMap::default(),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// The extra labels for a device, from all patterns matching any of the identifiers
+    pub fn device_labels(&self, identifiers: &[&str]) -> BTreeMap<String, String> {
+        self.devices
+            .iter()
+            .filter(|(pattern, _)| identifiers.iter().any(|id| pattern.matches(id)))
+            .flat_map(|(_, labels)| labels.clone())
+            .collect()
+    }
+}
+
+/// Periodically write all readings to influxdb
+#[derive(Debug, Clone, Deserialize)]
+pub struct InfluxDbConfig {
+    pub url: String,
+    #[serde(flatten)]
+    pub target: InfluxDbTarget,
+    /// Interval between writes in seconds
+    #[serde(
+        default = "default_influxdb_interval",
+        deserialize_with = "non_zero_interval"
+    )]
+    pub interval: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum InfluxDbTarget {
+    V2 {
+        org: String,
+        bucket: String,
+        token: Option<String>,
+        token_file: Option<String>,
+    },
+    V1 {
+        database: String,
+        #[serde(flatten)]
+        credentials: Option<Credentials>,
+    },
+}
+
+fn default_influxdb_interval() -> u64 {
+    60
+}
+
+/// Periodically export all readings to an OpenTelemetry collector
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtlpConfig {
+    /// Base url of the OTLP/HTTP receiver, `/v1/metrics` is appended
+    pub endpoint: String,
+    /// Extra headers sent with every export, for authentication
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Interval between exports in seconds
+    #[serde(
+        default = "default_otlp_interval",
+        deserialize_with = "non_zero_interval"
+    )]
+    pub interval: u64,
+}
+
+fn default_otlp_interval() -> u64 {
+    60
+}
+
+/// Periodically send all readings as statsd gauges
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatsdConfig {
+    /// Address of the statsd server, as `host:port`
+    pub address: String,
+    /// Prefix for all metric names
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub format: StatsdFormat,
+    /// Interval between sends in seconds
+    #[serde(
+        default = "default_statsd_interval",
+        deserialize_with = "non_zero_interval"
+    )]
+    pub interval: u64,
+}
+
+fn default_statsd_interval() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// Labels as DogStatsD tags, `name:1|g|#label:value`
+    #[default]
+    Datadog,
+    /// Label values appended to the metric name, `name.value:1|g`
+    Plain,
+}
+
+/// Periodically publish the readings of every device to mqtt
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PublishConfig {
+    /// Topic prefix, the device id is appended
+    pub topic: String,
+    /// Interval between publishes in seconds
+    #[serde(deserialize_with = "non_zero_interval")]
+    pub interval: u64,
+    /// Publish the snapshots as retained messages
+    pub retain: bool,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        PublishConfig {
+            topic: "taspromto/state".into(),
+            interval: 60,
+            retain: false,
+        }
+    }
+}
+
+/// Store the readings of every device in a sqlite database
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryConfig {
+    /// Path of the sqlite database
+    pub path: String,
+    /// Interval between snapshots in seconds
+    #[serde(
+        default = "default_history_interval",
+        deserialize_with = "non_zero_interval"
+    )]
+    pub interval: u64,
+    /// Seconds after which readings are removed
+    #[serde(default = "default_history_retention")]
+    pub retention: u64,
+}
+
+fn default_history_interval() -> u64 {
+    60
+}
+
+fn default_history_retention() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+/// Send a request when a device goes offline or comes back online
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Separate url to use when a device comes back online
+    pub recovery_url: Option<String>,
+}
+
+/// Push notifications for offline devices and alert rules
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierConfig {
+    /// Publish to an ntfy topic, the url includes the topic
+    Ntfy {
+        url: String,
+        token: Option<String>,
+        token_file: Option<String>,
+    },
+    /// Send trough an Apprise API server
+    Apprise {
+        url: String,
+        /// Apprise urls to notify, for the stateless `/notify` endpoint
+        urls: Option<String>,
+        /// Tag to notify, for persistent configurations
+        tag: Option<String>,
+    },
+}
+
+/// Alert when an exported metric crosses a threshold
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    /// Condition like `power_watts > 2000 for 5m`
+    #[serde(rename = "expr")]
+    pub condition: Condition,
+    /// How far the value needs to move back past the threshold before the alert is resolved
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Url to post the alert to
+    pub webhook: Option<String>,
+    /// Mqtt topic to publish the alert to
+    pub topic: Option<String>,
+}
+
+/// Rule state is tracked by name, so the names need to be unique
+fn unique_rules<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<RuleConfig>, D::Error> {
+    let rules = Vec::<RuleConfig>::deserialize(deserializer)?;
+    for (i, rule) in rules.iter().enumerate() {
+        if rules[..i].iter().any(|other| other.name == rule.name) {
+            return Err(serde::de::Error::custom(format!(
+                "duplicate rule name {}",
+                rule.name
+            )));
+        }
+    }
+    Ok(rules)
+}
+
+/// Allow controlling devices trough the http api
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Commands that can be sent trough the generic command endpoint, matched case-sensitive
+    pub commands: Vec<Pattern>,
+    /// Save names set trough the api to the config file
+    pub persist_names: bool,
+    /// Milliseconds between the commands sent to each device by a bulk command
+    pub bulk_interval: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            commands: Vec::new(),
+            persist_names: false,
+            bulk_interval: 200,
+        }
+    }
+}
+
+impl ControlConfig {
+    pub fn bulk_interval(&self) -> Duration {
+        Duration::from_millis(self.bulk_interval)
+    }
+
+    pub fn allows(&self, command: &str) -> bool {
+        self.commands.iter().any(|pattern| pattern.matches(command))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// If not empty, only clients from these networks can access the http endpoints
+    pub allow: Vec<IpNet>,
+}
+
+/// Authentication required for accessing the http endpoints
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AuthConfig {
+    Token { token: String },
+    TokenFile { token_file: String },
+    Basic(Credentials),
+}
+
+impl AuthConfig {
+    /// The expected value of the `Authorization` header
+    pub fn header_value(&self) -> Result<String> {
+        Ok(match self {
+            AuthConfig::Token { token } => format!("Bearer {token}"),
+            AuthConfig::TokenFile { token_file } => {
+                format!("Bearer {}", secretfile::load(token_file)?)
+            }
+            AuthConfig::Basic(credentials) => {
+                let password = credentials.try_password()?;
+                let encoded =
+                    BASE64_STANDARD.encode(format!("{}:{}", credentials.username(), password));
+                format!("Basic {encoded}")
+            }
+        })
+    }
+}
+
 impl Config {
     pub fn from_env() -> Result<Self> {
-        let mqtt_host = dotenvy::var("MQTT_HOSTNAME").wrap_err("MQTT_HOSTNAME not set")?;
+        // checked when connecting, simulating devices doesn't need a broker
+        let mqtt_host = dotenvy::var("MQTT_HOSTNAME").ok();
         let mqtt_port = dotenvy::var("MQTT_PORT")
             .ok()
             .and_then(|port| u16::from_str(&port).ok())
@@ -94,38 +1071,14 @@ impl Config {
             .ok()
             .and_then(|port| u16::from_str(&port).ok())
             .unwrap_or(80);
+        let host_address = match dotenvy::var("ADDRESS") {
+            Ok(address) => IpAddr::from_str(&address).wrap_err("Invalid ADDRESS")?,
+            Err(_) => default_address(),
+        };
 
-        let mi_temp_names = dotenvy::var("MITEMP_NAMES").unwrap_or_default();
-        let mi_temp_names = mi_temp_names
-            .split(',')
-            .map(|pair| {
-                let mut parts = pair.split('=');
-                if let (Some(mac), Some(name)) = (
-                    parts.next().map(BDAddr::from_mi_temp_mac_part),
-                    parts.next(),
-                ) {
-                    let mac = mac.wrap_err("Invalid MITEMP_NAMES")?;
-                    Ok((mac, name.to_string()))
-                } else {
-                    Err(Report::msg("Invalid MITEMP_NAMES"))
-                }
-            })
-            .collect::<Result<BTreeMap<BDAddr, String>, Report>>()?;
-
-        let rf_temp_names = dotenvy::var("RF_TEMP_NAMES").unwrap_or_default();
-        let rf_temp_names = rf_temp_names
-            .split(',')
-            .map(|pair| {
-                let mut parts = pair.split('=');
-                if let (Some(channel), Some(name)) = (parts.next(), parts.next()) {
-                    let device_id =
-                        RfDeviceId::from_str(channel).wrap_err("Invalid RF_TEMP_NAMES")?;
-                    Ok((device_id, name.to_string()))
-                } else {
-                    Err(Report::msg("Invalid RF_TEMP_NAMES"))
-                }
-            })
-            .collect::<Result<HashMap<_, _>, Report>>()?;
+        let mi_temp_names = parse_mi_temp_names(&dotenvy::var("MITEMP_NAMES").unwrap_or_default())?;
+        let rf_temp_names =
+            parse_rf_temp_names(&dotenvy::var("RF_TEMP_NAMES").unwrap_or_default())?;
 
         let mqtt_credentials = match dotenvy::var("MQTT_USERNAME") {
             Ok(username) => {
@@ -137,19 +1090,57 @@ impl Config {
         };
 
         Ok(Config {
-            listen: ListenConfig::Ip {
+            listen: vec![ListenConfig::Ip {
                 port: host_port,
-                address: default_address(),
-            },
+                address: host_address,
+                tls: None,
+            }],
+            admin_listen: Vec::new(),
             names: NamesConfig {
+                tasmota: HashMap::new(),
                 mi_temp: mi_temp_names,
                 rf_temp: rf_temp_names,
             },
             mqtt: MqttConfig {
                 port: mqtt_port,
-                host: mqtt_host,
+                hosts: mqtt_host.into_iter().collect(),
                 credentials: mqtt_credentials,
+                proxy: None,
             },
+            update_check: None,
+            min_firmware: None,
+            retention: RetentionConfig::default(),
+            limits: DeviceLimits::default(),
+            timezone: TimeZone::system(),
+            filter: FilterConfig::default(),
+            log: LogConfig::default(),
+            ignore: HashMap::new(),
+            bounds: BoundsConfig::default(),
+            spikes: SpikeConfig::default(),
+            auth: None,
+            access: AccessConfig::default(),
+            health: HealthConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            metrics: MetricsConfig::default(),
+            control: None,
+            influxdb: None,
+            otlp: None,
+            statsd: None,
+            publish: None,
+            webhook: None,
+            rules: Vec::new(),
+            notifiers: Vec::new(),
+            history: None,
+            anomaly: None,
+            occupancy: BTreeMap::new(),
+            ebusd: None,
+            solar: BTreeMap::new(),
+            p1: BTreeMap::new(),
+            evse: BTreeMap::new(),
+            water: BTreeMap::new(),
+            gas: BTreeMap::new(),
+            tenants: BTreeMap::new(),
+            revision: 0,
         })
     }
 
@@ -158,19 +1149,451 @@ impl Config {
         Ok(toml::from_str(&raw)?)
     }
 
-    pub fn mqtt(&self) -> Result<MqttOptions> {
+    /// Load the config file and apply any overrides from the environment
+    pub fn load(path: &str) -> Result<Config> {
+        let mut config = Config::from_file(path)
+            .wrap_err_with(|| format!("Failed to load config file {path}"))?;
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    /// Apply the settings from a reloaded config file that can be changed at runtime
+    pub fn reload(&mut self, new: Config) {
+        self.revision += 1;
+        self.names = new.names;
+        self.retention = new.retention;
+        self.limits = new.limits;
+        self.timezone = new.timezone;
+        self.min_firmware = new.min_firmware;
+        self.filter = new.filter;
+        self.log = new.log;
+        self.ignore = new.ignore;
+        self.bounds = new.bounds;
+        self.spikes = new.spikes;
+        self.health = new.health;
+        self.discovery = new.discovery;
+        self.metrics = new.metrics;
+        self.control = new.control;
+        self.rules = new.rules;
+        self.anomaly = new.anomaly;
+        self.occupancy = new.occupancy;
+        self.ebusd = new.ebusd;
+        self.water = new.water;
+        self.gas = new.gas;
+    }
+
+    /// Topics to subscribe to on top of the built-in ones
+    pub fn subscriptions(&self) -> Vec<String> {
+        let solar = self.solar.values().flat_map(|topics| topics.values());
+        self.ebusd
+            .iter()
+            .map(|ebusd| &ebusd.topic)
+            .chain(solar)
+            .chain(self.p1.keys())
+            .cloned()
+            .chain(
+                self.pulse_meters()
+                    .into_iter()
+                    .filter_map(|meter| match meter.source {
+                        PulseSource::Topic(topic) => Some(topic),
+                        PulseSource::Counter(_) => None,
+                    }),
+            )
+            .chain(self.evse.keys().map(|base| format!("{base}/+")))
+            .collect()
+    }
+
+    /// All meters read by counting pulses
+    pub fn pulse_meters(&self) -> Vec<PulseMeter> {
+        let water = self.water.iter().map(|(name, meter)| PulseMeter {
+            name: name.clone(),
+            ty: DsmrMessageType::Water,
+            source: meter.source.clone(),
+            m3_per_pulse: meter.liters_per_pulse / 1000.0,
+            offset: meter.offset,
+        });
+        let gas = self.gas.iter().map(|(name, meter)| PulseMeter {
+            name: name.clone(),
+            ty: DsmrMessageType::Gas,
+            source: meter
+                .source
+                .clone()
+                .unwrap_or_else(|| PulseSource::Topic(format!("{name}/gas_pulse"))),
+            m3_per_pulse: meter.m3_per_pulse,
+            offset: meter.offset,
+        });
+        water.chain(gas).collect()
+    }
+
+    /// How to read a topic that isn't recognized by itself, if configured
+    pub fn mapped_topic(&self, topic: &str) -> Option<MappedTopic> {
+        if let Some(name) = self.p1.get(topic) {
+            return Some(MappedTopic::P1(name.clone()));
+        }
+        let evse = topic.rsplit_once('/').and_then(|(base, field)| {
+            let name = self.evse.get(base)?;
+            let field = evse::FIELDS.into_iter().find(|tracked| *tracked == field)?;
+            Some(MappedTopic::Evse(name.clone(), field))
+        });
+        if evse.is_some() {
+            return evse;
+        }
+        let water = self.water.iter().find(
+            |(_, meter)| matches!(&meter.source, PulseSource::Topic(source) if source == topic),
+        );
+        if let Some((name, _)) = water {
+            return Some(MappedTopic::Pulse(name.clone(), DsmrMessageType::Water));
+        }
+        let gas = self
+            .gas
+            .iter()
+            .find(|(name, meter)| meter.reads_topic(name, topic));
+        if let Some((name, _)) = gas {
+            return Some(MappedTopic::Pulse(name.clone(), DsmrMessageType::Gas));
+        }
+        self.solar.iter().find_map(|(inverter, topics)| {
+            let (value, _) = topics.iter().find(|(_, t)| *t == topic)?;
+            Some(MappedTopic::Solar(inverter.clone(), *value))
+        })
+    }
+
+    /// Override the values loaded from the config file with any set environment variables
+    pub fn apply_env_overrides(&mut self) -> Result<()> {
+        if let Ok(host) = dotenvy::var("MQTT_HOSTNAME") {
+            self.mqtt.hosts = vec![host];
+        }
+        if let Ok(port) = dotenvy::var("MQTT_PORT") {
+            self.mqtt.port = u16::from_str(&port).wrap_err("Invalid MQTT_PORT")?;
+        }
+        if let Ok(username) = dotenvy::var("MQTT_USERNAME") {
+            let password = dotenvy::var("MQTT_PASSWORD")
+                .wrap_err("MQTT_USERNAME set, but MQTT_PASSWORD not set")?;
+            self.mqtt.credentials = Some(Credentials::Raw { username, password });
+        }
+        if let Ok(port) = dotenvy::var("PORT") {
+            let port = u16::from_str(&port).wrap_err("Invalid PORT")?;
+            let (address, tls) = self
+                .listen
+                .iter()
+                .find_map(|listen| match listen {
+                    ListenConfig::Ip { address, tls, .. } => Some((*address, tls.clone())),
+                    ListenConfig::Unix { .. } => None,
+                })
+                .unwrap_or_else(|| (default_address(), None));
+            let address = match dotenvy::var("ADDRESS") {
+                Ok(address) => IpAddr::from_str(&address).wrap_err("Invalid ADDRESS")?,
+                Err(_) => address,
+            };
+            self.listen = vec![ListenConfig::Ip { address, port, tls }];
+        }
+        if let Ok(names) = dotenvy::var("MITEMP_NAMES") {
+            self.names.mi_temp.extend(parse_mi_temp_names(&names)?);
+        }
+        if let Ok(names) = dotenvy::var("RF_TEMP_NAMES") {
+            self.names.rf_temp.extend(parse_rf_temp_names(&names)?);
+        }
+        Ok(())
+    }
+
+    pub fn mqtt(&self) -> Result<Vec<MqttOptions>> {
+        self.tenant_mqtt(None)
+    }
+
+    /// Whether the topic belongs to a tenant sharing the main broker
+    pub fn is_tenant_topic(&self, topic: &str) -> bool {
+        self.tenants
+            .values()
+            .any(|tenant| tenant.mqtt.is_none() && topic.starts_with(&tenant.prefix))
+    }
+
+    /// Proxy for the brokers of a tenant, `None` for the default namespace
+    pub fn tenant_proxy(&self, tenant: Option<&str>) -> Option<ProxyConfig> {
+        let mqtt = tenant
+            .and_then(|tenant| self.tenants.get(tenant))
+            .and_then(|config| config.mqtt.as_ref())
+            .unwrap_or(&self.mqtt);
+        mqtt.proxy.clone()
+    }
+
+    /// Connection options for every broker of a tenant in order of preference, `None` for the default namespace
+    pub fn tenant_mqtt(&self, tenant: Option<&str>) -> Result<Vec<MqttOptions>> {
         let hostname = hostname::get()?
             .into_string()
             .map_err(|_| Report::msg("invalid hostname"))?;
-        let mut mqtt_options = MqttOptions::new(
-            format!("taspromto-{}", hostname),
-            &self.mqtt.host,
-            self.mqtt.port,
-        );
-        if let Some(credentials) = self.mqtt.credentials.as_ref() {
-            mqtt_options.set_credentials(credentials.username(), credentials.password());
+        // every connection needs its own client id, even when connecting to the same broker
+        let (client_id, mqtt) = match tenant {
+            Some(tenant) => (
+                format!("taspromto-{hostname}-{tenant}"),
+                self.tenants
+                    .get(tenant)
+                    .and_then(|config| config.mqtt.as_ref())
+                    .unwrap_or(&self.mqtt),
+            ),
+            None => (format!("taspromto-{}", hostname), &self.mqtt),
+        };
+        if mqtt.hosts.is_empty() {
+            return Err(Report::msg(
+                "No mqtt broker configured, set MQTT_HOSTNAME or the [mqtt] section",
+            ));
         }
-        mqtt_options.set_keep_alive(Duration::from_secs(5));
-        Ok(mqtt_options)
+        mqtt.hosts
+            .iter()
+            .map(|broker| {
+                let (host, port) = broker_address(broker, mqtt.port);
+                let mut mqtt_options = MqttOptions::new(&client_id, host, port);
+                if let Some(credentials) = mqtt.credentials.as_ref() {
+                    mqtt_options
+                        .set_credentials(credentials.username(), credentials.try_password()?);
+                }
+                mqtt_options.set_keep_alive(Duration::from_secs(5));
+                Ok(mqtt_options)
+            })
+            .collect()
+    }
+}
+
+/// Save a name to the `[names]` section of the config file
+pub fn persist_name(
+    path: &str,
+    kind: &str,
+    key: &str,
+    name: &str,
+    matches: impl Fn(&str) -> bool,
+) -> Result<()> {
+    let raw = read_to_string(path).wrap_err_with(|| format!("Failed to read {path}"))?;
+    let updated = set_name(&raw, kind, key, name, matches)?;
+    write(path, updated).wrap_err_with(|| format!("Failed to write {path}"))
+}
+
+/// Set a name while keeping the formatting and comments of the rest of the file,
+/// existing entries for the same device are replaced
+fn set_name(
+    raw: &str,
+    kind: &str,
+    key: &str,
+    name: &str,
+    matches: impl Fn(&str) -> bool,
+) -> Result<String> {
+    let mut document: DocumentMut = raw.parse()?;
+    let names = document["names"]
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| Report::msg("names is not a table"))?;
+    let names = names
+        .entry(kind)
+        .or_insert(toml_edit::table())
+        .as_table_like_mut()
+        .ok_or_else(|| Report::msg(format!("names.{kind} is not a table")))?;
+    let existing: Vec<String> = names
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| matches(key))
+        .collect();
+    for existing in existing {
+        names.remove(&existing);
+    }
+    names.insert(key, toml_edit::value(name));
+    names.fmt();
+    Ok(document.to_string())
+}
+
+fn parse_mi_temp_names(names: &str) -> Result<BTreeMap<BDAddr, String>> {
+    names
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.split('=');
+            if let (Some(mac), Some(name)) = (
+                parts.next().map(BDAddr::from_mi_temp_mac_part),
+                parts.next(),
+            ) {
+                let mac = mac.wrap_err("Invalid MITEMP_NAMES")?;
+                Ok((mac, name.to_string()))
+            } else {
+                Err(Report::msg("Invalid MITEMP_NAMES"))
+            }
+        })
+        .collect()
+}
+
+fn parse_rf_temp_names(names: &str) -> Result<HashMap<RfDeviceId<'static>, String>> {
+    names
+        .split(',')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.split('=');
+            if let (Some(channel), Some(name)) = (parts.next(), parts.next()) {
+                let device_id = RfDeviceId::from_str(channel).wrap_err("Invalid RF_TEMP_NAMES")?;
+                Ok((device_id, name.to_string()))
+            } else {
+                Err(Report::msg("Invalid RF_TEMP_NAMES"))
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_listen() {
+    let config: Config = toml::from_str(
+        r#"
+        [listen]
+        port = 3030
+        [names]
+        mitemp = {}
+        rftemp = {}
+        [mqtt]
+        hostname = "mqtt"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(1, config.listen.len());
+
+    let config: Config = toml::from_str(
+        r#"
+        [[listen]]
+        address = "::"
+        port = 3030
+        [[listen]]
+        socket = "/run/taspromto.sock"
+        [names]
+        mitemp = {}
+        rftemp = {}
+        [mqtt]
+        hostname = "mqtt"
+        "#,
+    )
+    .unwrap();
+    assert!(matches!(
+        config.listen.as_slice(),
+        [
+            ListenConfig::Ip { port: 3030, .. },
+            ListenConfig::Unix { .. }
+        ]
+    ));
+}
+
+#[test]
+fn test_set_name() {
+    let raw = r#"listen = { port = 3030 }
+
+# names for the sensors
+[names]
+mitemp = { 351234 = "Bedroom" }
+
+[mqtt]
+hostname = "mqtt"
+"#;
+    let updated = set_name(raw, "mitemp", "ABCDEF", "Kitchen", |_| false).unwrap();
+    assert_eq!(
+        r#"listen = { port = 3030 }
+
+# names for the sensors
+[names]
+mitemp = { 351234 = "Bedroom", ABCDEF = "Kitchen" }
+
+[mqtt]
+hostname = "mqtt"
+"#,
+        updated
+    );
+    let updated = set_name(&updated, "mitemp", "351234", "Attic", |key| key == "351234").unwrap();
+    assert!(updated.contains(r#"{ ABCDEF = "Kitchen", 351234 = "Attic" }"#));
+
+    let updated = set_name(raw, "rftemp", "Bresser-3CH:73:1", "Front Yard", |_| false).unwrap();
+    let config: Config = toml::from_str(&updated).unwrap();
+    let id = RfDeviceId::from_str("Bresser-3CH:73:1").unwrap();
+    assert_eq!("Front Yard", config.names.rf_temp[&id]);
+}
+
+#[test]
+fn test_broker_address() {
+    assert_eq!(("mqtt.lan", 1883), broker_address("mqtt.lan", 1883));
+    assert_eq!(("mqtt.lan", 1884), broker_address("mqtt.lan:1884", 1883));
+    assert_eq!(("fd00::1", 1883), broker_address("fd00::1", 1883));
+    assert_eq!(("fd00::1", 8883), broker_address("[fd00::1]:8883", 1883));
+}
+
+#[test]
+fn test_pulse_meter_keys() {
+    let parse = |meter: &str| toml::from_str::<GasMeterConfig>(meter).map(|meter| meter.source);
+    assert_eq!(None, parse("m3_per_pulse = 0.01").unwrap());
+    assert_eq!(
+        Some(PulseSource::Topic("s0/gas".into())),
+        parse("topic = \"s0/gas\"\nm3_per_pulse = 0.01").unwrap()
+    );
+    assert!(parse("countr = \"tasmota_1A2B3C/C1\"\nm3_per_pulse = 0.01").is_err());
+    assert!(parse("counter = \"tasmota/C1\"\ntopic = \"s0/gas\"\nm3_per_pulse = 0.01").is_err());
+    assert!(parse("liters_per_pulse = 10").is_err());
+    assert!(toml::from_str::<WaterMeterConfig>("liters_per_pulse = 1").is_err());
+}
+
+#[test]
+fn test_default_retention() {
+    let retention = RetentionConfig::default();
+    let last_seen = Instant::now() - Duration::from_secs(16 * 60);
+    assert!(retention.tasmota.expired(last_seen));
+    assert!(retention.mi_temp.expired(last_seen));
+    assert!(!retention.dsmr.expired(last_seen));
+    assert!(!retention.rtl.expired(last_seen));
+    assert!(!retention.tasmota.expired(Instant::now()));
+}
+
+#[test]
+fn test_missing_password_file() {
+    let credentials = Credentials::File {
+        username: "taspromto".into(),
+        password_file: "/nonexistent/password".into(),
+    };
+    assert!(credentials.try_password().is_err());
+}
+
+#[test]
+fn test_log_patterns() {
+    let config: LogConfig = toml::from_str(
+        r#"
+        include = ["tele/*", "tasmota_kitchen"]
+        exclude = ["*/BTtoMQTT/*"]
+        "#,
+    )
+    .unwrap();
+    assert!(config.logs(None, "tele/tasmota_hall/SENSOR"));
+    assert!(config.logs(Some("tasmota_kitchen"), "stat/tasmota_kitchen/RESULT"));
+    assert!(!config.logs(None, "stat/tasmota_hall/RESULT"));
+    assert!(!config.logs(None, "tele/omg/BTtoMQTT/A4C138123456"));
+    assert!(!LogConfig::default().logs(None, "tele/tasmota_hall/SENSOR"));
+}
+
+#[test]
+fn test_zero_interval() {
+    let config = |section: &str| {
+        toml::from_str::<Config>(&format!(
+            "[listen]\nport = 3030\n[names]\nmitemp = {{}}\nrftemp = {{}}\n{section}"
+        ))
+    };
+    for section in [
+        "[history]\npath = \"history.db\"\ninterval = 0",
+        "[statsd]\naddress = \"localhost:8125\"\ninterval = 0",
+        "[publish]\ninterval = 0",
+    ] {
+        let error = config(section).unwrap_err().to_string();
+        assert!(
+            error.contains("interval must be at least 1 second"),
+            "{error}"
+        );
     }
+    let config = config("[history]\npath = \"history.db\"\ninterval = 5").unwrap();
+    assert_eq!(5, config.history.unwrap().interval);
+}
+
+#[test]
+fn test_duplicate_rule_names() {
+    let config = |rules: &str| {
+        toml::from_str::<Config>(&format!(
+            "[listen]\nport = 3030\n[names]\nmitemp = {{}}\nrftemp = {{}}\n{rules}"
+        ))
+    };
+    let rule = "[[rules]]\nname = \"high power\"\nexpr = \"power_watts > 2000\"\n";
+    assert_eq!(1, config(rule).unwrap().rules.len());
+    let error = config(&format!("{rule}{rule}")).unwrap_err().to_string();
+    assert!(error.contains("duplicate rule name high power"), "{error}");
 }
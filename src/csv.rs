@@ -0,0 +1,44 @@
+use crate::history::Row;
+use std::borrow::Cow;
+use std::fmt::Write;
+
+pub const CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+/// Format readings as csv with a header row
+pub fn format_csv(rows: &[Row]) -> String {
+    let mut out = String::from("time,device,metric,value\n");
+    for (time, device, metric, value) in rows {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            time,
+            escape(device),
+            escape(metric),
+            value
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn escape(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\"")).into()
+    } else {
+        value.into()
+    }
+}
+
+#[test]
+fn test_format_csv() {
+    let rows = [
+        (100, "plug".to_string(), "power_watts".to_string(), 12.5),
+        (100, "a,\"b\"".to_string(), "online".to_string(), 1.0),
+    ];
+    assert_eq!(
+        "time,device,metric,value\n\
+        100,plug,power_watts,12.5\n\
+        100,\"a,\"\"b\"\"\",online,1\n",
+        format_csv(&rows)
+    );
+}
@@ -0,0 +1,135 @@
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+
+/// The current date in the configured timezone
+pub fn today(timezone: &TimeZone) -> Date {
+    timezone.to_datetime(Timestamp::now()).date()
+}
+
+/// Lowest and highest value seen during a single day
+#[derive(Debug, Clone, Copy)]
+pub struct DailyRange {
+    pub date: Date,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl DailyRange {
+    /// Include a value in the range, starting over when the day changed
+    pub fn update(range: &mut Option<DailyRange>, date: Date, value: f32) {
+        match range {
+            Some(range) if range.date == date => {
+                range.min = range.min.min(value);
+                range.max = range.max.max(value);
+            }
+            _ => {
+                *range = Some(DailyRange {
+                    date,
+                    min: value,
+                    max: value,
+                })
+            }
+        }
+    }
+
+    /// Forget a range from a previous day, returns whether it was removed
+    pub fn roll_over(range: &mut Option<DailyRange>, date: Date) -> bool {
+        let expired = range.is_some_and(|range| range.date != date);
+        if expired {
+            *range = None;
+        }
+        expired
+    }
+}
+
+/// Increase of a cumulative total during a single day
+#[derive(Debug, Clone, Copy)]
+pub struct DailyTotal {
+    pub date: Date,
+    /// Increase of the total since midnight, or since the total was first seen
+    pub increase: f32,
+    last: f32,
+}
+
+impl DailyTotal {
+    pub fn update(daily: &mut Option<DailyTotal>, date: Date, total: f32) {
+        let Some(daily) = daily else {
+            *daily = Some(DailyTotal {
+                date,
+                increase: 0.0,
+                last: total,
+            });
+            return;
+        };
+        if daily.date != date {
+            daily.date = date;
+            daily.increase = 0.0;
+        }
+        // after a reset the device counts up from zero again
+        daily.increase += if total < daily.last {
+            total
+        } else {
+            total - daily.last
+        };
+        daily.last = total;
+    }
+
+    /// Start counting from zero when the day changed, returns whether it was reset
+    pub fn roll_over(daily: &mut Option<DailyTotal>, date: Date) -> bool {
+        match daily {
+            Some(daily) if daily.date != date => {
+                daily.date = date;
+                daily.increase = 0.0;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_daily_range() {
+    let monday = jiff::civil::date(2024, 1, 1);
+    let tuesday = jiff::civil::date(2024, 1, 2);
+    let mut range = None;
+    DailyRange::update(&mut range, monday, 12.0);
+    DailyRange::update(&mut range, monday, 8.5);
+    DailyRange::update(&mut range, monday, 10.0);
+    let today = range.unwrap();
+    assert_eq!((8.5, 12.0), (today.min, today.max));
+
+    assert!(!DailyRange::roll_over(&mut range, monday));
+    assert!(DailyRange::roll_over(&mut range, tuesday));
+    assert!(range.is_none());
+
+    DailyRange::update(&mut range, monday, 12.0);
+    DailyRange::update(&mut range, tuesday, 9.0);
+    let today = range.unwrap();
+    assert_eq!((tuesday, 9.0, 9.0), (today.date, today.min, today.max));
+
+    let utc = TimeZone::UTC;
+    let tokyo = TimeZone::fixed(jiff::tz::offset(9));
+    let time: Timestamp = "2024-01-01T20:00:00Z".parse().unwrap();
+    assert_eq!(monday, utc.to_datetime(time).date());
+    assert_eq!(tuesday, tokyo.to_datetime(time).date());
+}
+
+#[test]
+fn test_daily_total() {
+    let monday = jiff::civil::date(2024, 1, 1);
+    let tuesday = jiff::civil::date(2024, 1, 2);
+    let mut daily = None;
+    DailyTotal::update(&mut daily, monday, 100.0);
+    assert_eq!(0.0, daily.unwrap().increase);
+    DailyTotal::update(&mut daily, monday, 101.5);
+    DailyTotal::update(&mut daily, monday, 102.0);
+    assert_eq!(2.0, daily.unwrap().increase);
+
+    assert!(DailyTotal::roll_over(&mut daily, tuesday));
+    assert_eq!(0.0, daily.unwrap().increase);
+    DailyTotal::update(&mut daily, tuesday, 103.0);
+    // reset by the device
+    DailyTotal::update(&mut daily, tuesday, 0.5);
+    assert_eq!(1.5, daily.unwrap().increase);
+}
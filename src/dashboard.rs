@@ -0,0 +1,163 @@
+use crate::config::Config;
+use crate::device::{DeviceStates, TempSource};
+use std::fmt::{self, Display, Formatter, Write};
+use std::time::{Duration, Instant};
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse;margin-bottom:2em}\
+th,td{border:1px solid #ccc;padding:0.3em 0.8em;text-align:left}\
+tr.stale{background:#f8d0d0}";
+
+/// Escape text for use in html
+struct Html<'a>(&'a str);
+
+impl Display for Html<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Age(Instant);
+
+impl Display for Age {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let seconds = self.0.elapsed().as_secs();
+        match seconds {
+            0..=59 => write!(f, "{}s ago", seconds),
+            60..=3599 => write!(f, "{}m ago", seconds / 60),
+            _ => write!(f, "{}h ago", seconds / 3600),
+        }
+    }
+}
+
+fn option<T: Display>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn row_class(last_seen: Instant, stale: Duration) -> &'static str {
+    if last_seen.elapsed() > stale {
+        "stale"
+    } else {
+        ""
+    }
+}
+
+/// Render an overview of all tracked devices, devices that haven't been seen for longer than the ping interval are highlighted
+pub fn render(config: &Config, state: &DeviceStates) -> String {
+    render_inner(config, state).unwrap()
+}
+
+fn render_inner(config: &Config, state: &DeviceStates) -> Result<String, fmt::Error> {
+    let retention = &config.retention;
+    let mut out = String::new();
+    writeln!(
+        out,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"30\">\
+        <title>taspromto</title><style>{STYLE}</style></head><body>"
+    )?;
+
+    writeln!(out, "<h2>Tasmota</h2><table><tr><th>Hostname</th><th>Name</th><th>Last seen</th><th>Power</th><th>Watts</th><th>Total kWh</th><th>CO²</th><th>Firmware</th></tr>")?;
+    let mut devices: Vec<_> = state.devices().collect();
+    devices.sort_by(|a, b| a.0.hostname.cmp(&b.0.hostname));
+    for (device, device_state) in devices {
+        let class = if device_state.online {
+            row_class(device_state.last_seen, retention.tasmota.ping())
+        } else {
+            "stale"
+        };
+        let power = match device_state.state {
+            Some(true) => "on",
+            Some(false) => "off",
+            None => "",
+        };
+        writeln!(
+            out,
+            "<tr class=\"{class}\"><td>{}</td><td>{}</td><td>{}</td><td>{power}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            Html(&device.hostname),
+            Html(device_state.display_name(device, &config.names.tasmota)),
+            Age(device_state.last_seen),
+            option(device_state.power_watts),
+            option(device_state.power_total),
+            option(device_state.co2),
+            Html(&device_state.firmware),
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<h2>P1 meters</h2><table><tr><th>Hostname</th><th>Last seen</th><th>Watts</th><th>Tariff 1 kWh</th><th>Tariff 2 kWh</th><th>Gas m³</th><th>Water m³</th></tr>")?;
+    for (device, device_state) in state.dsmr_devices() {
+        writeln!(
+            out,
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            row_class(device_state.last_seen, retention.dsmr.ping()),
+            Html(&device.hostname),
+            Age(device_state.last_seen),
+            option(device_state.power),
+            option(device_state.power_total_tariff_1),
+            option(device_state.power_total_tariff_2),
+            option(device_state.gas_total),
+            option(device_state.water_total),
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<h2>Xiaomi sensors</h2><table><tr><th>Address</th><th>Name</th><th>Last seen</th><th>Temperature</th><th>Humidity</th><th>Battery</th></tr>")?;
+    for (addr, device_state) in state.mi_temp() {
+        let name = config.names.mi_temp.get(addr).map(String::as_str);
+        writeln!(
+            out,
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            row_class(device_state.last_seen, retention.mi_temp.ping()),
+            addr,
+            Html(name.unwrap_or_default()),
+            Age(device_state.last_seen),
+            option(device_state.temperature),
+            option(device_state.humidity),
+            option(device_state.battery),
+        )?;
+    }
+    writeln!(out, "</table>")?;
+
+    writeln!(out, "<h2>433Mhz sensors</h2><table><tr><th>Id</th><th>Name</th><th>Last seen</th><th>Temperature</th><th>Humidity</th></tr>")?;
+    for (id, device_state) in state.rf_temp() {
+        let name = config.names.rf_temp.get(&id.sensor()).map(String::as_str);
+        let stale = match device_state.source {
+            TempSource::RfLink => retention.rf.ping(),
+            TempSource::Rtl => retention.rtl.ping(),
+        };
+        writeln!(
+            out,
+            "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            row_class(device_state.last_seen, stale),
+            Html(&id.to_string()),
+            Html(name.unwrap_or_default()),
+            Age(device_state.last_seen),
+            option(
+                device_state
+                    .temperature
+                    .map(|temperature| format!("{temperature:.1}"))
+            ),
+            option(device_state.humidity),
+        )?;
+    }
+    writeln!(out, "</table></body></html>")?;
+    Ok(out)
+}
+
+#[test]
+fn test_escape_html() {
+    assert_eq!(
+        "&lt;b&gt;Tom &amp; Jerry&#39;s &quot;plug&quot;&lt;/b&gt;",
+        Html("<b>Tom & Jerry's \"plug\"</b>").to_string()
+    );
+}
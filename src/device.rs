@@ -1,27 +1,181 @@
+use crate::air_quality::format_air_quality;
+use crate::anomaly::PowerBaseline;
+use crate::climate::format_derived;
+use crate::config::{
+    AnomalyConfig, DeviceLimits, IgnoreList, NamesConfig, RetentionConfig, RetentionTimes,
+};
+use crate::daily::{self, DailyRange, DailyTotal};
+use crate::ebusd::EbusdState;
+use crate::evse::{self, EvseState};
+use crate::firmware::FirmwareVersion;
+use crate::metrics::{self, Registry};
+use crate::otgw::OtgwState;
+use crate::payload::{MiTemp, OmgBle, OmgRf, Pms5003, TasmotaPayload, P1};
+use crate::plausibility::{Plausibility, Quantity, SpikeFilter};
+use crate::pulse::PulseMeter;
+use crate::rate::Rate;
+use crate::rtl::{integrity_failed, RtlReading};
+use crate::solar::{SolarState, SolarValue};
 use color_eyre::{eyre::WrapErr, Report, Result};
-use jzon::JsonValue;
-use rumqttc::{AsyncClient, QoS};
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
-use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap};
+use serde_json::{json, Value};
+use std::borrow::{Borrow, Cow};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
-use std::fmt::{self, Debug, Display, Formatter, Write};
+use std::fmt::{self, Debug, Display, Formatter};
 use std::num::ParseIntError;
 use std::str::FromStr;
-use std::time::Instant;
-use tokio::task::spawn;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{debug, info};
+
+/// Device states shared between the mqtt worker and everything reading them
+///
+/// An async lock, so tasks waiting on a contended lock yield instead of blocking an executor thread.
+pub type SharedDeviceStates = Arc<RwLock<DeviceStates>>;
 
-#[derive(Default)]
 pub struct DeviceStates {
     pub devices: HashMap<Device, DeviceState>,
     pub dsmr_devices: HashMap<Device, DsmrState>,
+    pub otgw_devices: HashMap<Device, OtgwState>,
+    /// ebusd circuits, by circuit name
+    pub ebusd_devices: HashMap<Device, EbusdState>,
+    /// Solar inverters, by the configured inverter name
+    pub solar_devices: HashMap<Device, SolarState>,
+    pub evse_devices: HashMap<Device, EvseState>,
     pub mi_temp_devices: BTreeMap<BDAddr, MiTempState>,
     pub rf_temp_devices: HashMap<RfDeviceId<'static>, TempState>,
     active_rf_temp_id: RfDeviceId<'static>,
+    /// State of the active rtl_433 sensor from before the current frame, to undo the frame if it fails its integrity check
+    ///
+    /// `Some(None)` if the sensor wasn't tracked before the frame.
+    rtl_frame_start: Option<Option<TempState>>,
+    /// Number of rtl_433 frames dropped because they failed their integrity check
+    pub rejected_rf_frames: u64,
+    pub latest_firmware: Option<FirmwareVersion>,
+    updates: broadcast::Sender<StateUpdate>,
+    /// Incremented on every change, devices store the revision of their last change
+    revision: u64,
+    /// Number of devices dropped because of the device limits, by device class
+    pub dropped: HashMap<&'static str, u64>,
+    /// Timezone daily values roll over in
+    pub timezone: TimeZone,
+    /// Track the power baseline of devices when enabled
+    pub anomaly: Option<AnomalyConfig>,
+    /// Sensors that aren't exported, by device hostname
+    pub ignore: HashMap<String, IgnoreList>,
+    /// Values published by rules that are tracked besides the rule variables
+    pub named_variables: HashSet<String>,
+    /// Readings outside of the configured bounds are discarded
+    pub plausibility: Plausibility,
+    /// Water and gas meters read by counting pulses
+    pub pulse_meters: Vec<PulseMeter>,
+    /// Devices of the configured tenants, by tenant name
+    pub tenants: BTreeMap<String, DeviceStates>,
+}
+
+impl Default for DeviceStates {
+    fn default() -> Self {
+        DeviceStates {
+            devices: HashMap::default(),
+            dsmr_devices: HashMap::default(),
+            otgw_devices: HashMap::default(),
+            ebusd_devices: HashMap::default(),
+            solar_devices: HashMap::default(),
+            evse_devices: HashMap::default(),
+            mi_temp_devices: BTreeMap::default(),
+            rf_temp_devices: HashMap::default(),
+            active_rf_temp_id: RfDeviceId::default(),
+            rtl_frame_start: None,
+            rejected_rf_frames: 0,
+            latest_firmware: None,
+            updates: broadcast::channel(256).0,
+            revision: 0,
+            dropped: HashMap::default(),
+            timezone: TimeZone::system(),
+            anomaly: None,
+            ignore: HashMap::default(),
+            named_variables: HashSet::default(),
+            plausibility: Plausibility::default(),
+            pulse_meters: Vec::new(),
+            tenants: BTreeMap::new(),
+        }
+    }
+}
+
+/// A changed value for a tracked device
+#[derive(Debug, Clone)]
+pub struct StateUpdate {
+    pub device: String,
+    pub field: &'static str,
+    pub value: Value,
+}
+
+impl StateUpdate {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "device": self.device.as_str(),
+            "field": self.field,
+            "value": self.value.clone(),
+        })
+    }
+}
+
+pub type Readings = Vec<(&'static str, Value)>;
+
+fn snapshot(ty: &str, name: &str, readings: Readings) -> Value {
+    let mut json = json!({});
+    for (field, value) in readings {
+        json[field] = value;
+    }
+    json["type"] = ty.into();
+    json["name"] = name.into();
+    json
+}
+
+/// Send an update for every reading that changed
+fn notify_changes(
+    updates: &broadcast::Sender<StateUpdate>,
+    device: impl Display,
+    before: Readings,
+    after: Readings,
+) {
+    if updates.receiver_count() == 0 {
+        return;
+    }
+    let device = device.to_string();
+    for ((field, old), (_, new)) in before.into_iter().zip(after) {
+        if old != new {
+            notify(updates, device.clone(), field, new);
+        }
+    }
+}
+
+fn notify(
+    updates: &broadcast::Sender<StateUpdate>,
+    device: String,
+    field: &'static str,
+    value: Value,
+) {
+    // only fails if there are no subscribers
+    let _ = updates.send(StateUpdate {
+        device,
+        field,
+        value,
+    });
 }
 
 impl DeviceStates {
+    /// Receive an update for every changed value
+    pub fn subscribe(&self) -> broadcast::Receiver<StateUpdate> {
+        self.updates.subscribe()
+    }
+
     pub fn devices(&self) -> impl Iterator<Item = (&Device, &DeviceState)> {
         self.devices.iter()
     }
@@ -30,80 +184,452 @@ impl DeviceStates {
         self.dsmr_devices.iter()
     }
 
-    pub fn update(&mut self, device: Device, json: JsonValue) {
-        let device = self.devices.entry(device).or_default();
+    fn next_revision(&mut self) -> u64 {
+        self.revision += 1;
+        self.revision
+    }
 
-        for (key, value) in json.entries() {
-            if let Some(addr) = key.strip_prefix("MJ_HT_V1") {
-                let addr = addr.trim_start_matches('-');
-                match BDAddr::from_mi_temp_mac_part(addr) {
-                    Ok(addr) => {
-                        let state = self.mi_temp_devices.entry(addr).or_default();
-                        state.update(value);
-                    }
-                    Err(e) => eprintln!("Failed to parse mitemp mac: {:#}", e),
-                }
+    fn today(&self) -> Date {
+        daily::today(&self.timezone)
+    }
+
+    /// Apply a payload, parsed before taking the lock to keep it short
+    pub fn update(&mut self, hostname: &str, payload: TasmotaPayload) -> Result<()> {
+        let today = self.today();
+        let clock_drift = payload.clock_drift(&self.timezone, Timestamp::now());
+        let result = self.update_mi_temp(&payload, today);
+        self.update_counters(hostname, &payload);
+
+        let revision = self.next_revision();
+        let no_ignore = IgnoreList::default();
+        let ignore = self.ignore.get(hostname).unwrap_or(&no_ignore);
+        let device = device_entry(&mut self.devices, hostname);
+        let before = device.readings();
+        device.update(
+            &payload,
+            today,
+            ignore,
+            &self.named_variables,
+            &mut self.plausibility,
+        );
+        if clock_drift.is_some() {
+            device.clock_drift = clock_drift;
+        }
+        match (&self.anomaly, device.power_watts) {
+            (Some(config), Some(power)) => match &mut device.power_baseline {
+                Some(baseline) => baseline.update(power, Instant::now(), config),
+                None => device.power_baseline = Some(PowerBaseline::new(power, Instant::now())),
+            },
+            _ => device.power_baseline = None,
+        }
+        device.revision = revision;
+        device.last_payload = payload.raw;
+        notify_changes(&self.updates, hostname, before, device.readings());
+        result
+    }
+
+    fn update_mi_temp(&mut self, payload: &TasmotaPayload, today: Date) -> Result<()> {
+        let mut result = Ok(());
+        for (addr, reading) in payload.mi_temp() {
+            match BDAddr::from_mi_temp_mac_part(addr) {
+                Ok(addr) => self.update_mi_temp_reading(addr, &reading, today),
+                Err(e) => result = Err(e.wrap_err("Failed to parse mitemp mac")),
             }
         }
+        for nested in payload.nested() {
+            result = result.and(self.update_mi_temp(nested, today));
+        }
+        result
+    }
+
+    fn update_mi_temp_reading(&mut self, addr: BDAddr, reading: &MiTemp, today: Date) {
+        let revision = self.next_revision();
+        let state = self.mi_temp_devices.entry(addr).or_default();
+        let plausibility = &mut self.plausibility;
+        let temperature = plausibility.check(Quantity::Temperature, reading.temperature);
+        let humidity = plausibility.check(Quantity::Humidity, reading.humidity);
+        let reading = MiTemp {
+            temperature: plausibility.confirm(
+                &mut state.spikes,
+                Quantity::Temperature,
+                temperature,
+            ),
+            humidity: plausibility.confirm(&mut state.spikes, Quantity::Humidity, humidity),
+            ..*reading
+        };
+        let before = state.readings();
+        state.update(&reading, today);
+        state.revision = revision;
+        notify_changes(&self.updates, addr, before, state.readings());
+    }
+
+    /// Apply a bluetooth reading from OpenMQTTGateway, by the mac address from the topic
+    pub fn update_omg_ble(&mut self, mac: &str, reading: &OmgBle) -> Result<()> {
+        // the gateway publishes every bluetooth device it sees, not only sensors
+        if reading.tempc.is_none() {
+            return Ok(());
+        }
+        let part = mac.get(mac.len().saturating_sub(6)..).unwrap_or_default();
+        let addr = BDAddr::from_mi_temp_mac_part(part).wrap_err("Failed to parse mitemp mac")?;
+        let today = self.today();
+        self.update_mi_temp_reading(addr, &reading.mi_temp(), today);
+        Ok(())
+    }
+
+    /// Apply an rtl_433 reading from OpenMQTTGateway, the gateway is tracked as the receiver
+    pub fn update_omg_rf(&mut self, gateway: &str, reading: OmgRf) {
+        if reading.mic.as_deref().is_some_and(integrity_failed) {
+            self.rejected_rf_frames += 1;
+            return;
+        }
+        // signals from remotes and switches don't carry any readings
+        if reading.temperature.is_none()
+            && reading.humidity.is_none()
+            && reading.wind_avg_km_h.is_none()
+        {
+            return;
+        }
+        let plausibility = &mut self.plausibility;
+        let temperature = plausibility.check(Quantity::Temperature, reading.temperature);
+        let humidity = plausibility.check(Quantity::Humidity, reading.humidity);
+        let wind_speed = reading.wind_avg_km_h.map(|speed| speed / 3.6);
+        let wind_speed = plausibility.check(Quantity::WindSpeed, wind_speed);
+        let id = RfDeviceId {
+            name: reading.model.into(),
+            id: reading.id.unwrap_or_default(),
+            channel: reading.channel.unwrap_or_default(),
+            receiver: gateway.to_string().into(),
+        };
+        let today = self.today();
+        let revision = self.next_revision();
+        let state = self.rf_temp_devices.entry(id.clone()).or_default();
+        let before = state.readings();
+        let plausibility = &self.plausibility;
+        let spikes = &mut state.spikes;
+        if let Some(temperature) = plausibility.confirm(spikes, Quantity::Temperature, temperature)
+        {
+            state.temperature = Some(temperature);
+            DailyRange::update(&mut state.temperature_today, today, temperature);
+        }
+        if let Some(humidity) = plausibility.confirm(spikes, Quantity::Humidity, humidity) {
+            state.humidity = Some(humidity);
+        }
+        if let Some(wind_speed) = plausibility.confirm(spikes, Quantity::WindSpeed, wind_speed) {
+            state.wind_speed = Some(wind_speed);
+        }
+        if let Some(battery_ok) = reading.battery_ok {
+            state.set_battery(battery_ok != 0);
+        }
+        state.source = TempSource::Rtl;
+        state.last_seen = Instant::now();
+        state.revision = revision;
+        notify_changes(&self.updates, id, before, state.readings());
+    }
+
+    pub fn set_command_latency(&mut self, hostname: &str, latency: Duration) {
+        let revision = self.next_revision();
+        if let Some(state) = self.devices.get_mut(hostname) {
+            state.command_latency = Some(latency);
+            state.revision = revision;
+        }
+    }
 
-        device.update(json);
+    pub fn update_dsmr(
+        &mut self,
+        hostname: &str,
+        ty: DsmrMessageType,
+        payload: &str,
+    ) -> Result<()> {
+        let value = payload
+            .parse()
+            .wrap_err_with(|| format!("invalid dsmr payload: {payload}"))?;
+        self.update_meter(hostname, ty, value);
+        Ok(())
     }
 
-    pub fn update_dsmr(&mut self, device: Device, ty: DsmrMessageType, payload: &str) {
-        if let Ok(value) = payload.parse() {
-            let state = self.dsmr_devices.entry(device).or_default();
-            match ty {
-                DsmrMessageType::Water => state.water_total = Some(value),
-                DsmrMessageType::Gas => state.gas_total = Some(value),
-                DsmrMessageType::Energy1 => state.power_total_tariff_1 = Some(value),
-                DsmrMessageType::Energy2 => state.power_total_tariff_2 = Some(value),
-                DsmrMessageType::Power => state.power = Some(value),
+    /// Apply the pulse count published for a pulse meter
+    pub fn update_pulses(&mut self, name: &str, ty: DsmrMessageType, payload: &str) -> Result<()> {
+        let pulses: f64 = payload
+            .trim()
+            .parse()
+            .wrap_err_with(|| format!("invalid pulse count: {payload}"))?;
+        let meter = self
+            .pulse_meters
+            .iter()
+            .find(|meter| meter.name == name && meter.ty == ty);
+        if let Some(value) = meter.map(|meter| meter.reading(pulses)) {
+            self.update_meter(name, ty, value);
+        }
+        Ok(())
+    }
+
+    /// Apply the tasmota counters that are read as pulse meters
+    fn update_counters(&mut self, hostname: &str, payload: &TasmotaPayload) {
+        for (counter, pulses) in payload.counters() {
+            let readings: Vec<_> = self
+                .pulse_meters
+                .iter()
+                .filter(|meter| meter.reads_counter(hostname, counter))
+                .map(|meter| (meter.name.clone(), meter.ty, meter.reading(pulses)))
+                .collect();
+            for (name, ty, value) in readings {
+                self.update_meter(&name, ty, value);
             }
-            state.last_seen = Instant::now();
+        }
+        for nested in payload.nested() {
+            self.update_counters(hostname, nested);
         }
     }
 
-    pub fn update_rf(&mut self, payload: &str) {
-        if let Some(data) = parse_rf_payload(payload) {
-            let state = self
-                .rf_temp_devices
-                .entry(data.device_id().to_owned())
-                .or_default();
-            state.humidity = data.humidity;
-            state.temperature = data.temperature;
-        } else {
-            eprintln!("invalid rf payload: {payload}")
+    fn update_meter(&mut self, name: &str, ty: DsmrMessageType, value: f32) {
+        let today = self.today();
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.dsmr_devices, name);
+        state.revision = revision;
+        let before = state.readings();
+        state.set(ty, value, today);
+        notify_changes(&self.updates, name, before, state.readings());
+    }
+
+    /// Apply the readings from a HomeWizard P1 meter, tracked as a dsmr device
+    pub fn update_p1(&mut self, name: &str, reading: &P1) {
+        let today = self.today();
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.dsmr_devices, name);
+        state.revision = revision;
+        let before = state.readings();
+        for (ty, value) in reading.values() {
+            state.set(ty, value, today);
+        }
+        notify_changes(&self.updates, name, before, state.readings());
+    }
+
+    pub fn update_otgw(&mut self, hostname: &str, field: &str, payload: &str) {
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.otgw_devices, hostname);
+        let before = state.readings();
+        if state.update(field, payload) {
+            state.revision = revision;
+            notify_changes(&self.updates, hostname, before, state.readings());
+        }
+    }
+
+    pub fn update_ebusd(&mut self, circuit: &str, field: &str, payload: &str) {
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.ebusd_devices, circuit);
+        let before = state.readings();
+        if state.update(field, payload) {
+            state.revision = revision;
+            notify_changes(&self.updates, circuit, before, state.readings());
+        }
+    }
+
+    pub fn update_solar(&mut self, inverter: &str, value: SolarValue, payload: &str) {
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.solar_devices, inverter);
+        let before = state.readings();
+        if state.update(value, payload) {
+            state.revision = revision;
+            notify_changes(&self.updates, inverter, before, state.readings());
+        }
+    }
+
+    pub fn update_evse(&mut self, name: &str, field: &str, payload: &str) {
+        // don't create the charger for a value that isn't going to be applied
+        if !evse::is_tracked(field, payload) {
+            return;
+        }
+        let revision = self.next_revision();
+        let state = device_entry(&mut self.evse_devices, name);
+        let before = state.readings();
+        if state.update(field, payload) {
+            state.revision = revision;
+            notify_changes(&self.updates, name, before, state.readings());
+        }
+    }
+
+    pub fn update_rf(&mut self, payload: &str) -> Result<()> {
+        let data = parse_rf_payload(payload)
+            .ok_or_else(|| Report::msg(format!("invalid rf payload: {payload}")))?;
+        let id = data.device_id().to_owned();
+        let plausibility = &mut self.plausibility;
+        let Some(temperature) = plausibility.check(Quantity::Temperature, Some(data.temperature))
+        else {
+            return Ok(());
+        };
+        let humidity = plausibility.check(Quantity::Humidity, Some(data.humidity));
+        let today = self.today();
+        let revision = self.next_revision();
+        let state = self.rf_temp_devices.entry(id.clone()).or_default();
+        state.revision = revision;
+        let before = state.readings();
+        let plausibility = &self.plausibility;
+        let spikes = &mut state.spikes;
+        if let Some(humidity) = plausibility.confirm(spikes, Quantity::Humidity, humidity) {
+            state.humidity = Some(humidity);
+        }
+        if let Some(temperature) =
+            plausibility.confirm(spikes, Quantity::Temperature, Some(temperature))
+        {
+            state.temperature = Some(temperature);
+            DailyRange::update(&mut state.temperature_today, today, temperature);
         }
+        state.set_battery(data.battery);
+        state.source = TempSource::RfLink;
+        state.last_seen = Instant::now();
+        notify_changes(&self.updates, id, before, state.readings());
+        Ok(())
     }
 
-    pub fn update_rtl(&mut self, device: &str, field: &str, payload: &str) {
-        if self.active_rf_temp_id.name != device {
+    /// Apply a single field published by rtl_433, the receiver is empty if it isn't included in the topic
+    pub fn update_rtl(&mut self, receiver: &str, device: &str, field: &str, payload: &str) {
+        if self.active_rf_temp_id.name != device || self.active_rf_temp_id.receiver != receiver {
             self.active_rf_temp_id = RfDeviceId::default();
             self.active_rf_temp_id.name = device.to_string().into();
+            self.active_rf_temp_id.receiver = receiver.to_string().into();
+            self.rtl_frame_start = None;
         }
         match field {
-            "id" => self.active_rf_temp_id.id = payload.parse().unwrap_or_default(),
+            "id" => {
+                // the id is published at the start of every frame
+                self.active_rf_temp_id.id = payload.parse().unwrap_or_default();
+                self.rtl_frame_start = None;
+            }
             "channel" => self.active_rf_temp_id.channel = payload.parse().unwrap_or_default(),
-            "temperature_F" | "humidity" => self.update_active_rtl(field, payload),
-            _ => {}
+            // published after the readings of the frame
+            "mic" | "status" if integrity_failed(payload) => self.reject_rtl_frame(),
+            _ => {
+                if let Some(reading) = RtlReading::parse(field, payload) {
+                    self.update_active_rtl(reading)
+                }
+            }
         }
     }
 
-    fn update_active_rtl(&mut self, field: &str, payload: &str) {
+    fn update_active_rtl(&mut self, reading: RtlReading) {
+        if let Some((quantity, value)) = reading.quantity() {
+            if self.plausibility.check(quantity, Some(value)).is_none() {
+                return;
+            }
+        }
+        let today = self.today();
+        let revision = self.next_revision();
+        if self.rtl_frame_start.is_none() {
+            let state = self.rf_temp_devices.get(&self.active_rf_temp_id).cloned();
+            self.rtl_frame_start = Some(state);
+        }
         let state = self
             .rf_temp_devices
             .entry(self.active_rf_temp_id.to_owned())
             .or_default();
-        match field {
-            "temperature_F" => {
-                state.temperature = payload
-                    .parse()
-                    .map(|temp_f: f32| (temp_f - 32.0) * 5.0 / 9.0)
-                    .unwrap_or_default()
+        let before = state.readings();
+        state.source = TempSource::Rtl;
+        state.last_seen = Instant::now();
+        state.revision = revision;
+        if let Some((quantity, value)) = reading.quantity() {
+            let spikes = &mut state.spikes;
+            if self
+                .plausibility
+                .confirm(spikes, quantity, Some(value))
+                .is_none()
+            {
+                return;
+            }
+        }
+        match reading {
+            RtlReading::Temperature(temperature) => {
+                state.temperature = Some(temperature);
+                DailyRange::update(&mut state.temperature_today, today, temperature);
+            }
+            RtlReading::Humidity(humidity) => state.humidity = Some(humidity),
+            RtlReading::WindSpeed(speed) => state.wind_speed = Some(speed),
+            RtlReading::Pressure(pressure) => state.pressure = Some(pressure),
+            RtlReading::Rain(rain) => state.rain = Some(rain),
+            RtlReading::BatteryOk(battery_ok) => state.set_battery(battery_ok),
+        }
+        let after = state.readings();
+        notify_changes(&self.updates, &self.active_rf_temp_id, before, after);
+    }
+
+    /// Restore the active rtl_433 sensor to its state from before the current frame
+    fn reject_rtl_frame(&mut self) {
+        self.rejected_rf_frames += 1;
+        let revision = self.next_revision();
+        let id = &self.active_rf_temp_id;
+        match self.rtl_frame_start.take() {
+            Some(Some(mut state)) => {
+                let before = self.rf_temp_devices.get(id).map(TempState::readings);
+                notify_changes(
+                    &self.updates,
+                    id,
+                    before.unwrap_or_default(),
+                    state.readings(),
+                );
+                state.revision = revision;
+                self.rf_temp_devices.insert(id.to_owned(), state);
             }
-            "humidity" => state.humidity = payload.parse().unwrap_or_default(),
-            _ => {}
+            Some(None) => {
+                self.rf_temp_devices.remove(id);
+            }
+            None => {}
+        }
+    }
+
+    /// A json snapshot of the current readings of every tracked device, by device id
+    pub fn snapshots(&self, names: &NamesConfig) -> Vec<(String, Value)> {
+        let mut snapshots = Vec::new();
+        for (device, state) in self.devices() {
+            let name = state.display_name(device, &names.tasmota);
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("tasmota", name, state.readings()),
+            ));
+        }
+        for (device, state) in self.dsmr_devices() {
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("dsmr", &device.hostname, state.readings()),
+            ));
+        }
+        for (device, state) in &self.otgw_devices {
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("otgw", &device.hostname, state.readings()),
+            ));
+        }
+        for (device, state) in &self.ebusd_devices {
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("ebusd", &device.hostname, state.readings()),
+            ));
+        }
+        for (device, state) in &self.solar_devices {
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("solar", &device.hostname, state.readings()),
+            ));
+        }
+        for (device, state) in &self.evse_devices {
+            snapshots.push((
+                device.hostname.to_string(),
+                snapshot("evse", &device.hostname, state.readings()),
+            ));
+        }
+        for (addr, state) in self.mi_temp() {
+            let name = names.mi_temp.get(addr).map(String::as_str);
+            snapshots.push((
+                addr.to_string(),
+                snapshot("mitemp", name.unwrap_or_default(), state.readings()),
+            ));
+        }
+        for (id, state) in self.rf_temp() {
+            let name = names.rf_temp.get(&id.sensor()).map(String::as_str);
+            snapshots.push((
+                id.to_string(),
+                snapshot("rf", name.unwrap_or_default(), state.readings()),
+            ));
         }
+        snapshots
     }
 
     pub fn mi_temp(&self) -> impl Iterator<Item = (&BDAddr, &MiTempState)> {
@@ -114,43 +640,274 @@ impl DeviceStates {
         self.rf_temp_devices.iter()
     }
 
-    pub fn retain(&mut self, cleanup_time: Instant, ping_time: Instant, client: &AsyncClient) {
+    /// Whether any tracked device was received from `hostname` on a topic of `topic_type`, as named by `Topic::kind`
+    pub fn tracks_source(&self, hostname: &str, topic_type: &str) -> bool {
+        match topic_type {
+            // rf and bluetooth sensors are received trough a bridge or gateway
+            "rflink" => self
+                .rf_temp_devices
+                .values()
+                .any(|state| matches!(state.source, TempSource::RfLink)),
+            "rtl_433" | "omg_rf" => self
+                .rf_temp_devices
+                .keys()
+                .any(|id| id.receiver == hostname || id.name == hostname),
+            // the gateway a bluetooth sensor was last received trough isn't tracked
+            "omg_ble" => !self.mi_temp_devices.is_empty(),
+            _ => {
+                self.devices.contains_key(hostname)
+                    || self.dsmr_devices.contains_key(hostname)
+                    || self.otgw_devices.contains_key(hostname)
+                    || self.ebusd_devices.contains_key(hostname)
+                    || self.solar_devices.contains_key(hostname)
+                    || self.evse_devices.contains_key(hostname)
+            }
+        }
+    }
+
+    /// Drop the least recently seen devices of any type that has more devices than allowed
+    pub fn enforce_limits(&mut self, limits: &DeviceLimits) {
+        for device in over_limit(&self.devices, limits.tasmota, |state| state.last_seen) {
+            self.devices.remove(&device);
+            self.drop_device("tasmota", limits.tasmota, &device.hostname);
+        }
+        for device in over_limit(&self.dsmr_devices, limits.dsmr, |state| state.last_seen) {
+            self.dsmr_devices.remove(&device);
+            self.drop_device("dsmr", limits.dsmr, &device.hostname);
+        }
+        for device in over_limit(&self.otgw_devices, limits.otgw, |state| state.last_seen) {
+            self.otgw_devices.remove(&device);
+            self.drop_device("otgw", limits.otgw, &device.hostname);
+        }
+        for device in over_limit(&self.ebusd_devices, limits.ebusd, |state| state.last_seen) {
+            self.ebusd_devices.remove(&device);
+            self.drop_device("ebusd", limits.ebusd, &device.hostname);
+        }
+        for device in over_limit(&self.solar_devices, limits.solar, |state| state.last_seen) {
+            self.solar_devices.remove(&device);
+            self.drop_device("solar", limits.solar, &device.hostname);
+        }
+        for device in over_limit(&self.evse_devices, limits.evse, |state| state.last_seen) {
+            self.evse_devices.remove(&device);
+            self.drop_device("evse", limits.evse, &device.hostname);
+        }
+        for device in over_limit(&self.mi_temp_devices, limits.mi_temp, |state| {
+            state.last_seen
+        }) {
+            self.mi_temp_devices.remove(&device);
+            self.drop_device("mitemp", limits.mi_temp, device);
+        }
+        for device in over_limit(&self.rf_temp_devices, limits.rf, |state| state.last_seen) {
+            self.rf_temp_devices.remove(&device);
+            self.drop_device("rf", limits.rf, device);
+        }
+    }
+
+    fn drop_device(&mut self, class: &'static str, limit: Option<usize>, device: impl Display) {
+        info!(
+            "more than {} {} devices tracked, dropping {}",
+            limit.unwrap_or_default(),
+            class,
+            device
+        );
+        *self.dropped.entry(class).or_default() += 1;
+        notify(&self.updates, device.to_string(), "removed", true.into());
+    }
+
+    /// Remove any device that hasn't been seen for too long
+    ///
+    /// Returns the tasmota devices that should be pinged
+    pub fn retain(&mut self, retention: &RetentionConfig) -> Vec<Device> {
+        let mut ping = Vec::new();
+        let updates = &self.updates;
+        let revision = &mut self.revision;
         self.devices.retain(|device, state| {
-            if state.last_seen < cleanup_time {
-                println!("{} hasn't been seen for 15m, removing", device.hostname);
-                false
-            } else if state.last_seen < ping_time || state.name.is_empty() {
-                println!(
-                    "{} hasn't been seen for 10m or has no name set, pinging",
-                    device.hostname
+            let elapsed = state.last_seen.elapsed();
+            let remove = retention.tasmota.remove > 0;
+            if remove && elapsed > retention.tasmota.remove() + retention.tasmota.grace() {
+                info!(
+                    "{} hasn't been seen for {}s, removing",
+                    device.hostname,
+                    retention.tasmota.remove + retention.tasmota.grace
+                );
+                notify(updates, device.hostname.to_string(), "removed", true.into());
+                return false;
+            }
+            if remove && elapsed > retention.tasmota.remove() && state.online {
+                info!(
+                    "{} hasn't been seen for {}s, marking as offline",
+                    device.hostname, retention.tasmota.remove
                 );
-                let send_client = client.clone();
-                let topic = device.get_topic("cmnd", "DeviceName");
-                spawn(async move {
-                    if let Err(e) = send_client.publish(topic, QoS::AtMostOnce, false, "").await {
-                        eprintln!("Failed to ping device: {:#}", e);
-                    }
-                });
+                state.online = false;
+                *revision += 1;
+                state.revision = *revision;
+                notify(updates, device.hostname.to_string(), "online", false.into());
+            }
+            if elapsed > retention.tasmota.ping() || state.name.is_empty() {
+                debug!(
+                    "{} hasn't been seen for {}s or has no name set, pinging",
+                    device.hostname, retention.tasmota.ping
+                );
+                ping.push(device.clone());
                 true
             } else {
                 true
             }
         });
 
+        remove_stale(&mut self.dsmr_devices, retention.dsmr, updates, |state| {
+            state.last_seen
+        });
+        remove_stale(&mut self.otgw_devices, retention.otgw, updates, |state| {
+            state.last_seen
+        });
+        remove_stale(&mut self.ebusd_devices, retention.ebusd, updates, |state| {
+            state.last_seen
+        });
+        remove_stale(&mut self.solar_devices, retention.solar, updates, |state| {
+            state.last_seen
+        });
+        remove_stale(&mut self.evse_devices, retention.evse, updates, |state| {
+            state.last_seen
+        });
+
         self.mi_temp_devices.retain(|device, state| {
-            if state.last_seen < cleanup_time {
-                println!("{} hasn't been seen for 15m, removing", device);
+            if retention.mi_temp.expired(state.last_seen) {
+                info!(
+                    "{} hasn't been seen for {}s, removing",
+                    device, retention.mi_temp.remove
+                );
+                notify(updates, device.to_string(), "removed", true.into());
                 false
             } else {
                 true
             }
         });
+
+        self.rf_temp_devices.retain(|device, state| {
+            let times = match state.source {
+                TempSource::RfLink => retention.rf,
+                TempSource::Rtl => retention.rtl,
+            };
+            if times.expired(state.last_seen) {
+                info!(
+                    "{} hasn't been seen for {}s, removing",
+                    device, times.remove
+                );
+                notify(updates, device.to_string(), "removed", true.into());
+                false
+            } else {
+                true
+            }
+        });
+
+        ping
+    }
+
+    /// Forget the daily minimum and maximum values from before midnight
+    pub fn roll_over(&mut self) {
+        let today = self.today();
+        let mut revision = self.revision;
+        for state in self.devices.values_mut() {
+            if state.roll_over(today) {
+                revision += 1;
+                state.revision = revision;
+            }
+        }
+        let mut expire = |range: &mut Option<DailyRange>, state_revision: &mut u64| {
+            if DailyRange::roll_over(range, today) {
+                revision += 1;
+                *state_revision = revision;
+            }
+        };
+        for state in self.dsmr_devices.values_mut() {
+            expire(&mut state.power_max_today, &mut state.revision);
+        }
+        for state in self.mi_temp_devices.values_mut() {
+            expire(&mut state.temperature_today, &mut state.revision);
+        }
+        for state in self.rf_temp_devices.values_mut() {
+            expire(&mut state.temperature_today, &mut state.revision);
+        }
+        self.revision = revision;
     }
 }
 
+/// Remove the devices that haven't been seen for longer than the retention time
+fn remove_stale<T>(
+    devices: &mut HashMap<Device, T>,
+    retention: RetentionTimes,
+    updates: &broadcast::Sender<StateUpdate>,
+    last_seen: impl Fn(&T) -> Instant,
+) {
+    devices.retain(|device, state| {
+        if retention.expired(last_seen(state)) {
+            info!(
+                "{} hasn't been seen for {}s, removing",
+                device.hostname, retention.remove
+            );
+            notify(updates, device.hostname.to_string(), "removed", true.into());
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// The least recently seen devices that exceed the limit
+fn over_limit<'a, K: Clone + 'a, V: 'a, I>(
+    devices: I,
+    limit: Option<usize>,
+    last_seen: impl Fn(&V) -> Instant,
+) -> Vec<K>
+where
+    I: IntoIterator<Item = (&'a K, &'a V)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let devices = devices.into_iter();
+    let excess = match limit {
+        Some(limit) if devices.len() > limit => devices.len() - limit,
+        _ => return Vec::new(),
+    };
+    let mut devices: Vec<_> = devices.collect();
+    devices.sort_by_key(|(_, state)| last_seen(state));
+    devices
+        .into_iter()
+        .take(excess)
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Look up the state of a device, only allocating the hostname for devices that aren't known yet
+pub fn device_entry<'a, T: Default>(
+    devices: &'a mut HashMap<Device, T>,
+    hostname: &str,
+) -> &'a mut T {
+    if !devices.contains_key(hostname) {
+        devices.insert(Device::from(hostname), T::default());
+    }
+    devices.get_mut(hostname).unwrap()
+}
+
 #[derive(Debug, Eq, PartialEq, Clone, Hash)]
 pub struct Device {
-    pub hostname: String,
+    /// Shared between the maps a device is tracked in, so cloning a device doesn't allocate
+    pub hostname: Arc<str>,
+}
+
+impl From<&str> for Device {
+    fn from(hostname: &str) -> Self {
+        Device {
+            hostname: hostname.into(),
+        }
+    }
+}
+
+/// Allows looking up devices by the hostname borrowed from the topic, the derived `Hash` only hashes the hostname
+impl Borrow<str> for Device {
+    fn borrow(&self) -> &str {
+        &self.hostname
+    }
 }
 
 impl Device {
@@ -162,42 +919,109 @@ impl Device {
 #[derive(Debug)]
 pub struct DeviceState {
     pub state: Option<bool>,
+    /// When the power state last changed, `None` if it didn't change since the device was discovered
+    pub state_changed: Option<Instant>,
+    /// Switch inputs, such as motion sensors and door contacts, by name
+    pub switches: BTreeMap<String, SwitchState>,
     pub name: String,
     pub power_watts: Option<f32>,
+    pub power_max_today: Option<DailyRange>,
     pub power_yesterday: Option<f32>,
     pub power_today: Option<f32>,
     pub power_total: Option<f32>,
+    /// Energy used since midnight in the configured timezone
+    pub power_today_local: Option<DailyTotal>,
     pub power_total_low: Option<f32>,
     pub power_total_high: Option<f32>,
     pub gas_total: Option<f32>,
+    pub resets: TotalResets,
     pub co2: Option<f32>,
     pub pms_state: Option<PMSState>,
+    /// Temperature sensors connected to the device by sensor name
+    pub climate: BTreeMap<String, ClimateState>,
+    /// Rule variables and other numeric values published by the device, by key
+    pub variables: BTreeMap<String, f32>,
     pub last_seen: Instant,
+    pub online: bool,
     pub firmware: String,
     pub version: f32,
+    /// The last json payload received from the device
+    pub last_payload: String,
+    pub ip: Option<String>,
+    /// Time it took for the device to respond to the last name or status request
+    pub command_latency: Option<Duration>,
+    /// Rolling averages of `power_watts` when anomaly detection is enabled
+    pub power_baseline: Option<PowerBaseline>,
+    /// Seconds the clock of the device was ahead of ours when the last timestamped payload was received
+    pub clock_drift: Option<f64>,
+    pub revision: u64,
 }
 
 impl Default for DeviceState {
     fn default() -> Self {
         DeviceState {
             state: Default::default(),
+            state_changed: None,
+            switches: Default::default(),
             name: Default::default(),
             power_watts: Default::default(),
+            power_max_today: Default::default(),
             power_yesterday: Default::default(),
             power_today: Default::default(),
             power_total: Default::default(),
+            power_today_local: Default::default(),
             power_total_low: Default::default(),
             power_total_high: Default::default(),
             gas_total: Default::default(),
+            resets: Default::default(),
             co2: Default::default(),
             pms_state: Default::default(),
+            climate: Default::default(),
+            variables: Default::default(),
             last_seen: Instant::now(),
+            online: true,
             firmware: Default::default(),
             version: 0.0,
+            last_payload: String::new(),
+            ip: None,
+            command_latency: None,
+            clock_drift: None,
+            power_baseline: None,
+            revision: 0,
+        }
+    }
+}
+
+/// Resets of a cumulative total, for example when a device is re-flashed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Resets {
+    pub count: u64,
+    /// Sum of the totals from before every reset
+    pub offset: f32,
+}
+
+#[derive(Debug, Default)]
+pub struct TotalResets {
+    pub power_total: Resets,
+    pub power_total_low: Resets,
+    pub power_total_high: Resets,
+    pub gas_total: Resets,
+}
+
+fn set_total(total: &mut Option<f32>, resets: &mut Resets, value: f32) {
+    if let Some(last) = *total {
+        // a reset starts counting from zero again, a small dip is jitter or an out of order message
+        if value < last / 2.0 {
+            resets.count += 1;
+            resets.offset += last;
+        } else if value < last {
+            return;
         }
     }
+    *total = Some(value);
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DsmrMessageType {
     Water,
     Gas,
@@ -209,67 +1033,205 @@ pub enum DsmrMessageType {
 #[derive(Debug)]
 pub struct DsmrState {
     pub power: Option<f32>,
+    pub power_max_today: Option<DailyRange>,
     pub power_total_tariff_1: Option<f32>,
     pub power_total_tariff_2: Option<f32>,
     pub gas_total: Option<f32>,
     pub water_total: Option<f32>,
+    pub gas_flow: Rate,
+    pub water_flow: Rate,
     pub last_seen: Instant,
+    pub revision: u64,
+}
+
+impl DsmrState {
+    fn set(&mut self, ty: DsmrMessageType, value: f32, today: Date) {
+        match ty {
+            DsmrMessageType::Water => {
+                self.water_total = Some(value);
+                self.water_flow.update(Instant::now(), value);
+            }
+            DsmrMessageType::Gas => {
+                self.gas_total = Some(value);
+                self.gas_flow.update(Instant::now(), value);
+            }
+            DsmrMessageType::Energy1 => self.power_total_tariff_1 = Some(value),
+            DsmrMessageType::Energy2 => self.power_total_tariff_2 = Some(value),
+            DsmrMessageType::Power => {
+                self.power = Some(value);
+                DailyRange::update(&mut self.power_max_today, today, value);
+            }
+        }
+        self.last_seen = Instant::now();
+    }
+
+    fn readings(&self) -> Readings {
+        vec![
+            ("power", self.power.into()),
+            ("power_total_tariff_1", self.power_total_tariff_1.into()),
+            ("power_total_tariff_2", self.power_total_tariff_2.into()),
+            ("gas_total", self.gas_total.into()),
+            ("water_total", self.water_total.into()),
+        ]
+    }
 }
 
 impl Default for DsmrState {
     fn default() -> Self {
         DsmrState {
             power: None,
+            power_max_today: None,
             power_total_tariff_1: None,
             power_total_tariff_2: None,
             gas_total: None,
             water_total: None,
+            gas_flow: Rate::default(),
+            water_flow: Rate::default(),
             last_seen: Instant::now(),
+            revision: 0,
         }
     }
 }
 
+/// State of a switch input of a tasmota device
+#[derive(Debug, Clone, Copy)]
+pub struct SwitchState {
+    pub on: bool,
+    /// `None` if the state didn't change since the switch was first seen
+    pub changed: Option<Instant>,
+}
+
 impl DeviceState {
-    pub fn update(&mut self, json: JsonValue) {
+    /// The name configured for the device, falling back to the name reported by the device
+    pub fn display_name<'a>(
+        &'a self,
+        device: &Device,
+        names: &'a HashMap<String, String>,
+    ) -> &'a str {
+        names
+            .get(device.hostname.as_ref())
+            .map(String::as_str)
+            .unwrap_or(&self.name)
+    }
+
+    pub fn update(
+        &mut self,
+        payload: &TasmotaPayload,
+        today: Date,
+        ignore: &IgnoreList,
+        named_variables: &HashSet<String>,
+        plausibility: &mut Plausibility,
+    ) {
         self.last_seen = Instant::now();
+        self.online = true;
 
-        if json["DeviceName"].is_string() && !json["DeviceName"].is_empty() {
-            self.name = json["DeviceName"].to_string();
-        }
-        if json["POWER"].is_string() && !json["POWER"].is_empty() {
-            self.state = Some(json["POWER"] == "ON");
-        }
-        if let Some(power) = json["ENERGY"]["Power"].as_number().map(f32::from) {
-            self.power_watts = Some(power);
-        }
-        if let Some(yesterday) = json["ENERGY"]["Yesterday"].as_number().map(f32::from) {
-            self.power_yesterday = Some(yesterday);
+        if let Some(name) = payload
+            .device_name
+            .as_deref()
+            .filter(|name| !name.is_empty())
+        {
+            self.name = name.into();
         }
-        if let Some(today) = json["ENERGY"]["Today"].as_number().map(f32::from) {
-            self.power_today = Some(today);
+        if let Some(power) = payload
+            .power
+            .as_deref()
+            .filter(|power| !power.is_empty() && !ignore.ignores("POWER", None))
+        {
+            let state = Some(power == "ON");
+            if self.state.is_some() && self.state != state {
+                self.state_changed = Some(Instant::now());
+            }
+            self.state = state;
         }
-        if let Some(co2) = json["MHZ19B"]["CarbonDioxide"].as_number().map(f32::from) {
-            if co2 > 1.0 {
-                self.co2 = Some(co2);
+        if let Some(energy) = &payload.energy {
+            let ignored = |field| ignore.ignores("ENERGY", Some(field));
+            let power = energy.power.filter(|_| !ignored("Power"));
+            if let Some(power) = plausibility.check(Quantity::Power, power) {
+                self.set_power(power, today);
+            }
+            if let Some(yesterday) = energy.yesterday.filter(|_| !ignored("Yesterday")) {
+                self.power_yesterday = Some(yesterday);
+            }
+            if let Some(today) = energy.today.filter(|_| !ignored("Today")) {
+                self.power_today = Some(today);
+            }
+            if let Some(total) = energy.total.filter(|_| !ignored("Total")) {
+                self.set_power_total(total, today);
             }
         }
-        if let Some(power) = json["OBIS"]["Power"].as_number().map(f32::from) {
-            self.power_watts = Some(power);
+        for (sensor, reading) in payload.climate() {
+            let ignored = |field| ignore.ignores(sensor, Some(field));
+            let temperature = reading.temperature.filter(|_| !ignored("Temperature"));
+            let temperature = plausibility.check(Quantity::Temperature, temperature);
+            let humidity = reading.humidity.filter(|_| !ignored("Humidity"));
+            let humidity = plausibility.check(Quantity::Humidity, humidity);
+            if temperature.is_none() && humidity.is_none() {
+                continue;
+            }
+            let climate = self.climate.entry(sensor.into()).or_default();
+            if let Some(temperature) = temperature {
+                climate.temperature = Some(temperature);
+                DailyRange::update(&mut climate.temperature_today, today, temperature);
+            }
+            if let Some(humidity) = humidity {
+                climate.humidity = Some(humidity);
+            }
         }
-        if let Some(total) = json["OBIS"]["Total"].as_number().map(f32::from) {
-            self.power_total = Some(total);
+        let switches = payload.switches();
+        for (switch, on) in switches.filter(|(switch, _)| !ignore.ignores(switch, None)) {
+            match self.switches.get_mut(switch) {
+                Some(state) if state.on == on => {}
+                Some(state) => {
+                    state.on = on;
+                    state.changed = Some(Instant::now());
+                }
+                None => {
+                    let state = SwitchState { on, changed: None };
+                    self.switches.insert(switch.into(), state);
+                }
+            }
         }
-        if let Some(total) = json["OBIS"]["Total_high"].as_number().map(f32::from) {
-            self.power_total_high = Some(total);
+        let variables = payload.variables(named_variables);
+        for (key, value) in variables.filter(|(key, _)| !ignore.ignores(key, None)) {
+            match self.variables.get_mut(key) {
+                Some(variable) => *variable = value,
+                None => {
+                    self.variables.insert(key.into(), value);
+                }
+            }
         }
-        if let Some(total) = json["OBIS"]["Total_low"].as_number().map(f32::from) {
-            self.power_total_low = Some(total);
+        if let Some(co2) = payload.mhz19b.as_ref().and_then(|mhz19b| mhz19b.co2) {
+            if co2 > 1.0 && !ignore.ignores("MHZ19B", Some("CarbonDioxide")) {
+                self.co2 = Some(co2);
+            }
         }
-        if let Some(gas) = json["OBIS"]["Gas_total"].as_number().map(f32::from) {
-            self.gas_total = Some(gas);
+        if let Some(obis) = &payload.obis {
+            let ignored = |field| ignore.ignores("OBIS", Some(field));
+            let power = obis.power.filter(|_| !ignored("Power"));
+            if let Some(power) = plausibility.check(Quantity::Power, power) {
+                self.set_power(power, today);
+            }
+            if let Some(total) = obis.total.filter(|_| !ignored("Total")) {
+                self.set_power_total(total, today);
+            }
+            if let Some(total) = obis.total_high.filter(|_| !ignored("Total_high")) {
+                let resets = &mut self.resets.power_total_high;
+                set_total(&mut self.power_total_high, resets, total);
+            }
+            if let Some(total) = obis.total_low.filter(|_| !ignored("Total_low")) {
+                let resets = &mut self.resets.power_total_low;
+                set_total(&mut self.power_total_low, resets, total);
+            }
+            if let Some(gas) = obis.gas_total.filter(|_| !ignored("Gas_total")) {
+                set_total(&mut self.gas_total, &mut self.resets.gas_total, gas);
+            }
         }
 
-        if let Some(version) = json["StatusFWR"]["Version"].as_str() {
+        if let Some(version) = payload
+            .firmware
+            .as_ref()
+            .and_then(|firmware| firmware.version.as_deref())
+        {
             self.firmware = version.into();
             if let Some(version) = version
                 .rfind('.')
@@ -280,164 +1242,314 @@ impl DeviceState {
             }
         }
 
-        if json["PMS5003"].is_object() {
+        if let Some(ip) = payload
+            .network
+            .as_ref()
+            .and_then(|network| network.ip_address.as_deref())
+        {
+            self.ip = Some(ip.into());
+        }
+
+        if let Some(pms5003) = payload
+            .pms5003
+            .as_ref()
+            .filter(|_| !ignore.ignores("PMS5003", None))
+        {
             let pms = self.pms_state.get_or_insert(PMSState::default());
-            pms.update(&json["PMS5003"]);
+            pms.update(pms5003);
+        }
+
+        for nested in payload.nested() {
+            self.update(nested, today, ignore, named_variables, plausibility);
         }
     }
+
+    fn set_power(&mut self, power: f32, today: Date) {
+        self.power_watts = Some(power);
+        DailyRange::update(&mut self.power_max_today, today, power);
+    }
+
+    fn set_power_total(&mut self, total: f32, today: Date) {
+        set_total(&mut self.power_total, &mut self.resets.power_total, total);
+        DailyTotal::update(&mut self.power_today_local, today, total);
+    }
+
+    /// Forget the daily values from previous days, returns whether any were removed
+    fn roll_over(&mut self, today: Date) -> bool {
+        let mut expired = DailyRange::roll_over(&mut self.power_max_today, today);
+        expired |= DailyTotal::roll_over(&mut self.power_today_local, today);
+        for climate in self.climate.values_mut() {
+            expired |= DailyRange::roll_over(&mut climate.temperature_today, today);
+        }
+        expired
+    }
+
+    fn readings(&self) -> Readings {
+        vec![
+            ("online", self.online.into()),
+            ("name", self.name.as_str().into()),
+            ("state", self.state.into()),
+            ("power_watts", self.power_watts.into()),
+            ("power_yesterday", self.power_yesterday.into()),
+            ("power_today", self.power_today.into()),
+            ("power_total", self.power_total.into()),
+            ("power_total_low", self.power_total_low.into()),
+            ("power_total_high", self.power_total_high.into()),
+            ("gas_total", self.gas_total.into()),
+            ("co2", self.co2.into()),
+            ("pms", self.pms_state.as_ref().map(PMSState::to_json).into()),
+            ("firmware", self.firmware.as_str().into()),
+        ]
+    }
+
+    /// The parsed state of the device, for debugging
+    pub fn to_json(&self, device: &Device, names: &HashMap<String, String>) -> Value {
+        json!({
+            "hostname": device.hostname.as_ref(),
+            "name": self.display_name(device, names),
+            "online": self.online,
+            "last_seen": self.last_seen.elapsed().as_secs(),
+            "state": self.state,
+            "power_watts": self.power_watts,
+            "power_yesterday": self.power_yesterday,
+            "power_today": self.power_today,
+            "power_total": self.power_total,
+            "power_total_low": self.power_total_low,
+            "power_total_high": self.power_total_high,
+            "gas_total": self.gas_total,
+            "co2": self.co2,
+            "pms": self.pms_state.as_ref().map(PMSState::to_json),
+            "firmware": self.firmware.as_str(),
+            "version": self.version,
+            "ip": self.ip.as_deref(),
+            "last_payload": serde_json::from_str(&self.last_payload).unwrap_or(Value::Null),
+        })
+    }
+}
+
+/// Last reading of a temperature or humidity sensor connected to a tasmota device
+#[derive(Debug, Default)]
+pub struct ClimateState {
+    pub temperature: Option<f32>,
+    pub temperature_today: Option<DailyRange>,
+    pub humidity: Option<f32>,
 }
 
 #[derive(Debug)]
 pub struct MiTempState {
-    temperature: f32,
-    humidity: f32,
-    dew_point: f32,
-    battery: u8,
+    pub temperature: Option<f32>,
+    pub temperature_today: Option<DailyRange>,
+    pub humidity: Option<f32>,
+    pub dew_point: Option<f32>,
+    pub battery: Option<u8>,
     pub last_seen: Instant,
+    pub revision: u64,
+    pub spikes: SpikeFilter,
 }
 
 impl Default for MiTempState {
     fn default() -> Self {
         MiTempState {
-            temperature: 0.0,
-            humidity: 0.0,
-            dew_point: 0.0,
-            battery: 0,
+            temperature: None,
+            temperature_today: None,
+            humidity: None,
+            dew_point: None,
+            battery: None,
             last_seen: Instant::now(),
+            revision: 0,
+            spikes: SpikeFilter::default(),
         }
     }
 }
 
 impl MiTempState {
-    pub fn update(&mut self, json: &JsonValue) {
+    fn readings(&self) -> Readings {
+        vec![
+            ("temperature", self.temperature.into()),
+            ("humidity", self.humidity.into()),
+            ("dew_point", self.dew_point.into()),
+            ("battery", self.battery.into()),
+        ]
+    }
+
+    pub fn update(&mut self, reading: &MiTemp, today: Date) {
         self.last_seen = Instant::now();
-        if let Some(temperature) = json["Temperature"].as_number().map(f32::from) {
-            self.temperature = temperature;
+        if let Some(temperature) = reading.temperature {
+            self.temperature = Some(temperature);
+            DailyRange::update(&mut self.temperature_today, today, temperature);
         }
-        if let Some(humidity) = json["Humidity"].as_number().map(f32::from) {
-            self.humidity = humidity;
+        if let Some(humidity) = reading.humidity {
+            self.humidity = Some(humidity);
         }
-        if let Some(battery) = json["Battery"]
-            .as_number()
-            .and_then(|num| u8::try_from(num).ok())
-        {
-            self.battery = battery;
+        if let Some(battery) = reading.battery {
+            self.battery = Some(battery);
         }
-        if let Some(dew_point) = json["DewPoint"].as_number().map(f32::from) {
-            self.dew_point = dew_point;
+        if let Some(dew_point) = reading.dew_point {
+            self.dew_point = Some(dew_point);
         }
     }
 }
 
-pub fn format_device_state<W: Write>(
-    mut writer: W,
+pub fn format_device_state(
+    registry: &mut Registry,
     device: &Device,
+    names: &HashMap<String, String>,
     state: &DeviceState,
-) -> std::fmt::Result {
-    if state.name.is_empty() {
-        println!("{} has no name set, skipping", device.hostname);
-        return Ok(());
-    }
-    writeln!(
-        writer,
-        "tasmota_online{{tasmota_id=\"{}\", name=\"{}\"}} 1",
-        device.hostname, state.name
-    )?;
-    if let Some(switch_state) = state.state {
-        writeln!(
-            writer,
-            "switch_state{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname,
-            state.name,
-            if switch_state { 1 } else { 0 }
-        )?;
+) {
+    let name = state.display_name(device, names);
+    if name.is_empty() {
+        debug!("{} has no name set, skipping", device.hostname);
+        return;
     }
+    let labels = [("tasmota_id", device.hostname.as_ref()), ("name", name)];
 
-    if let Some(power_watts) = state.power_watts {
-        writeln!(
-            writer,
-            "power_watts{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_watts
-        )?;
+    registry.add(&metrics::TASMOTA_ONLINE, &labels, state.online);
+    if !state.online {
+        return;
     }
 
+    if let Some(switch_state) = state.state {
+        registry.add(&metrics::SWITCH_STATE, &labels, switch_state);
+    }
+    if let Some(power_watts) = state.power_watts {
+        registry.add(&metrics::POWER_WATTS, &labels, power_watts);
+    }
+    if let Some(range) = state.power_max_today {
+        registry.add(&metrics::POWER_WATTS_MAX_TODAY, &labels, range.max);
+    }
     if let Some(power_yesterday) = state.power_yesterday {
-        writeln!(
-            writer,
-            "power_yesterday_kwh{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_yesterday
-        )?;
+        registry.add(&metrics::POWER_YESTERDAY_KWH, &labels, power_yesterday);
     }
-
     if let Some(power_today) = state.power_today {
-        writeln!(
-            writer,
-            "power_today_kwh{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_today
-        )?;
+        registry.add(&metrics::POWER_TODAY_KWH, &labels, power_today);
     }
-
-    if let Some(power_total) = state.power_total {
-        writeln!(
-            writer,
-            "power_total_kwh{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_total
-        )?;
+    if let Some(daily) = state.power_today_local {
+        registry.add(&metrics::POWER_TODAY_KWH_LOCAL, &labels, daily.increase);
     }
-
-    if let Some(power_total) = state.power_total_high {
-        writeln!(
-            writer,
-            "power_total_high_kwh{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_total
-        )?;
+    let monotonic = registry.derived().monotonic_totals;
+    for (family, total, resets) in [
+        (
+            &metrics::POWER_TOTAL_KWH,
+            state.power_total,
+            state.resets.power_total,
+        ),
+        (
+            &metrics::POWER_TOTAL_HIGH_KWH,
+            state.power_total_high,
+            state.resets.power_total_high,
+        ),
+        (
+            &metrics::POWER_TOTAL_LOW_KWH,
+            state.power_total_low,
+            state.resets.power_total_low,
+        ),
+        (
+            &metrics::GAS_TOTAL_M3,
+            state.gas_total,
+            state.resets.gas_total,
+        ),
+    ] {
+        let Some(total) = total else {
+            continue;
+        };
+        if monotonic {
+            registry.add(family, &labels, total + resets.offset);
+            let reset_labels = [
+                ("tasmota_id", device.hostname.as_ref()),
+                ("name", name),
+                ("total", family.name),
+            ];
+            registry.add(&metrics::TOTAL_RESETS, &reset_labels, resets.count);
+        } else {
+            registry.add(family, &labels, total);
+        }
     }
-
-    if let Some(power_total) = state.power_total_low {
-        writeln!(
-            writer,
-            "power_total_low_kwh{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, power_total
-        )?;
+    if let Some(co2) = state.co2 {
+        registry.add(&metrics::SENSOR_CO2, &labels, co2);
     }
-
-    if let Some(gas_total) = state.gas_total {
-        writeln!(
-            writer,
-            "gas_total_m3{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, gas_total
-        )?;
+    if let Some(latency) = state.command_latency {
+        registry.add(&metrics::COMMAND_LATENCY, &labels, latency.as_secs_f64());
     }
-
-    if let Some(co2) = state.co2 {
-        writeln!(
-            writer,
-            "sensor_co2{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-            device.hostname, state.name, co2
-        )?;
+    if let Some(drift) = state.clock_drift {
+        registry.add(&metrics::CLOCK_DRIFT, &labels, drift);
+    }
+    if let Some(baseline) = &state.power_baseline {
+        if let Some(score) = baseline.score {
+            registry.add(&metrics::POWER_ANOMALY_SCORE, &labels, score);
+            registry.add(&metrics::POWER_ANOMALY, &labels, baseline.anomalous);
+        }
     }
 
     if let Some(pms) = state.pms_state.as_ref() {
-        format_pms_state(&mut writer, device, state, pms)?;
+        format_pms_state(registry, &labels, pms);
+    }
+    format_air_quality(
+        registry,
+        &labels,
+        state.co2,
+        state.pms_state.as_ref().map(|pms| pms.pm2_5.into()),
+    );
+
+    for (sensor, climate) in &state.climate {
+        let labels = [
+            ("tasmota_id", device.hostname.as_ref()),
+            ("name", name),
+            ("sensor", sensor.as_str()),
+        ];
+        if let Some(humidity) = climate.humidity {
+            registry.add(&metrics::SENSOR_HUMIDITY, &labels, humidity);
+        }
+        let Some(temperature) = climate.temperature else {
+            continue;
+        };
+        registry.add(&metrics::SENSOR_TEMPERATURE, &labels, temperature);
+        format_temperature_range(registry, &labels, climate.temperature_today);
+        format_derived(registry, &labels, temperature, climate.humidity, None);
+    }
+
+    for (key, value) in &state.variables {
+        let Some(variable) = registry.variable_name(key).map(String::from) else {
+            continue;
+        };
+        let labels = [
+            ("tasmota_id", device.hostname.as_ref()),
+            ("name", name),
+            ("variable", variable.as_str()),
+        ];
+        registry.add(&metrics::TASMOTA_VARIABLE, &labels, *value);
     }
 
     if !state.firmware.is_empty() {
-        writeln!(
-            writer,
-            r#"tasmota_version{{tasmota_id="{}", name="{}", firmware="{}", version="{}"}} 1"#,
-            device.hostname, state.name, state.firmware, state.version
-        )?;
+        registry.add(
+            &metrics::TASMOTA_VERSION,
+            &[
+                ("tasmota_id", device.hostname.as_ref()),
+                ("name", name),
+                ("firmware", &state.firmware),
+                ("version", &state.version.to_string()),
+            ],
+            1,
+        );
     }
+}
 
-    Ok(())
+fn format_temperature_range(
+    registry: &mut Registry,
+    labels: &[(&'static str, &str)],
+    range: Option<DailyRange>,
+) {
+    if let Some(range) = range {
+        registry.add(&metrics::SENSOR_TEMPERATURE_MIN_TODAY, labels, range.min);
+        registry.add(&metrics::SENSOR_TEMPERATURE_MAX_TODAY, labels, range.max);
+    }
 }
 
-pub fn format_mi_temp_state<W: Write>(
-    mut writer: W,
+pub fn format_mi_temp_state(
+    registry: &mut Registry,
     addr: BDAddr,
     names: &BTreeMap<BDAddr, String>,
     state: &MiTempState,
-) -> std::fmt::Result {
+) {
     // sensor_battery{name="Living Room", mac="58:2D:34:39:1D:5B"} 100
     // sensor_temperature{name="Living Room", mac="58:2D:34:39:1D:5B"} 16.2
     // sensor_humidity{name="Living Room", mac="58:2D:34:39:1D:5B"} 61.
@@ -445,119 +1557,191 @@ pub fn format_mi_temp_state<W: Write>(
     let name = if let Some(name) = names.get(&addr) {
         name
     } else {
-        return Ok(());
+        return;
     };
+    let mac = addr.to_string();
+    let labels = [("mac", mac.as_str()), ("name", name)];
 
-    if state.battery > 0 {
-        writeln!(
-            writer,
-            "sensor_battery{{mac=\"{}\", name=\"{}\"}} {}",
-            addr, name, state.battery
-        )?;
+    if let Some(battery) = state.battery {
+        registry.add(&metrics::SENSOR_BATTERY, &labels, battery);
+    }
+    if let Some(temperature) = state.temperature {
+        registry.add(&metrics::SENSOR_TEMPERATURE, &labels, temperature);
+        format_temperature_range(registry, &labels, state.temperature_today);
+    }
+    if let Some(humidity) = state.humidity {
+        registry.add(&metrics::SENSOR_HUMIDITY, &labels, humidity);
     }
+    if let Some(temperature) = state.temperature {
+        format_derived(registry, &labels, temperature, state.humidity, None);
+    }
+}
 
-    if state.temperature > 0.0 {
-        writeln!(
-            writer,
-            "sensor_temperature{{mac=\"{}\", name=\"{}\"}} {}",
-            addr, name, state.temperature
-        )?;
+#[derive(Debug, Clone)]
+pub struct TempState {
+    pub temperature: Option<f32>,
+    pub temperature_today: Option<DailyRange>,
+    pub humidity: Option<f32>,
+    /// Average wind speed in m/s, for weather stations
+    pub wind_speed: Option<f32>,
+    /// Air pressure in hPa
+    pub pressure: Option<f32>,
+    /// Total rainfall in mm, as counted by the sensor
+    pub rain: Option<f32>,
+    /// Whether the sensor reports its battery as ok or low
+    pub battery_ok: Option<bool>,
+    /// When the sensor first reported its battery as low
+    pub battery_low_since: Option<Timestamp>,
+    pub source: TempSource,
+    pub last_seen: Instant,
+    pub revision: u64,
+    pub spikes: SpikeFilter,
+}
+
+impl Default for TempState {
+    fn default() -> Self {
+        TempState {
+            temperature: None,
+            temperature_today: None,
+            humidity: None,
+            wind_speed: None,
+            pressure: None,
+            rain: None,
+            battery_ok: None,
+            battery_low_since: None,
+            source: TempSource::RfLink,
+            last_seen: Instant::now(),
+            revision: 0,
+            spikes: SpikeFilter::default(),
+        }
     }
+}
 
-    if state.humidity > 0.0 {
-        writeln!(
-            writer,
-            "sensor_humidity{{mac=\"{}\", name=\"{}\"}} {}",
-            addr, name, state.humidity
-        )?;
+impl TempState {
+    fn readings(&self) -> Readings {
+        vec![
+            ("temperature", self.temperature.into()),
+            ("humidity", self.humidity.into()),
+            ("wind_speed", self.wind_speed.into()),
+            ("pressure", self.pressure.into()),
+            ("rain", self.rain.into()),
+            ("battery_ok", self.battery_ok.into()),
+        ]
+    }
+
+    fn set_battery(&mut self, ok: bool) {
+        self.battery_ok = Some(ok);
+        if ok {
+            self.battery_low_since = None;
+        } else {
+            self.battery_low_since.get_or_insert_with(Timestamp::now);
+        }
     }
-    Ok(())
 }
 
-#[derive(Debug, Default)]
-pub struct TempState {
-    temperature: f32,
-    humidity: u8,
+/// Where the readings for an rf temperature sensor are received from
+#[derive(Debug, Clone, Copy)]
+pub enum TempSource {
+    RfLink,
+    Rtl,
 }
 
-pub fn format_rf_temp_state<W: Write>(
-    mut writer: W,
+pub fn format_rf_temp_state(
+    registry: &mut Registry,
     channel: &RfDeviceId,
     names: &HashMap<RfDeviceId, String>,
     state: &TempState,
-) -> std::fmt::Result {
-    let name = if let Some(name) = names.get(channel) {
+) {
+    let name = if let Some(name) = names.get(&channel.sensor()) {
         name
     } else {
-        return Ok(());
+        return;
+    };
+    let id = channel.id.to_string();
+    let channel_number = channel.channel.to_string();
+    let labels = [
+        ("model", channel.name.as_ref()),
+        ("id", id.as_str()),
+        ("channel", channel_number.as_str()),
+        ("name", name),
+        ("receiver", channel.receiver.as_ref()),
+    ];
+    // sensors from receivers that don't include their hostname in the topic don't get a receiver label
+    let labels = if channel.receiver.is_empty() {
+        &labels[..4]
+    } else {
+        &labels[..]
     };
 
-    if state.temperature > 0.0 {
-        writeln!(
-            writer,
-            "sensor_temperature{{model=\"{}\", id=\"{}\", channel=\"{}\", name=\"{}\"}} {}",
-            channel.name, channel.id, channel.channel, name, state.temperature
-        )?;
+    if let Some(temperature) = state.temperature {
+        registry.add(&metrics::SENSOR_TEMPERATURE, labels, temperature);
+        format_temperature_range(registry, labels, state.temperature_today);
     }
-
-    if state.humidity > 0 {
-        writeln!(
-            writer,
-            "sensor_humidity{{model=\"{}\", id=\"{}\", channel=\"{}\", name=\"{}\"}} {}",
-            channel.name, channel.id, channel.channel, name, state.humidity
-        )?;
+    if let Some(humidity) = state.humidity {
+        registry.add(&metrics::SENSOR_HUMIDITY, labels, humidity);
+    }
+    if let Some(wind_speed) = state.wind_speed {
+        registry.add(&metrics::SENSOR_WIND_SPEED, labels, wind_speed);
+    }
+    if let Some(pressure) = state.pressure {
+        registry.add(&metrics::SENSOR_PRESSURE, labels, pressure);
+    }
+    if let Some(rain) = state.rain {
+        registry.add(&metrics::SENSOR_RAIN, labels, rain);
+    }
+    if let Some(battery_ok) = state.battery_ok {
+        registry.add(&metrics::SENSOR_BATTERY_OK, labels, battery_ok);
+    }
+    if let Some(since) = state.battery_low_since {
+        registry.add(
+            &metrics::SENSOR_BATTERY_LOW_SINCE,
+            labels,
+            since.as_millisecond() as f64 / 1000.0,
+        );
+    }
+    if let Some(temperature) = state.temperature {
+        format_derived(
+            registry,
+            labels,
+            temperature,
+            state.humidity,
+            state.wind_speed,
+        );
     }
-    Ok(())
 }
 
-pub fn format_dsmr_state<W: Write>(
-    mut writer: W,
-    device: &str,
-    state: &DsmrState,
-) -> std::fmt::Result {
+pub fn format_dsmr_state(registry: &mut Registry, device: &str, state: &DsmrState) {
+    let labels = [("name", device)];
+
     let power_total = state.power_total_tariff_1.unwrap_or_default()
         + state.power_total_tariff_2.unwrap_or_default();
     if power_total > 0.0 {
-        writeln!(
-            writer,
-            "power_total_kwh{{name=\"{}\"}} {}",
-            device, power_total
-        )?;
+        registry.add(&metrics::POWER_TOTAL_KWH, &labels, power_total);
     }
-
     if let Some(power) = state.power_total_tariff_1 {
-        writeln!(
-            writer,
-            "power_total_low_kwh{{name=\"{}\"}} {}",
-            device, power
-        )?;
+        registry.add(&metrics::POWER_TOTAL_LOW_KWH, &labels, power);
     }
-
     if let Some(power) = state.power_total_tariff_2 {
-        writeln!(
-            writer,
-            "power_total_high_kwh{{name=\"{}\"}} {}",
-            device, power
-        )?;
+        registry.add(&metrics::POWER_TOTAL_HIGH_KWH, &labels, power);
     }
-
     if let Some(power) = state.power {
-        writeln!(
-            writer,
-            "power_watts{{name=\"{}\"}} {}",
-            device,
-            power * 1000.0
-        )?;
+        registry.add(&metrics::POWER_WATTS, &labels, power * 1000.0);
+    }
+    if let Some(range) = state.power_max_today {
+        registry.add(&metrics::POWER_WATTS_MAX_TODAY, &labels, range.max * 1000.0);
     }
-
     if let Some(gas) = state.gas_total {
-        writeln!(writer, "gas_total_m3{{name=\"{}\"}} {}", device, gas)?;
+        registry.add(&metrics::GAS_TOTAL_M3, &labels, gas);
     }
-
     if let Some(water) = state.water_total {
-        writeln!(writer, "water_total_m3{{name=\"{}\"}} {}", device, water)?;
+        registry.add(&metrics::WATER_TOTAL_M3, &labels, water);
+    }
+    if let Some(flow) = state.gas_flow.per_second() {
+        registry.add(&metrics::GAS_FLOW_M3_H, &labels, flow * 3600.0);
+    }
+    if let Some(flow) = state.water_flow.per_second() {
+        registry.add(&metrics::WATER_FLOW_L_MIN, &labels, flow * 1000.0 * 60.0);
     }
-    Ok(())
 }
 
 /// Stores the 6 byte address used to identify Bluetooth devices.
@@ -615,8 +1799,6 @@ impl Debug for BDAddr {
     }
 }
 
-//"PMS5003":{"CF1":6,"CF2.5":8,"CF10":8,"PM1":6,"PM2.5":8,"PM10":8,"PB0.3":0,"PB0.5":0,"PB1":0,"PB2.5":0,"PB5":0,"PB10":0}
-
 #[derive(Debug, Clone, Default)]
 pub struct PMSState {
     cf1: u16,
@@ -634,151 +1816,66 @@ pub struct PMSState {
 }
 
 impl PMSState {
-    pub fn update(&mut self, json: &JsonValue) {
-        if let Some(val) = json["CF1"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.cf1 = val;
-        }
-        if let Some(val) = json["CF2.5"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.cf2_5 = val;
-        }
-        if let Some(val) = json["CF10"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.cf10 = val;
-        }
-        if let Some(val) = json["PM1"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pm1 = val;
-        }
-        if let Some(val) = json["PM2.5"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pm2_5 = val;
-        }
-        if let Some(val) = json["PM10"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pm10 = val;
-        }
-        if let Some(val) = json["PB0.3"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb0_3 = val;
-        }
-        if let Some(val) = json["PB0.5"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb0_5 = val;
-        }
-        if let Some(val) = json["PB1"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb1 = val;
-        }
-        if let Some(val) = json["PB2.5"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb2_5 = val;
-        }
-        if let Some(val) = json["PB5"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb5 = val;
-        }
-        if let Some(val) = json["PB10"]
-            .as_number()
-            .and_then(|num| u16::try_from(num).ok())
-        {
-            self.pb10 = val;
+    pub fn to_json(&self) -> Value {
+        json!({
+            "cf1": self.cf1,
+            "cf2_5": self.cf2_5,
+            "cf10": self.cf10,
+            "pm1": self.pm1,
+            "pm2_5": self.pm2_5,
+            "pm10": self.pm10,
+            "pb0_3": self.pb0_3,
+            "pb0_5": self.pb0_5,
+            "pb1": self.pb1,
+            "pb2_5": self.pb2_5,
+            "pb5": self.pb5,
+            "pb10": self.pb10,
+        })
+    }
+
+    pub fn update(&mut self, reading: &Pms5003) {
+        for (field, value) in [
+            (&mut self.cf1, reading.cf1),
+            (&mut self.cf2_5, reading.cf2_5),
+            (&mut self.cf10, reading.cf10),
+            (&mut self.pm1, reading.pm1),
+            (&mut self.pm2_5, reading.pm2_5),
+            (&mut self.pm10, reading.pm10),
+            (&mut self.pb0_3, reading.pb0_3),
+            (&mut self.pb0_5, reading.pb0_5),
+            (&mut self.pb1, reading.pb1),
+            (&mut self.pb2_5, reading.pb2_5),
+            (&mut self.pb5, reading.pb5),
+            (&mut self.pb10, reading.pb10),
+        ] {
+            if let Some(value) = value {
+                *field = value;
+            }
         }
     }
 }
 
-pub fn format_pms_state<W: Write>(
-    mut writer: W,
-    device: &Device,
-    device_state: &DeviceState,
+pub fn format_pms_state(
+    registry: &mut Registry,
+    labels: &[(&'static str, &str)],
     state: &PMSState,
-) -> std::fmt::Result {
-    let name = &device_state.name;
-
-    writeln!(
-        writer,
-        "cf1{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.cf1
-    )?;
-    writeln!(
-        writer,
-        "cf2_5{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.cf2_5
-    )?;
-    writeln!(
-        writer,
-        "cf10{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.cf10
-    )?;
-    writeln!(
-        writer,
-        "pm1{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pm1
-    )?;
-    writeln!(
-        writer,
-        "pm2_5{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pm2_5
-    )?;
-    writeln!(
-        writer,
-        "pm10{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pm10
-    )?;
-    writeln!(
-        writer,
-        "pb0_3{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb0_3
-    )?;
-    writeln!(
-        writer,
-        "pb0_5{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb0_5
-    )?;
-    writeln!(
-        writer,
-        "pb1{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb1
-    )?;
-    writeln!(
-        writer,
-        "pb2_5{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb2_5
-    )?;
-    writeln!(
-        writer,
-        "pb5{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb5
-    )?;
-    writeln!(
-        writer,
-        "pb10{{tasmota_id=\"{}\", name=\"{}\"}} {}",
-        device.hostname, name, state.pb10
-    )?;
-    Ok(())
+) {
+    for (family, value) in [
+        (&metrics::PMS_CF1, state.cf1),
+        (&metrics::PMS_CF2_5, state.cf2_5),
+        (&metrics::PMS_CF10, state.cf10),
+        (&metrics::PMS_PM1, state.pm1),
+        (&metrics::PMS_PM2_5, state.pm2_5),
+        (&metrics::PMS_PM10, state.pm10),
+        (&metrics::PMS_PB0_3, state.pb0_3),
+        (&metrics::PMS_PB0_5, state.pb0_5),
+        (&metrics::PMS_PB1, state.pb1),
+        (&metrics::PMS_PB2_5, state.pb2_5),
+        (&metrics::PMS_PB5, state.pb5),
+        (&metrics::PMS_PB10, state.pb10),
+    ] {
+        registry.add(family, labels, value);
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -788,7 +1885,7 @@ struct RfPayload<'a> {
     channel: u8,
     battery: bool,
     temperature: f32,
-    humidity: u8,
+    humidity: f32,
 }
 
 impl<'a> RfPayload<'a> {
@@ -797,15 +1894,20 @@ impl<'a> RfPayload<'a> {
             name: Cow::Borrowed(self.name),
             id: self.id,
             channel: self.channel,
+            receiver: Cow::Borrowed(""),
         }
     }
 }
 
+/// A 433Mhz sensor, by the receiver that picked it up
+///
+/// Formatted as `<name>:<id>:<channel>`, with `@<receiver>` appended if the receiver is known.
 #[derive(Hash, PartialEq, Eq, Debug, Clone, Default)]
 pub struct RfDeviceId<'a> {
     name: Cow<'a, str>,
     id: u16,
     channel: u8,
+    receiver: Cow<'a, str>,
 }
 
 impl RfDeviceId<'_> {
@@ -814,10 +1916,31 @@ impl RfDeviceId<'_> {
             name: Cow::Owned(self.name.to_string()),
             id: self.id,
             channel: self.channel,
+            receiver: Cow::Owned(self.receiver.to_string()),
+        }
+    }
+
+    /// The sensor without the receiver, as used for configuring names
+    pub fn sensor(&self) -> RfDeviceId<'_> {
+        RfDeviceId {
+            name: Cow::Borrowed(&self.name),
+            id: self.id,
+            channel: self.channel,
+            receiver: Cow::Borrowed(""),
         }
     }
 }
 
+impl Display for RfDeviceId<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.name, self.id, self.channel)?;
+        if !self.receiver.is_empty() {
+            write!(f, "@{}", self.receiver)?;
+        }
+        Ok(())
+    }
+}
+
 impl<'de> Deserialize<'de> for RfDeviceId<'static> {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
@@ -835,16 +1958,18 @@ impl FromStr for RfDeviceId<'static> {
         let mut parts = s.splitn(3, ':');
         let name = parts.next().unwrap_or_default();
         let id = parts.next().unwrap_or_default().parse()?;
-        let channel = parts.next().unwrap_or_default().parse()?;
+        let channel = parts.next().unwrap_or_default();
+        let (channel, receiver) = channel.split_once('@').unwrap_or((channel, ""));
         Ok(RfDeviceId {
             name: name.to_string().into(),
             id,
-            channel,
+            channel: channel.parse()?,
+            receiver: receiver.to_string().into(),
         })
     }
 }
 
-fn parse_rf_payload(payload: &str) -> Option<RfPayload> {
+fn parse_rf_payload(payload: &str) -> Option<RfPayload<'_>> {
     let mut parts = payload.split(";").skip(2);
     let name = parts.next()?;
     let id = parts.next()?.strip_prefix("ID=")?.parse().ok()?;
@@ -873,8 +1998,443 @@ fn test_rf_payload() {
             channel: 1,
             battery: true,
             temperature: 16.1,
-            humidity: 58
+            humidity: 58.0
         },
         parse_rf_payload("20;1E;Bresser-3CH;ID=49;CHN=0001;BAT=OK;TEMP=00a1;HUM=58;").unwrap()
     )
 }
+
+#[test]
+fn test_device_json() {
+    let device = Device {
+        hostname: "plug".into(),
+    };
+    let mut state = DeviceState::default();
+    state.update(
+        &serde_json::from_str(r#"{"DeviceName":"Plug","POWER":"ON","ENERGY":{"Power":12}}"#)
+            .unwrap(),
+        jiff::civil::date(2024, 1, 1),
+        &IgnoreList::default(),
+        &HashSet::new(),
+        &mut Plausibility::default(),
+    );
+    state.last_payload = r#"{"POWER":"ON"}"#.into();
+
+    let json = state.to_json(&device, &HashMap::new());
+    assert_eq!("Plug", json["name"]);
+    assert_eq!(true, json["state"]);
+    assert_eq!(12.0, json["power_watts"]);
+    assert!(json["co2"].is_null());
+    assert_eq!("ON", json["last_payload"]["POWER"]);
+}
+
+#[test]
+fn test_status_payload() {
+    let device = Device {
+        hostname: "plug".into(),
+    };
+    let mut states = DeviceStates::default();
+    states
+        .update(
+            "plug",
+            TasmotaPayload::parse(
+                r#"{"Status":{"DeviceName":"Plug"},"StatusFWR":{"Version":"13.2.0(tasmota)"},
+            "StatusSNS":{"ENERGY":{"Power":12}},"StatusSTS":{"POWER":"ON"}}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+    let json = states.devices["plug"].to_json(&device, &HashMap::new());
+    assert_eq!(true, json["state"]);
+    assert_eq!(12.0, json["power_watts"]);
+    assert_eq!("13.2.0(tasmota)", json["firmware"]);
+
+    // separate responses to `Status 5` and `Status 11`
+    for payload in [
+        r#"{"StatusNET":{"Hostname":"plug","IPAddress":"192.168.1.10"}}"#,
+        r#"{"StatusSTS":{"POWER":"OFF","Wifi":{"RSSI":80}}}"#,
+    ] {
+        states
+            .update("plug", TasmotaPayload::parse(payload).unwrap())
+            .unwrap();
+    }
+    let json = states.devices["plug"].to_json(&device, &HashMap::new());
+    assert_eq!(false, json["state"]);
+    assert_eq!("192.168.1.10", json["ip"]);
+}
+
+#[test]
+fn test_ignored_sensors() {
+    let mut states = DeviceStates::default();
+    let ignore = serde_json::from_str(r#"["ESP32.Temperature", "ANALOG"]"#).unwrap();
+    states.ignore.insert("plug".into(), ignore);
+    let payload = r#"{"ENERGY":{"Power":12},"ESP32":{"Temperature":85.3},"ANALOG":{"Temperature":21.5},
+        "DS18B20":{"Temperature":19.5}}"#;
+    for hostname in ["plug", "other"] {
+        states
+            .update(hostname, TasmotaPayload::parse(payload).unwrap())
+            .unwrap();
+    }
+
+    assert_eq!(Some(12.0), states.devices["plug"].power_watts);
+    let sensors: Vec<_> = states.devices["plug"].climate.keys().collect();
+    assert_eq!(vec!["DS18B20"], sensors);
+    assert_eq!(3, states.devices["other"].climate.len());
+
+    // ignoring the temperature keeps the humidity
+    let ignore = serde_json::from_str(r#"["AM2301.Temperature"]"#).unwrap();
+    states.ignore.insert("plug".into(), ignore);
+    let payload = r#"{"AM2301":{"Temperature":21.5,"Humidity":45.2},"HTU21":{"Humidity":51.0}}"#;
+    states
+        .update("plug", TasmotaPayload::parse(payload).unwrap())
+        .unwrap();
+    let climate = &states.devices["plug"].climate;
+    assert_eq!(None, climate["AM2301"].temperature);
+    assert_eq!(Some(45.2), climate["AM2301"].humidity);
+    assert_eq!(Some(51.0), climate["HTU21"].humidity);
+}
+
+#[test]
+fn test_rf_receivers() {
+    let mut states = DeviceStates::default();
+    for receiver in ["attic", "garage"] {
+        states.update_rtl(receiver, "Bresser-3CH", "id", "73");
+        states.update_rtl(receiver, "Bresser-3CH", "channel", "1");
+        states.update_rtl(receiver, "Bresser-3CH", "temperature_C", "21.5");
+    }
+    let ids: Vec<_> = states.rf_temp().map(|(id, _)| id.to_string()).collect();
+    assert_eq!(2, ids.len());
+    assert!(ids.contains(&"Bresser-3CH:73:1@attic".to_string()));
+
+    let id = RfDeviceId::from_str("Bresser-3CH:73:1@garage").unwrap();
+    assert_eq!(Some(21.5), states.rf_temp_devices[&id].temperature);
+    assert_eq!("Bresser-3CH:73:1", id.sensor().to_string());
+}
+
+#[test]
+fn test_rejected_rtl_frames() {
+    let mut states = DeviceStates::default();
+    let mut frame = |temperature: &str, mic: &str| {
+        states.update_rtl("", "Bresser-3CH", "id", "73");
+        states.update_rtl("", "Bresser-3CH", "temperature_C", temperature);
+        states.update_rtl("", "Bresser-3CH", "mic", mic);
+    };
+    frame("-40", "CRC_FAIL");
+    frame("21.5", "CRC");
+    frame("85", "CRC_FAIL");
+
+    assert_eq!(2, states.rejected_rf_frames);
+    let readings: Vec<_> = states
+        .rf_temp()
+        .map(|(_, state)| state.temperature)
+        .collect();
+    assert_eq!(vec![Some(21.5)], readings);
+}
+
+#[test]
+fn test_freezing_readings() {
+    let mut states = DeviceStates::default();
+    let addr = BDAddr::from_mi_temp_mac_part("351234").unwrap();
+    let reading = MiTemp {
+        temperature: Some(0.0),
+        humidity: Some(0.0),
+        ..MiTemp::default()
+    };
+    states.update_mi_temp_reading(addr, &reading, jiff::civil::date(2024, 1, 1));
+    states.update_rtl("", "Bresser-3CH", "temperature_C", "0");
+
+    let mut registry = Registry::default();
+    let names = BTreeMap::from([(addr, "Attic".to_string())]);
+    format_mi_temp_state(&mut registry, addr, &names, &states.mi_temp_devices[&addr]);
+    let (id, state) = states.rf_temp().next().unwrap();
+    let names = HashMap::from([(id.clone(), "Garden".to_string())]);
+    format_rf_temp_state(&mut registry, id, &names, state);
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+
+    assert!(out.contains(r#"sensor_temperature{mac="58:2D:34:35:12:34", name="Attic"} 0"#));
+    assert!(out.contains(r#"sensor_humidity{mac="58:2D:34:35:12:34", name="Attic"} 0"#));
+    assert!(out.contains(r#"channel="0", name="Garden"} 0"#));
+}
+
+#[test]
+fn test_state_updates() {
+    let mut states = DeviceStates::default();
+    let mut updates = states.subscribe();
+    states
+        .update("plug", TasmotaPayload::parse(r#"{"POWER":"ON"}"#).unwrap())
+        .unwrap();
+    states
+        .update("plug", TasmotaPayload::parse(r#"{"POWER":"ON"}"#).unwrap())
+        .unwrap();
+
+    let update = updates.try_recv().unwrap();
+    assert_eq!("plug", update.device);
+    assert_eq!("state", update.field);
+    assert_eq!(true, update.value);
+    assert!(updates.try_recv().is_err());
+}
+
+#[test]
+fn test_snapshots() {
+    let mut states = DeviceStates::default();
+    states
+        .update("plug", TasmotaPayload::parse(r#"{"POWER":"OFF"}"#).unwrap())
+        .unwrap();
+    let names = NamesConfig {
+        tasmota: HashMap::from([("plug".to_string(), "Plug".to_string())]),
+        mi_temp: BTreeMap::new(),
+        rf_temp: HashMap::new(),
+    };
+
+    let snapshots = states.snapshots(&names);
+    assert_eq!(1, snapshots.len());
+    let (id, snapshot) = &snapshots[0];
+    assert_eq!("plug", id);
+    assert_eq!("tasmota", snapshot["type"]);
+    assert_eq!("Plug", snapshot["name"]);
+    assert_eq!(false, snapshot["state"]);
+}
+
+#[test]
+fn test_device_limits() {
+    let mut states = DeviceStates::default();
+    let limits = DeviceLimits {
+        tasmota: Some(2),
+        ..DeviceLimits::default()
+    };
+    for hostname in ["a", "b", "c"] {
+        states
+            .update(
+                hostname,
+                TasmotaPayload::parse(r#"{"POWER":"ON"}"#).unwrap(),
+            )
+            .unwrap();
+    }
+    states.devices.get_mut("b").unwrap().last_seen -= Duration::from_secs(60);
+    states.enforce_limits(&limits);
+    states.enforce_limits(&limits);
+
+    assert_eq!(2, states.devices.len());
+    assert!(!states.devices.contains_key("b"));
+    assert_eq!(Some(&1), states.dropped.get("tasmota"));
+}
+
+#[test]
+fn test_total_resets() {
+    let mut state = DeviceState::default();
+    for total in [10.0, 12.5, 0.5, 1.0] {
+        state.update(
+            &TasmotaPayload::parse(&format!(r#"{{"ENERGY":{{"Total":{total}}}}}"#)).unwrap(),
+            jiff::civil::date(2024, 1, 1),
+            &IgnoreList::default(),
+            &HashSet::new(),
+            &mut Plausibility::default(),
+        );
+    }
+    assert_eq!(Some(1.0), state.power_total);
+    assert_eq!(1, state.resets.power_total.count);
+    assert_eq!(12.5, state.resets.power_total.offset);
+}
+
+#[test]
+fn test_total_small_dip() {
+    let mut state = DeviceState::default();
+    for total in [12.5, 12.49, 12.6] {
+        state.update(
+            &TasmotaPayload::parse(&format!(r#"{{"ENERGY":{{"Total":{total}}}}}"#)).unwrap(),
+            jiff::civil::date(2024, 1, 1),
+            &IgnoreList::default(),
+            &HashSet::new(),
+            &mut Plausibility::default(),
+        );
+    }
+    assert_eq!(Some(12.6), state.power_total);
+    assert_eq!(0, state.resets.power_total.count);
+    assert_eq!(0.0, state.resets.power_total.offset);
+}
+
+#[test]
+fn test_daily_power() {
+    let monday = jiff::civil::date(2024, 1, 1);
+    let tuesday = jiff::civil::date(2024, 1, 2);
+    let mut state = DeviceState::default();
+    let payload = |power| {
+        TasmotaPayload::parse(&format!(
+            r#"{{"ENERGY":{{"Power":{power}}},"AM2301":{{"Temperature":{}}}}}"#,
+            power / 10
+        ))
+        .unwrap()
+    };
+    for power in [120, 300, 200] {
+        state.update(
+            &payload(power),
+            monday,
+            &IgnoreList::default(),
+            &HashSet::new(),
+            &mut Plausibility::default(),
+        );
+    }
+    assert_eq!(300.0, state.power_max_today.unwrap().max);
+    let climate = state.climate["AM2301"].temperature_today.unwrap();
+    assert_eq!((12.0, 30.0), (climate.min, climate.max));
+
+    assert!(!state.roll_over(monday));
+    assert!(state.roll_over(tuesday));
+    assert!(state.power_max_today.is_none());
+    state.update(
+        &payload(50),
+        tuesday,
+        &IgnoreList::default(),
+        &HashSet::new(),
+        &mut Plausibility::default(),
+    );
+    assert_eq!(50.0, state.power_max_today.unwrap().max);
+}
+
+#[test]
+fn test_rf_battery() {
+    let mut states = DeviceStates::default();
+    let battery = |states: &DeviceStates| {
+        let state = states.rf_temp().next().unwrap().1;
+        (state.battery_ok, state.battery_low_since)
+    };
+    states
+        .update_rf("20;1E;Bresser-3CH;ID=49;CHN=0001;BAT=LOW;TEMP=00a1;HUM=58;")
+        .unwrap();
+    let (ok, since) = battery(&states);
+    assert_eq!(Some(false), ok);
+    assert!(since.is_some());
+
+    states
+        .update_rf("20;1F;Bresser-3CH;ID=49;CHN=0001;BAT=LOW;TEMP=00a2;HUM=58;")
+        .unwrap();
+    assert_eq!(since, battery(&states).1);
+
+    states
+        .update_rf("20;20;Bresser-3CH;ID=49;CHN=0001;BAT=OK;TEMP=00a2;HUM=58;")
+        .unwrap();
+    assert_eq!((Some(true), None), battery(&states));
+}
+
+#[test]
+fn test_pulse_meters() {
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        listen = { port = 80 }
+        [names]
+        mitemp = {}
+        rftemp = {}
+        [mqtt]
+        hostname = "mqtt"
+        [water.kitchen]
+        counter = "tasmota_1A2B3C/C1"
+        liters_per_pulse = 0.5
+        offset = 100
+        [water.garden]
+        topic = "s0/garden/pulses"
+        liters_per_pulse = 1
+        [gas.kitchen]
+        counter = "tasmota_1A2B3C/C2"
+        m3_per_pulse = 0.01
+        offset = 2000
+        [gas.shed]
+        m3_per_pulse = 0.1
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        Some(crate::topic::MappedTopic::Pulse(
+            "garden".into(),
+            DsmrMessageType::Water
+        )),
+        config.mapped_topic("s0/garden/pulses")
+    );
+    assert_eq!(
+        Some(crate::topic::MappedTopic::Pulse(
+            "shed".into(),
+            DsmrMessageType::Gas
+        )),
+        config.mapped_topic("shed/gas_pulse")
+    );
+    // read from a tasmota counter instead
+    assert_eq!(None, config.mapped_topic("kitchen/gas_pulse"));
+    assert!(config.subscriptions().contains(&"shed/gas_pulse".into()));
+
+    let mut states = DeviceStates {
+        pulse_meters: config.pulse_meters(),
+        ..DeviceStates::default()
+    };
+    let payload = r#"{"Time":"2024-01-01T12:00:00","COUNTER":{"C1":2000,"C2":10}}"#;
+    states
+        .update("tasmota_1A2B3C", TasmotaPayload::parse(payload).unwrap())
+        .unwrap();
+    states
+        .update("tasmota_4D5E6F", TasmotaPayload::parse(payload).unwrap())
+        .unwrap();
+    states
+        .update_pulses("garden", DsmrMessageType::Water, "1234")
+        .unwrap();
+    assert!(states
+        .update_pulses("garden", DsmrMessageType::Water, "n/a")
+        .is_err());
+    states
+        .update_pulses("shed", DsmrMessageType::Gas, "25")
+        .unwrap();
+    states
+        .update_pulses("unknown", DsmrMessageType::Gas, "25")
+        .unwrap();
+
+    assert_eq!(3, states.dsmr_devices.len());
+    assert_eq!(Some(101.0), states.dsmr_devices["kitchen"].water_total);
+    assert_eq!(Some(2000.1), states.dsmr_devices["kitchen"].gas_total);
+    assert_eq!(Some(1.234), states.dsmr_devices["garden"].water_total);
+    assert_eq!(Some(2.5), states.dsmr_devices["shed"].gas_total);
+}
+
+#[test]
+fn test_omg_rf_without_temperature() {
+    let mut states = DeviceStates::default();
+    let wind = r#"{"model":"Fineoffset-WH1080","id":12,"wind_avg_km_h":18,"battery_ok":1}"#;
+    states.update_omg_rf("gateway", OmgRf::parse(wind).unwrap());
+    states.update_omg_rf(
+        "gateway",
+        OmgRf::parse(r#"{"value":1234,"protocol":1}"#).unwrap(),
+    );
+
+    let states: Vec<_> = states.rf_temp().collect();
+    assert_eq!(1, states.len());
+    let (id, state) = states[0];
+    assert_eq!("Fineoffset-WH1080:12:0@gateway", id.to_string());
+    assert_eq!(None, state.temperature);
+    assert_eq!(Some(5.0), state.wind_speed);
+}
+
+#[test]
+fn test_evse_topics() {
+    let config: crate::config::Config = toml::from_str(
+        r#"
+        listen = { port = 3030 }
+        [names]
+        mitemp = {}
+        rftemp = {}
+        [evse]
+        openevse = "garage"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        Some(crate::topic::MappedTopic::Evse("garage".into(), "amp")),
+        config.mapped_topic("openevse/amp")
+    );
+    assert_eq!(None, config.mapped_topic("openevse/srssi"));
+    assert_eq!(None, config.mapped_topic("weather/temp"));
+
+    let mut states = DeviceStates::default();
+    states.update_evse("garage", "srssi", "-60");
+    states.update_evse("garage", "amp", "n/a");
+    assert!(states.evse_devices.is_empty());
+    states.update_evse("garage", "amp", "16020");
+    assert_eq!(Some(16.02), states.evse_devices["garage"].current);
+}
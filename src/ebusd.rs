@@ -0,0 +1,110 @@
+use crate::config::EbusdConfig;
+use crate::device::Readings;
+use crate::metrics::{self, Registry};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Values published by ebusd for a single circuit, such as the `hmu` heat pump unit
+///
+/// ebusd publishes every message on its own topic, `ebusd/<circuit>/<field>`.
+#[derive(Debug)]
+pub struct EbusdState {
+    pub values: BTreeMap<String, f32>,
+    pub last_seen: Instant,
+    pub revision: u64,
+}
+
+impl Default for EbusdState {
+    fn default() -> Self {
+        EbusdState {
+            values: BTreeMap::new(),
+            last_seen: Instant::now(),
+            revision: 0,
+        }
+    }
+}
+
+impl EbusdState {
+    /// Apply a published value, returns false if the payload has no numeric value
+    pub fn update(&mut self, field: &str, payload: &str) -> bool {
+        let Some(value) = parse_value(payload) else {
+            return false;
+        };
+        self.values.insert(field.into(), value);
+        self.last_seen = Instant::now();
+        true
+    }
+
+    pub fn readings(&self) -> Readings {
+        let mut values = json!({});
+        for (field, value) in &self.values {
+            values[field.as_str()] = (*value).into();
+        }
+        vec![("values", values)]
+    }
+}
+
+/// Parse the first value of an ebusd message, either published as plain text or json
+///
+/// Plain text messages separate multiple values with `;`, json messages have an object per value
+/// containing the `value`. State values like `on` and `off` are exported as 1 and 0.
+fn parse_value(payload: &str) -> Option<f32> {
+    let payload = payload.trim();
+    if payload.starts_with('{') {
+        let json: Map<String, Value> = serde_json::from_str(payload).ok()?;
+        let (_, field) = json.iter().next()?;
+        return match &field["value"] {
+            Value::String(value) => parse_text(value),
+            Value::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            value => value.as_f64().map(|value| value as f32),
+        };
+    }
+    parse_text(payload.split(';').next()?)
+}
+
+fn parse_text(value: &str) -> Option<f32> {
+    match value.trim() {
+        "on" | "yes" | "true" => Some(1.0),
+        "off" | "no" | "false" => Some(0.0),
+        value => value.parse().ok(),
+    }
+}
+
+pub fn format_ebusd_state(
+    registry: &mut Registry,
+    config: &EbusdConfig,
+    circuit: &str,
+    state: &EbusdState,
+) {
+    for (field, value) in &state.values {
+        let labels = [
+            ("circuit", circuit),
+            ("field", config.field_name(circuit, field)),
+        ];
+        registry.add(&metrics::EBUSD_VALUE, &labels, *value);
+    }
+}
+
+#[test]
+fn test_ebusd_update() {
+    let mut state = EbusdState::default();
+    assert!(state.update("FlowTemp", "33.5;ok"));
+    assert!(state.update("CompressorState", r#"{"0": {"name": "", "value": "on"}}"#));
+    assert!(state.update("RunDataStatuscode", r#"{"status": {"value": 104}}"#));
+    assert!(!state.update("Status01", "-;-"));
+    assert_eq!(Some(&33.5), state.values.get("FlowTemp"));
+    assert_eq!(Some(&1.0), state.values.get("CompressorState"));
+    assert_eq!(Some(&104.0), state.values.get("RunDataStatuscode"));
+    assert_eq!(None, state.values.get("Status01"));
+}
+
+#[test]
+fn test_ebusd_field_name() {
+    let mut config = EbusdConfig::default();
+    config
+        .fields
+        .insert("hmu/FlowTemp".into(), "flow_temperature".into());
+    assert_eq!("flow_temperature", config.field_name("hmu", "FlowTemp"));
+    assert_eq!("FlowTemp", config.field_name("ctlv2", "FlowTemp"));
+}
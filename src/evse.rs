@@ -0,0 +1,163 @@
+use crate::device::Readings;
+use crate::metrics::{self, Registry};
+use std::time::Instant;
+
+/// State of the charger, from the numeric state published by OpenEVSE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargingState {
+    /// No vehicle connected
+    Ready,
+    Connected,
+    Charging,
+    Error,
+    Sleeping,
+    Disabled,
+}
+
+impl ChargingState {
+    const ALL: [ChargingState; 6] = [
+        ChargingState::Ready,
+        ChargingState::Connected,
+        ChargingState::Charging,
+        ChargingState::Error,
+        ChargingState::Sleeping,
+        ChargingState::Disabled,
+    ];
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ChargingState::Ready),
+            2 => Some(ChargingState::Connected),
+            3 => Some(ChargingState::Charging),
+            // vent required, gfci, ground, stuck relay and temperature faults
+            4..=11 => Some(ChargingState::Error),
+            254 => Some(ChargingState::Sleeping),
+            255 => Some(ChargingState::Disabled),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChargingState::Ready => "ready",
+            ChargingState::Connected => "connected",
+            ChargingState::Charging => "charging",
+            ChargingState::Error => "error",
+            ChargingState::Sleeping => "sleeping",
+            ChargingState::Disabled => "disabled",
+        }
+    }
+}
+
+/// Fields published by OpenEVSE on `<base topic>/<field>` that are tracked
+pub const FIELDS: [&str; 5] = ["state", "amp", "pilot", "wh", "temp"];
+
+/// State reported by an OpenEVSE charger, every value is published on `<base topic>/<field>`
+#[derive(Debug)]
+pub struct EvseState {
+    pub state: Option<ChargingState>,
+    pub current: Option<f32>,
+    /// Current the car is allowed to draw
+    pub pilot: Option<f32>,
+    /// Energy delivered in the current charging session in kWh
+    pub session_energy: Option<f32>,
+    pub temperature: Option<f32>,
+    pub last_seen: Instant,
+    pub revision: u64,
+}
+
+impl Default for EvseState {
+    fn default() -> Self {
+        EvseState {
+            state: None,
+            current: None,
+            pilot: None,
+            session_energy: None,
+            temperature: None,
+            last_seen: Instant::now(),
+            revision: 0,
+        }
+    }
+}
+
+/// Whether a published value is applied by [`EvseState::update`]
+pub fn is_tracked(field: &str, payload: &str) -> bool {
+    FIELDS.contains(&field) && payload.trim().parse::<f32>().is_ok()
+}
+
+impl EvseState {
+    /// Apply a published value, returns false for fields that aren't tracked
+    pub fn update(&mut self, field: &str, payload: &str) -> bool {
+        let Ok(value) = payload.trim().parse::<f32>() else {
+            return false;
+        };
+        let value = Some(value);
+        match field {
+            "state" => self.state = value.and_then(|code| ChargingState::from_code(code as u8)),
+            // published in milliamps
+            "amp" => self.current = value.map(|current| current / 1000.0),
+            "pilot" => self.pilot = value,
+            "wh" => self.session_energy = value.map(|energy| energy / 1000.0),
+            // published in tenths of a degree
+            "temp" => self.temperature = value.map(|temperature| temperature / 10.0),
+            _ => return false,
+        }
+        self.last_seen = Instant::now();
+        true
+    }
+
+    pub fn readings(&self) -> Readings {
+        vec![
+            ("state", self.state.map(|state| state.as_str()).into()),
+            ("current", self.current.into()),
+            ("pilot", self.pilot.into()),
+            ("session_energy", self.session_energy.into()),
+            ("temperature", self.temperature.into()),
+        ]
+    }
+}
+
+pub fn format_evse_state(registry: &mut Registry, device: &str, state: &EvseState) {
+    let labels = [("name", device)];
+
+    if let Some(current_state) = state.state {
+        for category in ChargingState::ALL {
+            let labels = [("name", device), ("state", category.as_str())];
+            registry.add(
+                &metrics::EVSE_CHARGING_STATE,
+                &labels,
+                category == current_state,
+            );
+        }
+    }
+    if let Some(current) = state.current {
+        registry.add(&metrics::EVSE_CURRENT_AMPS, &labels, current);
+    }
+    if let Some(pilot) = state.pilot {
+        registry.add(&metrics::EVSE_PILOT_AMPS, &labels, pilot);
+    }
+    if let Some(energy) = state.session_energy {
+        registry.add(&metrics::EVSE_SESSION_KWH, &labels, energy);
+    }
+    if let Some(temperature) = state.temperature {
+        registry.add(&metrics::EVSE_TEMPERATURE, &labels, temperature);
+    }
+}
+
+#[test]
+fn test_evse_update() {
+    let mut state = EvseState::default();
+    assert!(state.update("state", "3"));
+    assert!(state.update("amp", "16020"));
+    assert!(state.update("wh", "7450"));
+    assert!(state.update("temp", "325"));
+    assert!(!state.update("srssi", "-60"));
+    assert!(!state.update("amp", "n/a"));
+    assert_eq!(Some(ChargingState::Charging), state.state);
+    assert_eq!(Some(16.02), state.current);
+    assert_eq!(Some(7.45), state.session_energy);
+    assert_eq!(Some(32.5), state.temperature);
+
+    assert!(state.update("state", "6"));
+    assert_eq!(Some(ChargingState::Error), state.state);
+}
@@ -0,0 +1,151 @@
+use crate::config::UpdateCheckConfig;
+use crate::device::{Device, DeviceState, SharedDeviceStates};
+use crate::metrics::{self, Registry};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use tokio::time::{sleep, Duration};
+use tracing::{error, info};
+
+/// Tasmota firmware version, parsed from either a release tag ("v14.3.0")
+/// or the firmware string reported by a device ("14.3.0(tasmota)")
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Default)]
+pub struct FirmwareVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().trim_start_matches('v');
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let mut parts = s[..end].split('.');
+        let major = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .wrap_err_with(|| format!("Invalid firmware version {s}"))?;
+        let minor = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        let patch = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        Ok(FirmwareVersion {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Periodically fetch the latest available firmware version
+pub async fn check_for_updates(config: UpdateCheckConfig, state: SharedDeviceStates) {
+    let client = match reqwest::Client::builder()
+        .user_agent(concat!("taspromto/", env!("CARGO_PKG_VERSION")))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to setup http client for update check: {:#}", e);
+            return;
+        }
+    };
+
+    loop {
+        match fetch_latest_version(&client, &config.url).await {
+            Ok(version) => {
+                info!("latest tasmota version is {}", version);
+                state.write().await.latest_firmware = Some(version);
+            }
+            Err(e) => error!("Failed to check for firmware updates: {:#}", e),
+        }
+        sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+async fn fetch_latest_version(client: &reqwest::Client, url: &str) -> Result<FirmwareVersion> {
+    let body = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let json: Value = serde_json::from_str(&body).wrap_err("Invalid release response")?;
+    json["tag_name"]
+        .as_str()
+        .ok_or_else(|| Report::msg("No tag_name in release response"))?
+        .parse()
+}
+
+pub fn format_update_available(
+    registry: &mut Registry,
+    device: &Device,
+    names: &HashMap<String, String>,
+    state: &DeviceState,
+    latest: FirmwareVersion,
+) {
+    let name = state.display_name(device, names);
+    if name.is_empty() {
+        return;
+    }
+    let Ok(current) = state.firmware.parse::<FirmwareVersion>() else {
+        return;
+    };
+    registry.add(
+        &metrics::TASMOTA_UPDATE_AVAILABLE,
+        &[
+            ("tasmota_id", device.hostname.as_ref()),
+            ("name", name),
+            ("current", &current.to_string()),
+            ("latest", &latest.to_string()),
+        ],
+        latest > current,
+    );
+}
+
+pub fn format_firmware_outdated(
+    registry: &mut Registry,
+    device: &Device,
+    names: &HashMap<String, String>,
+    state: &DeviceState,
+    minimum: FirmwareVersion,
+) {
+    let name = state.display_name(device, names);
+    if name.is_empty() {
+        return;
+    }
+    let Ok(current) = state.firmware.parse::<FirmwareVersion>() else {
+        return;
+    };
+    registry.add(
+        &metrics::TASMOTA_FIRMWARE_OUTDATED,
+        &[
+            ("tasmota_id", device.hostname.as_ref()),
+            ("name", name),
+            ("current", &current.to_string()),
+            ("minimum", &minimum.to_string()),
+        ],
+        current < minimum,
+    );
+}
+
+#[test]
+fn test_parse_firmware_version() {
+    let release: FirmwareVersion = "v14.3.0".parse().unwrap();
+    let device: FirmwareVersion = "14.2.0.1(tasmota)".parse().unwrap();
+    assert_eq!("14.3.0", release.to_string());
+    assert_eq!("14.2.0", device.to_string());
+    assert!(release > device);
+    assert!("13.10.0".parse::<FirmwareVersion>().unwrap() > "13.9.1".parse().unwrap());
+}
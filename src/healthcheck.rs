@@ -0,0 +1,99 @@
+use crate::config::{Config, ListenConfig};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time::{timeout, Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check whether the exporter listening on the first configured address is ready
+pub async fn healthcheck(config: &Config) -> Result<bool> {
+    let listen = config
+        .listen
+        .first()
+        .ok_or_else(|| Report::msg("No listen address configured"))?;
+    let status = timeout(TIMEOUT, ready_status(listen))
+        .await
+        .wrap_err("Timeout while checking readiness")??;
+    Ok(status == 200)
+}
+
+async fn ready_status(listen: &ListenConfig) -> Result<u16> {
+    match listen {
+        ListenConfig::Ip {
+            address,
+            port,
+            tls: None,
+        } => {
+            let address = SocketAddr::new(local_address(*address), *port);
+            let stream = TcpStream::connect(address)
+                .await
+                .wrap_err_with(|| format!("Failed to connect to {address}"))?;
+            request(stream).await
+        }
+        ListenConfig::Ip {
+            address,
+            port,
+            tls: Some(_),
+        } => {
+            let address = SocketAddr::new(local_address(*address), *port);
+            // the certificate won't be valid for the local address
+            let client = reqwest::Client::builder()
+                .danger_accept_invalid_certs(true)
+                .build()?;
+            let response = client
+                .get(format!("https://{address}/ready"))
+                .send()
+                .await
+                .wrap_err_with(|| format!("Failed to connect to {address}"))?;
+            Ok(response.status().as_u16())
+        }
+        ListenConfig::Unix { socket, .. } => {
+            let stream = UnixStream::connect(socket)
+                .await
+                .wrap_err_with(|| format!("Failed to connect to {socket}"))?;
+            request(stream).await
+        }
+    }
+}
+
+/// Connect to the loopback address when listening on all addresses
+fn local_address(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(address) if address.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(address) if address.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        address => address,
+    }
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Result<u16> {
+    stream
+        .write_all(b"GET /ready HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse_status(&response)
+}
+
+fn parse_status(response: &[u8]) -> Result<u16> {
+    let status_line = response.split(|c| *c == b'\n').next().unwrap_or_default();
+    std::str::from_utf8(status_line)
+        .ok()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| Report::msg("Invalid http response"))
+}
+
+#[test]
+fn test_parse_status() {
+    assert_eq!(
+        200,
+        parse_status(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok").unwrap()
+    );
+    assert_eq!(
+        503,
+        parse_status(b"HTTP/1.1 503 Service Unavailable\r\n\r\n").unwrap()
+    );
+    assert!(parse_status(b"").is_err());
+}
@@ -0,0 +1,223 @@
+use crate::config::{Config, HistoryConfig};
+use crate::device::SharedDeviceStates;
+use color_eyre::{eyre::WrapErr, Result};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// Default time range for history queries
+const DEFAULT_RANGE: u64 = 24 * 60 * 60;
+
+/// A stored reading as `(time, device, metric, value)`
+pub type Row = (u64, String, String, f64);
+
+/// Numeric readings of all devices stored in sqlite
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub device: Option<String>,
+    pub metric: Option<String>,
+    /// Unix timestamp, defaults to 24 hours before `to`
+    pub from: Option<u64>,
+    /// Unix timestamp, defaults to now
+    pub to: Option<u64>,
+}
+
+impl History {
+    /// Run `f` on a blocking thread, sqlite calls shouldn't block the async executor
+    pub async fn blocking<T: Send + 'static>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&History) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let history = self.clone();
+        spawn_blocking(move || f(&history))
+            .await
+            .wrap_err("History task failed")?
+    }
+
+    pub fn open(path: &str) -> Result<Self> {
+        let connection =
+            Connection::open(path).wrap_err_with(|| format!("Failed to open {path}"))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS readings (
+                    time INTEGER NOT NULL,
+                    device TEXT NOT NULL,
+                    metric TEXT NOT NULL,
+                    value REAL NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS readings_device ON readings (device, metric, time);
+                CREATE INDEX IF NOT EXISTS readings_time ON readings (time);",
+            )
+            .wrap_err("Failed to setup history database")?;
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Store all numeric and boolean readings from the device snapshots
+    pub fn insert(&self, time: u64, snapshots: &[(String, Value)]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO readings (time, device, metric, value) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (device, snapshot) in snapshots {
+                for (metric, value) in numeric_readings(snapshot) {
+                    statement.execute(params![time, device, metric, value])?;
+                }
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Remove all readings older than `before`
+    pub fn prune(&self, before: u64) -> Result<usize> {
+        Ok(self
+            .connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM readings WHERE time < ?1", [before])?)
+    }
+
+    /// All matching readings as `(time, device, metric, value)`, ordered by device, metric and time
+    pub fn rows(&self, query: &HistoryQuery, now: u64) -> Result<Vec<Row>> {
+        let to = query.to.unwrap_or(now);
+        let from = query.from.unwrap_or(to.saturating_sub(DEFAULT_RANGE));
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(
+            "SELECT time, device, metric, value FROM readings
+            WHERE time >= ?1 AND time <= ?2 AND (?3 IS NULL OR device = ?3) AND (?4 IS NULL OR metric = ?4)
+            ORDER BY device, metric, time",
+        )?;
+        let rows = statement
+            .query_map(params![from, to, query.device, query.metric], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(rows)
+    }
+
+    /// All matching readings, grouped by device and metric
+    pub fn query(&self, query: &HistoryQuery, now: u64) -> Result<Value> {
+        let mut series = Vec::new();
+        let mut current: Option<(String, String, Value)> = None;
+        for (time, device, metric, value) in self.rows(query, now)? {
+            let point = json!([time, value]);
+            match &mut current {
+                Some((current_device, current_metric, points))
+                    if *current_device == device && *current_metric == metric =>
+                {
+                    points.as_array_mut().unwrap().push(point);
+                }
+                _ => {
+                    if let Some(done) = current.take() {
+                        series.push(series_json(done));
+                    }
+                    current = Some((device, metric, json!([point])));
+                }
+            }
+        }
+        if let Some(done) = current {
+            series.push(series_json(done));
+        }
+        Ok(series.into())
+    }
+}
+
+fn series_json((device, metric, points): (String, String, Value)) -> Value {
+    json!({
+        "device": device,
+        "metric": metric,
+        "points": points,
+    })
+}
+
+/// All numeric readings from a device snapshot, booleans are stored as 0 or 1
+pub fn numeric_readings(snapshot: &Value) -> impl Iterator<Item = (&str, f64)> {
+    snapshot
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(metric, value)| {
+            let value = value.as_f64().or_else(|| value.as_bool().map(f64::from))?;
+            Some((metric.as_str(), value))
+        })
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Periodically store the readings of every device and remove expired readings
+pub async fn record_history(
+    history: Arc<History>,
+    history_config: HistoryConfig,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+) {
+    let mut interval = interval(Duration::from_secs(history_config.interval));
+    loop {
+        interval.tick().await;
+        let snapshots = {
+            let device_states = device_states.read().await;
+            device_states.snapshots(&config.read().unwrap().names)
+        };
+        let now = unix_time();
+        let retention = history_config.retention;
+        let result = history
+            .blocking(move |history| {
+                if let Err(e) = history.insert(now, &snapshots) {
+                    error!("Failed to store history: {:#}", e);
+                }
+                history.prune(now.saturating_sub(retention))
+            })
+            .await;
+        if let Err(e) = result {
+            error!("Failed to remove expired history: {:#}", e);
+        }
+    }
+}
+
+#[test]
+fn test_history() {
+    let history = History::open(":memory:").unwrap();
+    let snapshot = |power: f32| json!({ "online": true, "name": "Plug", "power_watts": power, "type": "tasmota" });
+    history
+        .insert(100, &[("plug".into(), snapshot(10.0))])
+        .unwrap();
+    history
+        .insert(160, &[("plug".into(), snapshot(12.5))])
+        .unwrap();
+
+    let query = HistoryQuery {
+        metric: Some("power_watts".into()),
+        ..HistoryQuery::default()
+    };
+    let result = history.query(&query, 200).unwrap();
+    assert_eq!(1, result.as_array().unwrap().len());
+    assert_eq!("plug", result[0]["device"]);
+    assert_eq!(json!([[100, 10.0], [160, 12.5]]), result[0]["points"]);
+
+    let result = history.query(&HistoryQuery::default(), 200).unwrap();
+    assert_eq!("online", result[0]["metric"]);
+    assert_eq!(json!([[100, 1.0], [160, 1.0]]), result[0]["points"]);
+
+    assert_eq!(2, history.prune(150).unwrap());
+    let result = history.query(&query, 200).unwrap();
+    assert_eq!(json!([[160, 12.5]]), result[0]["points"]);
+}
@@ -0,0 +1,150 @@
+use crate::config::{Config, InfluxDbConfig, InfluxDbTarget};
+use crate::device::SharedDeviceStates;
+use crate::metrics::Registry;
+use crate::stats::Stats;
+use crate::{collect_metrics, MetricsQuery};
+use color_eyre::{eyre::WrapErr, Result};
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// Periodically write all current readings to influxdb
+pub async fn write_to_influxdb(
+    influx: InfluxDbConfig,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+) {
+    let client = match reqwest::Client::builder()
+        .user_agent(concat!("taspromto/", env!("CARGO_PKG_VERSION")))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to setup http client for influxdb: {:#}", e);
+            return;
+        }
+    };
+
+    let mut interval = interval(Duration::from_secs(influx.interval));
+    loop {
+        interval.tick().await;
+        let registry = {
+            let device_states = device_states.read().await;
+            collect_metrics(
+                &config.read().unwrap(),
+                &device_states,
+                &stats,
+                &MetricsQuery::default(),
+            )
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let body = line_protocol(&registry, timestamp);
+        if let Err(e) = write(&client, &influx, body).await {
+            error!("Failed to write to influxdb: {:#}", e);
+        }
+    }
+}
+
+async fn write(client: &reqwest::Client, influx: &InfluxDbConfig, body: String) -> Result<()> {
+    let url = influx.url.trim_end_matches('/');
+    let request = match &influx.target {
+        InfluxDbTarget::V2 {
+            org,
+            bucket,
+            token,
+            token_file,
+        } => {
+            let token = match (token, token_file) {
+                (Some(token), _) => token.clone(),
+                (None, Some(token_file)) => {
+                    secretfile::load(token_file).wrap_err("Failed to load influxdb token")?
+                }
+                (None, None) => String::new(),
+            };
+            client
+                .post(format!("{url}/api/v2/write"))
+                .query(&[
+                    ("org", org.as_str()),
+                    ("bucket", bucket.as_str()),
+                    ("precision", "s"),
+                ])
+                .header("Authorization", format!("Token {token}"))
+        }
+        InfluxDbTarget::V1 {
+            database,
+            credentials,
+        } => {
+            let request = client
+                .post(format!("{url}/write"))
+                .query(&[("db", database.as_str()), ("precision", "s")]);
+            match credentials {
+                Some(credentials) => {
+                    request.basic_auth(credentials.username(), Some(credentials.try_password()?))
+                }
+                None => request,
+            }
+        }
+    };
+    request.body(body).send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Format all samples as influxdb line protocol, using the metric name as measurement and the labels as tags
+fn line_protocol(registry: &Registry, timestamp: u64) -> String {
+    let mut out = String::new();
+    for (family, samples) in registry.families() {
+        for sample in samples {
+            // histograms don't map to a single value
+            if !sample.value.0.is_finite() || sample.histogram.is_some() {
+                continue;
+            }
+            escape(&mut out, registry.name(family), ", ");
+            for (name, value) in &sample.labels {
+                if value.is_empty() {
+                    continue;
+                }
+                out.push(',');
+                escape(&mut out, name, ", =");
+                out.push('=');
+                escape(&mut out, value, ", =");
+            }
+            writeln!(out, " value={} {}", sample.value.0, timestamp).unwrap();
+        }
+    }
+    out
+}
+
+fn escape(out: &mut String, value: &str, special: &str) {
+    for c in value.chars() {
+        if c == '\\' || special.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+#[test]
+fn test_line_protocol() {
+    use crate::metrics;
+
+    let mut registry = Registry::default();
+    registry.add(
+        &metrics::POWER_WATTS,
+        &[("tasmota_id", "plug"), ("name", "Washing Machine")],
+        12.5f32,
+    );
+    registry.add(&metrics::SWITCH_STATE, &[("name", "a=b,c")], true);
+    registry.add(&metrics::SENSOR_CO2, &[("name", "")], f64::NAN);
+
+    assert_eq!(
+        "power_watts,tasmota_id=plug,name=Washing\\ Machine value=12.5 1700000000\n\
+        switch_state,name=a\\=b\\,c value=1 1700000000\n",
+        line_protocol(&registry, 1_700_000_000)
+    );
+}
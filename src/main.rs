@@ -1,201 +1,1585 @@
+mod air_quality;
+mod anomaly;
+mod api;
+mod auth;
+mod cache;
+mod climate;
+mod compression;
 mod config;
+mod csv;
+mod daily;
+mod dashboard;
 mod device;
+mod ebusd;
+mod evse;
+mod firmware;
+mod healthcheck;
+mod history;
+mod influxdb;
+mod metrics;
 mod mqtt;
+mod notify;
+mod occupancy;
+mod otgw;
+mod otlp;
+mod pattern;
+mod payload;
+mod plausibility;
+mod process;
+mod protobuf;
+mod proxy;
+mod publish;
+mod pulse;
+mod rate;
+mod record;
+mod rtl;
+mod rules;
+mod simulate;
+mod solar;
+mod stats;
+mod statsd;
+mod systemd;
+mod tenant;
 mod topic;
 
-use crate::config::{Config, ListenConfig};
+use crate::api::ApiState;
+use crate::auth::{authorization, handle_rejection, remote_allowed};
+use crate::cache::{RenderCache, Rendered};
+use crate::compression::Encoding;
+use crate::config::{AuthConfig, Config, DeviceLimits, ListenConfig};
 use crate::device::{
     format_device_state, format_dsmr_state, format_mi_temp_state, format_rf_temp_state, Device,
-    DeviceStates,
+    DeviceStates, SharedDeviceStates,
 };
-use crate::mqtt::mqtt_stream;
-use crate::topic::Topic;
-use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
+use crate::ebusd::format_ebusd_state;
+use crate::evse::format_evse_state;
+use crate::firmware::{
+    check_for_updates, format_firmware_outdated, format_update_available, FirmwareVersion,
+};
+use crate::healthcheck::healthcheck;
+use crate::history::{record_history, History};
+use crate::influxdb::write_to_influxdb;
+use crate::metrics::Registry;
+use crate::mqtt::{command, mqtt_stream, warm_up_devices, ConnectionState};
+use crate::notify::send_device_notifications;
+use crate::occupancy::room_occupied;
+use crate::otgw::format_otgw_state;
+use crate::otlp::export_otlp;
+use crate::pattern::Pattern;
+use crate::payload::{OmgBle, OmgRf, TasmotaPayload, P1};
+use crate::publish::publish_snapshots;
+use crate::record::{replay, Recorder};
+use crate::rules::evaluate_rules;
+use crate::simulate::{simulate, Simulation};
+use crate::solar::format_solar_state;
+use crate::stats::{format_stats, ScrapeStats, Stats};
+use crate::statsd::write_to_statsd;
+use crate::tenant::Tenant;
+use crate::topic::{MappedTopic, Topic};
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use ipnet::IpNet;
 
 use pin_utils::pin_mut;
-use rumqttc::{AsyncClient, Publish, QoS};
+use rumqttc::{AsyncClient, MqttOptions, Publish};
+use serde::Deserialize;
 
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs::{remove_file, set_permissions, symlink_metadata, Permissions};
+use std::future::pending;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 use tokio::task::spawn;
-use tokio::time::{sleep, Duration};
+use tokio::time::{interval, sleep, Duration, Interval};
 use tokio_stream::wrappers::UnixListenerStream;
 use tokio_stream::{Stream, StreamExt};
-use warp::Filter;
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use warp::filters::BoxedFilter;
+use warp::http::{header, Response, StatusCode};
+use warp::hyper::Body;
+use warp::{Filter, Reply};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Config file to use, if omitted the config will be loaded from environment variables
+    #[arg(conflicts_with = "config_file")]
     config: Option<String>,
+    /// Config file to use, environment variables override the values from the config file
+    #[arg(long = "config", env = "CONFIG_FILE", global = true)]
+    config_file: Option<String>,
+    /// Log level filter, either a level or comma separated `module=level` directives
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Append every received mqtt message to a file
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+    /// Process the messages from a recording instead of connecting to mqtt
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Replayed and simulated messages don't come from a broker
+    fn needs_broker(&self) -> bool {
+        self.replay.is_none() && !matches!(self.command, Some(Command::Simulate(_)))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check if the exporter running with the same config is ready, exits with 1 if it's not
+    Healthcheck,
+    /// Collect messages for a while, print the metrics and exit
+    Scrape {
+        /// Seconds to collect messages for
+        #[arg(long, default_value_t = 10)]
+        duration: u64,
+    },
+    /// Process telemetry from virtual devices instead of connecting to mqtt, for demos and development
+    Simulate(Simulation),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One json object per line, for log shippers
+    Json,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    // stdout is reserved for the output of `scrape`
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(&args.log_level).wrap_err("Invalid log level")?)
+        .with_writer(std::io::stderr);
+    match args.log_format {
+        LogFormat::Text => logger.init(),
+        LogFormat::Json => logger
+            .json()
+            .flatten_event(true)
+            .with_span_list(false)
+            .init(),
+    }
 
-    let config = match args.config {
-        Some(path) => Config::from_file(path)?,
+    let needs_broker = args.needs_broker();
+    let config_path = args.config.or(args.config_file);
+    let config = match config_path.as_deref() {
+        Some(path) => Config::load(path)?,
         _ => Config::from_env()?,
     };
-    let mqtt_options = config.mqtt()?;
+    if let Some(Command::Healthcheck) = args.command {
+        if !healthcheck(&config).await? {
+            error!("exporter is not ready");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    let device_states = <Arc<Mutex<DeviceStates>>>::default();
+    let brokers = match needs_broker {
+        true => config.mqtt()?,
+        false => Vec::new(),
+    };
+    let mut recorder = args.record.as_deref().map(Recorder::create).transpose()?;
 
-    ctrlc::set_handler(move || {
-        std::process::exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let mut states = configured_states(&config);
+    for name in config.tenants.keys() {
+        states
+            .tenants
+            .insert(name.clone(), configured_states(&config));
+    }
+    let device_states = SharedDeviceStates::new(states.into());
+    let tenants: Vec<_> = config
+        .tenants
+        .iter()
+        .map(|(name, tenant)| {
+            Tenant::new(name.clone(), tenant.prefix.clone(), device_states.clone())
+        })
+        .collect();
+    let connection = <Arc<Mutex<ConnectionState>>>::default();
+    let stats = <Arc<Stats>>::default();
 
-    spawn(serve(device_states.clone(), config));
+    if let Some(Command::Scrape { duration }) = args.command {
+        // a single scrape doesn't fail over to other brokers
+        let mqtt_options = match config.tenant_proxy(None) {
+            Some(proxy) => proxy::tunnel(&brokers[0], proxy).await?,
+            None => brokers[0].clone(),
+        };
+        return scrape_once(
+            mqtt_options,
+            Arc::new(RwLock::new(config)),
+            device_states,
+            connection,
+            stats,
+            &mut recorder,
+            Duration::from_secs(duration),
+        )
+        .await;
+    }
 
-    loop {
-        let (client, stream) = mqtt_stream(mqtt_options.clone())
-            .await
-            .wrap_err("Failed to setup mqtt listener")?;
+    spawn(exit_on_signal());
+
+    if let Some(update_check) = config.update_check.clone() {
+        spawn(check_for_updates(update_check, device_states.clone()));
+    }
+    let influxdb = config.influxdb.clone();
+    let otlp = config.otlp.clone();
+    let statsd = config.statsd.clone();
+    let history_config = config.history.clone();
+    let webhook = config.webhook.clone();
+    let notifiers = config.notifiers.clone();
+
+    let auth = config
+        .auth
+        .as_ref()
+        .map(AuthConfig::header_value)
+        .transpose()
+        .wrap_err("Failed to load http authentication")?;
+    let allowed_networks = config.access.allow.clone();
+
+    let history = history_config
+        .as_ref()
+        .map(|history| History::open(&history.path).map(Arc::new))
+        .transpose()
+        .wrap_err("Failed to open history database")?;
+
+    let config = Arc::new(RwLock::new(config));
+    if let Some(path) = config_path.clone() {
+        spawn(reload_on_hangup(path, config.clone()));
+    }
+
+    if let Some(influxdb) = influxdb {
+        spawn(write_to_influxdb(
+            influxdb,
+            device_states.clone(),
+            config.clone(),
+            stats.clone(),
+        ));
+    }
+
+    if let Some(statsd) = statsd {
+        spawn(write_to_statsd(
+            statsd,
+            device_states.clone(),
+            config.clone(),
+            stats.clone(),
+        ));
+    }
+
+    if let Some(otlp) = otlp {
+        spawn(export_otlp(
+            otlp,
+            device_states.clone(),
+            config.clone(),
+            stats.clone(),
+        ));
+    }
+
+    if webhook.is_some() || !notifiers.is_empty() {
+        spawn(send_device_notifications(
+            webhook,
+            notifiers.clone(),
+            device_states.clone(),
+            config.clone(),
+        ));
+    }
+
+    if let (Some(history), Some(history_config)) = (history.clone(), history_config) {
+        spawn(record_history(
+            history,
+            history_config,
+            device_states.clone(),
+            config.clone(),
+        ));
+    }
 
-        let cleanup_task = spawn(cleanup(client.clone(), device_states.clone()));
+    spawn(evaluate_rules(
+        device_states.clone(),
+        config.clone(),
+        stats.clone(),
+        connection.clone(),
+        notifiers,
+    ));
 
+    let api_state = ApiState {
+        device_states: device_states.clone(),
+        config: config.clone(),
+        connection: connection.clone(),
+        stats: stats.clone(),
+        history,
+        config_path,
+    };
+    spawn(serve(api_state, auth, allowed_networks));
+
+    if let Some(Command::Simulate(simulation)) = args.command {
+        let stream = simulate(simulation);
         pin_mut!(stream);
+        let (warm_up, _) = mpsc::unbounded_channel();
+        // the simulated devices are always reachable
+        connection.lock().unwrap().set_connected(true);
+        info!(?simulation, "simulating devices");
+        return mqtt_client(
+            warm_up,
+            &mut stream,
+            Tenant::root(device_states),
+            stats,
+            config,
+            &mut recorder,
+        )
+        .await;
+    }
+
+    if let Some(path) = args.replay {
+        let stream = replay(&path)?;
+        pin_mut!(stream);
+        // there is no broker to ask for the device state
+        let (warm_up, _) = mpsc::unbounded_channel();
+        mqtt_client(
+            warm_up,
+            &mut stream,
+            Tenant::root(device_states.clone()),
+            stats.clone(),
+            config.clone(),
+            &mut recorder,
+        )
+        .await?;
+        info!("finished replaying {}", path);
+        // keep serving the replayed state
+        return pending().await;
+    }
 
-        if let Err(e) = mqtt_client(client.clone(), &mut stream, device_states.clone()).await {
-            eprintln!("lost mqtt collection: {:#}", e);
+    for tenant in tenants {
+        let brokers = config.read().unwrap().tenant_mqtt(tenant.name.as_deref())?;
+        let (stats, config) = (stats.clone(), config.clone());
+        spawn(async move {
+            let connection = <Arc<Mutex<ConnectionState>>>::default();
+            let name = tenant.name.clone().unwrap_or_default();
+            // a failing tenant doesn't stop the exporter, so keep retrying
+            loop {
+                let (tenant, brokers) = (tenant.clone(), brokers.clone());
+                let (connection, stats, config) =
+                    (connection.clone(), stats.clone(), config.clone());
+                if let Err(e) =
+                    mqtt_loop(tenant, brokers, connection, stats, config, &mut None).await
+                {
+                    error!("mqtt connection of tenant {name} failed: {:#}", e);
+                }
+                info!("restarting mqtt connection of tenant {name} after 10s");
+                sleep(Duration::from_secs(10)).await;
+            }
+        });
+    }
+
+    let root = Tenant::root(device_states);
+    mqtt_loop(root, brokers, connection, stats, config, &mut recorder).await
+}
+
+/// Receive the messages of a tenant, reconnecting when the connection is lost
+///
+/// When multiple brokers are configured, the next broker is tried every time the connection fails.
+async fn mqtt_loop(
+    tenant: Tenant,
+    brokers: Vec<MqttOptions>,
+    connection: Arc<Mutex<ConnectionState>>,
+    stats: Arc<Stats>,
+    config: Arc<RwLock<Config>>,
+    recorder: &mut Option<Recorder>,
+) -> Result<()> {
+    let proxy = config.read().unwrap().tenant_proxy(tenant.name.as_deref());
+    for broker in brokers.iter().cycle() {
+        let (host, port) = broker.broker_address();
+        stats.broker_selected(tenant.name.as_deref(), format!("{host}:{port}"));
+        // a tunnel only accepts a single connection, so every attempt sets up a new one
+        let mqtt_options = match &proxy {
+            Some(proxy) => proxy::tunnel(broker, proxy.clone()).await?,
+            None => broker.clone(),
+        };
+        let subscriptions = config.read().unwrap().subscriptions();
+        let (client, stream) = mqtt_stream(
+            mqtt_options,
+            connection.clone(),
+            subscriptions,
+            &tenant.prefix,
+        )
+        .await
+        .wrap_err("Failed to setup mqtt listener")?;
+
+        let cleanup_task = spawn(cleanup(
+            client.clone(),
+            tenant.clone(),
+            stats.clone(),
+            config.clone(),
+        ));
+
+        // snapshots are only published for the default namespace
+        let publish = config.read().unwrap().publish.clone();
+        let publish_task = publish.filter(|_| tenant.name.is_none()).map(|publish| {
+            spawn(publish_snapshots(
+                client.clone(),
+                publish,
+                tenant.states.clone(),
+                config.clone(),
+            ))
+        });
+
+        pin_mut!(stream);
+
+        let (warm_up, warm_up_queue) = mpsc::unbounded_channel();
+        let warm_up_task = spawn(warm_up_devices(
+            client.clone(),
+            stats.clone(),
+            config.clone(),
+            tenant.prefix.clone(),
+            warm_up_queue,
+        ));
+
+        if let Err(e) = mqtt_client(
+            warm_up,
+            &mut stream,
+            tenant.clone(),
+            stats.clone(),
+            config.clone(),
+            recorder,
+        )
+        .await
+        {
+            error!("lost mqtt collection: {:#}", e);
+            systemd::notify_status(&format!("Reconnecting to mqtt: {:#}", e));
         }
-        eprintln!("reconnecting after 1s");
+        connection.lock().unwrap().set_connected(false);
+        stats.reconnected();
+        info!("reconnecting after 1s");
         sleep(Duration::from_secs(1)).await;
+        // still alive while failing to connect
+        systemd::notify_watchdog();
 
         cleanup_task.abort();
+        warm_up_task.abort();
+        if let Some(publish_task) = publish_task {
+            publish_task.abort();
+        }
     }
+    Err(Report::msg("No mqtt broker configured"))
 }
 
-async fn serve(device_states: Arc<Mutex<DeviceStates>>, config: Config) {
-    let mi_temp_names = config.names.mi_temp.clone();
-    let rf_temp_names = config.names.rf_temp.clone();
+/// Empty device states with the settings from the config applied
+fn configured_states(config: &Config) -> DeviceStates {
+    let mut states = DeviceStates::default();
+    states.timezone = config.timezone.clone();
+    states.anomaly = config.anomaly;
+    states.ignore = config.ignore.clone();
+    states.named_variables = config.metrics.variables.keys().cloned().collect();
+    states.plausibility.bounds = config.bounds;
+    states.plausibility.spikes = config.spikes;
+    states.pulse_meters = config.pulse_meters();
+    states
+}
 
-    let state = warp::any().map(move || device_states.clone());
+/// Collect messages for `duration` and print the resulting metrics to stdout
+async fn scrape_once(
+    mqtt_options: MqttOptions,
+    config: Arc<RwLock<Config>>,
+    device_states: SharedDeviceStates,
+    connection: Arc<Mutex<ConnectionState>>,
+    stats: Arc<Stats>,
+    recorder: &mut Option<Recorder>,
+    duration: Duration,
+) -> Result<()> {
+    let subscriptions = config.read().unwrap().subscriptions();
+    let (client, stream) = mqtt_stream(mqtt_options, connection, subscriptions, "")
+        .await
+        .wrap_err("Failed to setup mqtt listener")?;
+    pin_mut!(stream);
 
-    let metrics = warp::path!("metrics")
-        .and(state)
-        .map(move |state: Arc<Mutex<DeviceStates>>| {
-            let state = state.lock().unwrap();
-            let mut response = String::new();
-            for (device, state) in state.devices() {
-                format_device_state(&mut response, device, state).unwrap();
-            }
-            for (device, state) in state.dsmr_devices() {
-                format_dsmr_state(&mut response, device.hostname.as_str(), state).unwrap();
+    let (warm_up, warm_up_queue) = mpsc::unbounded_channel();
+    spawn(warm_up_devices(
+        client.clone(),
+        stats.clone(),
+        config.clone(),
+        String::new(),
+        warm_up_queue,
+    ));
+
+    // stop reading after the duration, while still processing the messages already received
+    let deadline = sleep(duration);
+    let stream = async_stream::stream! {
+        pin_mut!(deadline);
+        loop {
+            tokio::select! {
+                message = stream.next() => match message {
+                    Some(message) => yield message,
+                    None => break,
+                },
+                _ = &mut deadline => break,
             }
-            for (addr, state) in state.mi_temp() {
-                format_mi_temp_state(&mut response, *addr, &mi_temp_names, state).unwrap()
+        }
+    };
+    pin_mut!(stream);
+    mqtt_client(
+        warm_up,
+        &mut stream,
+        Tenant::root(device_states.clone()),
+        stats.clone(),
+        config.clone(),
+        recorder,
+    )
+    .await?;
+
+    let device_states = device_states.read().await;
+    let registry = collect_metrics(
+        &config.read().unwrap(),
+        &device_states,
+        &stats,
+        &MetricsQuery::default(),
+    );
+    let mut output = String::new();
+    registry.render(&mut output).unwrap();
+    print!("{output}");
+    Ok(())
+}
+
+async fn exit_on_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+    std::process::exit(0);
+}
+
+/// Compress the rendered metrics if the client accepts it, or stream the chunks as is
+fn metrics_response(
+    rendered: Rendered,
+    content_type: &'static str,
+    accept_encoding: Option<&str>,
+) -> Response<Body> {
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        // caches need to keep the compressed and uncompressed responses apart
+        .header(header::VARY, "accept-encoding");
+    match accept_encoding.and_then(Encoding::negotiate) {
+        Some(encoding) => response
+            .header(header::CONTENT_ENCODING, encoding.header_value())
+            .body(Body::from(encoding.compress(&rendered.to_vec()))),
+        None => response.body(Body::wrap_stream(tokio_stream::iter(
+            rendered.chunks.into_iter().map(Ok::<_, Infallible>),
+        ))),
+    }
+    .unwrap()
+}
+
+async fn reload_on_hangup(path: String, config: Arc<RwLock<Config>>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Error setting SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match Config::load(&path) {
+            Ok(new_config) => {
+                config.write().unwrap().reload(new_config);
+                info!("reloaded config from {}", path);
             }
-            for (channel, state) in state.rf_temp() {
-                format_rf_temp_state(&mut response, channel, &rf_temp_names, state).unwrap()
+            Err(e) => error!("Failed to reload config: {:#}", e),
+        }
+    }
+}
+
+async fn serve(api_state: ApiState, auth: Option<String>, allowed_networks: Vec<IpNet>) {
+    let ApiState {
+        device_states,
+        connection,
+        stats,
+        config,
+        ..
+    } = api_state.clone();
+    let (listen, admin_listen, metrics_path) = {
+        let config = config.read().unwrap();
+        (
+            config.listen.clone(),
+            config.admin_listen.clone(),
+            config.metrics.path.clone(),
+        )
+    };
+
+    let api = warp::path("api")
+        .and(remote_allowed(allowed_networks.clone()))
+        .and(authorization(auth.clone()))
+        .and(api::routes(api_state.clone()));
+    let sd = warp::path!("sd")
+        .and(remote_allowed(allowed_networks.clone()))
+        .and(authorization(auth.clone()))
+        .and(api::service_discovery(api_state));
+
+    let dashboard_config = config.clone();
+    let dashboard_states = device_states.clone();
+    let dashboard = warp::path::end()
+        .and(warp::get())
+        .and(remote_allowed(allowed_networks.clone()))
+        .and(authorization(auth.clone()))
+        .then(move || {
+            let config = dashboard_config.clone();
+            let states = dashboard_states.clone();
+            async move {
+                let states = states.read().await;
+                warp::reply::html(dashboard::render(&config.read().unwrap(), &states))
             }
-            response
         });
 
-    match config.listen {
-        ListenConfig::Ip { address, port } => {
-            warp::serve(metrics).run((address, port)).await;
+    let state = warp::any().map(move || device_states.clone());
+
+    let tenant_config = config.clone();
+    // tenants are only read from the config at startup
+    let tenant_caches: Arc<HashMap<String, RenderCache>> = Arc::new(
+        config
+            .read()
+            .unwrap()
+            .tenants
+            .keys()
+            .map(|tenant| (tenant.clone(), RenderCache::default()))
+            .collect(),
+    );
+    let tenant_metrics = path_prefix(&metrics_path)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(remote_allowed(allowed_networks.clone()))
+        .and(authorization(auth.clone()))
+        .and(state.clone())
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::query::<MetricsQuery>())
+        .then(
+            move |tenant: String,
+                  state: SharedDeviceStates,
+                  accept_encoding: Option<String>,
+                  query: MetricsQuery| {
+                let config = tenant_config.clone();
+                let caches = tenant_caches.clone();
+                async move {
+                    let state = state.read().await;
+                    let (Some(tenant_state), Some(cache)) =
+                        (state.tenants.get(&tenant), caches.get(&tenant))
+                    else {
+                        return Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::from(format!("unknown tenant {tenant}")))
+                            .unwrap();
+                    };
+                    let rendered = {
+                        let config = config.read().unwrap();
+                        cache.render_tenant(&config, tenant_state, state.latest_firmware, &query)
+                    };
+                    metrics_response(rendered, metrics::CONTENT_TYPE, accept_encoding.as_deref())
+                }
+            },
+        );
+
+    let health = warp::path!("health").map(|| "ok");
+
+    let ready_config = config.clone();
+    let ready = warp::path!("ready").map(move || {
+        let grace = ready_config.read().unwrap().health.disconnect_grace();
+        if connection.lock().unwrap().is_ready(grace) {
+            warp::reply::with_status("ready", StatusCode::OK)
+        } else {
+            warp::reply::with_status("mqtt not connected", StatusCode::SERVICE_UNAVAILABLE)
         }
-        ListenConfig::Unix { socket: path } => {
-            let listener = UnixListener::bind(path).unwrap();
-            let incoming = UnixListenerStream::new(listener);
-            warp::serve(metrics).run_incoming(incoming).await;
+    });
+
+    let cache = Arc::new(RenderCache::default());
+    let metrics = path_filter(&metrics_path)
+        .and(remote_allowed(allowed_networks))
+        .and(authorization(auth))
+        .and(state)
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::query::<MetricsQuery>())
+        .then(
+            move |state: SharedDeviceStates,
+                  accept: Option<String>,
+                  accept_encoding: Option<String>,
+                  query: MetricsQuery| {
+                let config = config.clone();
+                let stats = stats.clone();
+                let cache = cache.clone();
+                async move {
+                    let start = Instant::now();
+                    let (rendered, content_type) = {
+                        let state = state.read().await;
+                        let config = config.read().unwrap();
+                        if accept.as_deref().is_some_and(protobuf::accepts_protobuf) {
+                            let registry = collect_metrics(&config, &state, &stats, &query);
+                            let rendered = Rendered {
+                                chunks: vec![protobuf::encode(&registry).into()],
+                                series: registry.series(),
+                            };
+                            (rendered, protobuf::CONTENT_TYPE)
+                        } else {
+                            let rendered = cache.render(&config, &state, &stats, &query);
+                            (rendered, metrics::CONTENT_TYPE)
+                        }
+                    };
+                    if !query.is_filtered() {
+                        stats.scraped(ScrapeStats {
+                            duration: start.elapsed(),
+                            series: rendered.series,
+                            size: rendered.size(),
+                        });
+                    }
+
+                    metrics_response(rendered, content_type, accept_encoding.as_deref())
+                }
+            },
+        );
+
+    let health = health.or(ready).map(Reply::into_response).boxed();
+    let metrics = metrics.or(tenant_metrics).unify().map(Reply::into_response);
+    let admin = api
+        .or(sd)
+        .unify()
+        .or(dashboard.map(Reply::into_response))
+        .unify();
+
+    let mut servers = Vec::new();
+    if admin_listen.is_empty() {
+        let routes = health
+            .or(metrics)
+            .unify()
+            .or(admin)
+            .unify()
+            .recover(handle_rejection)
+            .boxed();
+        servers.extend(
+            listen
+                .into_iter()
+                .map(|listen| spawn(listen_on(listen, routes.clone()))),
+        );
+    } else {
+        let routes = health
+            .clone()
+            .or(metrics)
+            .unify()
+            .recover(handle_rejection)
+            .boxed();
+        let admin_routes = health.or(admin).unify().recover(handle_rejection).boxed();
+        servers.extend(
+            listen
+                .into_iter()
+                .map(|listen| spawn(listen_on(listen, routes.clone()))),
+        );
+        servers.extend(
+            admin_listen
+                .into_iter()
+                .map(|listen| spawn(listen_on(listen, admin_routes.clone()))),
+        );
+    }
+    for server in servers {
+        if let Err(e) = server.await {
+            error!("metrics server failed: {:#}", e);
         }
     }
 }
 
-async fn command(client: &AsyncClient, device: &Device, command: &str, body: &str) -> Result<()> {
-    client
-        .publish(
-            device.get_topic("cmnd", command),
-            QoS::AtMostOnce,
-            false,
-            body,
-        )
-        .await?;
-    Ok(())
+/// Match a full request path like `/metrics`
+fn path_filter(path: &str) -> BoxedFilter<()> {
+    path_prefix(path).and(warp::path::end()).boxed()
+}
+
+/// Match the start of a request path like `/metrics`
+fn path_prefix(path: &str) -> BoxedFilter<()> {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            filter.and(warp::path(segment.to_string())).boxed()
+        })
+}
+
+/// Limit the scraped metrics to specific devices
+#[derive(Debug, Default, Deserialize)]
+struct MetricsQuery {
+    /// Only include the device with this hostname or address
+    device: Option<String>,
+    /// Only include devices with a name matching the pattern
+    name: Option<Pattern>,
+}
+
+impl MetricsQuery {
+    fn is_filtered(&self) -> bool {
+        self.device.is_some() || self.name.is_some()
+    }
+
+    fn matches(&self, id: &str, name: &str) -> bool {
+        self.device.as_ref().map_or(true, |device| device == id)
+            && self
+                .name
+                .as_ref()
+                .map_or(true, |pattern| pattern.matches(name))
+    }
+}
+
+fn collect_metrics(
+    config: &Config,
+    state: &DeviceStates,
+    stats: &Stats,
+    query: &MetricsQuery,
+) -> Registry {
+    let mut registry = Registry::new(&config.metrics);
+    visit_devices(config, state, query, |_, _, format| format(&mut registry));
+    if !query.is_filtered() {
+        format_stats(&mut registry, stats, state);
+    }
+    registry
+}
+
+/// Identifies a device of any type
+type DeviceKey = (&'static str, Arc<str>);
+
+/// Call `f` for every device matching the query with the device key, the revision of the
+/// device state and a function that adds the metrics of the device
+fn visit_devices(
+    config: &Config,
+    state: &DeviceStates,
+    query: &MetricsQuery,
+    mut f: impl FnMut(DeviceKey, u64, &dyn Fn(&mut Registry)),
+) {
+    let latest_firmware = state.latest_firmware;
+    visit_tenant(config, state, None, latest_firmware, query, &mut f);
+    for (tenant, tenant_state) in &state.tenants {
+        visit_tenant(
+            config,
+            tenant_state,
+            Some(tenant),
+            latest_firmware,
+            query,
+            &mut f,
+        );
+    }
+}
+
+/// Visit the devices of a single tenant, adding a `tenant` label to the devices of configured tenants
+fn visit_tenant(
+    config: &Config,
+    state: &DeviceStates,
+    tenant: Option<&str>,
+    latest_firmware: Option<FirmwareVersion>,
+    query: &MetricsQuery,
+    f: &mut impl FnMut(DeviceKey, u64, &dyn Fn(&mut Registry)),
+) {
+    let names = &config.names;
+    let device_key = |ty: &'static str, id: Arc<str>| -> DeviceKey {
+        match tenant {
+            Some(tenant) => (ty, format!("{tenant}/{id}").into()),
+            None => (ty, id),
+        }
+    };
+    let device_labels = |identifiers: &[&str]| {
+        let mut labels = config.metrics.device_labels(identifiers);
+        if let Some(tenant) = tenant {
+            labels.insert("tenant".into(), tenant.into());
+        }
+        labels
+    };
+    for (device, device_state) in state.devices() {
+        let name = device_state.display_name(device, &names.tasmota);
+        if !query.matches(&device.hostname, name) {
+            continue;
+        }
+        let key = device_key("tasmota", device.hostname.clone());
+        f(key, device_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname, name]);
+            registry.with_device_labels(labels, |registry| {
+                format_device_state(registry, device, &names.tasmota, device_state);
+                if let Some(latest) = latest_firmware {
+                    format_update_available(registry, device, &names.tasmota, device_state, latest);
+                }
+                if let Some(minimum) = config.min_firmware {
+                    format_firmware_outdated(
+                        registry,
+                        device,
+                        &names.tasmota,
+                        device_state,
+                        minimum,
+                    );
+                }
+            });
+        });
+    }
+    for (device, dsmr_state) in state.dsmr_devices() {
+        if !query.matches(&device.hostname, &device.hostname) {
+            continue;
+        }
+        let key = device_key("dsmr", device.hostname.clone());
+        f(key, dsmr_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname]);
+            registry.with_device_labels(labels, |registry| {
+                format_dsmr_state(registry, device.hostname.as_ref(), dsmr_state);
+            });
+        });
+    }
+    for (device, otgw_state) in &state.otgw_devices {
+        if !query.matches(&device.hostname, &device.hostname) {
+            continue;
+        }
+        let key = device_key("otgw", device.hostname.clone());
+        f(key, otgw_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname]);
+            registry.with_device_labels(labels, |registry| {
+                format_otgw_state(registry, device.hostname.as_ref(), otgw_state);
+            });
+        });
+    }
+    let ebusd = config.ebusd.clone().unwrap_or_default();
+    for (device, ebusd_state) in &state.ebusd_devices {
+        if !query.matches(&device.hostname, &device.hostname) {
+            continue;
+        }
+        let key = device_key("ebusd", device.hostname.clone());
+        f(key, ebusd_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname]);
+            registry.with_device_labels(labels, |registry| {
+                format_ebusd_state(registry, &ebusd, &device.hostname, ebusd_state);
+            });
+        });
+    }
+    for (device, solar_state) in &state.solar_devices {
+        if !query.matches(&device.hostname, &device.hostname) {
+            continue;
+        }
+        let key = device_key("solar", device.hostname.clone());
+        f(key, solar_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname]);
+            registry.with_device_labels(labels, |registry| {
+                format_solar_state(registry, device.hostname.as_ref(), solar_state);
+            });
+        });
+    }
+    for (device, evse_state) in &state.evse_devices {
+        if !query.matches(&device.hostname, &device.hostname) {
+            continue;
+        }
+        let key = device_key("evse", device.hostname.clone());
+        f(key, evse_state.revision, &|registry| {
+            let labels = device_labels(&[&device.hostname]);
+            registry.with_device_labels(labels, |registry| {
+                format_evse_state(registry, device.hostname.as_ref(), evse_state);
+            });
+        });
+    }
+    for (addr, mi_temp_state) in state.mi_temp() {
+        let mac = addr.to_string();
+        let name = names
+            .mi_temp
+            .get(addr)
+            .map(String::as_str)
+            .unwrap_or_default();
+        if !query.matches(&mac, name) {
+            continue;
+        }
+        f(
+            device_key("mitemp", mac.as_str().into()),
+            mi_temp_state.revision,
+            &|registry| {
+                let labels = device_labels(&[&mac, name]);
+                registry.with_device_labels(labels, |registry| {
+                    format_mi_temp_state(registry, *addr, &names.mi_temp, mi_temp_state);
+                });
+            },
+        );
+    }
+    for (channel, rf_state) in state.rf_temp() {
+        let name = names
+            .rf_temp
+            .get(&channel.sensor())
+            .map(String::as_str)
+            .unwrap_or_default();
+        if !query.matches(name, name) {
+            continue;
+        }
+        f(
+            device_key("rf", channel.to_string().into()),
+            rf_state.revision,
+            &|registry| {
+                let labels = device_labels(&[name]);
+                registry.with_device_labels(labels, |registry| {
+                    format_rf_temp_state(registry, channel, &names.rf_temp, rf_state);
+                });
+            },
+        );
+    }
+    let now = Instant::now();
+    for (room, occupancy) in &config.occupancy {
+        if !query.matches(room, room) {
+            continue;
+        }
+        let Some(occupied) = room_occupied(occupancy, state, &names.tasmota, now) else {
+            continue;
+        };
+        // the output only depends on whether the room is occupied
+        f(
+            device_key("occupancy", room.as_str().into()),
+            occupied.into(),
+            &|registry| {
+                let labels = device_labels(&[room]);
+                registry.with_device_labels(labels, |registry| {
+                    registry.add(&metrics::ROOM_OCCUPIED, &[("room", room)], occupied);
+                });
+            },
+        );
+    }
 }
 
+async fn listen_on<T: Reply + Send + 'static>(listen: ListenConfig, routes: BoxedFilter<(T,)>) {
+    match listen {
+        ListenConfig::Ip {
+            address,
+            port,
+            tls: None,
+        } => {
+            warp::serve(routes).run((address, port)).await;
+        }
+        ListenConfig::Ip {
+            address,
+            port,
+            tls: Some(tls),
+        } => {
+            warp::serve(routes)
+                .tls()
+                .cert_path(tls.cert)
+                .key_path(tls.key)
+                .run((address, port))
+                .await;
+        }
+        ListenConfig::Unix { socket: path, mode } => {
+            // clean up the socket left behind by a previous run
+            if symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                remove_file(&path).unwrap();
+            }
+            let listener = UnixListener::bind(&path).unwrap();
+            if let Some(mode) = mode {
+                set_permissions(&path, Permissions::from_mode(mode)).unwrap();
+            }
+            let incoming = UnixListenerStream::new(listener);
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+    }
+}
+
+/// Maximum number of received messages waiting to be processed,
+/// no new messages are read from the broker while the queue is full
+const QUEUE_SIZE: usize = 1024;
+
+/// Process all messages from the stream until it ends or fails
 async fn mqtt_client<S: Stream<Item = Result<Publish>>>(
-    client: AsyncClient,
+    warm_up: mpsc::UnboundedSender<Device>,
+    stream: &mut Pin<&mut S>,
+    tenant: Tenant,
+    stats: Arc<Stats>,
+    config: Arc<RwLock<Config>>,
+    recorder: &mut Option<Recorder>,
+) -> Result<()> {
+    let (queue, messages) = mpsc::channel(QUEUE_SIZE);
+    let worker = spawn(process_messages(
+        messages,
+        warm_up,
+        tenant,
+        stats.clone(),
+        config,
+    ));
+    let result = receive_messages(stream, queue, &stats, recorder).await;
+    // finish processing the messages that were already received
+    let _ = worker.await;
+    result
+}
+
+async fn receive_messages<S: Stream<Item = Result<Publish>>>(
     stream: &mut Pin<&mut S>,
-    device_states: Arc<Mutex<DeviceStates>>,
+    queue: mpsc::Sender<Publish>,
+    stats: &Stats,
+    recorder: &mut Option<Recorder>,
 ) -> Result<()> {
-    while let Some(message) = stream.next().await {
+    let mut watchdog = systemd::watchdog_interval().map(interval);
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = tick(&mut watchdog) => {
+                systemd::notify_watchdog();
+                continue;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
         let message = message?;
-        println!(
-            "{} {}",
-            message.topic,
-            std::str::from_utf8(message.payload.as_ref()).unwrap_or_default()
-        );
-        let topic = Topic::from(message.topic.as_str());
-
-        match topic {
-            Topic::Lwt(device) => {
-                // on discovery, ask the device for it's power state and name
-                let send_client = client.clone();
-                spawn(async move {
-                    if let Err(e) = command(&send_client, &device, "POWER", "").await {
-                        eprintln!("Failed to ask for power state: {:#}", e);
-                    }
-                    if let Err(e) = command(&send_client, &device, "DeviceName", "").await {
-                        eprintln!("Failed to ask for device name: {:#}", e);
-                    }
-                    if let Err(e) = command(&send_client, &device, "Status", "2").await {
-                        eprintln!("Failed to ask for firmware state: {:#}", e);
-                    }
-                });
+        if let Some(recorder) = recorder {
+            if let Err(e) = recorder.record(&message) {
+                error!("Failed to record message: {:#}", e);
             }
-            Topic::Power(_) => {}
-            Topic::Result(device) | Topic::Sensor(device) | Topic::Status(device) => {
-                let payload = std::str::from_utf8(message.payload.as_ref()).unwrap_or_default();
-                if let Ok(json) = jzon::parse(payload) {
-                    let mut device_states = device_states.lock().unwrap();
-                    device_states.update(device, json);
+        }
+        stats.message_queued();
+        if queue.send(message).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn process_messages(
+    mut messages: mpsc::Receiver<Publish>,
+    warm_up: mpsc::UnboundedSender<Device>,
+    tenant: Tenant,
+    stats: Arc<Stats>,
+    config: Arc<RwLock<Config>>,
+) {
+    while let Some(message) = messages.recv().await {
+        stats.message_dequeued();
+        process_message(&message, &warm_up, &tenant, &stats, &config).await;
+    }
+}
+
+async fn process_message(
+    message: &Publish,
+    warm_up: &mpsc::UnboundedSender<Device>,
+    tenant: &Tenant,
+    stats: &Stats,
+    config: &RwLock<Config>,
+) {
+    let Some(raw) = tenant.strip_prefix(&message.topic) else {
+        return;
+    };
+    // wildcard subscriptions of the default namespace also match the topics of tenants
+    if tenant.name.is_none() && config.read().unwrap().is_tenant_topic(&message.topic) {
+        return;
+    }
+    let topic = Topic::from(raw);
+    // inverter bridges, p1 meters and chargers publish on arbitrary topics, which are mapped in the config
+    let mapped = match topic {
+        Topic::Other(raw) => config.read().unwrap().mapped_topic(raw),
+        _ => None,
+    };
+    let topic = mapped.as_ref().map_or(topic, MappedTopic::topic);
+    stats.message_received(topic.kind());
+    // shared brokers can carry a lot of unrelated traffic, skip it before doing any other work
+    if matches!(topic, Topic::Other(_) | Topic::Power(_)) {
+        trace!(topic = message.topic.as_str(), "ignoring mqtt message");
+        return;
+    }
+    let (limits, log) = {
+        let config = config.read().unwrap();
+        if !config.filter.allows(topic.hostname(), &message.topic) {
+            return;
+        }
+        (
+            config.limits,
+            config.log.logs(topic.hostname(), &message.topic),
+        )
+    };
+    if let Some(hostname) = topic.hostname() {
+        stats.device_message(tenant.name.as_deref(), hostname, topic.kind());
+    }
+    let span = info_span!(
+        "message",
+        device = topic.hostname(),
+        topic = message.topic.as_str(),
+        payload_size = message.payload.len(),
+    );
+    handle_message(message, topic, limits, log, warm_up, tenant, stats)
+        .instrument(span)
+        .await;
+}
+
+async fn handle_message(
+    message: &Publish,
+    topic: Topic<'_>,
+    limits: DeviceLimits,
+    log: bool,
+    warm_up: &mpsc::UnboundedSender<Device>,
+    tenant: &Tenant,
+    stats: &Stats,
+) {
+    let payload = std::str::from_utf8(message.payload.as_ref()).unwrap_or_default();
+    if log {
+        info!(payload, "received mqtt message");
+    } else {
+        debug!(payload, "received mqtt message");
+    }
+
+    match topic {
+        // on discovery, ask the device for its full state
+        Topic::Lwt(hostname) if payload != "Offline" => {
+            let _ = warm_up.send(Device::from(hostname));
+        }
+        Topic::Result(hostname)
+        | Topic::Sensor(hostname)
+        | Topic::State(hostname)
+        | Topic::Status(hostname) => {
+            let latency = match topic.kind() {
+                "result" | "status" => stats.response_received(hostname),
+                _ => None,
+            };
+            let result = async {
+                let payload = TasmotaPayload::parse(payload)?;
+                let mut device_states = tenant.write().await;
+                let result = device_states.update(hostname, payload);
+                if let Some(latency) = latency {
+                    device_states.set_command_latency(hostname, latency);
                 }
+                device_states.enforce_limits(&limits);
+                result
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("{:#}", e);
+                stats.parse_error("tasmota", &message.topic, payload, &e);
+            }
+        }
+        Topic::Msg(_device) => {
+            let mut device_states = tenant.write().await;
+            if let Err(e) = device_states.update_rf(payload) {
+                warn!("{:#}", e);
+                stats.parse_error("rf", &message.topic, payload, &e);
+            }
+            device_states.enforce_limits(&limits);
+        }
+        Topic::OmgBle(_gateway, mac) => {
+            let result = async {
+                let reading = OmgBle::parse(payload)?;
+                let mut device_states = tenant.write().await;
+                let result = device_states.update_omg_ble(mac, &reading);
+                device_states.enforce_limits(&limits);
+                result
+            }
+            .await;
+            if let Err(e) = result {
+                warn!("{:#}", e);
+                stats.parse_error("mitemp", &message.topic, payload, &e);
             }
-            Topic::Msg(_device) => {
-                let payload = std::str::from_utf8(message.payload.as_ref()).unwrap_or_default();
-                let mut device_states = device_states.lock().unwrap();
-                device_states.update_rf(payload);
-            }
-            Topic::Rtl(device, field) => {
-                let payload = std::str::from_utf8(message.payload.as_ref()).unwrap_or_default();
-                let mut device_states = device_states.lock().unwrap();
-                device_states.update_rtl(&device.hostname, &field, payload);
-            }
-            topic @ (Topic::Water(_)
-            | Topic::Gas(_)
-            | Topic::Energy1(_)
-            | Topic::Energy2(_)
-            | Topic::DsmrPower(_)) => {
-                let payload = std::str::from_utf8(message.payload.as_ref()).unwrap_or_default();
-                let mut device_states = device_states.lock().unwrap();
-                if let Some(ty) = topic.dsmr_type() {
-                    device_states.update_dsmr(topic.into_device(), ty, payload);
+        }
+        Topic::OmgRf(gateway) => match OmgRf::parse(payload) {
+            Ok(reading) => {
+                let mut device_states = tenant.write().await;
+                device_states.update_omg_rf(gateway, reading);
+                device_states.enforce_limits(&limits);
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                stats.parse_error("rf", &message.topic, payload, &e);
+            }
+        },
+        Topic::Rtl(receiver, device, field) => {
+            let mut device_states = tenant.write().await;
+            device_states.update_rtl(receiver.unwrap_or_default(), device, field, payload);
+            device_states.enforce_limits(&limits);
+        }
+        Topic::Otgw(hostname, field) => {
+            let mut device_states = tenant.write().await;
+            device_states.update_otgw(hostname, field, payload);
+            device_states.enforce_limits(&limits);
+        }
+        Topic::Ebusd(circuit, field) => {
+            let mut device_states = tenant.write().await;
+            device_states.update_ebusd(circuit, field, payload);
+            device_states.enforce_limits(&limits);
+        }
+        Topic::P1(name) => match P1::parse(payload) {
+            Ok(reading) => {
+                let mut device_states = tenant.write().await;
+                device_states.update_p1(name, &reading);
+                device_states.enforce_limits(&limits);
+            }
+            Err(e) => {
+                warn!("{:#}", e);
+                stats.parse_error("dsmr", &message.topic, payload, &e);
+            }
+        },
+        Topic::Solar(inverter, value) => {
+            let mut device_states = tenant.write().await;
+            device_states.update_solar(inverter, value, payload);
+            device_states.enforce_limits(&limits);
+        }
+        Topic::Evse(hostname, field) => {
+            let mut device_states = tenant.write().await;
+            device_states.update_evse(hostname, field, payload);
+            device_states.enforce_limits(&limits);
+        }
+        Topic::Water(hostname)
+        | Topic::Gas(hostname)
+        | Topic::Energy1(hostname)
+        | Topic::Energy2(hostname)
+        | Topic::DsmrPower(hostname) => {
+            let mut device_states = tenant.write().await;
+            if let Some(ty) = topic.dsmr_type() {
+                if let Err(e) = device_states.update_dsmr(hostname, ty, payload) {
+                    warn!("{:#}", e);
+                    stats.parse_error("dsmr", &message.topic, payload, &e);
                 }
+                device_states.enforce_limits(&limits);
+            }
+        }
+        Topic::Pulse(name, ty) => {
+            let mut device_states = tenant.write().await;
+            if let Err(e) = device_states.update_pulses(name, ty, payload) {
+                warn!("{:#}", e);
+                stats.parse_error("pulse", &message.topic, payload, &e);
             }
-            _ => {}
+            device_states.enforce_limits(&limits);
         }
+        _ => {}
     }
-    Ok(())
 }
 
-async fn cleanup(client: AsyncClient, state: Arc<Mutex<DeviceStates>>) {
-    loop {
-        let ping_time = Instant::now() - Duration::from_secs(10 * 60);
-        let cleanup_time = Instant::now() - Duration::from_secs(15 * 60);
+/// Wait for the next tick, or forever without an interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => pending().await,
+    }
+}
 
-        state
-            .lock()
-            .unwrap()
-            .retain(cleanup_time, ping_time, &client);
+async fn cleanup(
+    client: AsyncClient,
+    tenant: Tenant,
+    stats: Arc<Stats>,
+    config: Arc<RwLock<Config>>,
+) {
+    loop {
+        let (retention, timezone, anomaly, ignore, named_variables, bounds, spikes, pulse_meters) = {
+            let config = config.read().unwrap();
+            (
+                config.retention.clone(),
+                config.timezone.clone(),
+                config.anomaly,
+                config.ignore.clone(),
+                config.metrics.variables.keys().cloned().collect(),
+                config.bounds,
+                config.spikes,
+                config.pulse_meters(),
+            )
+        };
+        let ping = {
+            let mut state = tenant.write().await;
+            state.timezone = timezone;
+            state.anomaly = anomaly;
+            state.ignore = ignore;
+            state.named_variables = named_variables;
+            state.plausibility.bounds = bounds;
+            state.plausibility.spikes = spikes;
+            state.pulse_meters = pulse_meters;
+            state.roll_over();
+            state.retain(&retention)
+        };
+        for device in ping {
+            if let Err(e) =
+                command(&client, &stats, &tenant.prefix, &device, "DeviceName", "").await
+            {
+                error!("Failed to ping device: {:#}", e);
+            }
+        }
 
         sleep(Duration::from_secs(60)).await;
     }
 }
+
+/// Replay every recording in `fixtures/` and compare the metrics of the resulting devices with the
+/// `.prom` file next to it, run with `UPDATE_FIXTURES=1` to write the current output instead
+#[test]
+fn test_fixtures() {
+    use std::fs::{read_dir, read_to_string, write};
+
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+    let config: Config =
+        toml::from_str(&read_to_string(format!("{fixtures}/config.toml")).unwrap()).unwrap();
+    let config = Arc::new(RwLock::new(config));
+    let update = std::env::var_os("UPDATE_FIXTURES").is_some();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut recordings: Vec<_> = read_dir(fixtures)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "jsonl")
+        })
+        .collect();
+    recordings.sort();
+    assert!(!recordings.is_empty());
+
+    let mut failed = Vec::new();
+    for recording in recordings {
+        let states = SharedDeviceStates::new(configured_states(&config.read().unwrap()).into());
+        runtime
+            .block_on(async {
+                let stream = replay(recording.to_str().unwrap())?;
+                pin_mut!(stream);
+                let (warm_up, _) = mpsc::unbounded_channel();
+                let tenant = Tenant::root(states.clone());
+                let stats = Arc::default();
+                mqtt_client(
+                    warm_up,
+                    &mut stream,
+                    tenant,
+                    stats,
+                    config.clone(),
+                    &mut None,
+                )
+                .await
+            })
+            .unwrap();
+
+        // only the device metrics, the metrics about the exporter itself depend on timing
+        let config = config.read().unwrap();
+        let states = states.blocking_read();
+        let mut registry = Registry::new(&config.metrics);
+        visit_devices(
+            &config,
+            &states,
+            &MetricsQuery::default(),
+            |_, _, format| format(&mut registry),
+        );
+        let mut rendered = String::new();
+        registry.render(&mut rendered).unwrap();
+        // devices are kept in hash maps, sort the families and their samples to get a stable output
+        let mut families: Vec<_> = rendered
+            .split("# HELP ")
+            .skip(1)
+            .map(|family| {
+                let mut lines: Vec<_> = family.lines().collect();
+                lines[2..].sort();
+                lines
+            })
+            .collect();
+        families.sort();
+        let mut output = String::new();
+        for lines in families {
+            output.push_str("# HELP ");
+            for line in lines {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+
+        let expected_path = recording.with_extension("prom");
+        if update {
+            write(&expected_path, &output).unwrap();
+        } else if read_to_string(&expected_path).unwrap_or_default() != output {
+            eprintln!("{}:\n{output}", expected_path.display());
+            failed.push(expected_path);
+        }
+    }
+    assert!(failed.is_empty(), "output differs from {failed:?}");
+}
+
+#[test]
+fn test_metrics_response_vary() {
+    let rendered = || Rendered {
+        chunks: vec!["taspromto_uptime_seconds 1\n".into()],
+        series: 1,
+    };
+    for accept_encoding in [None, Some("gzip")] {
+        let response = metrics_response(rendered(), "text/plain", accept_encoding);
+        assert_eq!("accept-encoding", response.headers()[header::VARY]);
+    }
+}
+
+#[test]
+fn test_simulate_without_broker() {
+    let config: Config =
+        toml::from_str("listen = { port = 3030 }\n[names]\nmitemp = {}\nrftemp = {}").unwrap();
+    assert!(config.mqtt().is_err());
+    let states = SharedDeviceStates::new(configured_states(&config).into());
+    let simulation = Simulation {
+        plugs: 2,
+        sensors: 1,
+        rf: 1,
+        meters: 2,
+        interval: 1,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let stream = simulate(simulation);
+        pin_mut!(stream);
+        let (warm_up, _) = mpsc::unbounded_channel();
+        let mut recorder = None;
+        let client = mqtt_client(
+            warm_up,
+            &mut stream,
+            Tenant::root(states.clone()),
+            Arc::default(),
+            Arc::new(RwLock::new(config)),
+            &mut recorder,
+        );
+        // the simulation runs until stopped
+        assert!(tokio::time::timeout(Duration::from_millis(200), client)
+            .await
+            .is_err());
+    });
+    // every simulated device is tracked as the expected device class, and nothing else
+    let states = states.blocking_read();
+    assert_eq!(3, states.devices.len());
+    assert_eq!(1, states.rf_temp_devices.len());
+    let mut meters: Vec<_> = states
+        .dsmr_devices
+        .keys()
+        .map(|device| device.hostname.as_ref())
+        .collect();
+    meters.sort();
+    assert_eq!(vec!["dsmr", "dsmr_2"], meters);
+    assert!(states.otgw_devices.is_empty());
+    assert!(states.ebusd_devices.is_empty());
+    assert!(states.solar_devices.is_empty());
+    assert!(states.evse_devices.is_empty());
+    assert!(states.mi_temp_devices.is_empty());
+}
+
+#[test]
+fn test_replay_without_broker() {
+    let args = Args::try_parse_from(["taspromto", "--replay", "messages.jsonl"]).unwrap();
+    assert!(!args.needs_broker());
+    assert!(!Args::try_parse_from(["taspromto", "simulate"])
+        .unwrap()
+        .needs_broker());
+    assert!(Args::try_parse_from(["taspromto"]).unwrap().needs_broker());
+
+    let config: Config =
+        toml::from_str("listen = { port = 3030 }\n[names]\nmitemp = {}\nrftemp = {}").unwrap();
+    assert!(config.mqtt().is_err());
+    let states = SharedDeviceStates::new(configured_states(&config).into());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime
+        .block_on(async {
+            let stream = replay(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/tasmota.jsonl"
+            ))?;
+            pin_mut!(stream);
+            let (warm_up, _) = mpsc::unbounded_channel();
+            mqtt_client(
+                warm_up,
+                &mut stream,
+                Tenant::root(states.clone()),
+                Arc::default(),
+                Arc::new(RwLock::new(config)),
+                &mut None,
+            )
+            .await
+        })
+        .unwrap();
+    assert!(!states.blocking_read().devices.is_empty());
+}
@@ -0,0 +1,862 @@
+use crate::config::{AirQualityConfig, DerivedConfig, MetricsConfig};
+use crate::pattern::Pattern;
+use crate::payload::is_rule_variable;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter, Write};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+    Histogram,
+}
+
+impl Display for MetricType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MetricType::Gauge => f.write_str("gauge"),
+            MetricType::Counter => f.write_str("counter"),
+            MetricType::Histogram => f.write_str("histogram"),
+        }
+    }
+}
+
+/// Description of an exported metric
+#[derive(Debug, Eq, PartialEq)]
+pub struct MetricFamily {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub ty: MetricType,
+}
+
+const fn gauge(name: &'static str, help: &'static str) -> MetricFamily {
+    MetricFamily {
+        name,
+        help,
+        ty: MetricType::Gauge,
+    }
+}
+
+const fn counter(name: &'static str, help: &'static str) -> MetricFamily {
+    MetricFamily {
+        name,
+        help,
+        ty: MetricType::Counter,
+    }
+}
+
+const fn histogram(name: &'static str, help: &'static str) -> MetricFamily {
+    MetricFamily {
+        name,
+        help,
+        ty: MetricType::Histogram,
+    }
+}
+
+// tasmota
+pub static TASMOTA_ONLINE: MetricFamily = gauge("tasmota_online", "Whether the device is online");
+pub static COMMAND_LATENCY: MetricFamily = gauge(
+    "tasmota_command_latency_seconds",
+    "Time it took for the device to respond to the last name or status request",
+);
+pub static ROOM_OCCUPIED: MetricFamily = gauge(
+    "room_occupied",
+    "Whether any of the devices configured for the room showed activity within the timeout",
+);
+pub static POWER_ANOMALY_SCORE: MetricFamily = gauge(
+    "power_anomaly_score",
+    "Ratio between the recent average power usage and the baseline, in either direction",
+);
+pub static POWER_ANOMALY: MetricFamily = gauge(
+    "power_anomaly",
+    "Whether the recent power usage deviates from the baseline by more than the configured factor",
+);
+pub static CLOCK_DRIFT: MetricFamily = gauge(
+    "tasmota_clock_drift_seconds",
+    "Difference between the clock of the device and the exporter, positive when the device is ahead",
+);
+pub static SWITCH_STATE: MetricFamily = gauge("switch_state", "Whether the switch is turned on");
+pub static TASMOTA_VERSION: MetricFamily =
+    gauge("tasmota_version", "Firmware version running on the device");
+pub static TASMOTA_VARIABLE: MetricFamily = gauge(
+    "tasmota_variable",
+    "Value of a rule variable or a value published by a rule",
+);
+pub static TASMOTA_UPDATE_AVAILABLE: MetricFamily = gauge(
+    "tasmota_update_available",
+    "Whether a newer firmware version is available for the device",
+);
+pub static TASMOTA_FIRMWARE_OUTDATED: MetricFamily = gauge(
+    "tasmota_firmware_outdated",
+    "Whether the device runs a firmware older than the configured minimum version",
+);
+
+// power and gas
+pub static POWER_WATTS: MetricFamily = gauge("power_watts", "Current power usage in watts");
+pub static POWER_WATTS_MAX_TODAY: MetricFamily = gauge(
+    "power_watts_max_today",
+    "Highest power usage in watts since midnight",
+);
+pub static POWER_YESTERDAY_KWH: MetricFamily =
+    gauge("power_yesterday_kwh", "Energy used yesterday in kWh");
+pub static POWER_TODAY_KWH: MetricFamily = gauge("power_today_kwh", "Energy used today in kWh");
+pub static POWER_TODAY_KWH_LOCAL: MetricFamily = gauge(
+    "power_today_kwh_local",
+    "Energy used since midnight in the configured timezone in kWh",
+);
+pub static POWER_TOTAL_KWH: MetricFamily = gauge("power_total_kwh", "Total energy used in kWh");
+pub static POWER_TOTAL_HIGH_KWH: MetricFamily = gauge(
+    "power_total_high_kwh",
+    "Total energy used in the high tariff in kWh",
+);
+pub static POWER_TOTAL_LOW_KWH: MetricFamily = gauge(
+    "power_total_low_kwh",
+    "Total energy used in the low tariff in kWh",
+);
+pub static GAS_TOTAL_M3: MetricFamily = gauge("gas_total_m3", "Total gas used in m³");
+pub static WATER_TOTAL_M3: MetricFamily = gauge("water_total_m3", "Total water used in m³");
+pub static TOTAL_RESETS: MetricFamily = counter(
+    "tasmota_total_resets_total",
+    "Number of times a cumulative total was reset by the device",
+);
+pub static GAS_FLOW_M3_H: MetricFamily = gauge(
+    "gas_flow_m3_h",
+    "Gas usage over the last minute in m³ per hour",
+);
+pub static WATER_FLOW_L_MIN: MetricFamily = gauge(
+    "water_flow_l_min",
+    "Water usage over the last minute in liters per minute",
+);
+
+// heating
+pub static HEATING_ROOM_TEMPERATURE: MetricFamily = gauge(
+    "heating_room_temperature",
+    "Room temperature measured by the thermostat in degrees celsius",
+);
+pub static HEATING_ROOM_SETPOINT: MetricFamily = gauge(
+    "heating_room_setpoint",
+    "Room temperature set on the thermostat in degrees celsius",
+);
+pub static HEATING_CONTROL_SETPOINT: MetricFamily = gauge(
+    "heating_control_setpoint",
+    "Water temperature requested from the boiler in degrees celsius",
+);
+pub static HEATING_BOILER_TEMPERATURE: MetricFamily = gauge(
+    "heating_boiler_temperature",
+    "Temperature of the water leaving the boiler in degrees celsius",
+);
+pub static HEATING_RETURN_TEMPERATURE: MetricFamily = gauge(
+    "heating_return_temperature",
+    "Temperature of the water returning to the boiler in degrees celsius",
+);
+pub static HEATING_DHW_TEMPERATURE: MetricFamily = gauge(
+    "heating_dhw_temperature",
+    "Domestic hot water temperature in degrees celsius",
+);
+pub static HEATING_MODULATION: MetricFamily = gauge(
+    "heating_modulation_percent",
+    "Relative modulation level of the boiler in percent",
+);
+pub static HEATING_FLAME: MetricFamily = gauge("heating_flame", "Whether the boiler flame is on");
+pub static EBUSD_VALUE: MetricFamily = gauge("ebusd_value", "Numeric value published by ebusd");
+
+// ev chargers
+pub static EVSE_CHARGING_STATE: MetricFamily =
+    gauge("evse_charging_state", "Current state of the charger");
+pub static EVSE_CURRENT_AMPS: MetricFamily =
+    gauge("evse_current_amps", "Current drawn by the car in amps");
+pub static EVSE_PILOT_AMPS: MetricFamily = gauge(
+    "evse_pilot_amps",
+    "Current the car is allowed to draw in amps",
+);
+pub static EVSE_SESSION_KWH: MetricFamily = gauge(
+    "evse_session_kwh",
+    "Energy delivered in the current charging session in kWh",
+);
+pub static EVSE_TEMPERATURE: MetricFamily = gauge(
+    "evse_temperature",
+    "Temperature of the charger in degrees celsius",
+);
+
+// solar inverters
+pub static SOLAR_PV_POWER: MetricFamily = gauge(
+    "solar_pv_power_watts",
+    "Power generated by the solar panels in watts",
+);
+pub static SOLAR_BATTERY_VOLTAGE: MetricFamily =
+    gauge("solar_battery_voltage", "Voltage of the inverter battery");
+pub static SOLAR_BATTERY_STATE_OF_CHARGE: MetricFamily = gauge(
+    "solar_battery_state_of_charge_percent",
+    "Charge of the inverter battery in percent",
+);
+pub static SOLAR_BATTERY_POWER: MetricFamily = gauge(
+    "solar_battery_power_watts",
+    "Power flowing into the inverter battery in watts, negative while discharging",
+);
+pub static SOLAR_GRID_POWER: MetricFamily = gauge(
+    "solar_grid_power_watts",
+    "Power drawn from the grid by the inverter in watts, negative while feeding in",
+);
+pub static SOLAR_GRID_VOLTAGE: MetricFamily = gauge(
+    "solar_grid_voltage",
+    "Grid voltage measured by the inverter",
+);
+pub static SOLAR_GRID_FREQUENCY: MetricFamily = gauge(
+    "solar_grid_frequency_hz",
+    "Grid frequency measured by the inverter in hertz",
+);
+pub static SOLAR_LOAD_POWER: MetricFamily = gauge(
+    "solar_load_power_watts",
+    "Power used by the loads connected to the inverter in watts",
+);
+
+// sensors
+pub static SENSOR_CO2: MetricFamily = gauge("sensor_co2", "CO² concentration in ppm");
+pub static CO2_LEVEL: MetricFamily = gauge(
+    "co2_level",
+    "Whether the CO² concentration is in the band of the category",
+);
+pub static AIR_QUALITY_INDEX: MetricFamily = gauge(
+    "air_quality_index",
+    "Air quality index calculated from the PM2.5 concentration",
+);
+pub static SENSOR_BATTERY: MetricFamily = gauge("sensor_battery", "Battery level in percent");
+pub static SENSOR_BATTERY_OK: MetricFamily = gauge(
+    "sensor_battery_ok",
+    "Whether the sensor reports its battery as ok, for sensors without a battery level",
+);
+pub static SENSOR_BATTERY_LOW_SINCE: MetricFamily = gauge(
+    "sensor_battery_low_since_timestamp_seconds",
+    "Unix time the sensor first reported its battery as low, while the battery is low",
+);
+pub static SENSOR_TEMPERATURE: MetricFamily =
+    gauge("sensor_temperature", "Temperature in degrees celsius");
+pub static SENSOR_TEMPERATURE_MIN_TODAY: MetricFamily = gauge(
+    "sensor_temperature_min_today",
+    "Lowest temperature in degrees celsius since midnight",
+);
+pub static SENSOR_TEMPERATURE_MAX_TODAY: MetricFamily = gauge(
+    "sensor_temperature_max_today",
+    "Highest temperature in degrees celsius since midnight",
+);
+pub static SENSOR_HUMIDITY: MetricFamily = gauge("sensor_humidity", "Relative humidity in percent");
+pub static SENSOR_WIND_SPEED: MetricFamily =
+    gauge("sensor_wind_speed_m_s", "Average wind speed in m/s");
+pub static SENSOR_PRESSURE: MetricFamily = gauge("sensor_pressure_hpa", "Air pressure in hPa");
+pub static SENSOR_RAIN: MetricFamily = gauge(
+    "sensor_rain_mm",
+    "Total rainfall in mm, as counted by the sensor since its batteries were inserted",
+);
+pub static SENSOR_DEW_POINT: MetricFamily =
+    gauge("sensor_dew_point", "Dew point in degrees celsius");
+pub static SENSOR_ABSOLUTE_HUMIDITY: MetricFamily = gauge(
+    "sensor_absolute_humidity_g_m3",
+    "Absolute humidity in grams of water vapor per m³",
+);
+pub static SENSOR_HEAT_INDEX: MetricFamily = gauge(
+    "sensor_heat_index",
+    "Apparent temperature from temperature and humidity in degrees celsius",
+);
+pub static SENSOR_HUMIDEX: MetricFamily = gauge(
+    "sensor_humidex",
+    "Humidity index, the apparent temperature as used in Canada",
+);
+pub static SENSOR_WIND_CHILL: MetricFamily = gauge(
+    "sensor_wind_chill",
+    "Apparent temperature from temperature and wind speed in degrees celsius",
+);
+
+// PMS5003 particle sensor
+pub static PMS_CF1: MetricFamily =
+    gauge("cf1", "PM1.0 concentration in µg/m³ (factory environment)");
+pub static PMS_CF2_5: MetricFamily = gauge(
+    "cf2_5",
+    "PM2.5 concentration in µg/m³ (factory environment)",
+);
+pub static PMS_CF10: MetricFamily =
+    gauge("cf10", "PM10 concentration in µg/m³ (factory environment)");
+pub static PMS_PM1: MetricFamily = gauge("pm1", "PM1.0 concentration in µg/m³");
+pub static PMS_PM2_5: MetricFamily = gauge("pm2_5", "PM2.5 concentration in µg/m³");
+pub static PMS_PM10: MetricFamily = gauge("pm10", "PM10 concentration in µg/m³");
+pub static PMS_PB0_3: MetricFamily = gauge("pb0_3", "Particles larger than 0.3µm per 0.1L of air");
+pub static PMS_PB0_5: MetricFamily = gauge("pb0_5", "Particles larger than 0.5µm per 0.1L of air");
+pub static PMS_PB1: MetricFamily = gauge("pb1", "Particles larger than 1µm per 0.1L of air");
+pub static PMS_PB2_5: MetricFamily = gauge("pb2_5", "Particles larger than 2.5µm per 0.1L of air");
+pub static PMS_PB5: MetricFamily = gauge("pb5", "Particles larger than 5µm per 0.1L of air");
+pub static PMS_PB10: MetricFamily = gauge("pb10", "Particles larger than 10µm per 0.1L of air");
+
+// exporter
+pub static MQTT_MESSAGES: MetricFamily = counter(
+    "taspromto_mqtt_messages_total",
+    "Number of received mqtt messages",
+);
+pub static MQTT_QUEUE_DEPTH: MetricFamily = gauge(
+    "taspromto_mqtt_queue_depth",
+    "Number of received mqtt messages waiting to be processed",
+);
+pub static MQTT_BROKER: MetricFamily = gauge(
+    "taspromto_mqtt_broker",
+    "The mqtt broker currently in use, always 1",
+);
+pub static MQTT_RECONNECTS: MetricFamily = counter(
+    "taspromto_mqtt_reconnects_total",
+    "Number of times the mqtt connection was lost",
+);
+pub static PARSE_ERRORS: MetricFamily = counter(
+    "taspromto_parse_errors_total",
+    "Number of received payloads that couldn't be parsed",
+);
+pub static COMMANDS_SENT: MetricFamily = counter(
+    "taspromto_commands_sent_total",
+    "Number of commands sent to devices",
+);
+pub static SCRAPE_DURATION: MetricFamily = gauge(
+    "taspromto_scrape_duration_seconds",
+    "Time it took to build the previous metrics response",
+);
+pub static SCRAPE_SERIES: MetricFamily = gauge(
+    "taspromto_scrape_series",
+    "Number of series in the previous metrics response",
+);
+pub static SCRAPE_SIZE: MetricFamily = gauge(
+    "taspromto_scrape_size_bytes",
+    "Size of the previous metrics response before compression",
+);
+pub static TRACKED_DEVICES: MetricFamily = gauge(
+    "taspromto_tracked_devices",
+    "Number of devices currently tracked, by device class",
+);
+pub static DEVICES_DROPPED: MetricFamily = counter(
+    "taspromto_devices_dropped_total",
+    "Number of devices dropped because more devices than the configured limit were tracked",
+);
+pub static RF_FRAMES_REJECTED: MetricFamily = counter(
+    "taspromto_rf_frames_rejected_total",
+    "Number of rtl_433 frames dropped because they failed their integrity check",
+);
+pub static READINGS_REJECTED: MetricFamily = counter(
+    "taspromto_readings_rejected_total",
+    "Number of sensor readings discarded because they were outside the configured bounds",
+);
+pub static MESSAGE_INTERVAL: MetricFamily = histogram(
+    "taspromto_message_interval_seconds",
+    "Time between consecutive messages of the same type from a device",
+);
+pub static UPTIME: MetricFamily = gauge(
+    "taspromto_uptime_seconds",
+    "Time since the exporter was started",
+);
+pub static TOKIO_WORKERS: MetricFamily = gauge(
+    "taspromto_tokio_workers",
+    "Number of worker threads used by the runtime",
+);
+pub static TOKIO_ALIVE_TASKS: MetricFamily = gauge(
+    "taspromto_tokio_alive_tasks",
+    "Number of tasks currently alive in the runtime",
+);
+pub static TOKIO_GLOBAL_QUEUE_DEPTH: MetricFamily = gauge(
+    "taspromto_tokio_global_queue_depth",
+    "Number of tasks waiting in the global queue of the runtime",
+);
+
+// process
+pub static PROCESS_CPU: MetricFamily = counter(
+    "process_cpu_seconds_total",
+    "Total user and system CPU time spent in seconds",
+);
+pub static PROCESS_RESIDENT_MEMORY: MetricFamily = gauge(
+    "process_resident_memory_bytes",
+    "Resident memory size in bytes",
+);
+pub static PROCESS_VIRTUAL_MEMORY: MetricFamily = gauge(
+    "process_virtual_memory_bytes",
+    "Virtual memory size in bytes",
+);
+pub static PROCESS_THREADS: MetricFamily = gauge("process_threads", "Number of OS threads");
+pub static PROCESS_OPEN_FDS: MetricFamily =
+    gauge("process_open_fds", "Number of open file descriptors");
+
+/// Numeric value of a sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value(pub f64);
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        // go trough the string representation to prevent `21.1` from becoming `21.100000381469727`
+        Value(value.to_string().parse().unwrap_or(value as f64))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value(if value { 1.0 } else { 0.0 })
+    }
+}
+
+macro_rules! impl_value_from_int {
+    ($($ty:ty),*) => {
+        $(impl From<$ty> for Value {
+            fn from(value: $ty) -> Self {
+                Value(value as f64)
+            }
+        })*
+    };
+}
+
+impl_value_from_int!(u8, u16, u32, u64, usize, i32, i64);
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.0.is_nan() {
+            f.write_str("NaN")
+        } else if self.0.is_infinite() {
+            f.write_str(if self.0 > 0.0 { "+Inf" } else { "-Inf" })
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub labels: Vec<(Cow<'static, str>, String)>,
+    /// The number of observations for histograms
+    pub value: Value,
+    pub histogram: Option<Box<HistogramValue>>,
+}
+
+/// Observations counted in buckets by their upper bound
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations per bucket, the last bucket counts the observations above the highest bound
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn value(&self) -> HistogramValue {
+        let mut count = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&self.counts)
+            .map(|(bound, bucket_count)| {
+                count += bucket_count;
+                (*bound, count)
+            })
+            .collect();
+        HistogramValue {
+            buckets,
+            sum: self.sum,
+            count: self.counts.iter().sum(),
+        }
+    }
+}
+
+/// The state of a histogram when it was added to the registry
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramValue {
+    /// Upper bound of every bucket, with the cumulative count of observations, without the `+Inf` bucket
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+/// All samples collected for a single scrape, grouped by metric family
+#[derive(Debug, Default)]
+pub struct Registry {
+    families: Vec<(&'static MetricFamily, Vec<Sample>)>,
+    index: HashMap<&'static str, usize>,
+    /// Labels added to every sample
+    labels: Vec<(Cow<'static, str>, String)>,
+    /// Labels added to the samples of the device currently being formatted
+    device_labels: Vec<(Cow<'static, str>, String)>,
+    /// Metric families that are not exported
+    disabled: Vec<Pattern>,
+    /// Names to export metric families under instead of their default name
+    renames: HashMap<String, String>,
+    derived: DerivedConfig,
+    air_quality: Option<AirQualityConfig>,
+    /// Names for values published by rules
+    variables: HashMap<String, String>,
+}
+
+impl Registry {
+    pub fn new(config: &MetricsConfig) -> Self {
+        Registry {
+            labels: config
+                .labels
+                .iter()
+                .map(|(name, value)| (Cow::Owned(name.clone()), value.clone()))
+                .collect(),
+            disabled: config.disable.clone(),
+            renames: config.rename.clone(),
+            derived: config.derived,
+            air_quality: config.air_quality.clone(),
+            variables: config.variables.clone(),
+            ..Registry::default()
+        }
+    }
+
+    /// Which derived metrics should be calculated
+    pub fn derived(&self) -> &DerivedConfig {
+        &self.derived
+    }
+
+    /// The name to export a value published by a rule as, if it should be exported
+    pub fn variable_name<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        if let Some(name) = self.variables.get(key) {
+            return Some(name);
+        }
+        is_rule_variable(key).then_some(key)
+    }
+
+    /// Breakpoints for the air quality metrics, if enabled
+    pub fn air_quality(&self) -> Option<&AirQualityConfig> {
+        self.air_quality.as_ref()
+    }
+
+    pub fn add(
+        &mut self,
+        family: &'static MetricFamily,
+        labels: &[(&'static str, &str)],
+        value: impl Into<Value>,
+    ) {
+        self.push(family, labels, value.into(), None);
+    }
+
+    pub fn add_histogram(
+        &mut self,
+        family: &'static MetricFamily,
+        labels: &[(&'static str, &str)],
+        histogram: &Histogram,
+    ) {
+        let histogram = histogram.value();
+        let count = histogram.count.into();
+        self.push(family, labels, count, Some(Box::new(histogram)));
+    }
+
+    fn push(
+        &mut self,
+        family: &'static MetricFamily,
+        labels: &[(&'static str, &str)],
+        value: Value,
+        histogram: Option<Box<HistogramValue>>,
+    ) {
+        if self
+            .disabled
+            .iter()
+            .any(|pattern| pattern.matches(family.name))
+        {
+            return;
+        }
+        let index = *self.index.entry(family.name).or_insert_with(|| {
+            self.families.push((family, Vec::new()));
+            self.families.len() - 1
+        });
+        let mut sample_labels: Vec<_> = labels
+            .iter()
+            .map(|(name, value)| (Cow::Borrowed(*name), value.to_string()))
+            .collect();
+        for (name, value) in self.device_labels.iter().chain(&self.labels) {
+            if !sample_labels.iter().any(|(existing, _)| existing == name) {
+                sample_labels.push((name.clone(), value.clone()));
+            }
+        }
+        self.families[index].1.push(Sample {
+            labels: sample_labels,
+            value,
+            histogram,
+        });
+    }
+
+    /// Add extra labels to all samples added by `f`
+    pub fn with_device_labels(
+        &mut self,
+        labels: BTreeMap<String, String>,
+        f: impl FnOnce(&mut Registry),
+    ) {
+        self.device_labels = labels
+            .into_iter()
+            .map(|(name, value)| (Cow::Owned(name), value))
+            .collect();
+        f(self);
+        self.device_labels.clear();
+    }
+
+    pub fn families(&self) -> impl Iterator<Item = (&'static MetricFamily, &[Sample])> {
+        self.families
+            .iter()
+            .map(|(family, samples)| (*family, samples.as_slice()))
+    }
+
+    /// Total number of samples
+    pub fn series(&self) -> usize {
+        self.families.iter().map(|(_, samples)| samples.len()).sum()
+    }
+
+    /// The name a metric family is exported as
+    pub fn name<'a>(&'a self, family: &'a MetricFamily) -> &'a str {
+        self.renames
+            .get(family.name)
+            .map(String::as_str)
+            .unwrap_or(family.name)
+    }
+
+    /// Render the samples in the prometheus text exposition format
+    pub fn render<W: Write>(&self, mut writer: W) -> fmt::Result {
+        for (family, samples) in self.families() {
+            let name = self.name(family);
+            render_header(&mut writer, name, family)?;
+            for sample in samples {
+                render_sample(&mut writer, name, sample)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the samples of every family, without the `HELP` and `TYPE` lines
+    pub fn render_samples(&self) -> Vec<(&'static MetricFamily, String)> {
+        self.families()
+            .map(|(family, samples)| {
+                let name = self.name(family);
+                let mut out = String::new();
+                for sample in samples {
+                    render_sample(&mut out, name, sample).unwrap();
+                }
+                (family, out)
+            })
+            .collect()
+    }
+}
+
+pub fn render_header<W: Write>(mut writer: W, name: &str, family: &MetricFamily) -> fmt::Result {
+    writeln!(writer, "# HELP {} {}", name, family.help)?;
+    writeln!(writer, "# TYPE {} {}", name, family.ty)
+}
+
+fn render_sample<W: Write>(mut writer: W, name: &str, sample: &Sample) -> fmt::Result {
+    let Some(histogram) = &sample.histogram else {
+        writer.write_str(name)?;
+        render_labels(&mut writer, &sample.labels, None)?;
+        return writeln!(writer, " {}", sample.value);
+    };
+    let buckets = histogram
+        .buckets
+        .iter()
+        .copied()
+        .chain([(f64::INFINITY, histogram.count)]);
+    for (bound, count) in buckets {
+        write!(writer, "{name}_bucket")?;
+        let bound = Value(bound).to_string();
+        render_labels(&mut writer, &sample.labels, Some(("le", &bound)))?;
+        writeln!(writer, " {count}")?;
+    }
+    write!(writer, "{name}_sum")?;
+    render_labels(&mut writer, &sample.labels, None)?;
+    writeln!(writer, " {}", Value(histogram.sum))?;
+    write!(writer, "{name}_count")?;
+    render_labels(&mut writer, &sample.labels, None)?;
+    writeln!(writer, " {}", histogram.count)
+}
+
+fn render_labels<W: Write>(
+    mut writer: W,
+    labels: &[(Cow<'static, str>, String)],
+    extra: Option<(&str, &str)>,
+) -> fmt::Result {
+    let mut labels = labels
+        .iter()
+        .map(|(name, value)| (name.as_ref(), value.as_str()))
+        .chain(extra)
+        .peekable();
+    if labels.peek().is_none() {
+        return Ok(());
+    }
+    writer.write_char('{')?;
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            writer.write_str(", ")?;
+        }
+        write!(writer, "{}=\"{}\"", name, escape_label_value(value))?;
+    }
+    writer.write_char('}')
+}
+
+/// Escape a label value for the text exposition format
+fn escape_label_value(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '"', '\n']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[test]
+fn test_render() {
+    let mut registry = Registry::default();
+    registry.add(
+        &POWER_WATTS,
+        &[("tasmota_id", "plug"), ("name", "Plug")],
+        12.3f32,
+    );
+    registry.add(&MQTT_RECONNECTS, &[], 2u64);
+    registry.add(&POWER_WATTS, &[("name", "meter")], 1200.0f32);
+    registry.add(&POWER_WATTS, &[("name", "\"big\"\nC:\\")], 1.0f32);
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert_eq!(
+        r#"# HELP power_watts Current power usage in watts
+# TYPE power_watts gauge
+power_watts{tasmota_id="plug", name="Plug"} 12.3
+power_watts{name="meter"} 1200
+power_watts{name="\"big\"\nC:\\"} 1
+# HELP taspromto_mqtt_reconnects_total Number of times the mqtt connection was lost
+# TYPE taspromto_mqtt_reconnects_total counter
+taspromto_mqtt_reconnects_total 2
+"#,
+        out
+    );
+}
+
+#[test]
+fn test_extra_labels() {
+    let mut registry = Registry::new(&MetricsConfig {
+        labels: BTreeMap::from([
+            ("site".to_string(), "home".to_string()),
+            ("name".to_string(), "ignored".to_string()),
+        ]),
+        ..MetricsConfig::default()
+    });
+    registry.add(&POWER_WATTS, &[("name", "Plug")], 12.3f32);
+    registry.add(&MQTT_RECONNECTS, &[], 2u64);
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert!(out.contains(r#"power_watts{name="Plug", site="home"} 12.3"#));
+    assert!(out.contains(r#"taspromto_mqtt_reconnects_total{name="ignored", site="home"} 2"#));
+}
+
+#[test]
+fn test_device_labels() {
+    let mut registry = Registry::new(&MetricsConfig {
+        labels: BTreeMap::from([("site".to_string(), "home".to_string())]),
+        ..MetricsConfig::default()
+    });
+    let device_labels = BTreeMap::from([
+        ("room".to_string(), "kitchen".to_string()),
+        ("site".to_string(), "shed".to_string()),
+    ]);
+    registry.with_device_labels(device_labels, |registry| {
+        registry.add(&POWER_WATTS, &[("name", "Plug")], 12.3f32);
+    });
+    registry.add(&POWER_WATTS, &[("name", "Other")], 1.0f32);
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert!(out.contains(r#"power_watts{name="Plug", room="kitchen", site="shed"} 12.3"#));
+    assert!(out.contains(r#"power_watts{name="Other", site="home"} 1"#));
+}
+
+#[test]
+fn test_disabled_families() {
+    let mut registry = Registry::new(&MetricsConfig {
+        disable: vec![Pattern::new("pm*"), Pattern::new("tasmota_version")],
+        ..MetricsConfig::default()
+    });
+    registry.add(&PMS_PM2_5, &[], 1u8);
+    registry.add(&TASMOTA_VERSION, &[], 1u8);
+    registry.add(&POWER_WATTS, &[], 1u8);
+
+    let names: Vec<_> = registry.families().map(|(family, _)| family.name).collect();
+    assert_eq!(vec!["power_watts"], names);
+}
+
+#[test]
+fn test_rename() {
+    let mut registry = Registry::new(&MetricsConfig {
+        rename: HashMap::from([("power_watts".to_string(), "tasmota_power_watts".to_string())]),
+        ..MetricsConfig::default()
+    });
+    registry.add(&POWER_WATTS, &[], 1u8);
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert_eq!(
+        r#"# HELP tasmota_power_watts Current power usage in watts
+# TYPE tasmota_power_watts gauge
+tasmota_power_watts 1
+"#,
+        out
+    );
+}
+
+#[test]
+fn test_render_histogram() {
+    let mut histogram = Histogram::new(&[1.0, 10.0]);
+    for value in [0.5, 1.0, 4.0, 30.0] {
+        histogram.observe(value);
+    }
+    let mut registry = Registry::default();
+    registry.add_histogram(&MESSAGE_INTERVAL, &[("device", "plug")], &histogram);
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert_eq!(
+        r#"# HELP taspromto_message_interval_seconds Time between consecutive messages of the same type from a device
+# TYPE taspromto_message_interval_seconds histogram
+taspromto_message_interval_seconds_bucket{device="plug", le="1"} 2
+taspromto_message_interval_seconds_bucket{device="plug", le="10"} 3
+taspromto_message_interval_seconds_bucket{device="plug", le="+Inf"} 4
+taspromto_message_interval_seconds_sum{device="plug"} 35.5
+taspromto_message_interval_seconds_count{device="plug"} 4
+"#,
+        out
+    );
+}
+
+#[test]
+fn test_variable_name() {
+    let registry = Registry::new(&MetricsConfig {
+        variables: HashMap::from([("Level".to_string(), "water_level".to_string())]),
+        ..MetricsConfig::default()
+    });
+    assert_eq!(Some("Var1"), registry.variable_name("Var1"));
+    assert_eq!(Some("Mem16"), registry.variable_name("Mem16"));
+    assert_eq!(Some("water_level"), registry.variable_name("Level"));
+    assert_eq!(None, registry.variable_name("Variable"));
+    assert_eq!(None, registry.variable_name("UptimeSec"));
+}
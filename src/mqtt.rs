@@ -1,30 +1,89 @@
+use crate::config::Config;
+use crate::device::Device;
+use crate::stats::Stats;
+use crate::systemd;
 use async_stream::try_stream;
 use color_eyre::Result;
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS};
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS, SubscribeFilter};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time::{interval, MissedTickBehavior};
 use tokio_stream::{Stream, StreamExt};
+use tracing::error;
+
+/// Minimum time between requesting the state of two discovered devices
+const WARM_UP_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    connected: bool,
+    /// When we were last connected, `None` if we never managed to connect
+    last_connected: Option<Instant>,
+    /// Client for the current mqtt connection
+    client: Option<AsyncClient>,
+}
+
+impl ConnectionState {
+    pub fn set_connected(&mut self, connected: bool) {
+        if self.connected != connected {
+            self.connected = connected;
+            self.last_connected = Some(Instant::now());
+        }
+    }
+
+    /// Client for the current mqtt connection, if connected
+    pub fn client(&self) -> Option<AsyncClient> {
+        self.client.clone().filter(|_| self.connected)
+    }
+
+    /// Whether we're connected, or have only lost the connection less than `grace` ago
+    pub fn is_ready(&self, grace: Duration) -> bool {
+        self.connected
+            || self
+                .last_connected
+                .is_some_and(|last_connected| last_connected.elapsed() < grace)
+    }
+}
 
 pub async fn mqtt_stream(
     mqtt_options: MqttOptions,
+    connection: Arc<Mutex<ConnectionState>>,
+    subscriptions: Vec<String>,
+    prefix: &str,
 ) -> Result<(AsyncClient, impl Stream<Item = Result<Publish>>)> {
+    let (host, port) = mqtt_options.broker_address();
     let (client, event_loop) = AsyncClient::new(mqtt_options, 10);
-    client.subscribe("stat/+/+", QoS::AtMostOnce).await?;
-    client.subscribe("tele/+/+", QoS::AtMostOnce).await?;
-    client.subscribe("rflink/msg", QoS::AtMostOnce).await?;
-    client.subscribe("rtl_433/#", QoS::AtMostOnce).await?;
-    client.subscribe("+/water", QoS::AtMostOnce).await?;
-    client.subscribe("+/gas_delivered", QoS::AtMostOnce).await?;
-    client
-        .subscribe("+/energy_delivered_tariff1", QoS::AtMostOnce)
-        .await?;
-    client
-        .subscribe("+/energy_delivered_tariff2", QoS::AtMostOnce)
-        .await?;
-    client
-        .subscribe("+/power_delivered_l1", QoS::AtMostOnce)
-        .await?;
+    connection.lock().unwrap().client = Some(client.clone());
+    // a single request, the request queue isn't polled until the stream is
+    let topics = [
+        "stat/+/+",
+        "tele/+/+",
+        "rflink/msg",
+        "rtl_433/#",
+        "OTGW/value/#",
+        "+/water",
+        "+/gas_delivered",
+        "+/energy_delivered_tariff1",
+        "+/energy_delivered_tariff2",
+        "+/power_delivered_l1",
+        "+/+/BTtoMQTT/+",
+        "+/+/433toMQTT",
+        "+/+/RTL_433toMQTT",
+    ]
+    .into_iter()
+    .map(String::from)
+    .chain(subscriptions)
+    .map(|topic| SubscribeFilter::new(format!("{prefix}{topic}"), QoS::AtMostOnce));
+    client.subscribe_many(topics).await?;
 
-    let stream = event_loop_to_stream(event_loop).filter_map(|event| match event {
+    let stream = event_loop_to_stream(event_loop).filter_map(move |event| match event {
         Ok(Event::Incoming(Packet::Publish(message))) => Some(Ok(message)),
+        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+            connection.lock().unwrap().set_connected(true);
+            systemd::notify_ready(&format!("Connected to mqtt broker {host}:{port}"));
+            None
+        }
         Ok(_) => None,
         Err(e) => Some(Err(e)),
     });
@@ -32,6 +91,71 @@ pub async fn mqtt_stream(
     Ok((client, stream))
 }
 
+/// Send a command to a device, `prefix` is the topic prefix of the tenant the device belongs to
+pub async fn command(
+    client: &AsyncClient,
+    stats: &Stats,
+    prefix: &str,
+    device: &Device,
+    command: &str,
+    body: &str,
+) -> Result<()> {
+    client
+        .publish(
+            format!("{prefix}{}", device.get_topic("cmnd", command)),
+            QoS::AtMostOnce,
+            false,
+            body,
+        )
+        .await?;
+    stats.command_sent();
+    if matches!(command, "DeviceName" | "Status") {
+        stats.request_sent(device);
+    }
+    Ok(())
+}
+
+/// Ask newly discovered devices for their full state, spread out to not flood the broker after (re)connecting
+pub async fn warm_up_devices(
+    client: AsyncClient,
+    stats: Arc<Stats>,
+    config: Arc<RwLock<Config>>,
+    prefix: String,
+    mut devices: mpsc::UnboundedReceiver<Device>,
+) {
+    let mut interval = interval(WARM_UP_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    while let Some(device) = devices.recv().await {
+        interval.tick().await;
+        let tele_period = config.read().unwrap().discovery.tele_period;
+        for (command_name, body) in warm_up_commands(tele_period) {
+            if let Err(e) = command(&client, &stats, &prefix, &device, command_name, &body).await {
+                error!(
+                    "Failed to request {command_name} {body} from {}: {:#}",
+                    device.hostname, e
+                );
+            }
+        }
+    }
+}
+
+/// Commands sent to a newly discovered device
+///
+/// The sensor readings are requested with `Status 8`, so they are populated without waiting for the next telemetry period.
+fn warm_up_commands(tele_period: Option<u16>) -> Vec<(&'static str, String)> {
+    // power state, name, firmware, network, state and sensors, and the telemetry period if configured
+    let mut commands = vec![
+        ("POWER", String::new()),
+        ("DeviceName", String::new()),
+        ("Status", "0".into()),
+        ("Status", "8".into()),
+    ];
+    if let Some(tele_period) = tele_period {
+        commands.push(("TelePeriod", tele_period.to_string()));
+    }
+    commands
+}
+
 fn event_loop_to_stream(mut event_loop: EventLoop) -> impl Stream<Item = Result<Event>> {
     try_stream! {
         loop {
@@ -40,3 +164,14 @@ fn event_loop_to_stream(mut event_loop: EventLoop) -> impl Stream<Item = Result<
         }
     }
 }
+
+#[test]
+fn test_warm_up_commands() {
+    let commands = warm_up_commands(None);
+    assert!(commands.contains(&("Status", "8".into())));
+    assert!(!commands.iter().any(|(command, _)| *command == "TelePeriod"));
+    assert_eq!(
+        Some(&("TelePeriod", "60".into())),
+        warm_up_commands(Some(60)).last()
+    );
+}
@@ -0,0 +1,235 @@
+use crate::config::{Config, NamesConfig, NotifierConfig, WebhookConfig};
+use crate::device::{Device, DeviceStates, SharedDeviceStates};
+use color_eyre::{eyre::WrapErr, Result};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Level {
+    Warning,
+    Success,
+}
+
+/// A human readable message for the configured notifiers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    pub level: Level,
+}
+
+impl Notification {
+    fn device_event(event: &str, device: &str, name: &str) -> Self {
+        let (state, level) = match event {
+            "offline" => ("went offline", Level::Warning),
+            "removed" => ("was removed", Level::Warning),
+            _ => ("is back online", Level::Success),
+        };
+        let title = if name == device {
+            format!("{name} {state}")
+        } else {
+            format!("{name} ({device}) {state}")
+        };
+        Notification {
+            message: format!("{title}."),
+            title,
+            level,
+        }
+    }
+}
+
+/// Notify the configured webhook and notifiers when a device goes offline, is removed or comes back
+pub async fn send_device_notifications(
+    webhook: Option<WebhookConfig>,
+    notifiers: Vec<NotifierConfig>,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+) {
+    let Some(client) = http_client() else {
+        return;
+    };
+
+    let mut updates = device_states.read().await.subscribe();
+    // devices we've sent an offline notification for
+    let mut down = HashSet::new();
+    loop {
+        let update = match updates.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Skipped {} state updates for notifications", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let event = match update.field {
+            "online" if update.value == false => {
+                down.insert(update.device.clone());
+                "offline"
+            }
+            "removed" => {
+                down.insert(update.device.clone());
+                "removed"
+            }
+            _ if down.remove(&update.device) => "online",
+            _ => continue,
+        };
+
+        let name = {
+            let device_states = device_states.read().await;
+            device_name(
+                &device_states,
+                &config.read().unwrap().names,
+                &update.device,
+            )
+        };
+        if let Some(webhook) = &webhook {
+            let url = match event {
+                "online" => webhook.recovery_url.as_ref().unwrap_or(&webhook.url),
+                _ => &webhook.url,
+            };
+            let payload = json!({
+                "event": event,
+                "device": update.device.as_str(),
+                "name": name.as_str(),
+            });
+            if let Err(e) = post(&client, url, &payload).await {
+                error!("Failed to send webhook: {:#}", e);
+            }
+        }
+        let notification = Notification::device_event(event, &update.device, &name);
+        notify(&client, &notifiers, &notification).await;
+    }
+}
+
+fn device_name(device_states: &DeviceStates, names: &NamesConfig, id: &str) -> String {
+    let device = Device {
+        hostname: id.into(),
+    };
+    match device_states.devices.get(&device) {
+        Some(state) if !state.display_name(&device, &names.tasmota).is_empty() => {
+            state.display_name(&device, &names.tasmota).to_string()
+        }
+        _ => names.tasmota.get(id).cloned().unwrap_or_else(|| id.into()),
+    }
+}
+
+pub fn http_client() -> Option<reqwest::Client> {
+    match reqwest::Client::builder()
+        .user_agent(concat!("taspromto/", env!("CARGO_PKG_VERSION")))
+        .build()
+    {
+        Ok(client) => Some(client),
+        Err(e) => {
+            error!("Failed to setup http client for notifications: {:#}", e);
+            None
+        }
+    }
+}
+
+pub async fn post(client: &reqwest::Client, url: &str, payload: &Value) -> Result<()> {
+    client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(payload.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Send a notification to all notifiers, logging any failures
+pub async fn notify(
+    client: &reqwest::Client,
+    notifiers: &[NotifierConfig],
+    notification: &Notification,
+) {
+    for notifier in notifiers {
+        if let Err(e) = send(client, notifier, notification).await {
+            error!("Failed to send notification: {:#}", e);
+        }
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    notifier: &NotifierConfig,
+    notification: &Notification,
+) -> Result<()> {
+    match notifier {
+        NotifierConfig::Ntfy {
+            url,
+            token,
+            token_file,
+        } => {
+            let token = match (token, token_file) {
+                (Some(token), _) => Some(token.clone()),
+                (None, Some(token_file)) => {
+                    Some(secretfile::load(token_file).wrap_err("Failed to load ntfy token")?)
+                }
+                (None, None) => None,
+            };
+            let (priority, tags) = match notification.level {
+                Level::Warning => ("high", "warning"),
+                Level::Success => ("default", "white_check_mark"),
+            };
+            let mut request = client
+                .post(url)
+                .header("Title", &notification.title)
+                .header("Priority", priority)
+                .header("Tags", tags);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request
+                .body(notification.message.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        }
+        NotifierConfig::Apprise { url, urls, tag } => {
+            post(client, url, &apprise_payload(notification, urls, tag)).await
+        }
+    }
+}
+
+fn apprise_payload(
+    notification: &Notification,
+    urls: &Option<String>,
+    tag: &Option<String>,
+) -> Value {
+    let mut payload = json!({
+        "title": notification.title.as_str(),
+        "body": notification.message.as_str(),
+        "type": match notification.level {
+            Level::Warning => "warning",
+            Level::Success => "success",
+        },
+    });
+    if let Some(urls) = urls {
+        payload["urls"] = urls.as_str().into();
+    }
+    if let Some(tag) = tag {
+        payload["tag"] = tag.as_str().into();
+    }
+    payload
+}
+
+#[test]
+fn test_device_notification() {
+    let notification = Notification::device_event("offline", "tasmota_1A2B3C", "Freezer");
+    assert_eq!("Freezer (tasmota_1A2B3C) went offline", notification.title);
+    assert_eq!(Level::Warning, notification.level);
+
+    let notification = Notification::device_event("online", "tasmota_1A2B3C", "tasmota_1A2B3C");
+    assert_eq!("tasmota_1A2B3C is back online", notification.title);
+
+    let payload = apprise_payload(&notification, &None, &Some("phone".into()));
+    assert_eq!("success", payload["type"]);
+    assert_eq!("phone", payload["tag"]);
+    assert!(payload["urls"].is_null());
+}
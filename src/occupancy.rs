@@ -0,0 +1,105 @@
+use crate::config::OccupancyConfig;
+use crate::device::{DeviceState, DeviceStates};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Whether any of the devices configured for the room showed activity within the timeout
+///
+/// Returns `None` if none of the configured devices are tracked.
+pub fn room_occupied(
+    config: &OccupancyConfig,
+    states: &DeviceStates,
+    names: &HashMap<String, String>,
+    now: Instant,
+) -> Option<bool> {
+    let recent = |changed: Option<Instant>| {
+        changed.is_some_and(|changed| now.duration_since(changed) < config.timeout())
+    };
+    let switch = |reference: &str| {
+        let (device, switch) = reference.rsplit_once('/')?;
+        find_device(states, names, device)?.switches.get(switch)
+    };
+
+    let motion = config
+        .motion
+        .iter()
+        .filter_map(|reference| switch(reference));
+    let doors = config
+        .doors
+        .iter()
+        .filter_map(|reference| switch(reference));
+    let lights = config
+        .lights
+        .iter()
+        .filter_map(|reference| find_device(states, names, reference));
+
+    let active = motion
+        .map(|switch| switch.on || recent(switch.changed))
+        .chain(doors.map(|switch| recent(switch.changed)))
+        .chain(lights.map(|light| light.state == Some(true) || recent(light.state_changed)));
+    active.reduce(|occupied, active| occupied || active)
+}
+
+/// Find a tasmota device by hostname or name
+fn find_device<'a>(
+    states: &'a DeviceStates,
+    names: &HashMap<String, String>,
+    reference: &str,
+) -> Option<&'a DeviceState> {
+    states.devices.get(reference).or_else(|| {
+        states
+            .devices()
+            .find(|(device, state)| state.display_name(device, names) == reference)
+            .map(|(_, state)| state)
+    })
+}
+
+#[test]
+fn test_room_occupied() {
+    use crate::payload::TasmotaPayload;
+
+    fn update(states: &mut DeviceStates, hostname: &str, payload: &str) {
+        let payload = TasmotaPayload::parse(payload).unwrap();
+        states.update(hostname, payload).unwrap();
+    }
+
+    let mut states = DeviceStates::default();
+    update(&mut states, "tasmota_pir", r#"{"Switch1":"OFF"}"#);
+    update(&mut states, "tasmota_door", r#"{"Switch1":"OFF"}"#);
+    update(
+        &mut states,
+        "tasmota_lamp",
+        r#"{"DeviceName":"Living Lamp","POWER":"OFF"}"#,
+    );
+
+    let mut config = OccupancyConfig {
+        timeout: 60,
+        motion: vec!["tasmota_pir/Switch1".into()],
+        doors: vec!["tasmota_door/Switch1".into()],
+        lights: vec!["Living Lamp".into()],
+    };
+    let names = HashMap::new();
+    let now = Instant::now;
+    assert_eq!(Some(false), room_occupied(&config, &states, &names, now()));
+
+    // opening the door counts as activity until the timeout passed
+    update(
+        &mut states,
+        "tasmota_door",
+        r#"{"Switch1":{"Action":"ON"}}"#,
+    );
+    assert_eq!(Some(true), room_occupied(&config, &states, &names, now()));
+    config.timeout = 0;
+    assert_eq!(Some(false), room_occupied(&config, &states, &names, now()));
+
+    update(&mut states, "tasmota_pir", r#"{"Switch1":"ON"}"#);
+    assert_eq!(Some(true), room_occupied(&config, &states, &names, now()));
+    update(&mut states, "tasmota_pir", r#"{"Switch1":"OFF"}"#);
+    update(&mut states, "tasmota_lamp", r#"{"POWER":"ON"}"#);
+    assert_eq!(Some(true), room_occupied(&config, &states, &names, now()));
+
+    config.lights = vec!["Kitchen Lamp".into()];
+    config.motion.clear();
+    config.doors.clear();
+    assert_eq!(None, room_occupied(&config, &states, &names, now()));
+}
@@ -0,0 +1,119 @@
+use crate::device::Readings;
+use crate::metrics::{self, Registry};
+use std::time::Instant;
+
+/// Heating state reported by an OpenTherm gateway running the OTGW firmware
+///
+/// The gateway publishes every value on its own topic, `OTGW/value/<id>/<field>`.
+#[derive(Debug)]
+pub struct OtgwState {
+    pub room_temperature: Option<f32>,
+    pub room_setpoint: Option<f32>,
+    /// Water temperature the thermostat requests from the boiler
+    pub control_setpoint: Option<f32>,
+    /// Temperature of the water leaving the boiler
+    pub boiler_temperature: Option<f32>,
+    pub return_temperature: Option<f32>,
+    pub dhw_temperature: Option<f32>,
+    pub modulation: Option<f32>,
+    pub flame: Option<bool>,
+    pub last_seen: Instant,
+    pub revision: u64,
+}
+
+impl Default for OtgwState {
+    fn default() -> Self {
+        OtgwState {
+            room_temperature: None,
+            room_setpoint: None,
+            control_setpoint: None,
+            boiler_temperature: None,
+            return_temperature: None,
+            dhw_temperature: None,
+            modulation: None,
+            flame: None,
+            last_seen: Instant::now(),
+            revision: 0,
+        }
+    }
+}
+
+impl OtgwState {
+    /// Apply a published value, returns false for fields that aren't tracked
+    pub fn update(&mut self, field: &str, payload: &str) -> bool {
+        let value = match field {
+            "Tr" => &mut self.room_temperature,
+            "TrSet" => &mut self.room_setpoint,
+            "TSet" => &mut self.control_setpoint,
+            "Tboiler" => &mut self.boiler_temperature,
+            "Tret" => &mut self.return_temperature,
+            "Tdhw" => &mut self.dhw_temperature,
+            "RelModLevel" => &mut self.modulation,
+            "flame" => {
+                self.flame = match payload {
+                    "ON" | "1" => Some(true),
+                    "OFF" | "0" => Some(false),
+                    _ => None,
+                };
+                self.last_seen = Instant::now();
+                return true;
+            }
+            _ => return false,
+        };
+        *value = payload.trim().parse().ok();
+        self.last_seen = Instant::now();
+        true
+    }
+
+    pub fn readings(&self) -> Readings {
+        vec![
+            ("room_temperature", self.room_temperature.into()),
+            ("room_setpoint", self.room_setpoint.into()),
+            ("control_setpoint", self.control_setpoint.into()),
+            ("boiler_temperature", self.boiler_temperature.into()),
+            ("return_temperature", self.return_temperature.into()),
+            ("dhw_temperature", self.dhw_temperature.into()),
+            ("modulation", self.modulation.into()),
+            ("flame", self.flame.into()),
+        ]
+    }
+}
+
+pub fn format_otgw_state(registry: &mut Registry, device: &str, state: &OtgwState) {
+    let labels = [("name", device)];
+
+    for (family, value) in [
+        (&metrics::HEATING_ROOM_TEMPERATURE, state.room_temperature),
+        (&metrics::HEATING_ROOM_SETPOINT, state.room_setpoint),
+        (&metrics::HEATING_CONTROL_SETPOINT, state.control_setpoint),
+        (
+            &metrics::HEATING_BOILER_TEMPERATURE,
+            state.boiler_temperature,
+        ),
+        (
+            &metrics::HEATING_RETURN_TEMPERATURE,
+            state.return_temperature,
+        ),
+        (&metrics::HEATING_DHW_TEMPERATURE, state.dhw_temperature),
+        (&metrics::HEATING_MODULATION, state.modulation),
+    ] {
+        if let Some(value) = value {
+            registry.add(family, &labels, value);
+        }
+    }
+    if let Some(flame) = state.flame {
+        registry.add(&metrics::HEATING_FLAME, &labels, flame);
+    }
+}
+
+#[test]
+fn test_otgw_update() {
+    let mut state = OtgwState::default();
+    assert!(state.update("Tboiler", "45.50"));
+    assert!(state.update("flame", "ON"));
+    assert!(state.update("RelModLevel", "garbage"));
+    assert!(!state.update("MaxRelModLevel", "100"));
+    assert_eq!(Some(45.5), state.boiler_temperature);
+    assert_eq!(Some(true), state.flame);
+    assert_eq!(None, state.modulation);
+}
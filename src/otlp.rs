@@ -0,0 +1,173 @@
+use crate::config::{Config, OtlpConfig};
+use crate::device::SharedDeviceStates;
+use crate::metrics::{MetricType, Registry};
+use crate::stats::Stats;
+use crate::{collect_metrics, MetricsQuery};
+use color_eyre::Result;
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// OTLP aggregation temporality for counters that are never reset
+const AGGREGATION_TEMPORALITY_CUMULATIVE: u8 = 2;
+
+/// Periodically export all current readings to an OpenTelemetry collector using OTLP/HTTP
+pub async fn export_otlp(
+    otlp: OtlpConfig,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+) {
+    let client = match reqwest::Client::builder()
+        .user_agent(concat!("taspromto/", env!("CARGO_PKG_VERSION")))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to setup http client for otlp: {:#}", e);
+            return;
+        }
+    };
+
+    let start_time = unix_nanos();
+    let mut interval = interval(Duration::from_secs(otlp.interval));
+    loop {
+        interval.tick().await;
+        let registry = {
+            let device_states = device_states.read().await;
+            collect_metrics(
+                &config.read().unwrap(),
+                &device_states,
+                &stats,
+                &MetricsQuery::default(),
+            )
+        };
+        let body = export_request(&registry, start_time, unix_nanos());
+        if let Err(e) = send(&client, &otlp, body.to_string()).await {
+            error!("Failed to export otlp metrics: {:#}", e);
+        }
+    }
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+async fn send(client: &reqwest::Client, otlp: &OtlpConfig, body: String) -> Result<()> {
+    let mut request = client
+        .post(format!(
+            "{}/v1/metrics",
+            otlp.endpoint.trim_end_matches('/')
+        ))
+        .header("Content-Type", "application/json");
+    for (name, value) in &otlp.headers {
+        request = request.header(name, value);
+    }
+    request.body(body).send().await?.error_for_status()?;
+    Ok(())
+}
+
+/// Build an `ExportMetricsServiceRequest` in the OTLP json encoding
+fn export_request(registry: &Registry, start_time: u128, time: u128) -> Value {
+    let mut metrics = Vec::new();
+    for (family, samples) in registry.families() {
+        let mut data_points = Vec::new();
+        for sample in samples {
+            let mut attributes = Vec::new();
+            for (name, value) in &sample.labels {
+                attributes.push(json!({
+                    "key": name.as_ref(),
+                    "value": { "stringValue": value.as_str() },
+                }));
+            }
+            let mut point = json!({
+                "attributes": attributes,
+                "timeUnixNano": time.to_string(),
+            });
+            if let Some(histogram) = &sample.histogram {
+                // otlp counts the observations per bucket instead of cumulative
+                let mut previous = 0;
+                let mut bucket_counts = Vec::new();
+                let cumulative = histogram.buckets.iter().map(|(_, count)| *count);
+                for count in cumulative.chain([histogram.count]) {
+                    bucket_counts.push((count - previous).to_string());
+                    previous = count;
+                }
+                let bounds: Vec<f64> = histogram.buckets.iter().map(|(bound, _)| *bound).collect();
+                point["count"] = histogram.count.to_string().into();
+                point["sum"] = histogram.sum.into();
+                point["bucketCounts"] = bucket_counts.into();
+                point["explicitBounds"] = bounds.into();
+            } else {
+                point["asDouble"] = sample.value.0.into();
+            }
+            if family.ty != MetricType::Gauge {
+                point["startTimeUnixNano"] = start_time.to_string().into();
+            }
+            data_points.push(point);
+        }
+
+        let mut metric = json!({
+            "name": registry.name(family),
+            "description": family.help,
+        });
+        match family.ty {
+            MetricType::Gauge => {
+                metric["gauge"] = json!({ "dataPoints": data_points });
+            }
+            MetricType::Counter => {
+                metric["sum"] = json!({
+                    "dataPoints": data_points,
+                    "aggregationTemporality": AGGREGATION_TEMPORALITY_CUMULATIVE,
+                    "isMonotonic": true,
+                });
+            }
+            MetricType::Histogram => {
+                metric["histogram"] = json!({
+                    "dataPoints": data_points,
+                    "aggregationTemporality": AGGREGATION_TEMPORALITY_CUMULATIVE,
+                });
+            }
+        }
+        metrics.push(metric);
+    }
+
+    let mut scope_metrics = json!({
+        "scope": { "name": "taspromto", "version": env!("CARGO_PKG_VERSION") },
+    });
+    scope_metrics["metrics"] = metrics.into();
+    let mut resource_metrics = json!({
+        "resource": {
+            "attributes": [{ "key": "service.name", "value": { "stringValue": "taspromto" } }],
+        },
+    });
+    resource_metrics["scopeMetrics"] = json!([scope_metrics]);
+    json!({ "resourceMetrics": [resource_metrics] })
+}
+
+#[test]
+fn test_export_request() {
+    use crate::metrics;
+
+    let mut registry = Registry::default();
+    registry.add(&metrics::POWER_WATTS, &[("name", "Plug")], 12.5f32);
+    registry.add(&metrics::MQTT_RECONNECTS, &[], 2u64);
+
+    let request = export_request(&registry, 1, 2);
+    let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+    assert_eq!("power_watts", metrics[0]["name"]);
+    let point = &metrics[0]["gauge"]["dataPoints"][0];
+    assert_eq!(12.5, point["asDouble"]);
+    assert_eq!("2", point["timeUnixNano"]);
+    assert_eq!("name", point["attributes"][0]["key"]);
+    assert_eq!("Plug", point["attributes"][0]["value"]["stringValue"]);
+
+    assert_eq!("taspromto_mqtt_reconnects_total", metrics[1]["name"]);
+    assert_eq!(true, metrics[1]["sum"]["isMonotonic"]);
+    assert_eq!("1", metrics[1]["sum"]["dataPoints"][0]["startTimeUnixNano"]);
+}
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Deserializer};
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+
+/// Simple glob pattern where `*` matches any number of characters and `?` matches a single character
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Pattern {
+    pattern: String,
+}
+
+impl Pattern {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Pattern {
+            pattern: pattern.into(),
+        }
+    }
+
+    pub fn matches(&self, input: &str) -> bool {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let input: Vec<char> = input.chars().collect();
+
+        let (mut p, mut i) = (0, 0);
+        let mut backtrack = None;
+        while i < input.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    backtrack = Some((p, i));
+                    p += 1;
+                }
+                Some('?') => {
+                    p += 1;
+                    i += 1;
+                }
+                Some(c) if *c == input[i] => {
+                    p += 1;
+                    i += 1;
+                }
+                _ => match backtrack {
+                    Some((star_p, star_i)) => {
+                        p = star_p + 1;
+                        i = star_i + 1;
+                        backtrack = Some((star_p, star_i + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.pattern.fmt(f)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let str = <Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(Pattern::new(str))
+    }
+}
+
+#[test]
+fn test_pattern_matches() {
+    assert!(Pattern::new("tasmota_*").matches("tasmota_1A2B3C"));
+    assert!(Pattern::new("*").matches(""));
+    assert!(Pattern::new("tele/*/LWT").matches("tele/plug/LWT"));
+    assert!(Pattern::new("plug_?").matches("plug_1"));
+    assert!(Pattern::new("*_plug_*").matches("kitchen_plug_2"));
+    assert!(!Pattern::new("plug_?").matches("plug_12"));
+    assert!(!Pattern::new("tasmota_*").matches("shelly_1"));
+    assert!(!Pattern::new("plug").matches("plug_1"));
+}
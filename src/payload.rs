@@ -0,0 +1,541 @@
+use crate::device::DsmrMessageType;
+use color_eyre::{eyre::WrapErr, Result};
+use jiff::civil::DateTime;
+use jiff::tz::TimeZone;
+use jiff::Timestamp;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// The fields we use from `STATE`, `SENSOR`, `RESULT` and `STATUS` payloads
+///
+/// Values of an unexpected type are ignored instead of rejecting the whole payload,
+/// since devices with multiple channels report some fields as arrays.
+#[derive(Debug, Default, Deserialize)]
+pub struct TasmotaPayload {
+    #[serde(rename = "DeviceName", default, deserialize_with = "string")]
+    pub device_name: Option<String>,
+    /// Local time of the device, with the timezone offset if `SetOption52` is enabled
+    #[serde(rename = "Time", default, deserialize_with = "string")]
+    pub time: Option<String>,
+    #[serde(rename = "POWER", default, deserialize_with = "string")]
+    pub power: Option<String>,
+    #[serde(rename = "ENERGY", default, deserialize_with = "lenient")]
+    pub energy: Option<Energy>,
+    #[serde(rename = "MHZ19B", default, deserialize_with = "lenient")]
+    pub mhz19b: Option<Mhz19b>,
+    #[serde(rename = "OBIS", default, deserialize_with = "lenient")]
+    pub obis: Option<Obis>,
+    #[serde(rename = "PMS5003", default, deserialize_with = "lenient")]
+    pub pms5003: Option<Pms5003>,
+    /// Pulse counters, by counter name such as `C1`
+    #[serde(rename = "COUNTER", default, deserialize_with = "lenient")]
+    pub counter: Option<HashMap<String, f64>>,
+    #[serde(rename = "StatusFWR", default, deserialize_with = "lenient")]
+    pub firmware: Option<StatusFirmware>,
+    #[serde(rename = "StatusNET", default, deserialize_with = "lenient")]
+    pub network: Option<StatusNetwork>,
+    /// `Status 0` nests the state
+    #[serde(rename = "StatusSTS", default, deserialize_with = "lenient")]
+    pub status_state: Option<Box<TasmotaPayload>>,
+    /// `Status 0` and `Status 8` nest the sensor readings
+    #[serde(rename = "StatusSNS", default, deserialize_with = "lenient")]
+    pub status_sensors: Option<Box<TasmotaPayload>>,
+    /// Sensors with a name per device, such as `MJ_HT_V1-<mac>`
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+    /// The payload as received
+    #[serde(skip)]
+    pub raw: String,
+}
+
+impl TasmotaPayload {
+    pub fn parse(payload: &str) -> Result<Self> {
+        let mut parsed: TasmotaPayload = serde_json::from_str(payload)
+            .wrap_err_with(|| format!("invalid json payload: {payload}"))?;
+        parsed.raw = payload.into();
+        Ok(parsed)
+    }
+
+    /// The pulse count of every counter, by counter name such as `C1`
+    pub fn counters(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.counter
+            .iter()
+            .flatten()
+            .map(|(counter, pulses)| (counter.as_str(), *pulses))
+    }
+
+    /// The payloads nested in a status response
+    pub fn nested(&self) -> impl Iterator<Item = &TasmotaPayload> {
+        self.status_state
+            .as_deref()
+            .into_iter()
+            .chain(self.status_sensors.as_deref())
+    }
+
+    /// Seconds the clock of the device is ahead of `now`, times without offset are in the configured timezone
+    pub fn clock_drift(&self, timezone: &TimeZone, now: Timestamp) -> Option<f64> {
+        let Some(time) = self.time.as_deref() else {
+            return self
+                .nested()
+                .find_map(|nested| nested.clock_drift(timezone, now));
+        };
+        let device_time = match time.parse::<Timestamp>() {
+            Ok(time) => time,
+            Err(_) => time
+                .parse::<DateTime>()
+                .ok()?
+                .to_zoned(timezone.clone())
+                .ok()?
+                .timestamp(),
+        };
+        Some(device_time.duration_since(now).as_secs_f64())
+    }
+
+    /// All readings from Mi temperature sensors, by the mac suffix from the sensor name
+    pub fn mi_temp(&self) -> impl Iterator<Item = (&str, MiTemp)> {
+        self.other.iter().filter_map(|(key, value)| {
+            let addr = key.strip_prefix("MJ_HT_V1")?.trim_start_matches('-');
+            Some((addr, MiTemp::deserialize(value).unwrap_or_default()))
+        })
+    }
+
+    /// Numeric rule variables (`Var1`, `Mem1`) and values published by rules under one of the `named` keys
+    ///
+    /// Rule variables are reported as strings, so numeric strings are included.
+    pub fn variables<'a>(
+        &'a self,
+        named: &'a HashSet<String>,
+    ) -> impl Iterator<Item = (&'a str, f32)> + 'a {
+        self.other.iter().filter_map(|(key, value)| {
+            if !is_rule_variable(key) && !named.contains(key) {
+                return None;
+            }
+            let value = match value {
+                Value::Number(number) => number.as_f64()?,
+                Value::String(value) => value.trim().parse().ok()?,
+                _ => return None,
+            };
+            Some((key.as_str(), value as f32))
+        })
+    }
+
+    /// States of switch inputs, such as `Switch1`, reported either as `ON`/`OFF` or as an `Action` object
+    pub fn switches(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.other.iter().filter_map(|(key, value)| {
+            let index = key.strip_prefix("Switch")?;
+            if index.is_empty() || !index.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let state = match value {
+                Value::String(state) => state.as_str(),
+                Value::Object(object) => object.get("Action")?.as_str()?,
+                _ => return None,
+            };
+            match state {
+                "ON" => Some((key.as_str(), true)),
+                "OFF" => Some((key.as_str(), false)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Readings from temperature and humidity sensors connected to the device, such as `AM2301` or `DS18B20`, by sensor name
+    pub fn climate(&self) -> impl Iterator<Item = (&str, Climate)> {
+        self.other.iter().filter_map(|(key, value)| {
+            if key.starts_with("MJ_HT_V1") {
+                return None;
+            }
+            let climate = Climate::deserialize(value).ok()?;
+            (climate.temperature.is_some() || climate.humidity.is_some())
+                .then_some((key.as_str(), climate))
+        })
+    }
+}
+
+/// Whether the key is a rule variable, `Var<n>` or `Mem<n>`
+pub fn is_rule_variable(key: &str) -> bool {
+    ["Var", "Mem"].iter().any(|prefix| {
+        key.strip_prefix(prefix)
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|c| c.is_ascii_digit()))
+    })
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Energy {
+    #[serde(default, deserialize_with = "number")]
+    pub power: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub yesterday: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub today: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub total: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Mhz19b {
+    #[serde(rename = "CarbonDioxide", default, deserialize_with = "number")]
+    pub co2: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Obis {
+    #[serde(rename = "Power", default, deserialize_with = "number")]
+    pub power: Option<f32>,
+    #[serde(rename = "Total", default, deserialize_with = "number")]
+    pub total: Option<f32>,
+    #[serde(rename = "Total_high", default, deserialize_with = "number")]
+    pub total_high: Option<f32>,
+    #[serde(rename = "Total_low", default, deserialize_with = "number")]
+    pub total_low: Option<f32>,
+    #[serde(rename = "Gas_total", default, deserialize_with = "number")]
+    pub gas_total: Option<f32>,
+}
+
+//"PMS5003":{"CF1":6,"CF2.5":8,"CF10":8,"PM1":6,"PM2.5":8,"PM10":8,"PB0.3":0,"PB0.5":0,"PB1":0,"PB2.5":0,"PB5":0,"PB10":0}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Pms5003 {
+    #[serde(rename = "CF1", default, deserialize_with = "count")]
+    pub cf1: Option<u16>,
+    #[serde(rename = "CF2.5", default, deserialize_with = "count")]
+    pub cf2_5: Option<u16>,
+    #[serde(rename = "CF10", default, deserialize_with = "count")]
+    pub cf10: Option<u16>,
+    #[serde(rename = "PM1", default, deserialize_with = "count")]
+    pub pm1: Option<u16>,
+    #[serde(rename = "PM2.5", default, deserialize_with = "count")]
+    pub pm2_5: Option<u16>,
+    #[serde(rename = "PM10", default, deserialize_with = "count")]
+    pub pm10: Option<u16>,
+    #[serde(rename = "PB0.3", default, deserialize_with = "count")]
+    pub pb0_3: Option<u16>,
+    #[serde(rename = "PB0.5", default, deserialize_with = "count")]
+    pub pb0_5: Option<u16>,
+    #[serde(rename = "PB1", default, deserialize_with = "count")]
+    pub pb1: Option<u16>,
+    #[serde(rename = "PB2.5", default, deserialize_with = "count")]
+    pub pb2_5: Option<u16>,
+    #[serde(rename = "PB5", default, deserialize_with = "count")]
+    pub pb5: Option<u16>,
+    #[serde(rename = "PB10", default, deserialize_with = "count")]
+    pub pb10: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusFirmware {
+    #[serde(rename = "Version", default, deserialize_with = "string")]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusNetwork {
+    #[serde(rename = "IPAddress", default, deserialize_with = "string")]
+    pub ip_address: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MiTemp {
+    #[serde(default, deserialize_with = "number")]
+    pub temperature: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub humidity: Option<f32>,
+    #[serde(default, deserialize_with = "count")]
+    pub battery: Option<u8>,
+    #[serde(default, deserialize_with = "number")]
+    pub dew_point: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Climate {
+    #[serde(default, deserialize_with = "number")]
+    pub temperature: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub humidity: Option<f32>,
+}
+
+/// Bluetooth sensor reading published by OpenMQTTGateway on `<base>/<gateway>/BTtoMQTT/<mac>`
+#[derive(Debug, Default, Deserialize)]
+pub struct OmgBle {
+    #[serde(default, deserialize_with = "number")]
+    pub tempc: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub hum: Option<f32>,
+    #[serde(default, deserialize_with = "count")]
+    pub batt: Option<u8>,
+}
+
+impl OmgBle {
+    pub fn parse(payload: &str) -> Result<Self> {
+        serde_json::from_str(payload)
+            .wrap_err_with(|| format!("invalid OpenMQTTGateway payload: {payload}"))
+    }
+
+    pub fn mi_temp(&self) -> MiTemp {
+        MiTemp {
+            temperature: self.tempc,
+            humidity: self.hum,
+            battery: self.batt,
+            dew_point: None,
+        }
+    }
+}
+
+/// 433Mhz sensor reading decoded by rtl_433 in OpenMQTTGateway, published on `<base>/<gateway>/RTL_433toMQTT`
+#[derive(Debug, Default, Deserialize)]
+pub struct OmgRf {
+    /// Not set for signals from remotes and switches
+    #[serde(default)]
+    pub model: String,
+    #[serde(default, deserialize_with = "count")]
+    pub id: Option<u16>,
+    #[serde(default, deserialize_with = "count")]
+    pub channel: Option<u8>,
+    #[serde(rename = "temperature_C", default, deserialize_with = "number")]
+    pub temperature: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub humidity: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub wind_avg_km_h: Option<f32>,
+    #[serde(default, deserialize_with = "count")]
+    pub battery_ok: Option<u8>,
+    /// Integrity check of the frame
+    #[serde(default, deserialize_with = "string")]
+    pub mic: Option<String>,
+}
+
+impl OmgRf {
+    pub fn parse(payload: &str) -> Result<Self> {
+        serde_json::from_str(payload)
+            .wrap_err_with(|| format!("invalid OpenMQTTGateway payload: {payload}"))
+    }
+}
+
+/// Meter readings from a HomeWizard P1 meter, published as a single json payload
+#[derive(Debug, Default, Deserialize)]
+pub struct P1 {
+    #[serde(default, deserialize_with = "number")]
+    pub active_power_w: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub total_power_import_kwh: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub total_power_import_t1_kwh: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub total_power_import_t2_kwh: Option<f32>,
+    #[serde(default, deserialize_with = "number")]
+    pub total_gas_m3: Option<f32>,
+    /// Set by the HomeWizard water meter
+    #[serde(default, deserialize_with = "number")]
+    pub total_liter_m3: Option<f32>,
+}
+
+impl P1 {
+    pub fn parse(payload: &str) -> Result<Self> {
+        serde_json::from_str(payload).wrap_err_with(|| format!("invalid p1 payload: {payload}"))
+    }
+
+    /// The readings in the units used for dsmr messages
+    pub fn values(&self) -> impl Iterator<Item = (DsmrMessageType, f32)> {
+        // single tariff meters only report the total
+        let tariff_1 = self.total_power_import_t1_kwh.or(self
+            .total_power_import_kwh
+            .filter(|_| self.total_power_import_t2_kwh.is_none()));
+        [
+            (
+                DsmrMessageType::Power,
+                self.active_power_w.map(|power| power / 1000.0),
+            ),
+            (DsmrMessageType::Energy1, tariff_1),
+            (DsmrMessageType::Energy2, self.total_power_import_t2_kwh),
+            (DsmrMessageType::Gas, self.total_gas_m3),
+            (DsmrMessageType::Water, self.total_liter_m3),
+        ]
+        .into_iter()
+        .filter_map(|(ty, value)| Some((ty, value?)))
+    }
+}
+
+/// Ignore values that don't match the expected structure instead of rejecting the whole payload
+fn lenient<'de, D: Deserializer<'de>, T: DeserializeOwned>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Ok(T::deserialize(Value::deserialize(deserializer)?).ok())
+}
+
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f32>, D::Error> {
+    Ok(Value::deserialize(deserializer)?
+        .as_f64()
+        .map(|value| value as f32))
+}
+
+fn count<'de, D: Deserializer<'de>, T: TryFrom<u64>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    Ok(Value::deserialize(deserializer)?
+        .as_u64()
+        .and_then(|value| T::try_from(value).ok()))
+}
+
+fn string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(value) => Some(value),
+        _ => None,
+    })
+}
+
+#[test]
+fn test_parse_payloads() {
+    let sensor: TasmotaPayload = serde_json::from_str(
+        r#"{"Time":"2024-01-01T12:00:00","ENERGY":{"TotalStartTime":"2023-01-01T00:00:00",
+        "Total":12.3,"Yesterday":1.2,"Today":0.5,"Power":[12,0],"ApparentPower":15},
+        "MJ_HT_V1-351234":{"Temperature":21.5,"Humidity":45.1,"DewPoint":9.1,"Battery":87},
+        "PMS5003":{"CF1":6,"CF2.5":8,"PM2.5":8,"PB10":-1}}"#,
+    )
+    .unwrap();
+    let energy = sensor.energy.as_ref().unwrap();
+    assert_eq!(None, energy.power);
+    assert_eq!(Some(1.2), energy.yesterday);
+    assert_eq!(Some(0.5), energy.today);
+    assert_eq!(Some(12.3), energy.total);
+    let pms = sensor.pms5003.as_ref().unwrap();
+    assert_eq!(Some(8), pms.cf2_5);
+    assert_eq!(None, pms.pb10);
+    let mi_temp: Vec<_> = sensor.mi_temp().collect();
+    assert_eq!(1, mi_temp.len());
+    assert_eq!("351234", mi_temp[0].0);
+    assert_eq!(Some(21.5), mi_temp[0].1.temperature);
+    assert_eq!(Some(87), mi_temp[0].1.battery);
+    assert_eq!(0, sensor.climate().count());
+    assert_eq!(0, sensor.variables(&HashSet::new()).count());
+
+    let dht: TasmotaPayload = serde_json::from_str(
+        r#"{"AM2301":{"Temperature":22.1,"Humidity":51.2,"DewPoint":11.5},"TempUnit":"C"}"#,
+    )
+    .unwrap();
+    let climate: Vec<_> = dht.climate().collect();
+    assert_eq!(1, climate.len());
+    assert_eq!("AM2301", climate[0].0);
+    assert_eq!(Some(22.1), climate[0].1.temperature);
+    assert_eq!(Some(51.2), climate[0].1.humidity);
+
+    let status: TasmotaPayload = serde_json::from_str(
+        r#"{"Status":{"Module":1,"DeviceName":"Plug","Power":1},
+        "StatusFWR":{"Version":"13.2.0(tasmota)","BuildDateTime":"2023-10-06T10:17:27"},
+        "StatusNET":{"Hostname":"plug","IPAddress":"192.168.1.10"},
+        "StatusSTS":{"POWER":"ON","Wifi":{"RSSI":80}},
+        "StatusSNS":{"ENERGY":{"Power":12}}}"#,
+    )
+    .unwrap();
+    assert_eq!(None, status.device_name);
+    assert_eq!(
+        Some("13.2.0(tasmota)"),
+        status.firmware.as_ref().unwrap().version.as_deref()
+    );
+    assert_eq!(
+        Some("192.168.1.10"),
+        status.network.as_ref().unwrap().ip_address.as_deref()
+    );
+    let nested: Vec<_> = status.nested().collect();
+    assert_eq!(2, nested.len());
+    assert_eq!(Some("ON"), nested[0].power.as_deref());
+    assert_eq!(Some(12.0), nested[1].energy.as_ref().unwrap().power);
+
+    let result: TasmotaPayload = serde_json::from_str(r#"{"DeviceName":"Plug"}"#).unwrap();
+    assert_eq!(Some("Plug"), result.device_name.as_deref());
+
+    let rule: TasmotaPayload = serde_json::from_str(
+        r#"{"Var1":"12.5","Mem2":"off","Level":3,"UptimeSec":1200,"Variable":1}"#,
+    )
+    .unwrap();
+    let named = HashSet::new();
+    let variables: Vec<_> = rule.variables(&named).collect();
+    assert_eq!(vec![("Var1", 12.5)], variables);
+    let named = HashSet::from(["Level".into()]);
+    let mut variables: Vec<_> = rule.variables(&named).collect();
+    variables.sort_by_key(|(key, _)| *key);
+    assert_eq!(vec![("Level", 3.0), ("Var1", 12.5)], variables);
+
+    assert!(serde_json::from_str::<TasmotaPayload>("[1]").is_err());
+
+    let unexpected = TasmotaPayload::parse(
+        r#"{"POWER":"ON","ENERGY":"off","COUNTER":{"C1":"x"},"StatusSNS":1}"#,
+    )
+    .unwrap();
+    assert_eq!(Some("ON"), unexpected.power.as_deref());
+    assert!(unexpected.energy.is_none());
+    assert_eq!(0, unexpected.counters().count());
+    assert_eq!(0, unexpected.nested().count());
+
+    let ble = OmgBle::parse(
+        r#"{"id":"A4:C1:38:12:34:56","name":"ATC_123456","rssi":-70,"model":"LYWSD03MMC",
+        "tempc":21.5,"tempf":70.7,"hum":45,"batt":87,"volt":3.012}"#,
+    )
+    .unwrap();
+    assert_eq!(Some(21.5), ble.mi_temp().temperature);
+    assert_eq!(Some(45.0), ble.mi_temp().humidity);
+    assert_eq!(Some(87), ble.mi_temp().battery);
+
+    let rf = OmgRf::parse(
+        r#"{"model":"Bresser-3CH","id":73,"channel":1,"battery_ok":1,"temperature_C":21.3,"humidity":45}"#,
+    )
+    .unwrap();
+    assert_eq!("Bresser-3CH", rf.model);
+    assert_eq!(Some(73), rf.id);
+    assert_eq!(Some(21.3), rf.temperature);
+    assert_eq!(Some(1), rf.battery_ok);
+    assert_eq!(
+        None,
+        OmgRf::parse(r#"{"value":1234,"protocol":1}"#)
+            .unwrap()
+            .temperature
+    );
+
+    let p1 = P1::parse(
+        r#"{"wifi_ssid":"home","meter_model":"ISKRA 2M550T-101","active_power_w":-543,
+        "total_power_import_t1_kwh":10830.511,"total_power_import_t2_kwh":2948.827,"total_gas_m3":2569.646}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        vec![
+            (DsmrMessageType::Power, -0.543),
+            (DsmrMessageType::Energy1, 10830.511),
+            (DsmrMessageType::Energy2, 2948.827),
+            (DsmrMessageType::Gas, 2569.646),
+        ],
+        p1.values().collect::<Vec<_>>()
+    );
+    let single_tariff = P1::parse(r#"{"total_power_import_kwh":123.4}"#).unwrap();
+    assert_eq!(
+        vec![(DsmrMessageType::Energy1, 123.4)],
+        single_tariff.values().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_clock_drift() {
+    let timezone = TimeZone::get("Europe/Amsterdam").unwrap();
+    let now: Timestamp = "2024-01-01T11:00:00Z".parse().unwrap();
+    let state = TasmotaPayload::parse(r#"{"Time":"2024-01-01T12:00:30","POWER":"ON"}"#).unwrap();
+    assert_eq!(Some(30.0), state.clock_drift(&timezone, now));
+    let state = TasmotaPayload::parse(r#"{"Time":"2024-01-01T11:59:00+01:00"}"#).unwrap();
+    assert_eq!(Some(-60.0), state.clock_drift(&timezone, now));
+    let status = TasmotaPayload::parse(r#"{"StatusSTS":{"Time":"2024-01-01T12:00:00"}}"#).unwrap();
+    assert_eq!(Some(0.0), status.clock_drift(&timezone, now));
+    let result = TasmotaPayload::parse(r#"{"POWER":"OFF"}"#).unwrap();
+    assert_eq!(None, result.clock_drift(&timezone, now));
+}
+
+#[test]
+fn test_switches() {
+    let payload =
+        TasmotaPayload::parse(r#"{"Switch1":"ON","Switch2":{"Action":"OFF"},"SwitchMode":"1"}"#)
+            .unwrap();
+    let mut switches: Vec<_> = payload.switches().collect();
+    switches.sort();
+    assert_eq!(vec![("Switch1", true), ("Switch2", false)], switches);
+}
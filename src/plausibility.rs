@@ -0,0 +1,149 @@
+use crate::config::{BoundsConfig, SpikeConfig};
+use std::collections::BTreeMap;
+use tracing::debug;
+
+/// The kinds of readings that can be checked against the configured bounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Quantity {
+    Temperature,
+    Humidity,
+    Power,
+    WindSpeed,
+    Pressure,
+}
+
+impl Quantity {
+    pub const ALL: [Quantity; 5] = [
+        Quantity::Temperature,
+        Quantity::Humidity,
+        Quantity::Power,
+        Quantity::WindSpeed,
+        Quantity::Pressure,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quantity::Temperature => "temperature",
+            Quantity::Humidity => "humidity",
+            Quantity::Power => "power",
+            Quantity::WindSpeed => "wind_speed",
+            Quantity::Pressure => "pressure",
+        }
+    }
+}
+
+/// Discards readings outside the configured bounds, counting the discarded readings by quantity
+#[derive(Debug, Default)]
+pub struct Plausibility {
+    pub bounds: BoundsConfig,
+    pub spikes: SpikeConfig,
+    pub rejected: BTreeMap<Quantity, u64>,
+}
+
+/// The last accepted reading of a sensor and a jump waiting for confirmation, by quantity
+#[derive(Debug, Clone, Default)]
+pub struct SpikeFilter(BTreeMap<Quantity, (f32, Option<f32>)>);
+
+impl Plausibility {
+    /// The reading, if it is within the bounds configured for the quantity
+    pub fn check(&mut self, quantity: Quantity, value: Option<f32>) -> Option<f32> {
+        let value = value?;
+        let bounds = match quantity {
+            Quantity::Temperature => self.bounds.temperature,
+            Quantity::Humidity => self.bounds.humidity,
+            Quantity::Power => self.bounds.power,
+            Quantity::WindSpeed => self.bounds.wind_speed,
+            Quantity::Pressure => self.bounds.pressure,
+        };
+        if bounds.is_some_and(|bounds| !bounds.contains(value)) {
+            debug!(
+                quantity = quantity.name(),
+                value, "discarding implausible reading"
+            );
+            *self.rejected.entry(quantity).or_default() += 1;
+            return None;
+        }
+        Some(value)
+    }
+
+    /// The reading, unless it jumped more than the configured threshold since the last accepted reading of the sensor
+    ///
+    /// Such a jump is accepted once the next reading is consistent with it.
+    pub fn confirm(
+        &self,
+        filter: &mut SpikeFilter,
+        quantity: Quantity,
+        value: Option<f32>,
+    ) -> Option<f32> {
+        let value = value?;
+        let threshold = match quantity {
+            Quantity::Temperature => self.spikes.temperature,
+            Quantity::Humidity => self.spikes.humidity,
+            Quantity::WindSpeed => self.spikes.wind_speed,
+            Quantity::Pressure => self.spikes.pressure,
+            Quantity::Power => None,
+        };
+        let Some(threshold) = threshold else {
+            return Some(value);
+        };
+        let consistent = |other: f32| (value - other).abs() <= threshold;
+        match filter.0.get_mut(&quantity) {
+            Some((accepted, pending))
+                if !consistent(*accepted) && !pending.is_some_and(consistent) =>
+            {
+                debug!(
+                    quantity = quantity.name(),
+                    value, "holding back reading until the next reading confirms it"
+                );
+                *pending = Some(value);
+                None
+            }
+            _ => {
+                filter.0.insert(quantity, (value, None));
+                Some(value)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_plausibility() {
+    let mut plausibility = Plausibility {
+        bounds: toml::from_str("temperature = [-40, 60]").unwrap(),
+        ..Plausibility::default()
+    };
+    assert_eq!(
+        Some(21.5),
+        plausibility.check(Quantity::Temperature, Some(21.5))
+    );
+    assert_eq!(
+        None,
+        plausibility.check(Quantity::Temperature, Some(-144.7))
+    );
+    assert_eq!(
+        None,
+        plausibility.check(Quantity::Temperature, Some(f32::NAN))
+    );
+    assert_eq!(None, plausibility.check(Quantity::Temperature, None));
+    assert_eq!(Some(1e6), plausibility.check(Quantity::Power, Some(1e6)));
+    assert_eq!(Some(&2), plausibility.rejected.get(&Quantity::Temperature));
+}
+
+#[test]
+fn test_spike_filter() {
+    let plausibility = Plausibility {
+        spikes: toml::from_str("temperature = 5").unwrap(),
+        ..Plausibility::default()
+    };
+    let mut filter = SpikeFilter::default();
+    let mut confirm = |value| plausibility.confirm(&mut filter, Quantity::Temperature, Some(value));
+    assert_eq!(Some(20.0), confirm(20.0));
+    assert_eq!(Some(21.0), confirm(21.0));
+    // single frame glitch
+    assert_eq!(None, confirm(-40.0));
+    assert_eq!(Some(21.5), confirm(21.5));
+    // genuine jump, confirmed by the next reading
+    assert_eq!(None, confirm(35.0));
+    assert_eq!(Some(34.5), confirm(34.5));
+    assert_eq!(Some(34.0), confirm(34.0));
+}
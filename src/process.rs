@@ -0,0 +1,96 @@
+use crate::metrics::{self, Registry};
+use std::fs;
+
+/// Clock ticks per second used by `/proc/self/stat`, fixed at 100 in the linux userspace abi
+const CLOCK_TICKS: f64 = 100.0;
+
+/// Resource usage of the exporter process, only available on linux
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProcessStats {
+    pub cpu_seconds: f64,
+    pub resident_memory: u64,
+    pub virtual_memory: u64,
+    pub threads: u64,
+}
+
+impl ProcessStats {
+    pub fn current() -> Option<Self> {
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        let status = fs::read_to_string("/proc/self/status").ok()?;
+        Self::parse(&stat, &status)
+    }
+
+    fn parse(stat: &str, status: &str) -> Option<Self> {
+        // the process name can contain spaces and parentheses, the fields start after the last `)`
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        // `utime` and `stime` are the 14th and 15th field, counting from the pid
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+
+        let status_field = |name: &str| -> Option<u64> {
+            status.lines().find_map(|line| {
+                let value = line.strip_prefix(name)?.strip_prefix(':')?;
+                value.split_whitespace().next()?.parse().ok()
+            })
+        };
+
+        Some(ProcessStats {
+            cpu_seconds: (utime + stime) as f64 / CLOCK_TICKS,
+            resident_memory: status_field("VmRSS")? * 1024,
+            virtual_memory: status_field("VmSize")? * 1024,
+            threads: status_field("Threads")?,
+        })
+    }
+}
+
+fn open_fds() -> Option<usize> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count())
+}
+
+pub fn format_process_stats(registry: &mut Registry) {
+    if let Some(process) = ProcessStats::current() {
+        registry.add(&metrics::PROCESS_CPU, &[], process.cpu_seconds);
+        registry.add(
+            &metrics::PROCESS_RESIDENT_MEMORY,
+            &[],
+            process.resident_memory,
+        );
+        registry.add(
+            &metrics::PROCESS_VIRTUAL_MEMORY,
+            &[],
+            process.virtual_memory,
+        );
+        registry.add(&metrics::PROCESS_THREADS, &[], process.threads);
+    }
+    if let Some(fds) = open_fds() {
+        registry.add(&metrics::PROCESS_OPEN_FDS, &[], fds);
+    }
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        let runtime = runtime.metrics();
+        registry.add(&metrics::TOKIO_WORKERS, &[], runtime.num_workers());
+        registry.add(&metrics::TOKIO_ALIVE_TASKS, &[], runtime.num_alive_tasks());
+        registry.add(
+            &metrics::TOKIO_GLOBAL_QUEUE_DEPTH,
+            &[],
+            runtime.global_queue_depth(),
+        );
+    }
+}
+
+#[test]
+fn test_parse_process_stats() {
+    let stat = "1234 (tas (promto)) S 1 1234 1234 0 -1 4194560 2150 0 0 0 \
+        250 130 0 0 20 0 9 0 4567 123456789 2048 18446744073709551615";
+    let status = "Name:\ttaspromto\nVmSize:\t  120564 kB\nVmRSS:\t    8192 kB\nThreads:\t9\n";
+    assert_eq!(
+        Some(ProcessStats {
+            cpu_seconds: 3.8,
+            resident_memory: 8192 * 1024,
+            virtual_memory: 120564 * 1024,
+            threads: 9,
+        }),
+        ProcessStats::parse(stat, status)
+    );
+    assert_eq!(None, ProcessStats::parse("1234 (taspromto) S", status));
+}
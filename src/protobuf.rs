@@ -0,0 +1,129 @@
+//! Minimal encoder for the prometheus protobuf exposition format
+//!
+//! See https://github.com/prometheus/client_model/blob/master/io/prometheus/client/metrics.proto
+
+use crate::metrics::{MetricType, Registry};
+
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+
+/// Whether the `Accept` header of a scrape request asks for the protobuf format
+pub fn accepts_protobuf(accept: &str) -> bool {
+    accept.contains("application/vnd.google.protobuf")
+        && accept.contains("io.prometheus.client.MetricFamily")
+}
+
+/// Encode all metric families as length delimited `MetricFamily` messages
+pub fn encode(registry: &Registry) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (family, samples) in registry.families() {
+        let mut message = Vec::new();
+        write_bytes(&mut message, 1, registry.name(family).as_bytes());
+        write_bytes(&mut message, 2, family.help.as_bytes());
+        write_tag(&mut message, 3, WIRE_VARINT);
+        write_varint(
+            &mut message,
+            match family.ty {
+                MetricType::Counter => 0,
+                MetricType::Gauge => 1,
+                MetricType::Histogram => 4,
+            },
+        );
+
+        for sample in samples {
+            let mut metric = Vec::new();
+            for (name, value) in &sample.labels {
+                let mut label = Vec::new();
+                write_bytes(&mut label, 1, name.as_bytes());
+                write_bytes(&mut label, 2, value.as_bytes());
+                write_bytes(&mut metric, 1, &label);
+            }
+
+            if let Some(histogram) = &sample.histogram {
+                let mut value = Vec::new();
+                write_tag(&mut value, 1, WIRE_VARINT);
+                write_varint(&mut value, histogram.count);
+                write_double(&mut value, 2, histogram.sum);
+                for (bound, count) in &histogram.buckets {
+                    let mut bucket = Vec::new();
+                    write_tag(&mut bucket, 1, WIRE_VARINT);
+                    write_varint(&mut bucket, *count);
+                    write_double(&mut bucket, 2, *bound);
+                    write_bytes(&mut value, 3, &bucket);
+                }
+                write_bytes(&mut metric, 7, &value);
+            } else {
+                let mut value = Vec::new();
+                write_double(&mut value, 1, sample.value.0);
+                let field = match family.ty {
+                    MetricType::Gauge => 2,
+                    MetricType::Counter | MetricType::Histogram => 3,
+                };
+                write_bytes(&mut metric, field, &value);
+            }
+
+            write_bytes(&mut message, 4, &metric);
+        }
+
+        write_varint(&mut out, message.len() as u64);
+        out.extend_from_slice(&message);
+    }
+    out
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_tag(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(out, (field << 3) | wire_type);
+}
+
+fn write_double(out: &mut Vec<u8>, field: u64, value: f64) {
+    write_tag(out, field, WIRE_FIXED64);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_tag(out, field, WIRE_LENGTH_DELIMITED);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[test]
+fn test_encode() {
+    use crate::metrics::MetricFamily;
+
+    static FAMILY: MetricFamily = MetricFamily {
+        name: "a",
+        help: "b",
+        ty: MetricType::Gauge,
+    };
+    let mut registry = Registry::default();
+    registry.add(&FAMILY, &[], 1.0);
+
+    assert_eq!(
+        vec![
+            0x15, // message length
+            0x0a, 0x01, b'a', // name
+            0x12, 0x01, b'b', // help
+            0x18, 0x01, // type
+            0x22, 0x0b, // metric
+            0x12, 0x09, // gauge
+            0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf0, 0x3f, // value
+        ],
+        encode(&registry)
+    );
+
+    let mut varint = Vec::new();
+    write_varint(&mut varint, 300);
+    assert_eq!(vec![0xac, 0x02], varint);
+}
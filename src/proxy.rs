@@ -0,0 +1,237 @@
+use crate::config::{ProxyConfig, ProxyType};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use rumqttc::MqttOptions;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error};
+
+/// Maximum size of the response headers of an http proxy
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+/// How long a tunnel waits for the mqtt client to connect
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tunnel a connection to a broker trough a proxy
+///
+/// rumqttc can't connect trough a socks proxy, so a local listener is started that forwards a single connection
+/// to the broker trough the proxy. The returned options connect to this listener instead of the broker,
+/// a new tunnel is needed for every connection attempt.
+pub async fn tunnel(options: &MqttOptions, proxy: ProxyConfig) -> Result<MqttOptions> {
+    let (host, port) = options.broker_address();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .wrap_err("Failed to setup proxy tunnel")?;
+    let local = listener.local_addr()?;
+    spawn(async move {
+        let mut client = match timeout(ACCEPT_TIMEOUT, listener.accept()).await {
+            Ok(Ok((client, _))) => client,
+            Ok(Err(e)) => {
+                error!("Failed to accept proxy tunnel connection: {:#}", e);
+                return;
+            }
+            Err(_) => {
+                debug!("proxy tunnel wasn't used");
+                return;
+            }
+        };
+        // only our own client gets to use the tunnel, other local connections are refused
+        drop(listener);
+        match connect(&proxy, &host, port).await {
+            Ok(mut upstream) => {
+                if let Err(e) = copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("proxy tunnel closed: {:#}", e);
+                }
+            }
+            Err(e) => error!(
+                "Failed to connect to {host}:{port} trough {}: {:#}",
+                proxy.address, e
+            ),
+        }
+    });
+
+    let mut tunneled = MqttOptions::new(options.client_id(), local.ip().to_string(), local.port());
+    if let Some((username, password)) = options.credentials() {
+        tunneled.set_credentials(username, password);
+    }
+    tunneled.set_keep_alive(options.keep_alive());
+    Ok(tunneled)
+}
+
+async fn connect(proxy: &ProxyConfig, host: &str, port: u16) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(&proxy.address)
+        .await
+        .wrap_err("Failed to connect to proxy")?;
+    let credentials = match &proxy.credentials {
+        Some(credentials) => Some((credentials.username(), credentials.try_password()?)),
+        None => None,
+    };
+    match proxy.kind {
+        ProxyType::Http => http_connect(&mut stream, host, port, credentials).await?,
+        ProxyType::Socks5 => socks5_connect(&mut stream, host, port, credentials).await?,
+    }
+    Ok(stream)
+}
+
+fn http_request(host: &str, port: u16, credentials: Option<(String, String)>) -> String {
+    let target = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((username, password)) = credentials {
+        let auth = BASE64_STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {auth}\r\n"));
+    }
+    request.push_str("\r\n");
+    request
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<()> {
+    let request = http_request(host, port, credentials);
+    stream.write_all(request.as_bytes()).await?;
+
+    // read byte by byte, to not consume any data from the broker after the headers
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() > MAX_RESPONSE_SIZE {
+            return Err(Report::msg("Proxy response too large"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(()),
+        _ => Err(Report::msg(format!("Proxy refused connection: {status}"))),
+    }
+}
+
+fn socks5_request(host: &str, port: u16) -> Result<Vec<u8>> {
+    // version, connect, reserved
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            let length = u8::try_from(host.len()).wrap_err("Hostname too long")?;
+            request.push(3);
+            request.push(length);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    Ok(request)
+}
+
+async fn socks5_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<()> {
+    // no authentication or username and password
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(Report::msg(
+            "Proxy doesn't support the authentication method",
+        ));
+    }
+
+    if let Some((username, password)) = credentials {
+        let username_length = u8::try_from(username.len()).wrap_err("Username too long")?;
+        let password_length = u8::try_from(password.len()).wrap_err("Password too long")?;
+        let mut request = vec![1, username_length];
+        request.extend(username.as_bytes());
+        request.push(password_length);
+        request.extend(password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(Report::msg("Proxy authentication failed"));
+        }
+    }
+
+    stream.write_all(&socks5_request(host, port)?).await?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(Report::msg(format!(
+            "Proxy refused connection with error {}",
+            reply[1]
+        )));
+    }
+    // skip the address the proxy bound to
+    let address_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await?.into(),
+        _ => return Err(Report::msg("Invalid proxy response")),
+    };
+    let mut address = vec![0; address_length + 2];
+    stream.read_exact(&mut address).await?;
+    Ok(())
+}
+
+#[test]
+fn test_proxy_requests() {
+    assert_eq!(
+        "CONNECT mqtt.lan:1883 HTTP/1.1\r\nHost: mqtt.lan:1883\r\n\
+        Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n",
+        http_request("mqtt.lan", 1883, Some(("user".into(), "pass".into())))
+    );
+    assert_eq!(
+        vec![5, 1, 0, 3, 8, b'm', b'q', b't', b't', b'.', b'l', b'a', b'n', 7, 91],
+        socks5_request("mqtt.lan", 1883).unwrap()
+    );
+    assert_eq!(
+        vec![5, 1, 0, 1, 10, 0, 0, 1, 7, 91],
+        socks5_request("10.0.0.1", 1883).unwrap()
+    );
+}
+
+#[test]
+fn test_tunnel_single_connection() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let proxy_listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let proxy = ProxyConfig {
+            kind: ProxyType::Http,
+            address: proxy_listener.local_addr().unwrap().to_string(),
+            credentials: None,
+        };
+        let options = MqttOptions::new("taspromto", "mqtt.lan", 1883);
+        let tunneled = tunnel(&options, proxy).await.unwrap();
+        let (host, port) = tunneled.broker_address();
+
+        let _client = TcpStream::connect((host.as_str(), port)).await.unwrap();
+        let (mut upstream, _) = proxy_listener.accept().await.unwrap();
+        let mut request = [0; 28];
+        upstream.read_exact(&mut request).await.unwrap();
+        assert_eq!(b"CONNECT mqtt.lan:1883 HTTP/1", &request);
+
+        // the tunnel is closed for other connections
+        assert!(TcpStream::connect((host.as_str(), port)).await.is_err());
+    });
+}
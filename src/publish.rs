@@ -0,0 +1,32 @@
+use crate::config::{Config, PublishConfig};
+use crate::device::SharedDeviceStates;
+use rumqttc::{AsyncClient, QoS};
+use std::sync::{Arc, RwLock};
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// Periodically publish the readings of every device as json
+pub async fn publish_snapshots(
+    client: AsyncClient,
+    publish: PublishConfig,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+) {
+    let mut interval = interval(Duration::from_secs(publish.interval));
+    loop {
+        interval.tick().await;
+        let snapshots = {
+            let device_states = device_states.read().await;
+            device_states.snapshots(&config.read().unwrap().names)
+        };
+        for (device, snapshot) in snapshots {
+            let topic = format!("{}/{}", publish.topic.trim_end_matches('/'), device);
+            if let Err(e) = client
+                .publish(topic, QoS::AtMostOnce, publish.retain, snapshot.to_string())
+                .await
+            {
+                error!("Failed to publish state snapshot: {:#}", e);
+            }
+        }
+    }
+}
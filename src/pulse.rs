@@ -0,0 +1,81 @@
+use crate::device::DsmrMessageType;
+use color_eyre::{Report, Result};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::str::FromStr;
+
+/// A counter of a tasmota device, such as `C1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TasmotaCounter {
+    pub hostname: String,
+    pub counter: String,
+}
+
+impl FromStr for TasmotaCounter {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((hostname, counter)) if !hostname.is_empty() && !counter.is_empty() => {
+                Ok(TasmotaCounter {
+                    hostname: hostname.into(),
+                    counter: counter.into(),
+                })
+            }
+            _ => Err(Report::msg(format!(
+                "invalid counter {s}, expected <hostname>/<counter>"
+            ))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TasmotaCounter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let counter = String::deserialize(deserializer)?;
+        counter.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Where the pulse count of a meter is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PulseSource {
+    /// A counter of a tasmota device, as `<hostname>/<counter>`
+    Counter(TasmotaCounter),
+    /// A topic with the pulse count as payload, such as published by S0 pulse bridges
+    Topic(String),
+}
+
+/// A water or gas meter read by counting pulses, tracked as a DSMR device
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseMeter {
+    /// The name the meter is exported under
+    pub name: String,
+    pub ty: DsmrMessageType,
+    pub source: PulseSource,
+    pub m3_per_pulse: f64,
+    /// Meter reading in m³ at a pulse count of 0
+    pub offset: f64,
+}
+
+impl PulseMeter {
+    /// The meter reading in m³
+    pub fn reading(&self, pulses: f64) -> f32 {
+        (self.offset + pulses * self.m3_per_pulse) as f32
+    }
+
+    pub fn reads_counter(&self, hostname: &str, counter: &str) -> bool {
+        matches!(&self.source, PulseSource::Counter(source) if source.hostname == hostname && source.counter == counter)
+    }
+}
+
+#[test]
+fn test_parse_counter() {
+    assert_eq!(
+        TasmotaCounter {
+            hostname: "tasmota_1A2B3C".into(),
+            counter: "C1".into(),
+        },
+        "tasmota_1A2B3C/C1".parse().unwrap()
+    );
+    assert!("tasmota_1A2B3C".parse::<TasmotaCounter>().is_err());
+    assert!("/C1".parse::<TasmotaCounter>().is_err());
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Time span rates of change are calculated over
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Rate of change of a cumulative total over the last minute
+///
+/// Only updated when a new total is received, so this relies on the total being published periodically even when it
+/// doesn't change, as the DSMR bridges do.
+#[derive(Debug, Default)]
+pub struct Rate {
+    samples: VecDeque<(Instant, f32)>,
+}
+
+impl Rate {
+    pub fn update(&mut self, time: Instant, total: f32) {
+        if self.samples.back().is_some_and(|(_, last)| total < *last) {
+            // the meter was reset or replaced
+            self.samples.clear();
+        }
+        self.samples.push_back((time, total));
+        // keep the last sample from before the window, so the rate covers the full window
+        while self.samples.len() > 2 && time.duration_since(self.samples[1].0) >= WINDOW {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Change of the total per second
+    pub fn per_second(&self) -> Option<f32> {
+        let (start, first) = self.samples.front()?;
+        let (end, last) = self.samples.back()?;
+        let elapsed = end.duration_since(*start).as_secs_f32();
+        (elapsed > 0.0).then(|| (last - first) / elapsed)
+    }
+}
+
+#[test]
+fn test_rate() {
+    let start = Instant::now();
+    let at = |seconds| start + Duration::from_secs(seconds);
+    let mut rate = Rate::default();
+    rate.update(at(0), 10.0);
+    assert_eq!(None, rate.per_second());
+    rate.update(at(10), 11.0);
+    assert_eq!(Some(0.1), rate.per_second());
+    for seconds in (20..=100).step_by(10) {
+        rate.update(at(seconds), 11.0);
+    }
+    assert_eq!(Some(0.0), rate.per_second());
+    rate.update(at(110), 17.0);
+    assert_eq!(Some(0.1), rate.per_second());
+
+    // a reset starts over instead of reporting a negative rate
+    rate.update(at(120), 1.0);
+    assert_eq!(None, rate.per_second());
+}
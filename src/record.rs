@@ -0,0 +1,93 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use rumqttc::{Publish, QoS};
+use serde_json::{json, Value};
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_stream::Stream;
+
+/// Append every received message to a file as json lines
+pub struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open {path} for recording"))?;
+        Ok(Recorder { file })
+    }
+
+    pub fn record(&mut self, message: &Publish) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let line = format_message(time, message);
+        writeln!(self.file, "{line}")?;
+        Ok(())
+    }
+}
+
+/// Format a message as json with the receive time in milliseconds, non-utf8 payloads are base64 encoded
+fn format_message(time: u128, message: &Publish) -> String {
+    let mut json = json!({
+        "time": time as u64,
+        "topic": message.topic.as_str(),
+    });
+    match std::str::from_utf8(&message.payload) {
+        Ok(payload) => json["payload"] = payload.into(),
+        Err(_) => json["payload_base64"] = BASE64_STANDARD.encode(&message.payload).into(),
+    }
+    json.to_string()
+}
+
+fn parse_message(line: &str) -> Result<Publish> {
+    let json: Value = serde_json::from_str(line)?;
+    let topic = json["topic"]
+        .as_str()
+        .ok_or_else(|| Report::msg("missing topic"))?;
+    let payload = match (json["payload"].as_str(), json["payload_base64"].as_str()) {
+        (Some(payload), _) => payload.as_bytes().to_vec(),
+        (None, Some(payload)) => BASE64_STANDARD.decode(payload)?,
+        (None, None) => return Err(Report::msg("missing payload")),
+    };
+    Ok(Publish::new(topic, QoS::AtMostOnce, payload))
+}
+
+/// All messages from a recording
+pub fn replay(path: &str) -> Result<impl Stream<Item = Result<Publish>>> {
+    let recording = read_to_string(path).wrap_err_with(|| format!("Failed to read {path}"))?;
+    let messages: Vec<_> = recording
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            parse_message(line)
+                .wrap_err_with(|| format!("Invalid message on line {} of {path}", index + 1))
+        })
+        .collect();
+    Ok(tokio_stream::iter(messages))
+}
+
+#[test]
+fn test_recording() {
+    let message = Publish::new("tele/plug/SENSOR", QoS::AtMostOnce, r#"{"ENERGY":{}}"#);
+    let line = format_message(1_700_000_000_000, &message);
+    assert_eq!(
+        r#"{"time":1700000000000,"topic":"tele/plug/SENSOR","payload":"{\"ENERGY\":{}}"}"#,
+        line
+    );
+    let parsed = parse_message(&line).unwrap();
+    assert_eq!(message.topic, parsed.topic);
+    assert_eq!(message.payload, parsed.payload);
+
+    let message = Publish::new("rtl_433/raw", QoS::AtMostOnce, vec![0xff, 0x00]);
+    let line = format_message(0, &message);
+    assert!(line.contains("payload_base64"));
+    assert_eq!(message.payload, parse_message(&line).unwrap().payload);
+}
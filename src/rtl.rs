@@ -0,0 +1,118 @@
+use crate::plausibility::Quantity;
+
+/// A reading from an rtl_433 field, converted to the units the readings are tracked in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtlReading {
+    /// Degrees celsius
+    Temperature(f32),
+    /// Relative humidity in percent
+    Humidity(f32),
+    /// Average wind speed in m/s
+    WindSpeed(f32),
+    /// Air pressure in hPa
+    Pressure(f32),
+    /// Total rainfall in mm
+    Rain(f32),
+    BatteryOk(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dimension {
+    Temperature,
+    Speed,
+    Pressure,
+    Length,
+}
+
+type Conversion = fn(f32) -> f32;
+
+/// The unit suffixes rtl_433 adds to field names, with the conversion to the tracked unit
+const UNITS: &[(&str, Dimension, Conversion)] = &[
+    ("_C", Dimension::Temperature, |value| value),
+    ("_F", Dimension::Temperature, |value| {
+        (value - 32.0) * 5.0 / 9.0
+    }),
+    ("_m_s", Dimension::Speed, |value| value),
+    ("_km_h", Dimension::Speed, |value| value / 3.6),
+    ("_mi_h", Dimension::Speed, |value| value * 0.44704),
+    ("_mph", Dimension::Speed, |value| value * 0.44704),
+    ("_hPa", Dimension::Pressure, |value| value),
+    ("_kPa", Dimension::Pressure, |value| value * 10.0),
+    ("_inHg", Dimension::Pressure, |value| value * 33.8639),
+    ("_mm", Dimension::Length, |value| value),
+    ("_in", Dimension::Length, |value| value * 25.4),
+];
+
+impl RtlReading {
+    /// The reading as a quantity that can be checked against the configured bounds
+    pub fn quantity(&self) -> Option<(Quantity, f32)> {
+        match *self {
+            RtlReading::Temperature(value) => Some((Quantity::Temperature, value)),
+            RtlReading::Humidity(value) => Some((Quantity::Humidity, value)),
+            RtlReading::WindSpeed(value) => Some((Quantity::WindSpeed, value)),
+            RtlReading::Pressure(value) => Some((Quantity::Pressure, value)),
+            RtlReading::Rain(_) | RtlReading::BatteryOk(_) => None,
+        }
+    }
+
+    /// Parse the value of a field by its name, `None` for fields that aren't tracked or invalid values
+    pub fn parse(field: &str, payload: &str) -> Option<Self> {
+        let payload = payload.trim();
+        match field {
+            "humidity" => return payload.parse().ok().map(RtlReading::Humidity),
+            "battery_ok" => return Some(RtlReading::BatteryOk(payload != "0")),
+            _ => {}
+        }
+        let value: f32 = payload.parse().ok()?;
+        let (quantity, dimension, value) =
+            UNITS.iter().find_map(|(suffix, dimension, convert)| {
+                Some((field.strip_suffix(suffix)?, *dimension, convert(value)))
+            })?;
+        match (quantity, dimension) {
+            ("temperature", Dimension::Temperature) => Some(RtlReading::Temperature(value)),
+            ("wind_avg", Dimension::Speed) => Some(RtlReading::WindSpeed(value)),
+            ("pressure", Dimension::Pressure) => Some(RtlReading::Pressure(value)),
+            ("rain", Dimension::Length) => Some(RtlReading::Rain(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a `mic` or `status` field reports that the frame failed its integrity check
+///
+/// For frames that passed, `mic` names the kind of check, such as `CRC` or `CHECKSUM`.
+pub fn integrity_failed(value: &str) -> bool {
+    let value = value.trim().to_ascii_uppercase();
+    value.contains("FAIL") || value.contains("ERR") || value == "BAD"
+}
+
+#[test]
+fn test_parse_rtl_reading() {
+    let parse = |field, payload| match RtlReading::parse(field, payload) {
+        Some(RtlReading::Temperature(value))
+        | Some(RtlReading::WindSpeed(value))
+        | Some(RtlReading::Pressure(value))
+        | Some(RtlReading::Rain(value)) => Some((value * 100.0).round() / 100.0),
+        Some(reading) => panic!("unexpected reading {reading:?}"),
+        None => None,
+    };
+    assert_eq!(Some(21.5), parse("temperature_C", "21.5"));
+    assert_eq!(Some(20.0), parse("temperature_F", "68"));
+    assert_eq!(Some(5.0), parse("wind_avg_km_h", "18"));
+    assert_eq!(Some(4.47), parse("wind_avg_mi_h", "10"));
+    assert_eq!(Some(1013.0), parse("pressure_hPa", "1013"));
+    assert_eq!(Some(1015.92), parse("pressure_inHg", "30"));
+    assert_eq!(Some(25.4), parse("rain_in", "1"));
+    assert_eq!(Some(12.5), parse("rain_mm", "12.5"));
+    assert_eq!(None, parse("wind_max_m_s", "3"));
+    assert_eq!(None, parse("temperature_mm", "3"));
+    assert_eq!(None, parse("temperature_C", "-"));
+    assert_eq!(
+        Some(RtlReading::Humidity(45.5)),
+        RtlReading::parse("humidity", "45.5")
+    );
+    assert_eq!(
+        Some(RtlReading::BatteryOk(false)),
+        RtlReading::parse("battery_ok", "0")
+    );
+}
@@ -0,0 +1,430 @@
+use crate::config::{Config, NotifierConfig, RuleConfig};
+use crate::device::SharedDeviceStates;
+use crate::metrics::Registry;
+use crate::mqtt::ConnectionState;
+use crate::notify::{http_client, notify, post, Level, Notification};
+use crate::stats::Stats;
+use crate::{collect_metrics, MetricsQuery};
+use color_eyre::{eyre::WrapErr, Report, Result};
+use rumqttc::QoS;
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+use tracing::{error, info};
+
+const EVALUATION_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Operator {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl Operator {
+    fn matches(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Operator::Greater => value > threshold,
+            Operator::GreaterOrEqual => value >= threshold,
+            Operator::Less => value < threshold,
+            Operator::LessOrEqual => value <= threshold,
+        }
+    }
+
+    /// Whether a firing alert is resolved, the value needs to move `hysteresis` past the threshold
+    fn resolved(self, value: f64, threshold: f64, hysteresis: f64) -> bool {
+        match self {
+            Operator::Greater | Operator::GreaterOrEqual => value < threshold - hysteresis,
+            Operator::Less | Operator::LessOrEqual => value > threshold + hysteresis,
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+        })
+    }
+}
+
+/// A condition like `power_watts > 2000 for 5m`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub metric: String,
+    pub operator: Operator,
+    pub threshold: f64,
+    /// How long the condition needs to hold before the alert fires
+    pub duration: Duration,
+}
+
+impl FromStr for Condition {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split_whitespace();
+        let (Some(metric), Some(operator), Some(threshold)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Report::msg(format!("Invalid rule {s}")));
+        };
+        let operator = match operator {
+            ">" => Operator::Greater,
+            ">=" => Operator::GreaterOrEqual,
+            "<" => Operator::Less,
+            "<=" => Operator::LessOrEqual,
+            _ => return Err(Report::msg(format!("Invalid operator {operator} in {s}"))),
+        };
+        let threshold = threshold
+            .parse()
+            .wrap_err_with(|| format!("Invalid threshold in {s}"))?;
+        let duration = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => Duration::ZERO,
+            (Some("for"), Some(duration), None) => parse_duration(duration)?,
+            _ => return Err(Report::msg(format!("Invalid rule {s}"))),
+        };
+        Ok(Condition {
+            metric: metric.into(),
+            operator,
+            threshold,
+            duration,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let str = <Cow<'de, str>>::deserialize(deserializer)?;
+        Self::from_str(&str).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parse a duration like `30s`, `5m` or `1h`
+fn parse_duration(s: &str) -> Result<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .wrap_err_with(|| format!("Invalid duration {s}"))?;
+    let seconds = match unit {
+        "" | "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => return Err(Report::msg(format!("Invalid duration unit in {s}"))),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// A rule that started firing or was resolved for a single series
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub rule: String,
+    pub status: AlertStatus,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+impl Alert {
+    pub fn to_json(&self, condition: &Condition) -> Value {
+        let mut labels = json!({});
+        for (name, value) in &self.labels {
+            labels[name.as_str()] = value.as_str().into();
+        }
+        json!({
+            "rule": self.rule.as_str(),
+            "status": self.status.as_str(),
+            "metric": condition.metric.as_str(),
+            "condition": format!("{} {}", condition.operator, condition.threshold),
+            "labels": labels,
+            "value": self.value,
+        })
+    }
+
+    pub fn notification(&self, condition: &Condition) -> Notification {
+        let series = self
+            .labels
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| format!("{name}=\"{value}\""))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (state, level) = match self.status {
+            AlertStatus::Firing => ("firing", Level::Warning),
+            AlertStatus::Resolved => ("resolved", Level::Success),
+        };
+        Notification {
+            title: format!("{} {}", self.rule, state),
+            message: format!(
+                "{}{{{}}} is {} ({} {} {})",
+                condition.metric,
+                series,
+                self.value,
+                condition.metric,
+                condition.operator,
+                condition.threshold
+            ),
+            level,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SeriesState {
+    pending_since: Option<Instant>,
+    firing: bool,
+    /// Last evaluated value
+    value: f64,
+}
+
+/// Tracks the state of every rule for every series
+#[derive(Debug, Default)]
+pub struct Evaluator {
+    series: HashMap<(String, Vec<(String, String)>), SeriesState>,
+}
+
+impl Evaluator {
+    pub fn evaluate(
+        &mut self,
+        rules: &[RuleConfig],
+        registry: &Registry,
+        now: Instant,
+    ) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        let mut seen = HashSet::new();
+        for rule in rules {
+            let condition = &rule.condition;
+            let samples = registry
+                .families()
+                .filter(|(family, _)| family.name == condition.metric)
+                .flat_map(|(_, samples)| samples);
+            for sample in samples {
+                let labels: Vec<(String, String)> = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect();
+                let key = (rule.name.clone(), labels);
+                let value = sample.value.0;
+                let state = self.series.entry(key.clone()).or_default();
+                state.value = value;
+                if state.firing {
+                    if condition
+                        .operator
+                        .resolved(value, condition.threshold, rule.hysteresis)
+                    {
+                        state.firing = false;
+                        state.pending_since = None;
+                        alerts.push(Alert {
+                            rule: rule.name.clone(),
+                            status: AlertStatus::Resolved,
+                            labels: key.1.clone(),
+                            value,
+                        });
+                    }
+                } else if condition.operator.matches(value, condition.threshold) {
+                    let since = *state.pending_since.get_or_insert(now);
+                    if now.duration_since(since) >= condition.duration {
+                        state.firing = true;
+                        alerts.push(Alert {
+                            rule: rule.name.clone(),
+                            status: AlertStatus::Firing,
+                            labels: key.1.clone(),
+                            value,
+                        });
+                    }
+                } else {
+                    state.pending_since = None;
+                }
+                seen.insert(key);
+            }
+        }
+        // forget series that are no longer exported, resolving the ones that were firing
+        self.series.retain(|key, state| {
+            if seen.contains(key) {
+                return true;
+            }
+            if state.firing {
+                alerts.push(Alert {
+                    rule: key.0.clone(),
+                    status: AlertStatus::Resolved,
+                    labels: key.1.clone(),
+                    value: state.value,
+                });
+            }
+            false
+        });
+        alerts
+    }
+}
+
+/// Periodically evaluate the configured rules and send notifications for every change
+pub async fn evaluate_rules(
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+    connection: Arc<Mutex<ConnectionState>>,
+    notifiers: Vec<NotifierConfig>,
+) {
+    let Some(client) = http_client() else {
+        return;
+    };
+
+    let mut evaluator = Evaluator::default();
+    let mut interval = interval(EVALUATION_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (rules, alerts) = {
+            let device_states = device_states.read().await;
+            let config = config.read().unwrap();
+            if config.rules.is_empty() {
+                continue;
+            }
+            let registry =
+                collect_metrics(&config, &device_states, &stats, &MetricsQuery::default());
+            let alerts = evaluator.evaluate(&config.rules, &registry, Instant::now());
+            (config.rules.clone(), alerts)
+        };
+
+        for alert in alerts {
+            let Some(rule) = rules.iter().find(|rule| rule.name == alert.rule) else {
+                continue;
+            };
+            info!("rule {} is {}", rule.name, alert.status.as_str());
+            let payload = alert.to_json(&rule.condition);
+            if let Some(url) = &rule.webhook {
+                if let Err(e) = post(&client, url, &payload).await {
+                    error!("Failed to send webhook for rule {}: {:#}", rule.name, e);
+                }
+            }
+            if let Some(topic) = &rule.topic {
+                let client = connection.lock().unwrap().client();
+                if let Some(client) = client {
+                    if let Err(e) = client
+                        .publish(topic, QoS::AtLeastOnce, false, payload.to_string())
+                        .await
+                    {
+                        error!("Failed to publish alert for rule {}: {:#}", rule.name, e);
+                    }
+                }
+            }
+            notify(&client, &notifiers, &alert.notification(&rule.condition)).await;
+        }
+    }
+}
+
+#[test]
+fn test_parse_condition() {
+    let condition: Condition = "power_watts > 2000 for 5m".parse().unwrap();
+    assert_eq!("power_watts", condition.metric);
+    assert_eq!(Operator::Greater, condition.operator);
+    assert_eq!(2000.0, condition.threshold);
+    assert_eq!(Duration::from_secs(300), condition.duration);
+
+    let condition: Condition = "sensor_battery <= 15".parse().unwrap();
+    assert_eq!(Operator::LessOrEqual, condition.operator);
+    assert_eq!(Duration::ZERO, condition.duration);
+
+    assert!("sensor_co2 1200".parse::<Condition>().is_err());
+    assert!("sensor_co2 > 1200 for 5x".parse::<Condition>().is_err());
+}
+
+#[test]
+fn test_evaluate() {
+    use crate::metrics;
+
+    let rules = [RuleConfig {
+        name: "high power".into(),
+        condition: "power_watts > 2000 for 1m".parse().unwrap(),
+        hysteresis: 100.0,
+        webhook: None,
+        topic: None,
+    }];
+    let registry = |value: f32| {
+        let mut registry = Registry::default();
+        registry.add(&metrics::POWER_WATTS, &[("name", "Heater")], value);
+        registry
+    };
+
+    let start = Instant::now();
+    let mut evaluator = Evaluator::default();
+    assert!(evaluator
+        .evaluate(&rules, &registry(2500.0), start)
+        .is_empty());
+    let alerts = evaluator.evaluate(&rules, &registry(2500.0), start + Duration::from_secs(60));
+    assert_eq!(1, alerts.len());
+    assert_eq!(AlertStatus::Firing, alerts[0].status);
+    assert_eq!(
+        vec![("name".to_string(), "Heater".to_string())],
+        alerts[0].labels
+    );
+
+    // still above threshold - hysteresis
+    assert!(evaluator
+        .evaluate(&rules, &registry(1950.0), start + Duration::from_secs(70))
+        .is_empty());
+    let alerts = evaluator.evaluate(&rules, &registry(1800.0), start + Duration::from_secs(80));
+    assert_eq!(AlertStatus::Resolved, alerts[0].status);
+    let notification = alerts[0].notification(&rules[0].condition);
+    assert_eq!("high power resolved", notification.title);
+    assert_eq!(
+        "power_watts{name=\"Heater\"} is 1800 (power_watts > 2000)",
+        notification.message
+    );
+}
+
+#[test]
+fn test_evaluate_removed_series() {
+    use crate::metrics;
+
+    let rules = [RuleConfig {
+        name: "high power".into(),
+        condition: "power_watts > 2000".parse().unwrap(),
+        hysteresis: 0.0,
+        webhook: None,
+        topic: None,
+    }];
+    let mut registry = Registry::default();
+    registry.add(&metrics::POWER_WATTS, &[("name", "Heater")], 2500.0);
+
+    let now = Instant::now();
+    let mut evaluator = Evaluator::default();
+    let alerts = evaluator.evaluate(&rules, &registry, now);
+    assert_eq!(AlertStatus::Firing, alerts[0].status);
+
+    // the device is removed while the alert is firing
+    let alerts = evaluator.evaluate(&rules, &Registry::default(), now);
+    assert_eq!(1, alerts.len());
+    assert_eq!(AlertStatus::Resolved, alerts[0].status);
+    assert_eq!(2500.0, alerts[0].value);
+    assert!(evaluator
+        .evaluate(&rules, &Registry::default(), now)
+        .is_empty());
+}
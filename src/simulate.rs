@@ -0,0 +1,362 @@
+use async_stream::stream;
+use clap::Args;
+use color_eyre::Result;
+use jiff::Zoned;
+use rumqttc::{Publish, QoS};
+use serde_json::json;
+use std::f64::consts::TAU;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+use tokio_stream::Stream;
+
+/// The virtual devices to simulate
+#[derive(Args, Debug, Clone, Copy)]
+pub struct Simulation {
+    /// Number of tasmota plugs with power monitoring
+    #[arg(long, default_value_t = 3)]
+    pub plugs: usize,
+    /// Number of tasmota climate sensors
+    #[arg(long, default_value_t = 2)]
+    pub sensors: usize,
+    /// Number of rtl_433 outdoor sensors
+    #[arg(long, default_value_t = 1)]
+    pub rf: usize,
+    /// Number of DSMR smart meters
+    #[arg(long, default_value_t = 1)]
+    pub meters: usize,
+    /// Seconds between the updates of every device
+    #[arg(long, default_value_t = 10)]
+    pub interval: u64,
+}
+
+/// Appliances behind the simulated plugs, with their power in watts while on and the chance to switch per update
+const APPLIANCES: &[(&str, f64, f64)] = &[
+    ("Washing machine", 1900.0, 0.05),
+    ("Fridge", 120.0, 0.2),
+    ("Television", 85.0, 0.02),
+    ("Dishwasher", 1750.0, 0.04),
+    ("Desk lamp", 9.0, 0.03),
+];
+
+const ROOMS: &[&str] = &["Living room", "Bedroom", "Kitchen", "Attic", "Office"];
+
+/// Small xorshift generator, the simulation doesn't need good randomness
+struct Noise(u64);
+
+impl Noise {
+    /// Uniformly distributed in `0..1`
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniformly distributed in `-range..range`
+    fn around(&mut self, range: f64) -> f64 {
+        (self.next() * 2.0 - 1.0) * range
+    }
+}
+
+fn round(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+struct Plug {
+    hostname: String,
+    name: &'static str,
+    power: f64,
+    switch_chance: f64,
+    on: bool,
+    /// kWh
+    total: f64,
+}
+
+struct Climate {
+    hostname: String,
+    name: String,
+    offset: f64,
+}
+
+struct Meter {
+    name: String,
+    /// kWh
+    low: f64,
+    /// kWh
+    high: f64,
+    /// m³
+    gas: f64,
+    /// m³
+    water: f64,
+}
+
+/// State of the simulated devices
+struct Simulator {
+    interval: u64,
+    noise: Noise,
+    uptime: u64,
+    plugs: Vec<Plug>,
+    sensors: Vec<Climate>,
+    rf: usize,
+    meters: Vec<Meter>,
+}
+
+impl Simulator {
+    fn new(simulation: Simulation, seed: u64) -> Self {
+        let plugs = (0..simulation.plugs)
+            .map(|i| {
+                let (name, power, switch_chance) = APPLIANCES[i % APPLIANCES.len()];
+                Plug {
+                    hostname: format!("simulated_plug_{}", i + 1),
+                    name,
+                    power,
+                    switch_chance,
+                    on: false,
+                    total: 100.0 * (i + 1) as f64,
+                }
+            })
+            .collect();
+        let sensors = (0..simulation.sensors)
+            .map(|i| Climate {
+                hostname: format!("simulated_climate_{}", i + 1),
+                name: ROOMS[i % ROOMS.len()].into(),
+                offset: i as f64 * 0.8 - 1.0,
+            })
+            .collect();
+        let meters = (0..simulation.meters)
+            .map(|i| Meter {
+                name: match i {
+                    0 => "dsmr".into(),
+                    _ => format!("dsmr_{}", i + 1),
+                },
+                low: 4000.0,
+                high: 3500.0,
+                gas: 2000.0,
+                water: 500.0,
+            })
+            .collect();
+        Simulator {
+            interval: simulation.interval.max(1),
+            noise: Noise(seed | 1),
+            uptime: 0,
+            plugs,
+            sensors,
+            rf: simulation.rf,
+            meters,
+        }
+    }
+
+    /// The messages devices send when they come online
+    fn announce(&self) -> Vec<Publish> {
+        let tasmota = self
+            .plugs
+            .iter()
+            .map(|plug| (&plug.hostname, plug.name))
+            .chain(
+                self.sensors
+                    .iter()
+                    .map(|sensor| (&sensor.hostname, sensor.name.as_str())),
+            );
+        let mut messages = Vec::new();
+        for (hostname, name) in tasmota {
+            messages.push(message(format!("tele/{hostname}/LWT"), "Online".into()));
+            messages.push(message(
+                format!("stat/{hostname}/RESULT"),
+                json!({ "DeviceName": name }).to_string(),
+            ));
+        }
+        messages
+    }
+
+    /// Advance the simulation by one interval, with `hour` the local time of day in hours
+    fn update(&mut self, time: &str, hour: f64) -> Vec<Publish> {
+        self.uptime += self.interval;
+        let hours = self.interval as f64 / 3600.0;
+        // warmest in the afternoon, coldest at night
+        let daily = ((hour - 9.0) / 24.0 * TAU).sin();
+        let mut messages = Vec::new();
+
+        let mut total_power = 0.0;
+        for plug in &mut self.plugs {
+            let switched = self.noise.next() < plug.switch_chance;
+            if switched {
+                plug.on = !plug.on;
+            }
+            let state = if plug.on { "ON" } else { "OFF" };
+            if switched {
+                messages.push(message(
+                    format!("stat/{}/RESULT", plug.hostname),
+                    json!({ "POWER": state }).to_string(),
+                ));
+            }
+            let power = match plug.on {
+                true => round(plug.power * (1.0 + self.noise.around(0.05)), 0),
+                false => 0.0,
+            };
+            total_power += power;
+            plug.total += power * hours / 1000.0;
+            let voltage = round(230.0 + self.noise.around(3.0), 0);
+            let rssi = 70 + (self.noise.next() * 20.0) as u8;
+            messages.push(message(
+                format!("tele/{}/STATE", plug.hostname),
+                json!({
+                    "Time": time,
+                    "UptimeSec": self.uptime,
+                    "POWER": state,
+                    "Wifi": { "RSSI": rssi },
+                })
+                .to_string(),
+            ));
+            messages.push(message(
+                format!("tele/{}/SENSOR", plug.hostname),
+                json!({
+                    "Time": time,
+                    "ENERGY": {
+                        "Total": round(plug.total, 3),
+                        "Power": power,
+                        "Voltage": voltage,
+                        "Current": round(power / voltage, 3),
+                    },
+                })
+                .to_string(),
+            ));
+        }
+
+        for sensor in &self.sensors {
+            let temperature = 20.5 + sensor.offset + daily + self.noise.around(0.1);
+            let humidity = 50.0 - daily * 5.0 + self.noise.around(0.5);
+            messages.push(message(
+                format!("tele/{}/SENSOR", sensor.hostname),
+                json!({
+                    "Time": time,
+                    "BME280": {
+                        "Temperature": round(temperature, 1),
+                        "Humidity": round(humidity, 1),
+                        "Pressure": round(1013.0 + self.noise.around(0.3), 1),
+                    },
+                    "PressureUnit": "hPa",
+                    "TempUnit": "C",
+                })
+                .to_string(),
+            ));
+        }
+
+        let outdoor = 10.0 + daily * 6.0;
+        for id in 1..=self.rf {
+            let temperature = outdoor + id as f64 * 0.3 + self.noise.around(0.2);
+            let humidity = 75.0 - daily * 15.0 + self.noise.around(1.0);
+            for (field, value) in [
+                ("id", id.to_string()),
+                ("channel", "1".into()),
+                ("battery_ok", "1".into()),
+                ("temperature_C", round(temperature, 1).to_string()),
+                ("humidity", round(humidity, 0).to_string()),
+                ("mic", "CHECKSUM".into()),
+            ] {
+                messages.push(message(format!("rtl_433/Simulated-TH/{field}"), value));
+            }
+        }
+
+        // the low tariff applies at night
+        let low_tariff = !(7.0..23.0).contains(&hour);
+        for meter in &mut self.meters {
+            let power = total_power / 1000.0 + 0.15 + self.noise.around(0.02);
+            let energy = power * hours;
+            if low_tariff {
+                meter.low += energy;
+            } else {
+                meter.high += energy;
+            }
+            // heating when it's cold outside
+            meter.gas += (15.0 - outdoor).max(0.0) * 0.02 * hours;
+            if self.noise.next() < 0.1 {
+                meter.water += self.noise.next() * 0.01;
+            }
+            for (field, value) in [
+                ("energy_delivered_tariff1", meter.low),
+                ("energy_delivered_tariff2", meter.high),
+                ("power_delivered_l1", power),
+                ("gas_delivered", meter.gas),
+                ("water", meter.water),
+            ] {
+                messages.push(message(
+                    format!("{}/{field}", meter.name),
+                    round(value, 3).to_string(),
+                ));
+            }
+        }
+
+        messages
+    }
+}
+
+fn message(topic: String, payload: String) -> Publish {
+    Publish::new(topic, QoS::AtMostOnce, payload)
+}
+
+/// Messages from the simulated devices, updated every interval
+pub fn simulate(simulation: Simulation) -> impl Stream<Item = Result<Publish>> {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut simulator = Simulator::new(simulation, seed);
+    stream! {
+        for message in simulator.announce() {
+            yield Ok(message);
+        }
+        let mut ticks = interval(Duration::from_secs(simulator.interval));
+        loop {
+            ticks.tick().await;
+            let now = Zoned::now();
+            let time = now.strftime("%Y-%m-%dT%H:%M:%S").to_string();
+            let hour = now.hour() as f64 + now.minute() as f64 / 60.0;
+            for message in simulator.update(&time, hour) {
+                yield Ok(message);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_simulated_messages() {
+    use crate::device::DeviceStates;
+    use crate::payload::TasmotaPayload;
+    use crate::topic::Topic;
+
+    let simulation = Simulation {
+        plugs: 2,
+        sensors: 1,
+        rf: 1,
+        meters: 1,
+        interval: 10,
+    };
+    let mut simulator = Simulator::new(simulation, 1234);
+    let mut states = DeviceStates::default();
+    let messages = simulator.announce().into_iter();
+    let messages = messages.chain((0..100).flat_map(|i| simulator.update("", i as f64 / 10.0)));
+    for message in messages {
+        let payload = std::str::from_utf8(&message.payload).unwrap();
+        match Topic::from(message.topic.as_str()) {
+            Topic::Lwt(_) => {}
+            Topic::Result(hostname) | Topic::State(hostname) | Topic::Sensor(hostname) => {
+                states
+                    .update(hostname, TasmotaPayload::parse(payload).unwrap())
+                    .unwrap();
+            }
+            Topic::Rtl(receiver, device, field) => {
+                states.update_rtl(receiver.unwrap_or_default(), device, field, payload)
+            }
+            topic => {
+                let ty = topic.dsmr_type().unwrap();
+                states
+                    .update_dsmr(topic.hostname().unwrap(), ty, payload)
+                    .unwrap();
+            }
+        }
+    }
+    assert_eq!(3, states.devices.len());
+    assert_eq!(1, states.rf_temp_devices.len());
+    assert_eq!(1, states.dsmr_devices.len());
+}
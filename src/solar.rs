@@ -0,0 +1,112 @@
+use crate::device::Readings;
+use crate::metrics::{self, MetricFamily, Registry};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Value published by an inverter bridge, such as SolarAssistant, on its own topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SolarValue {
+    PvPower,
+    BatteryVoltage,
+    BatteryStateOfCharge,
+    BatteryPower,
+    GridPower,
+    GridVoltage,
+    GridFrequency,
+    LoadPower,
+}
+
+impl SolarValue {
+    const ALL: [SolarValue; 8] = [
+        SolarValue::PvPower,
+        SolarValue::BatteryVoltage,
+        SolarValue::BatteryStateOfCharge,
+        SolarValue::BatteryPower,
+        SolarValue::GridPower,
+        SolarValue::GridVoltage,
+        SolarValue::GridFrequency,
+        SolarValue::LoadPower,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SolarValue::PvPower => "pv_power",
+            SolarValue::BatteryVoltage => "battery_voltage",
+            SolarValue::BatteryStateOfCharge => "battery_state_of_charge",
+            SolarValue::BatteryPower => "battery_power",
+            SolarValue::GridPower => "grid_power",
+            SolarValue::GridVoltage => "grid_voltage",
+            SolarValue::GridFrequency => "grid_frequency",
+            SolarValue::LoadPower => "load_power",
+        }
+    }
+
+    fn family(&self) -> &'static MetricFamily {
+        match self {
+            SolarValue::PvPower => &metrics::SOLAR_PV_POWER,
+            SolarValue::BatteryVoltage => &metrics::SOLAR_BATTERY_VOLTAGE,
+            SolarValue::BatteryStateOfCharge => &metrics::SOLAR_BATTERY_STATE_OF_CHARGE,
+            SolarValue::BatteryPower => &metrics::SOLAR_BATTERY_POWER,
+            SolarValue::GridPower => &metrics::SOLAR_GRID_POWER,
+            SolarValue::GridVoltage => &metrics::SOLAR_GRID_VOLTAGE,
+            SolarValue::GridFrequency => &metrics::SOLAR_GRID_FREQUENCY,
+            SolarValue::LoadPower => &metrics::SOLAR_LOAD_POWER,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SolarState {
+    pub values: BTreeMap<SolarValue, f32>,
+    pub last_seen: Instant,
+    pub revision: u64,
+}
+
+impl Default for SolarState {
+    fn default() -> Self {
+        SolarState {
+            values: BTreeMap::new(),
+            last_seen: Instant::now(),
+            revision: 0,
+        }
+    }
+}
+
+impl SolarState {
+    /// Apply a published value, returns false if the payload isn't numeric
+    pub fn update(&mut self, value: SolarValue, payload: &str) -> bool {
+        let Ok(parsed) = payload.trim().parse() else {
+            return false;
+        };
+        self.values.insert(value, parsed);
+        self.last_seen = Instant::now();
+        true
+    }
+
+    pub fn readings(&self) -> Readings {
+        SolarValue::ALL
+            .iter()
+            .map(|value| (value.as_str(), self.values.get(value).copied().into()))
+            .collect()
+    }
+}
+
+pub fn format_solar_state(registry: &mut Registry, inverter: &str, state: &SolarState) {
+    let labels = [("name", inverter)];
+    for (value, reading) in &state.values {
+        registry.add(value.family(), &labels, *reading);
+    }
+}
+
+#[test]
+fn test_solar_update() {
+    let mut state = SolarState::default();
+    assert!(state.update(SolarValue::PvPower, "1520"));
+    assert!(state.update(SolarValue::GridFrequency, "49.98\n"));
+    assert!(!state.update(SolarValue::BatteryVoltage, "unavailable"));
+    assert_eq!(Some(&1520.0), state.values.get(&SolarValue::PvPower));
+    assert_eq!(Some(&49.98), state.values.get(&SolarValue::GridFrequency));
+    assert_eq!(None, state.values.get(&SolarValue::BatteryVoltage));
+}
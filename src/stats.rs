@@ -0,0 +1,405 @@
+use crate::device::{device_entry, Device, DeviceStates};
+use crate::history::unix_time;
+use crate::metrics::{self, Histogram, Registry};
+use crate::plausibility::Quantity;
+use crate::process::format_process_stats;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Responses arriving later than this aren't counted as a response to the request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Buckets for the time between messages, around the common tele periods
+const MESSAGE_INTERVAL_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// Number of failed payloads kept for `/api/errors`
+const FAILED_PAYLOAD_LIMIT: usize = 50;
+
+/// Failed payloads are cut off after this many characters
+const FAILED_PAYLOAD_LENGTH: usize = 1024;
+
+/// Time between the messages of one type from a device
+#[derive(Debug)]
+struct MessageInterval {
+    last: Instant,
+    histogram: Histogram,
+}
+
+/// Details about a metrics response
+#[derive(Debug, Clone, Copy)]
+pub struct ScrapeStats {
+    pub duration: Duration,
+    pub series: usize,
+    pub size: usize,
+}
+
+/// A received payload that couldn't be parsed
+#[derive(Debug, Clone)]
+pub struct FailedPayload {
+    pub source: &'static str,
+    pub topic: String,
+    pub payload: String,
+    pub error: String,
+    /// Unix timestamp of when the payload was received
+    pub time: u64,
+}
+
+impl FailedPayload {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "source": self.source,
+            "topic": self.topic.as_str(),
+            "payload": self.payload.as_str(),
+            "error": self.error.as_str(),
+            "time": self.time,
+        })
+    }
+}
+
+type IntervalsByTenant =
+    BTreeMap<Option<String>, HashMap<Device, HashMap<&'static str, MessageInterval>>>;
+
+/// When the exporter was started
+#[derive(Debug)]
+struct Started(Instant);
+
+impl Default for Started {
+    fn default() -> Self {
+        Started(Instant::now())
+    }
+}
+
+/// Internal counters for the exporter itself
+#[derive(Debug, Default)]
+pub struct Stats {
+    started: Started,
+    messages: Mutex<BTreeMap<&'static str, u64>>,
+    reconnects: AtomicU64,
+    /// By source, such as `tasmota` or `dsmr`
+    parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    /// The most recent payloads that couldn't be parsed, oldest first
+    failed_payloads: Mutex<VecDeque<FailedPayload>>,
+    commands_sent: AtomicU64,
+    /// Received messages waiting to be processed
+    queued: AtomicU64,
+    /// When we first asked a device for its name or status without it responding yet
+    pending_requests: Mutex<HashMap<Device, Instant>>,
+    /// By tenant, device and topic type
+    message_intervals: Mutex<IntervalsByTenant>,
+    last_scrape: Mutex<Option<ScrapeStats>>,
+    /// Broker in use by the default namespace and every tenant, by tenant name
+    brokers: Mutex<BTreeMap<Option<String>, String>>,
+}
+
+impl Stats {
+    pub fn message_received(&self, topic_type: &'static str) {
+        *self.messages.lock().unwrap().entry(topic_type).or_default() += 1;
+    }
+
+    /// Record the time since the previous message of the same type from the device
+    pub fn device_message(&self, tenant: Option<&str>, hostname: &str, topic_type: &'static str) {
+        let now = Instant::now();
+        let mut intervals = self.message_intervals.lock().unwrap();
+        let intervals = intervals.entry(tenant.map(String::from)).or_default();
+        let intervals = device_entry(intervals, hostname);
+        match intervals.get_mut(topic_type) {
+            Some(interval) => {
+                interval
+                    .histogram
+                    .observe(now.duration_since(interval.last).as_secs_f64());
+                interval.last = now;
+            }
+            None => {
+                intervals.insert(
+                    topic_type,
+                    MessageInterval {
+                        last: now,
+                        histogram: Histogram::new(MESSAGE_INTERVAL_BUCKETS),
+                    },
+                );
+            }
+        }
+    }
+
+    pub fn message_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_error(
+        &self,
+        source: &'static str,
+        topic: &str,
+        payload: &str,
+        error: impl Display,
+    ) {
+        *self.parse_errors.lock().unwrap().entry(source).or_default() += 1;
+        let mut failed = self.failed_payloads.lock().unwrap();
+        if failed.len() >= FAILED_PAYLOAD_LIMIT {
+            failed.pop_front();
+        }
+        failed.push_back(FailedPayload {
+            source,
+            topic: topic.into(),
+            payload: payload.chars().take(FAILED_PAYLOAD_LENGTH).collect(),
+            error: format!("{error:#}"),
+            time: unix_time(),
+        });
+    }
+
+    /// The most recent payloads that couldn't be parsed, newest first
+    pub fn failed_payloads(&self) -> Vec<FailedPayload> {
+        let failed = self.failed_payloads.lock().unwrap();
+        failed.iter().rev().cloned().collect()
+    }
+
+    pub fn command_sent(&self) {
+        self.commands_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_sent(&self, device: &Device) {
+        let mut pending = self.pending_requests.lock().unwrap();
+        let sent = pending.entry(device.clone()).or_insert_with(Instant::now);
+        if sent.elapsed() > RESPONSE_TIMEOUT {
+            *sent = Instant::now();
+        }
+    }
+
+    /// Time since the oldest unanswered request to the device
+    pub fn response_received(&self, hostname: &str) -> Option<Duration> {
+        let sent = self.pending_requests.lock().unwrap().remove(hostname)?;
+        Some(sent.elapsed()).filter(|latency| *latency <= RESPONSE_TIMEOUT)
+    }
+
+    pub fn broker_selected(&self, tenant: Option<&str>, broker: String) {
+        let mut brokers = self.brokers.lock().unwrap();
+        brokers.insert(tenant.map(String::from), broker);
+    }
+
+    pub fn scraped(&self, scrape: ScrapeStats) {
+        *self.last_scrape.lock().unwrap() = Some(scrape);
+    }
+}
+
+pub fn format_stats(registry: &mut Registry, stats: &Stats, device_states: &DeviceStates) {
+    registry.add(
+        &metrics::UPTIME,
+        &[],
+        stats.started.0.elapsed().as_secs_f64(),
+    );
+    format_process_stats(registry);
+    for (topic_type, count) in stats.messages.lock().unwrap().iter() {
+        registry.add(
+            &metrics::MQTT_MESSAGES,
+            &[("topic_type", topic_type)],
+            *count,
+        );
+    }
+    registry.add(
+        &metrics::MQTT_QUEUE_DEPTH,
+        &[],
+        stats.queued.load(Ordering::Relaxed),
+    );
+    registry.add(
+        &metrics::MQTT_RECONNECTS,
+        &[],
+        stats.reconnects.load(Ordering::Relaxed),
+    );
+    for (tenant, broker) in stats.brokers.lock().unwrap().iter() {
+        match tenant {
+            Some(tenant) => registry.add(
+                &metrics::MQTT_BROKER,
+                &[("broker", broker.as_str()), ("tenant", tenant.as_str())],
+                1,
+            ),
+            None => registry.add(&metrics::MQTT_BROKER, &[("broker", broker.as_str())], 1),
+        }
+    }
+    for (source, count) in stats.parse_errors.lock().unwrap().iter() {
+        registry.add(&metrics::PARSE_ERRORS, &[("source", source)], *count);
+    }
+    registry.add(
+        &metrics::COMMANDS_SENT,
+        &[],
+        stats.commands_sent.load(Ordering::Relaxed),
+    );
+    if let Some(scrape) = *stats.last_scrape.lock().unwrap() {
+        registry.add(
+            &metrics::SCRAPE_DURATION,
+            &[],
+            scrape.duration.as_secs_f64(),
+        );
+        registry.add(&metrics::SCRAPE_SERIES, &[], scrape.series);
+        registry.add(&metrics::SCRAPE_SIZE, &[], scrape.size);
+    }
+    // counted on every scrape, so devices removed by the retention show up immediately
+    for (class, count) in [
+        ("tasmota", device_states.devices.len()),
+        ("dsmr", device_states.dsmr_devices.len()),
+        ("otgw", device_states.otgw_devices.len()),
+        ("ebusd", device_states.ebusd_devices.len()),
+        ("solar", device_states.solar_devices.len()),
+        ("evse", device_states.evse_devices.len()),
+        ("mitemp", device_states.mi_temp_devices.len()),
+        ("rf", device_states.rf_temp_devices.len()),
+    ] {
+        registry.add(&metrics::TRACKED_DEVICES, &[("class", class)], count);
+        let dropped = device_states
+            .dropped
+            .get(class)
+            .copied()
+            .unwrap_or_default();
+        registry.add(&metrics::DEVICES_DROPPED, &[("class", class)], dropped);
+    }
+    registry.add(
+        &metrics::RF_FRAMES_REJECTED,
+        &[],
+        device_states.rejected_rf_frames,
+    );
+    for quantity in Quantity::ALL {
+        let rejected = device_states.plausibility.rejected.get(&quantity);
+        registry.add(
+            &metrics::READINGS_REJECTED,
+            &[("quantity", quantity.name())],
+            rejected.copied().unwrap_or_default(),
+        );
+    }
+
+    let mut intervals = stats.message_intervals.lock().unwrap();
+    // forget devices that are no longer tracked
+    intervals.retain(|tenant, devices| {
+        let states = match tenant {
+            Some(tenant) => device_states.tenants.get(tenant),
+            None => Some(device_states),
+        };
+        let Some(states) = states else {
+            return false;
+        };
+        devices.retain(|device, intervals| {
+            intervals.retain(|topic_type, _| states.tracks_source(&device.hostname, topic_type));
+            !intervals.is_empty()
+        });
+        !devices.is_empty()
+    });
+    for (tenant, devices) in intervals.iter() {
+        let mut devices: Vec<_> = devices
+            .iter()
+            .flat_map(|(device, intervals)| {
+                intervals.iter().map(move |(topic_type, interval)| {
+                    (device.hostname.as_ref(), *topic_type, interval)
+                })
+            })
+            .collect();
+        devices.sort_by_key(|(hostname, topic_type, _)| (*hostname, *topic_type));
+        for (hostname, topic_type, interval) in devices {
+            let labels = [("device", hostname), ("topic_type", topic_type)];
+            match tenant {
+                Some(tenant) => registry.add_histogram(
+                    &metrics::MESSAGE_INTERVAL,
+                    &[labels[0], labels[1], ("tenant", tenant.as_str())],
+                    &interval.histogram,
+                ),
+                None => {
+                    registry.add_histogram(&metrics::MESSAGE_INTERVAL, &labels, &interval.histogram)
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_response_latency() {
+    let stats = Stats::default();
+    let device = Device {
+        hostname: "plug".into(),
+    };
+    assert_eq!(None, stats.response_received("plug"));
+
+    stats.request_sent(&device);
+    std::thread::sleep(Duration::from_millis(10));
+    stats.request_sent(&device);
+    let latency = stats.response_received("plug").unwrap();
+    assert!(latency >= Duration::from_millis(10));
+    assert_eq!(None, stats.response_received("plug"));
+}
+
+#[test]
+fn test_failed_payloads() {
+    let stats = Stats::default();
+    for i in 0..(FAILED_PAYLOAD_LIMIT + 2) {
+        stats.parse_error(
+            "rf",
+            "tele/bridge/RESULT",
+            &i.to_string(),
+            "invalid rf payload",
+        );
+    }
+    stats.parse_error("dsmr", "dsmr/reading/gas", "n/a", "invalid dsmr value");
+    let failed = stats.failed_payloads();
+    assert_eq!(FAILED_PAYLOAD_LIMIT, failed.len());
+    assert_eq!("dsmr", failed[0].source);
+    assert_eq!("51", failed[1].payload);
+    assert_eq!("3", failed[FAILED_PAYLOAD_LIMIT - 1].payload);
+    assert_eq!(
+        Some(&(FAILED_PAYLOAD_LIMIT as u64 + 2)),
+        stats.parse_errors.lock().unwrap().get("rf")
+    );
+}
+
+#[test]
+fn test_message_intervals() {
+    use crate::payload::{OmgRf, TasmotaPayload};
+
+    let stats = Stats::default();
+    let mut states = DeviceStates::default();
+    let mut garage = DeviceStates::default();
+    garage
+        .update("plug", TasmotaPayload::parse(r#"{"POWER":"ON"}"#).unwrap())
+        .unwrap();
+    states.tenants.insert("garage".into(), garage);
+    let reading = r#"{"model":"Bresser-3CH","id":73,"channel":1,"temperature_C":20.5}"#;
+    states.update_omg_rf("gateway", OmgRf::parse(reading).unwrap());
+
+    for _ in 0..2 {
+        stats.device_message(Some("garage"), "plug", "state");
+        stats.device_message(None, "gateway", "omg_rf");
+        stats.device_message(None, "removed", "state");
+    }
+    let mut registry = Registry::default();
+    format_stats(&mut registry, &stats, &states);
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    let intervals: Vec<_> = out
+        .lines()
+        .filter(|line| line.starts_with("taspromto_message_interval_seconds_count"))
+        .collect();
+    assert_eq!(2, intervals.len());
+    assert!(intervals[0].contains(r#"device="gateway""#));
+    assert!(intervals[1].contains(r#"device="plug""#));
+    assert!(intervals[1].contains(r#"tenant="garage""#));
+}
+
+#[test]
+fn test_device_counts() {
+    let mut states = DeviceStates::default();
+    states.dropped.insert("rf", 3);
+    let mut registry = Registry::default();
+    format_stats(&mut registry, &Stats::default(), &states);
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+    assert!(out.contains("taspromto_tracked_devices{class=\"rf\"} 0\n"));
+    assert!(out.contains("taspromto_devices_dropped_total{class=\"rf\"} 3\n"));
+}
@@ -0,0 +1,168 @@
+use crate::config::{Config, StatsdConfig, StatsdFormat};
+use crate::device::SharedDeviceStates;
+use crate::metrics::Registry;
+use crate::stats::Stats;
+use crate::{collect_metrics, MetricsQuery};
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use tokio::net::UdpSocket;
+use tokio::time::{interval, Duration};
+use tracing::error;
+
+/// Maximum payload size of a single datagram, small enough to avoid fragmentation
+const MAX_PACKET_SIZE: usize = 1432;
+
+/// Periodically send all current readings to statsd
+pub async fn write_to_statsd(
+    statsd: StatsdConfig,
+    device_states: SharedDeviceStates,
+    config: Arc<RwLock<Config>>,
+    stats: Arc<Stats>,
+) {
+    let socket = match UdpSocket::bind("[::]:0").await {
+        Ok(socket) => socket,
+        Err(_) => match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("Failed to setup socket for statsd: {:#}", e);
+                return;
+            }
+        },
+    };
+
+    let mut interval = interval(Duration::from_secs(statsd.interval));
+    loop {
+        interval.tick().await;
+        let registry = {
+            let device_states = device_states.read().await;
+            collect_metrics(
+                &config.read().unwrap(),
+                &device_states,
+                &stats,
+                &MetricsQuery::default(),
+            )
+        };
+        let lines = format_statsd(&registry, &statsd.prefix, statsd.format);
+        for packet in packets(&lines) {
+            if let Err(e) = socket.send_to(packet.as_bytes(), &statsd.address).await {
+                error!("Failed to send to statsd: {:#}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Format all samples as statsd gauges
+fn format_statsd(registry: &Registry, prefix: &str, format: StatsdFormat) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (family, samples) in registry.families() {
+        for sample in samples {
+            let value = sample.value.0;
+            // histograms don't map to a single gauge
+            if !value.is_finite() || sample.histogram.is_some() {
+                continue;
+            }
+            let mut name = String::new();
+            if !prefix.is_empty() {
+                name.push_str(prefix);
+                name.push('.');
+            }
+            escape(&mut name, registry.name(family));
+            let mut tags = String::new();
+            for (label, label_value) in &sample.labels {
+                if label_value.is_empty() {
+                    continue;
+                }
+                match format {
+                    StatsdFormat::Datadog => {
+                        tags.push(if tags.is_empty() { '#' } else { ',' });
+                        escape(&mut tags, label);
+                        tags.push(':');
+                        escape(&mut tags, label_value);
+                    }
+                    StatsdFormat::Plain => {
+                        name.push('.');
+                        escape(&mut name, label_value);
+                    }
+                }
+            }
+            let suffix = if tags.is_empty() {
+                String::new()
+            } else {
+                format!("|{tags}")
+            };
+            // gauges starting with a sign are relative, so negative values need to be reset first
+            if value < 0.0 {
+                lines.push(format!("{name}:0|g{suffix}"));
+            }
+            let mut line = String::new();
+            write!(line, "{name}:{value}|g{suffix}").unwrap();
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Replace characters that have a meaning in the statsd protocol
+fn escape(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            ':' | '|' | '#' | ',' | '@' | '\n' | ' ' => out.push('_'),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Combine lines into as few datagrams as possible
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+#[test]
+fn test_format_statsd() {
+    use crate::metrics;
+
+    let mut registry = Registry::default();
+    registry.add(
+        &metrics::POWER_WATTS,
+        &[("tasmota_id", "plug"), ("name", "Washing Machine")],
+        12.5f32,
+    );
+    registry.add(&metrics::SENSOR_TEMPERATURE, &[("name", "")], -2.0f32);
+
+    assert_eq!(
+        vec![
+            "tasmota.power_watts:12.5|g|#tasmota_id:plug,name:Washing_Machine",
+            "tasmota.sensor_temperature:0|g",
+            "tasmota.sensor_temperature:-2|g",
+        ],
+        format_statsd(&registry, "tasmota", StatsdFormat::Datadog)
+    );
+    assert_eq!(
+        vec![
+            "power_watts.plug.Washing_Machine:12.5|g",
+            "sensor_temperature:0|g",
+            "sensor_temperature:-2|g",
+        ],
+        format_statsd(&registry, "", StatsdFormat::Plain)
+    );
+
+    let lines = vec!["a".repeat(1000), "b".repeat(400), "c".repeat(100)];
+    let packets = packets(&lines);
+    assert_eq!(2, packets.len());
+    assert_eq!(1401, packets[0].len());
+}
@@ -0,0 +1,29 @@
+use sd_notify::NotifyState;
+use std::time::Duration;
+use tracing::error;
+
+/// Let systemd know we're up, does nothing when not started as a `Type=notify` service
+pub fn notify_ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+pub fn notify_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn notify_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// How often the watchdog needs to be notified, if `WatchdogSec` is configured
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    // notify twice per timeout, so a single late tick doesn't trigger a restart
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec / 2))
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        error!("Failed to notify systemd: {:#}", e);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::device::{DeviceStates, SharedDeviceStates};
+use tokio::sync::{RwLockMappedWriteGuard, RwLockWriteGuard};
+
+/// A topic namespace whose devices are tracked separately from the other namespaces
+///
+/// The devices of the default namespace are tracked in the root of the device states,
+/// the devices of configured tenants in the `tenants` of the root.
+#[derive(Clone)]
+pub struct Tenant {
+    /// `None` for the default namespace
+    pub name: Option<String>,
+    /// Prefix of every topic in the namespace, including the trailing `/`
+    pub prefix: String,
+    pub states: SharedDeviceStates,
+}
+
+impl Tenant {
+    pub fn root(states: SharedDeviceStates) -> Self {
+        Tenant {
+            name: None,
+            prefix: String::new(),
+            states,
+        }
+    }
+
+    pub fn new(name: String, prefix: String, states: SharedDeviceStates) -> Self {
+        Tenant {
+            name: Some(name),
+            prefix,
+            states,
+        }
+    }
+
+    pub async fn write(&self) -> RwLockMappedWriteGuard<'_, DeviceStates> {
+        RwLockWriteGuard::map(self.states.write().await, |states| match &self.name {
+            Some(name) => states.tenants.entry(name.clone()).or_default(),
+            None => states,
+        })
+    }
+
+    /// The topic without the namespace prefix, `None` for topics outside the namespace
+    pub fn strip_prefix<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        topic.strip_prefix(self.prefix.as_str())
+    }
+}
+
+#[test]
+fn test_tenant_topic() {
+    let states = SharedDeviceStates::default();
+    let tenant = Tenant::new("garage".into(), "garage/".into(), states.clone());
+    assert_eq!(
+        Some("tele/plug/STATE"),
+        tenant.strip_prefix("garage/tele/plug/STATE")
+    );
+    assert_eq!(None, tenant.strip_prefix("house/tele/plug/STATE"));
+    assert_eq!(
+        Some("tele/plug/STATE"),
+        Tenant::root(states).strip_prefix("tele/plug/STATE")
+    );
+}
@@ -1,24 +1,59 @@
-use crate::device::{Device, DsmrMessageType};
+use crate::device::DsmrMessageType;
+use crate::solar::SolarValue;
 
+/// A parsed mqtt topic, borrowing the hostname from the topic
 #[derive(Debug, Eq, PartialEq)]
-pub enum Topic {
-    Lwt(Device),
-    Power(Device),
-    State(Device),
-    Sensor(Device),
-    Result(Device),
-    Other(String),
-    Status(Device),
-    Msg(Device),
-    Water(Device),
-    Gas(Device),
-    Energy1(Device),
-    Energy2(Device),
-    DsmrPower(Device),
-    Rtl(Device, String),
+pub enum Topic<'a> {
+    Lwt(&'a str),
+    Power(&'a str),
+    State(&'a str),
+    Sensor(&'a str),
+    Result(&'a str),
+    Other(&'a str),
+    Status(&'a str),
+    Msg(&'a str),
+    Water(&'a str),
+    Gas(&'a str),
+    Energy1(&'a str),
+    Energy2(&'a str),
+    DsmrPower(&'a str),
+    /// Receiver, if the topic includes it, device and field
+    Rtl(Option<&'a str>, &'a str, &'a str),
+    Otgw(&'a str, &'a str),
+    Ebusd(&'a str, &'a str),
+    /// A configured inverter topic, not parsed from the topic itself
+    Solar(&'a str, SolarValue),
+    /// A value of a configured OpenEVSE charger, with the charger name and field
+    Evse(&'a str, &'a str),
+    OmgBle(&'a str, &'a str),
+    OmgRf(&'a str),
+    /// A configured HomeWizard P1 topic
+    P1(&'a str),
+    /// The pulse count of a configured meter, with the meter name
+    Pulse(&'a str, DsmrMessageType),
 }
 
-impl Topic {
+/// A topic configured to be read as a specific source, for sources that publish on arbitrary topics
+#[derive(Debug, Eq, PartialEq)]
+pub enum MappedTopic {
+    Solar(String, SolarValue),
+    P1(String),
+    Pulse(String, DsmrMessageType),
+    Evse(String, &'static str),
+}
+
+impl MappedTopic {
+    pub fn topic(&self) -> Topic<'_> {
+        match self {
+            MappedTopic::Solar(inverter, value) => Topic::Solar(inverter, *value),
+            MappedTopic::P1(name) => Topic::P1(name),
+            MappedTopic::Pulse(name, ty) => Topic::Pulse(name, *ty),
+            MappedTopic::Evse(name, field) => Topic::Evse(name, field),
+        }
+    }
+}
+
+impl<'a> Topic<'a> {
     pub fn dsmr_type(&self) -> Option<DsmrMessageType> {
         match self {
             Topic::Water(_) => Some(DsmrMessageType::Water),
@@ -30,114 +65,219 @@ impl Topic {
         }
     }
 
-    pub fn into_device(self) -> Device {
+    /// Short name for the type of the topic, used in the exporter metrics
+    pub fn kind(&self) -> &'static str {
         match self {
-            Topic::Lwt(device) => device,
-            Topic::Power(device) => device,
-            Topic::State(device) => device,
-            Topic::Sensor(device) => device,
-            Topic::Result(device) => device,
-            Topic::Other(device) => Device { hostname: device },
-            Topic::Status(device) => device,
-            Topic::Msg(device) => device,
-            Topic::Water(device) => device,
-            Topic::Gas(device) => device,
-            Topic::Energy1(device) => device,
-            Topic::Energy2(device) => device,
-            Topic::DsmrPower(device) => device,
-            Topic::Rtl(device, _) => device,
+            Topic::Lwt(_) => "lwt",
+            Topic::Power(_) => "power",
+            Topic::State(_) => "state",
+            Topic::Sensor(_) => "sensor",
+            Topic::Result(_) => "result",
+            Topic::Other(_) => "other",
+            Topic::Status(_) => "status",
+            Topic::Msg(_) => "rflink",
+            Topic::Water(_) => "water",
+            Topic::Gas(_) => "gas",
+            Topic::Energy1(_) => "energy_tariff1",
+            Topic::Energy2(_) => "energy_tariff2",
+            Topic::DsmrPower(_) => "dsmr_power",
+            Topic::Rtl(_, _, _) => "rtl_433",
+            Topic::Otgw(_, _) => "otgw",
+            Topic::Ebusd(_, _) => "ebusd",
+            Topic::Solar(_, _) => "solar",
+            Topic::Evse(_, _) => "evse",
+            Topic::OmgBle(_, _) => "omg_ble",
+            Topic::OmgRf(_) => "omg_rf",
+            Topic::P1(_) => "p1",
+            Topic::Pulse(_, _) => "pulse",
+        }
+    }
+
+    pub fn hostname(&self) -> Option<&'a str> {
+        match *self {
+            Topic::Lwt(hostname)
+            | Topic::Power(hostname)
+            | Topic::State(hostname)
+            | Topic::Sensor(hostname)
+            | Topic::Result(hostname)
+            | Topic::Status(hostname)
+            | Topic::Msg(hostname)
+            | Topic::Water(hostname)
+            | Topic::Gas(hostname)
+            | Topic::Energy1(hostname)
+            | Topic::Energy2(hostname)
+            | Topic::DsmrPower(hostname)
+            | Topic::Rtl(None, hostname, _)
+            | Topic::Rtl(Some(hostname), _, _)
+            | Topic::Otgw(hostname, _)
+            | Topic::Ebusd(hostname, _)
+            | Topic::Solar(hostname, _)
+            | Topic::Evse(hostname, _)
+            | Topic::OmgBle(hostname, _)
+            | Topic::OmgRf(hostname)
+            | Topic::P1(hostname)
+            | Topic::Pulse(hostname, _) => Some(hostname),
+            Topic::Other(_) => None,
         }
     }
 }
 
-impl From<&str> for Topic {
-    fn from(raw: &str) -> Self {
+impl<'a> From<&'a str> for Topic<'a> {
+    fn from(raw: &'a str) -> Self {
         if let Some(rf_name) = raw.strip_suffix("/msg") {
-            let device = Device {
-                hostname: rf_name.to_string(),
-            };
-            return Topic::Msg(device);
+            return Topic::Msg(rf_name);
         }
         if let Some((device, topic)) = raw
             .strip_prefix("rtl_433/")
             .and_then(|topic| topic.split_once('/'))
         {
-            let device = Device {
-                hostname: device.to_string(),
+            // `rtl_433/<receiver>/<model>/<field>` when the receiver hostname is included in the topic
+            return match topic.split_once('/') {
+                Some((model, field)) if !field.contains('/') => {
+                    Topic::Rtl(Some(device), model, field)
+                }
+                _ => Topic::Rtl(None, device, topic),
             };
-            return Topic::Rtl(device, topic.into());
+        }
+        if let Some((device, field)) = raw
+            .strip_prefix("OTGW/value/")
+            .and_then(|topic| topic.split_once('/'))
+        {
+            return Topic::Otgw(device, field);
+        }
+        // requests from other clients are published on `ebusd/<circuit>/<field>/get`
+        if let Some((circuit, field)) = raw
+            .strip_prefix("ebusd/")
+            .and_then(|topic| topic.split_once('/'))
+            .filter(|(_, field)| !field.contains('/'))
+        {
+            return Topic::Ebusd(circuit, field);
+        }
+        // openmqttgateway, `<base>/<gateway>/BTtoMQTT/<mac>` and `<base>/<gateway>/RTL_433toMQTT`
+        if let Some((base, mac)) = raw
+            .split_once("/BTtoMQTT/")
+            .filter(|(_, mac)| !mac.contains('/'))
+        {
+            return Topic::OmgBle(gateway_name(base), mac);
+        }
+        if let Some(base) = raw
+            .strip_suffix("/RTL_433toMQTT")
+            .or_else(|| raw.strip_suffix("/433toMQTT"))
+        {
+            return Topic::OmgRf(gateway_name(base));
         }
         if let Some(name) = raw.strip_suffix("/water") {
-            let device = Device {
-                hostname: name.to_string(),
-            };
-            return Topic::Water(device);
+            return Topic::Water(name);
         }
         if let Some(name) = raw.strip_suffix("/gas_delivered") {
-            let device = Device {
-                hostname: name.to_string(),
-            };
-            return Topic::Gas(device);
+            return Topic::Gas(name);
         }
         if let Some(name) = raw.strip_suffix("/energy_delivered_tariff1") {
-            let device = Device {
-                hostname: name.to_string(),
-            };
-            return Topic::Energy1(device);
+            return Topic::Energy1(name);
         }
         if let Some(name) = raw.strip_suffix("/energy_delivered_tariff2") {
-            let device = Device {
-                hostname: name.to_string(),
-            };
-            return Topic::Energy2(device);
+            return Topic::Energy2(name);
         }
         if let Some(name) = raw.strip_suffix("/power_delivered_l1") {
-            let device = Device {
-                hostname: name.to_string(),
-            };
-            return Topic::DsmrPower(device);
+            return Topic::DsmrPower(name);
         }
-
         let mut parts = raw.split('/');
         if let (Some(prefix), Some(hostname), Some(cmd)) =
             (parts.next(), parts.next(), parts.next())
         {
-            let device = Device {
-                hostname: hostname.to_string(),
-            };
             match (prefix, cmd) {
-                ("tele", "LWT") => Topic::Lwt(device),
-                ("tele", "STATE") => Topic::State(device),
-                ("stat", "POWER") => Topic::Power(device),
-                ("tele", "SENSOR") => Topic::Sensor(device),
-                ("stat", "RESULT") => Topic::Result(device),
-                ("stat", "STATUS") => Topic::Status(device),
-                ("stat", "STATUS2") => Topic::Status(device),
-                _ => Topic::Other(raw.to_string()),
+                ("tele", "LWT") => Topic::Lwt(hostname),
+                ("tele", "STATE") => Topic::State(hostname),
+                ("stat", "POWER") => Topic::Power(hostname),
+                ("tele", "SENSOR") => Topic::Sensor(hostname),
+                // rules can publish their variables and events on the telemetry topic
+                ("stat" | "tele", "RESULT") => Topic::Result(hostname),
+                // responses to `Status <n>`, such as `STATUS5` for the network or `STATUS11` for the state
+                ("stat", status)
+                    if status
+                        .strip_prefix("STATUS")
+                        .is_some_and(|n| n.bytes().all(|c| c.is_ascii_digit())) =>
+                {
+                    Topic::Status(hostname)
+                }
+                _ => Topic::Other(raw),
             }
         } else {
-            Topic::Other(raw.to_string())
+            Topic::Other(raw)
         }
     }
 }
 
+fn gateway_name(base: &str) -> &str {
+    base.rsplit('/').next().unwrap_or(base)
+}
+
 #[test]
 fn parse_topic() {
-    let device = Device {
-        hostname: "hostname".to_string(),
-    };
-    assert_eq!(Topic::Lwt(device.clone()), Topic::from("tele/hostname/LWT"));
+    assert_eq!(Topic::Lwt("hostname"), Topic::from("tele/hostname/LWT"));
+    assert_eq!(Topic::Power("hostname"), Topic::from("stat/hostname/POWER"));
+    assert_eq!(Topic::State("hostname"), Topic::from("tele/hostname/STATE"));
     assert_eq!(
-        Topic::Power(device.clone()),
-        Topic::from("stat/hostname/POWER")
+        Topic::Sensor("hostname"),
+        Topic::from("tele/hostname/SENSOR")
     );
     assert_eq!(
-        Topic::State(device.clone()),
-        Topic::from("tele/hostname/STATE")
+        Topic::Status("hostname"),
+        Topic::from("stat/hostname/STATUS8")
     );
     assert_eq!(
-        Topic::Sensor(device.clone()),
-        Topic::from("tele/hostname/SENSOR")
+        Topic::Status("hostname"),
+        Topic::from("stat/hostname/STATUS11")
+    );
+    assert_eq!(
+        Topic::Status("hostname"),
+        Topic::from("stat/hostname/STATUS")
+    );
+    assert_eq!(
+        Topic::Other("stat/hostname/STATUSX"),
+        Topic::from("stat/hostname/STATUSX")
+    );
+    assert_eq!(
+        Topic::Result("hostname"),
+        Topic::from("stat/hostname/RESULT")
+    );
+    assert_eq!(
+        Topic::Result("hostname"),
+        Topic::from("tele/hostname/RESULT")
+    );
+    assert_eq!(
+        Topic::Rtl(None, "Acurite-Tower", "temperature_F"),
+        Topic::from("rtl_433/Acurite-Tower/temperature_F")
+    );
+    assert_eq!(
+        Topic::Rtl(Some("attic"), "Acurite-Tower", "temperature_F"),
+        Topic::from("rtl_433/attic/Acurite-Tower/temperature_F")
+    );
+    assert_eq!(
+        Topic::Otgw("otgw-1234", "Tboiler"),
+        Topic::from("OTGW/value/otgw-1234/Tboiler")
+    );
+    assert_eq!(
+        Topic::Ebusd("hmu", "FlowTemp"),
+        Topic::from("ebusd/hmu/FlowTemp")
+    );
+    assert_eq!(
+        Topic::Other("ebusd/hmu/FlowTemp/get"),
+        Topic::from("ebusd/hmu/FlowTemp/get")
+    );
+    assert_eq!(Topic::Other("openevse/amp"), Topic::from("openevse/amp"));
+    assert_eq!(Topic::Other("weather/temp"), Topic::from("weather/temp"));
+    assert_eq!(
+        Topic::Other("meter/gas_pulse"),
+        Topic::from("meter/gas_pulse")
+    );
+    assert_eq!(
+        Topic::OmgBle("OMG_ESP32_BLE", "A4C138123456"),
+        Topic::from("home/OMG_ESP32_BLE/BTtoMQTT/A4C138123456")
+    );
+    assert_eq!(
+        Topic::OmgRf("OMG_lilygo_rtl_433"),
+        Topic::from("home/OMG_lilygo_rtl_433/RTL_433toMQTT")
     );
-    assert_eq!(Topic::Result(device), Topic::from("stat/hostname/RESULT"));
+    assert_eq!(Topic::Other("foo/bar"), Topic::from("foo/bar"));
 }